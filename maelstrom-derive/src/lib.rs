@@ -0,0 +1,62 @@
+//! Derive macro for the bodies of Maelstrom messages, see `maelstrom::body::MaelstromBody`.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitStr};
+
+/// Derives `maelstrom::body::MaelstromBody`, the message type of a body and of its replies.
+///
+/// The type is the name of the struct in snake case, `CommitOffsets` is `commit_offsets`,
+/// unless set with `#[maelstrom(type = "...")]`. Replies are of the type with `_ok` appended,
+/// unless set with `#[maelstrom(reply = "...")]`.
+#[proc_macro_derive(MaelstromBody, attributes(maelstrom))]
+pub fn derive_maelstrom_body(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let (mut typ, mut reply) = (None, None);
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("maelstrom"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("type") {
+                typ = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("reply") {
+                reply = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else {
+                return Err(meta.error("expected `type` or `reply`"));
+            }
+            Ok(())
+        })?;
+    }
+    let typ = typ.unwrap_or_else(|| snake_case(&input.ident.to_string()));
+    let reply = reply.unwrap_or_else(|| format!("{typ}_ok"));
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::maelstrom::body::MaelstromBody for #name #ty_generics #where_clause {
+            const TYPE: &'static str = #typ;
+            const REPLY_TYPE: &'static str = #reply;
+        }
+    })
+}
+
+/// `name` in snake case, an underscore before every uppercase letter but the first.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
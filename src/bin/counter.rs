@@ -0,0 +1,8 @@
+use anyhow::Result;
+use clap::Parser;
+use maelstrom::workload::{self, Options, Workload};
+
+fn main() -> Result<()> {
+    maelstrom::logging::init()?;
+    workload::run(Workload::GCounter, Options::parse())
+}
@@ -0,0 +1,120 @@
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    message::Body,
+    node::{replying_typed_handler, Context, Handler},
+};
+
+/// Derives [`MaelstromBody`] for a struct, see its documentation.
+pub use maelstrom_derive::MaelstromBody;
+
+/// The body of a message type, with the type of its replies.
+///
+/// Derive it with `#[derive(MaelstromBody)]`, the type is the name of the struct in snake case
+/// and replies are of the type with `_ok` appended, unless set with
+/// `#[maelstrom(type = "...", reply = "...")]`:
+///
+/// ```
+/// use maelstrom::body::MaelstromBody;
+///
+/// #[derive(MaelstromBody)]
+/// struct CommitOffsets {}
+///
+/// #[derive(MaelstromBody)]
+/// #[maelstrom(type = "txn", reply = "txn_ok")]
+/// struct Transaction {}
+///
+/// assert_eq!(CommitOffsets::TYPE, "commit_offsets");
+/// assert_eq!(CommitOffsets::REPLY_TYPE, "commit_offsets_ok");
+/// assert_eq!(Transaction::TYPE, "txn");
+/// ```
+pub trait MaelstromBody {
+    /// Type of the messages with this body.
+    const TYPE: &'static str;
+    /// Type of the replies to them.
+    const REPLY_TYPE: &'static str;
+}
+
+/// A typed handler (see [`typed_handler`](crate::node::typed_handler)) for requests whose body
+/// is a `Req`, with the type it handles, to register it for that type. Replies are of
+/// `Req::REPLY_TYPE`.
+pub fn handler<'a, Req, Resp, F>(handler: F) -> (String, Handler<'a>)
+where
+    Req: MaelstromBody + DeserializeOwned,
+    Resp: Serialize,
+    F: Fn(&Context, Req) -> Result<Resp> + Send + Sync + 'a,
+{
+    (
+        Req::TYPE.to_string(),
+        replying_typed_handler(Req::REPLY_TYPE.to_string(), handler),
+    )
+}
+
+/// The body of a message of type `T::TYPE` with the fields of `value`, to send it.
+pub fn to_body<T: MaelstromBody + Serialize>(value: &T) -> Result<Body> {
+    match serde_json::to_value(value)? {
+        Value::Object(extra) => Ok(Body {
+            typ: T::TYPE.to_string(),
+            extra,
+            ..Default::default()
+        }),
+        other => Err(anyhow!(
+            "Internal: {} body must serialize as an object, got {other}",
+            T::TYPE
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::body::{to_body, MaelstromBody};
+    use crate::message::Message;
+    use crate::node::Node;
+
+    #[derive(Debug, Serialize, Deserialize, MaelstromBody)]
+    #[maelstrom(reply = "added")]
+    struct AddDelta {
+        delta: u64,
+    }
+
+    #[derive(Serialize)]
+    struct Total {
+        total: u64,
+    }
+
+    #[test]
+    fn derived_bodies_register_and_send() -> Result<()> {
+        let node = Node::builder()
+            .handle_request(|_, add: AddDelta| {
+                Ok(Total {
+                    total: add.delta + 1,
+                })
+            })
+            .build()?;
+        node.handle(serde_json::from_value(json!({
+            "src": "c0", "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }
+        }))?)?;
+        let body = to_body(&AddDelta { delta: 2 })?;
+
+        let reply = node.handle(Message {
+            src: "c1".into(),
+            dest: "n1".into(),
+            body: crate::message::Body { msg_id: 2, ..body },
+        })?;
+
+        assert_eq!(
+            (AddDelta::TYPE, AddDelta::REPLY_TYPE),
+            ("add_delta", "added")
+        );
+        assert_eq!(reply.body.typ, "added");
+        assert_eq!(reply.body.extra["total"], 3);
+        Ok(())
+    }
+}
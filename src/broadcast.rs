@@ -0,0 +1,1271 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{
+    body, checksum, compress,
+    digest::Digest,
+    error::NodeError,
+    message::{Body, Message},
+    node::{no_reply, Handler, Topology},
+    outbox::Outbox,
+    persistence::Persist,
+    reply, watermark,
+};
+
+/// Broadcast workload, every message broadcast to any node is eventually read from every node.
+///
+/// Nodes gossip messages to their neighbors on a timer, see [`Broadcast::gossip`]. Neighbors are
+/// every other node until a `topology` message says otherwise. A node tracks which messages
+/// every neighbor is known to have, and only gossips the ones it is missing. Gossip goes
+/// through an [`Outbox`], so it is sent again until the neighbor acks it with its `gossip_ok`,
+/// and the messages in it are then known to the neighbor. Messages so make it across
+/// partitions once they heal, and every message crosses every link about once. Should gossip
+/// still be lost, nodes also sync every message with a random peer now and then, see
+/// [`Broadcast::anti_entropy`].
+///
+/// With [`Broadcast::with_batched_acks`] gossip is not acked right away, every gossip received
+/// from a neighbor since the last round is acked by one `gossip_ok` listing their msg_ids, see
+/// [`Outbox::ack`], so a burst of gossip costs a single ack. Gossip below the
+/// [`watermark`] of its sender is not acked again.
+#[derive(Debug)]
+pub struct Broadcast {
+    // ID of this node, set on init.
+    node_id: Mutex<String>,
+    // Every message seen so far.
+    messages: Mutex<Box<dyn BroadcastStore>>,
+    // Nodes messages are gossiped to.
+    neighbors: Mutex<Vec<String>>,
+    // Messages every node is known to have, because it sent them or acked them.
+    known: Mutex<Known>,
+    // Gossip not acked yet, the dest and messages of it keyed by msg_id.
+    in_flight: Mutex<HashMap<u64, (String, Vec<u64>)>>,
+    outbox: Arc<Outbox>,
+    // How many neighbors are gossiped to each round.
+    fanout: Fanout,
+    // Every other node in the cluster, set on init.
+    peers: Mutex<Vec<String>>,
+    // Who messages are gossiped to.
+    mode: GossipMode,
+    // Messages still spread in epidemic mode, with the number of rounds they still are.
+    rumors: Mutex<HashMap<u64, usize>>,
+    // msg_id of the last anti-entropy sync sent, see `Broadcast::anti_entropy`.
+    syncing: Mutex<Option<u64>>,
+    // Whether gossip is acked once a round, see `Broadcast::ack_gossip`.
+    batched_acks: bool,
+    // msg_ids of the gossip received from every node and not acked yet.
+    unacked_gossip: Mutex<HashMap<String, Vec<u64>>>,
+    // Whether gossip is compressed to peers that accept it, see `Broadcast::with_compression`.
+    compression: bool,
+    // Peers that advertised they accept compressed gossip.
+    compressing: Mutex<HashSet<String>>,
+}
+
+/// The messages every peer is known to have. Once every peer is known to have a message it is
+/// only kept once, for all of them, so the sets of the peers only hold the messages that have
+/// not made it everywhere yet instead of growing with every message for the whole run.
+#[derive(Debug, Default)]
+struct Known {
+    everywhere: BTreeSet<u64>,
+    by_peer: HashMap<String, BTreeSet<u64>>,
+}
+
+impl Known {
+    /// Whether `peer` is known to have `message`.
+    fn has(&self, peer: &str, message: u64) -> bool {
+        self.everywhere.contains(&message)
+            || self
+                .by_peer
+                .get(peer)
+                .is_some_and(|known| known.contains(&message))
+    }
+
+    /// Every message `peer` is known to have.
+    fn of(&self, peer: &str) -> BTreeSet<u64> {
+        let mut known = self.everywhere.clone();
+        known.extend(self.by_peer.get(peer).into_iter().flatten());
+        known
+    }
+
+    fn add(&mut self, peer: &str, messages: impl IntoIterator<Item = u64>) {
+        let messages = messages
+            .into_iter()
+            .filter(|m| !self.everywhere.contains(m));
+        self.by_peer
+            .entry(peer.to_string())
+            .or_default()
+            .extend(messages);
+    }
+
+    /// Keeps the messages known to every one of `peers` once, for all of them.
+    fn prune(&mut self, peers: &[String]) {
+        let Some(first) = peers.first() else {
+            return;
+        };
+        let settled: Vec<u64> = self
+            .by_peer
+            .get(first)
+            .into_iter()
+            .flatten()
+            .filter(|m| peers.iter().all(|peer| self.has(peer, **m)))
+            .copied()
+            .collect();
+        if settled.is_empty() {
+            return;
+        }
+        for known in self.by_peer.values_mut() {
+            known.retain(|m| settled.binary_search(m).is_err());
+        }
+        self.everywhere.extend(settled);
+    }
+}
+
+/// Where a [`Broadcast`] keeps the messages it has seen, so other stores, e.g. sorted for range
+/// digests or persistent, can be used without changing how messages are gossiped.
+pub trait BroadcastStore: fmt::Debug + Send {
+    /// Adds `message`, returns whether it was not in the store yet.
+    fn insert(&mut self, message: u64) -> bool;
+
+    fn contains(&self, message: u64) -> bool;
+
+    /// Every message in the store.
+    fn all(&self) -> BTreeSet<u64>;
+
+    /// The messages in the store that are not in `known`, i.e. that a peer which has seen
+    /// `known` since it started is missing, in order.
+    fn missing_since(&self, known: &BTreeSet<u64>) -> Vec<u64>;
+}
+
+/// The default [`BroadcastStore`], messages in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    messages: HashSet<u64>,
+}
+
+impl BroadcastStore for MemoryStore {
+    fn insert(&mut self, message: u64) -> bool {
+        self.messages.insert(message)
+    }
+
+    fn contains(&self, message: u64) -> bool {
+        self.messages.contains(&message)
+    }
+
+    fn all(&self) -> BTreeSet<u64> {
+        self.messages.iter().copied().collect()
+    }
+
+    fn missing_since(&self, known: &BTreeSet<u64>) -> Vec<u64> {
+        let mut missing: Vec<u64> = self
+            .messages
+            .iter()
+            .filter(|m| !known.contains(m))
+            .copied()
+            .collect();
+        missing.sort();
+        missing
+    }
+}
+
+/// Number of buckets of the digest anti-entropy syncs send, once there are more messages than
+/// this, see [`Broadcast::anti_entropy`].
+pub const SYNC_BUCKETS: usize = 64;
+
+/// Who a node gossips messages to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GossipMode {
+    // Its neighbors, each sent the messages it is known to miss, until it acks them.
+    #[default]
+    Neighbors,
+    // Random peers out of every node, whatever the topology, sent every message the node is
+    // still spreading. A message is spread (infective) for a few rounds after the node learns
+    // it, log2 of the cluster size plus two, then no more (removed). Peers pushed to push back
+    // the messages they are spreading that they were not sent, so messages also spread by pull.
+    Epidemic,
+}
+
+/// How many neighbors a node gossips to each round, trading how fast messages spread for how
+/// many messages are sent per broadcast.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Fanout {
+    // Every neighbor missing messages.
+    #[default]
+    All,
+    // At most this many neighbors missing messages, picked at random every round.
+    Peers(usize),
+    // Like Peers, log2 of the cluster size of them, rounded up.
+    Log,
+}
+
+impl Fanout {
+    /// Most neighbors gossiped to each round in a cluster of `cluster_size` nodes, None if not
+    /// limited.
+    pub fn limit(self, cluster_size: usize) -> Option<usize> {
+        match self {
+            Fanout::All => None,
+            Fanout::Peers(peers) => Some(peers),
+            Fanout::Log => Some((cluster_size.max(2) as f64).log2().ceil() as usize),
+        }
+    }
+}
+
+impl FromStr for Fanout {
+    type Err = String;
+
+    /// Parses "all", "log" or a number of peers.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Fanout::All),
+            "log" => Ok(Fanout::Log),
+            peers => peers
+                .parse()
+                .map(Fanout::Peers)
+                .map_err(|e| format!("fanout must be all, log or a number of peers: {e}")),
+        }
+    }
+}
+
+impl fmt::Display for Fanout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fanout::All => write!(f, "all"),
+            Fanout::Peers(peers) => write!(f, "{peers}"),
+            Fanout::Log => write!(f, "log"),
+        }
+    }
+}
+
+/// Returns the handlers of the broadcast workload, backed by `broadcast`.
+pub fn handlers(broadcast: &Broadcast) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert(
+        "broadcast".into(),
+        Box::new(|msg, id| broadcast.broadcast(msg, id)),
+    );
+    funs.insert(
+        "gossip".into(),
+        Box::new(|msg, id| broadcast.receive_gossip(msg, id)),
+    );
+    funs.insert(
+        "sync".into(),
+        Box::new(|msg, id| broadcast.receive_sync(msg, id)),
+    );
+    funs.insert(
+        "sync_diff".into(),
+        Box::new(|msg, id| broadcast.receive_sync_diff(msg, id)),
+    );
+    funs.insert("read".into(), Box::new(|msg, id| broadcast.read(msg, id)));
+    funs.insert(
+        "topology".into(),
+        Box::new(|msg, id| broadcast.topology(msg, id)),
+    );
+    funs
+}
+
+impl Broadcast {
+    /// Creates a broadcast store that gossips messages through `outbox`.
+    pub fn new(outbox: Arc<Outbox>) -> Self {
+        Self {
+            node_id: Mutex::default(),
+            messages: Mutex::new(Box::new(MemoryStore::default())),
+            neighbors: Mutex::default(),
+            known: Mutex::default(),
+            in_flight: Mutex::default(),
+            outbox,
+            fanout: Fanout::All,
+            peers: Mutex::default(),
+            mode: GossipMode::Neighbors,
+            rumors: Mutex::default(),
+            syncing: Mutex::default(),
+            batched_acks: false,
+            unacked_gossip: Mutex::default(),
+            compression: false,
+            compressing: Mutex::default(),
+        }
+    }
+
+    /// Keeps the messages seen in `store`, instead of a [`MemoryStore`].
+    pub fn with_store(self, store: impl BroadcastStore + 'static) -> Self {
+        *self.messages.lock().unwrap() = Box::new(store);
+        self
+    }
+
+    /// Gossips in `mode`, instead of to neighbors.
+    pub fn with_mode(mut self, mode: GossipMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Who this node gossips to.
+    pub fn mode(&self) -> GossipMode {
+        self.mode
+    }
+
+    /// Gossips to as many neighbors each round as `fanout` says, instead of every neighbor.
+    pub fn with_fanout(mut self, fanout: Fanout) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    /// Acks the gossip received from every node once a round, in one cumulative ack, instead of
+    /// every gossip right away.
+    pub fn with_batched_acks(mut self) -> Self {
+        self.batched_acks = true;
+        self
+    }
+
+    /// Advertises in gossip and syncs that compressed gossip is accepted, and compresses the
+    /// messages in gossip to peers that advertised it too, see [`compress`].
+    pub fn with_compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
+    /// Makes every other node a neighbor, meant to be used as the node's init handler.
+    pub fn init(&self, node_id: &str, node_ids: &[String]) {
+        *self.node_id.lock().unwrap() = node_id.to_string();
+        let peers: Vec<String> = node_ids
+            .iter()
+            .filter(|&id| id != node_id)
+            .cloned()
+            .collect();
+        *self.neighbors.lock().unwrap() = peers.clone();
+        *self.peers.lock().unwrap() = peers;
+    }
+
+    /// Every message seen so far, in order.
+    pub fn messages(&self) -> Vec<u64> {
+        self.messages.lock().unwrap().all().into_iter().collect()
+    }
+
+    /// Sends every neighbor the messages it is missing, that are not already in gossip waiting
+    /// for its ack, returns the number of gossip messages sent. With a limited [`Fanout`], only
+    /// some of the neighbors missing messages are sent them, picked at random.
+    ///
+    /// In epidemic mode, sends the messages it is spreading to random peers instead.
+    pub fn gossip(&self, now: Instant) -> Result<usize> {
+        self.ack_gossip()?;
+        if self.mode == GossipMode::Epidemic {
+            return self.spread(now);
+        }
+        let node_id = self.node_id.lock().unwrap().clone();
+        let known = self.settle_acked();
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        let messages = self.messages.lock().unwrap();
+        let mut gossip = Vec::new();
+        for neighbor in self.neighbors.lock().unwrap().iter() {
+            let mut known = known.of(neighbor);
+            known.extend(
+                in_flight
+                    .values()
+                    .filter(|(dest, _)| dest == neighbor)
+                    .flat_map(|(_, messages)| messages.iter().copied()),
+            );
+            let missing = messages.missing_since(&known);
+            if !missing.is_empty() {
+                gossip.push((neighbor.clone(), missing));
+            }
+        }
+        let cluster_size = self.peers.lock().unwrap().len() + 1;
+        if let Some(limit) = self.fanout.limit(cluster_size) {
+            gossip.shuffle(&mut rand::thread_rng());
+            gossip.truncate(limit);
+        }
+
+        let sent = gossip.len();
+        for (neighbor, missing) in gossip {
+            let msg = Message {
+                src: node_id.clone(),
+                dest: neighbor.clone(),
+                body: self.gossip_body(&neighbor, &missing, json!({})),
+            };
+            let msg_id = self.outbox.send(msg, now)?;
+            in_flight.insert(msg_id, (neighbor, missing));
+        }
+        Ok(sent)
+    }
+
+    /// Marks the messages of every acked gossip as known to its dest, returns the messages
+    /// every node is known to have.
+    fn settle_acked(&self) -> MutexGuard<'_, Known> {
+        let mut known = self.known.lock().unwrap();
+        let mut settled = false;
+        self.in_flight
+            .lock()
+            .unwrap()
+            .retain(|&msg_id, (dest, messages)| {
+                let acked = !self.outbox.is_unacked(msg_id);
+                if acked {
+                    known.add(dest, messages.iter().copied());
+                    settled = true;
+                }
+                !acked
+            });
+        if settled {
+            known.prune(&self.peers.lock().unwrap());
+        }
+        known
+    }
+
+    /// Acks all the gossip received from every node since the last call, with one `gossip_ok`
+    /// per node in reply to its latest gossip, listing every gossip it acks. Returns the number
+    /// of acks sent. Only gossip received with batched acks waits for this.
+    pub fn ack_gossip(&self) -> Result<usize> {
+        let node_id = self.node_id.lock().unwrap().clone();
+        let unacked = std::mem::take(&mut *self.unacked_gossip.lock().unwrap());
+        let sent = unacked.len();
+        for (peer, msg_ids) in unacked {
+            let latest = msg_ids.last().copied().unwrap_or_default();
+            self.outbox.send_once(Message {
+                src: node_id.clone(),
+                dest: peer,
+                body: Body {
+                    in_reply_to: latest,
+                    ..body!("gossip_ok", { "acks": msg_ids })
+                },
+            })?;
+        }
+        Ok(sent)
+    }
+
+    /// Pushes every message being spread to as many random peers as the fanout says, and
+    /// counts down the rounds they are spread for, returns the number of gossip messages sent.
+    ///
+    /// Peers are only pushed the messages they are not known to have, because they sent them
+    /// or acked them, and peers known to have them all are not pushed at all.
+    fn spread(&self, now: Instant) -> Result<usize> {
+        let mut rumors = self.rumors.lock().unwrap();
+        if rumors.is_empty() {
+            return Ok(0);
+        }
+        let mut spreading: Vec<u64> = rumors.keys().copied().collect();
+        spreading.sort();
+        rumors.retain(|_, rounds| {
+            *rounds -= 1;
+            *rounds > 0
+        });
+        drop(rumors);
+
+        let peers = self.peers.lock().unwrap().clone();
+        let cluster_size = peers.len() + 1;
+        let known = self.settle_acked();
+        let mut pushes: Vec<(String, Vec<u64>)> = peers
+            .into_iter()
+            .map(|peer| {
+                let missing = spreading
+                    .iter()
+                    .filter(|&&m| !known.has(&peer, m))
+                    .copied()
+                    .collect();
+                (peer, missing)
+            })
+            .filter(|(_, missing): &(String, Vec<u64>)| !missing.is_empty())
+            .collect();
+        drop(known);
+        pushes.shuffle(&mut rand::thread_rng());
+        if let Some(limit) = self.fanout.limit(cluster_size) {
+            pushes.truncate(limit);
+        }
+        for (peer, missing) in &pushes {
+            self.push(peer, missing, false, now)?;
+        }
+        Ok(pushes.len())
+    }
+
+    /// Sends `messages` to `peer` in gossip, in answer to its own gossip if `pull`.
+    fn push(&self, peer: &str, messages: &[u64], pull: bool, now: Instant) -> Result<()> {
+        let gossip = Message {
+            src: self.node_id.lock().unwrap().clone(),
+            dest: peer.to_string(),
+            body: self.gossip_body(peer, messages, json!({ "pull": pull })),
+        };
+        let msg_id = self.outbox.send(gossip, now)?;
+        self.in_flight
+            .lock()
+            .unwrap()
+            .insert(msg_id, (peer.to_string(), messages.to_vec()));
+        Ok(())
+    }
+
+    /// Body of gossip of `messages` to `dest`, with the fields of `extra` and the checksum of
+    /// the messages. The messages are compressed if both ends accept it.
+    fn gossip_body(&self, dest: &str, messages: &[u64], extra: Value) -> Body {
+        let mut gossip = body!("gossip", extra);
+        if self.compression {
+            compress::accept(&mut gossip);
+        }
+        let messages = json!(messages);
+        checksum::stamp(&mut gossip, &messages);
+        let compressed = self.compression && self.compressing.lock().unwrap().contains(dest);
+        compress::insert(&mut gossip, "messages", messages, compressed);
+        gossip
+    }
+
+    /// Remembers whether the sender of `msg` accepts compressed gossip.
+    fn note_compression(&self, msg: &Message) {
+        if compress::accepts(&msg.body) {
+            self.compressing.lock().unwrap().insert(msg.src.clone());
+        }
+    }
+
+    /// Adds `messages` to the messages seen, in epidemic mode the ones not seen before are
+    /// spread.
+    fn learn(&self, messages: impl IntoIterator<Item = u64>) {
+        let mut seen = self.messages.lock().unwrap();
+        let rounds = self.infective_rounds();
+        for message in messages {
+            if seen.insert(message) && self.mode == GossipMode::Epidemic {
+                self.rumors.lock().unwrap().insert(message, rounds);
+            }
+        }
+    }
+
+    /// Number of rounds a new message is spread for in epidemic mode.
+    fn infective_rounds(&self) -> usize {
+        let cluster_size = self.peers.lock().unwrap().len() + 1;
+        (cluster_size as f64).log2().ceil() as usize + 2
+    }
+
+    /// Sends every message seen to a random peer in a `sync`, returns whether one was sent.
+    ///
+    /// The peer sends back the messages it has that are not in the sync, in gossip. This is a
+    /// safety net for gossip lost to long partitions, so a sync is only sent once the last one
+    /// is acked.
+    ///
+    /// Once there are more messages than [`SYNC_BUCKETS`], the sync has a [`Digest`] of them
+    /// instead. The peer then sends back, in a `sync_diff`, its messages in the buckets that
+    /// differ, and is sent back the messages of those buckets it is missing.
+    pub fn anti_entropy(&self, now: Instant) -> Result<bool> {
+        let mut syncing = self.syncing.lock().unwrap();
+        if syncing.is_some_and(|msg_id| self.outbox.is_unacked(msg_id)) {
+            return Ok(false);
+        }
+        let Some(peer) = self
+            .peers
+            .lock()
+            .unwrap()
+            .choose(&mut rand::thread_rng())
+            .cloned()
+        else {
+            return Ok(false);
+        };
+        let messages = self.messages.lock().unwrap().all();
+        let sync = if messages.len() > SYNC_BUCKETS {
+            json!({ "digest": Digest::new(&messages, SYNC_BUCKETS).buckets() })
+        } else {
+            json!({ "messages": messages })
+        };
+        let mut sync = Message {
+            src: self.node_id.lock().unwrap().clone(),
+            dest: peer,
+            body: body!("sync", sync),
+        };
+        if self.compression {
+            compress::accept(&mut sync.body);
+        }
+        *syncing = Some(self.outbox.send(sync, now)?);
+        Ok(true)
+    }
+
+    /// Calls [`Broadcast::anti_entropy`] every `interval` from a background thread, until syncs
+    /// cannot be sent anymore.
+    pub fn anti_entropy_every(self: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()> {
+        let broadcast = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = broadcast.anti_entropy(Instant::now()) {
+                warn!("stopped anti-entropy: {e:#}");
+                return;
+            }
+        })
+    }
+
+    /// Handles the `sync` of a peer, which has every message in it, and sends it back the
+    /// messages it is missing. A sync with a digest is answered with the messages in the
+    /// buckets that differ instead.
+    fn receive_sync(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        self.note_compression(&msg);
+        if msg.body.extra.contains_key("digest") {
+            let theirs = Digest::from_buckets(msg.body.get_as("digest")?);
+            let messages = self.messages.lock().unwrap().all();
+            let ours = Digest::new(&messages, theirs.buckets().len());
+            let differing = ours.diff(&theirs);
+            if !differing.is_empty() {
+                let in_differing: Vec<u64> = messages
+                    .into_iter()
+                    .filter(|m| differing.contains(&ours.bucket_of(m)))
+                    .collect();
+                let diff = Message {
+                    src: msg.dest.clone(),
+                    dest: msg.src.clone(),
+                    body: body!("sync_diff", {
+                        "buckets": differing,
+                        "of": ours.buckets().len(),
+                        "messages": in_differing
+                    }),
+                };
+                self.outbox.send(diff, Instant::now())?;
+            }
+            return Ok(reply!(msg, msg_id, "sync_ok", {}));
+        }
+        let theirs: BTreeSet<u64> = msg.body.get_as("messages")?;
+        let missing = self.messages.lock().unwrap().missing_since(&theirs);
+        if !missing.is_empty() {
+            self.push(&msg.src, &missing, true, Instant::now())?;
+        }
+        self.learn(theirs);
+        Ok(reply!(msg, msg_id, "sync_ok", {}))
+    }
+
+    /// Handles the `sync_diff` a peer answered a digest with: its messages in the buckets, of a
+    /// digest of `of` buckets, that differ. Sends it back the messages of those buckets it is
+    /// missing.
+    fn receive_sync_diff(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let buckets: Vec<usize> = msg.body.get_as("buckets")?;
+        let digest = Digest::from_buckets(vec![0; msg.body.get_as::<usize>("of")?]);
+        let theirs: BTreeSet<u64> = msg.body.get_as("messages")?;
+        let missing: Vec<u64> = self
+            .messages
+            .lock()
+            .unwrap()
+            .missing_since(&theirs)
+            .into_iter()
+            .filter(|m| buckets.contains(&digest.bucket_of(m)))
+            .collect();
+        if !missing.is_empty() {
+            self.push(&msg.src, &missing, true, Instant::now())?;
+        }
+        self.learn(theirs);
+        Ok(reply!(msg, msg_id, "sync_diff_ok", {}))
+    }
+
+    /// Calls [`Broadcast::gossip`] every `interval` from a background thread, until gossip
+    /// cannot be sent anymore.
+    pub fn gossip_every(self: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()> {
+        let broadcast = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = broadcast.gossip(Instant::now()) {
+                warn!("stopped gossiping: {e:#}");
+                return;
+            }
+        })
+    }
+
+    /// Handles a `broadcast` from a client.
+    fn broadcast(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let message = msg.body.get_u64("message")?;
+        self.learn([message]);
+        Ok(reply!(msg, msg_id, "broadcast_ok", {}))
+    }
+
+    /// Handles `gossip` from a neighbor, which so has every message in it.
+    ///
+    /// In epidemic mode, gossip that does not answer gossip of this node is answered with the
+    /// messages this node is spreading that the sender did not send and is not known to have.
+    fn receive_gossip(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        self.note_compression(&msg);
+        // Corrupt gossip is answered with an error the sender retries on.
+        let messages = checksum::get(&msg.body, "messages")?;
+        let messages: Vec<u64> = serde_json::from_value(messages)
+            .map_err(|e| NodeError::Malformed(format!("invalid gossip messages: {e}")))?;
+        if self.mode == GossipMode::Epidemic && msg.body.extra.get("pull") != Some(&true.into()) {
+            let known = self.settle_acked().of(&msg.src);
+            let mut missing: Vec<u64> = self
+                .rumors
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|m| !messages.contains(m) && !known.contains(m))
+                .copied()
+                .collect();
+            missing.sort();
+            if !missing.is_empty() {
+                self.push(&msg.src, &missing, true, Instant::now())?;
+            }
+        }
+        self.learn(messages.iter().copied());
+        self.known.lock().unwrap().add(&msg.src, messages);
+        if self.batched_acks {
+            let mut unacked_gossip = self.unacked_gossip.lock().unwrap();
+            let unacked = unacked_gossip.entry(msg.src.clone()).or_default();
+            // Gossip below the watermark of the sender is acked already, e.g. gossip it sent
+            // again before the ack made it, so it is not acked again.
+            if let Some(low) = watermark::of(&msg.body) {
+                unacked.retain(|&msg_id| msg_id >= low);
+            }
+            unacked.push(msg.body.msg_id);
+            return Ok(no_reply());
+        }
+        Ok(reply!(msg, msg_id, "gossip_ok", {}))
+    }
+
+    fn read(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        Ok(reply!(msg, msg_id, "read_ok", { "messages": self.messages() }))
+    }
+
+    /// Makes the neighbors of this node the ones `topology` lists for it. Can be used as the
+    /// node's topology handler, to follow topology changes made at runtime.
+    pub fn set_topology(&self, node_id: &str, topology: &Topology) -> Result<()> {
+        let neighbors = topology.get(node_id).ok_or(anyhow!(
+            "topology has no neighbors for {node_id}: {:?}",
+            topology
+        ))?;
+        *self.neighbors.lock().unwrap() = neighbors.clone();
+        Ok(())
+    }
+
+    /// Handles a `topology`, which can come more than once, see [`Broadcast::set_topology`].
+    fn topology(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        self.set_topology(&msg.dest, &msg.body.get_as("topology")?)?;
+        Ok(reply!(msg, msg_id, "topology_ok", {}))
+    }
+}
+
+impl Persist for Broadcast {
+    /// The messages seen, neighbors come from the next topology message. Restoring adds the
+    /// messages of the snapshot to the store.
+    fn snapshot(&self) -> Value {
+        json!(self.messages())
+    }
+
+    fn restore(&self, snapshot: Value) -> Result<()> {
+        let snapshot: Vec<u64> = serde_json::from_value(snapshot)?;
+        let mut messages = self.messages.lock().unwrap();
+        for message in snapshot {
+            messages.insert(message);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeSet,
+        sync::{
+            mpsc::{self, Receiver},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::broadcast::{handlers, Broadcast, BroadcastStore, Fanout, GossipMode};
+    use crate::checksum;
+    use crate::compress;
+    use crate::message::{Body, Message, MsgIds, TEMPORARILY_UNAVAILABLE};
+    use crate::node::Node;
+    use crate::outbox::Outbox;
+    use crate::watermark;
+
+    fn msg(src: &str, body: serde_json::Value) -> Message {
+        serde_json::from_value(json!({ "src": src, "dest": "n1", "body": body }))
+            .expect("invalid message json.")
+    }
+
+    /// An outbox sending to the returned receiver, with the msg IDs it shares with its node.
+    fn outbox() -> (Arc<Outbox>, Arc<MsgIds>, Receiver<Message>) {
+        let (tx, rx) = mpsc::channel();
+        let msg_ids = Arc::new(MsgIds::new());
+        let outbox = Arc::new(Outbox::new(tx, msg_ids.clone(), Duration::from_secs(1)));
+        (outbox, msg_ids, rx)
+    }
+
+    /// A node serving `broadcast`, that acks its outbox with replies, inited as `node_id` of
+    /// `node_ids`.
+    fn node_with_outbox<'a>(
+        broadcast: &'a Broadcast,
+        msg_ids: Arc<MsgIds>,
+        node_id: &str,
+        node_ids: &[&str],
+    ) -> Result<Node<'a>> {
+        let node = Node::with_init_handler(
+            handlers(broadcast),
+            Box::new(|id, ids| broadcast.init(id, ids)),
+        )?
+        .with_msg_ids(msg_ids)
+        .with_outbox(broadcast.outbox.clone());
+        node.handle(serde_json::from_value(json!({
+            "src": "c0", "dest": node_id,
+            "body": { "type": "init", "msg_id": 1, "node_id": node_id, "node_ids": node_ids }
+        }))?)?;
+        Ok(node)
+    }
+
+    #[test]
+    fn gossip_sends_only_missing_messages() -> Result<()> {
+        // Tests that neighbors are only sent messages they did not send or ack, and that gossip
+        // waiting for an ack is not sent again.
+        let (outbox, msg_ids, rx) = outbox();
+        let broadcast = Broadcast::new(outbox);
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2", "n3"])?;
+        let now = Instant::now();
+
+        node.handle(msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 2, "messages": [7] }),
+        ))?;
+        let reply = node.handle(msg(
+            "c1",
+            json!({ "type": "broadcast", "msg_id": 3, "message": 8 }),
+        ))?;
+        assert_eq!(broadcast.gossip(now)?, 2);
+        assert_eq!(broadcast.gossip(now)?, 0, "gossip is waiting for acks");
+
+        assert_eq!(reply.body.typ, "broadcast_ok");
+        let sent: Vec<Message> = rx.try_iter().collect();
+        let gossiped: Vec<(&str, &serde_json::Value)> = sent
+            .iter()
+            .map(|m| (m.dest.as_str(), &m.body.extra["messages"]))
+            .collect();
+        assert_eq!(gossiped, vec![("n2", &json!([8])), ("n3", &json!([7, 8]))]);
+
+        // n3 acks, n2 does not: n2 is not sent 8 again, n3 only gets the new message.
+        let ack = sent[1].reply_with(Body {
+            typ: "gossip_ok".into(),
+            ..Default::default()
+        });
+        assert_eq!(node.dispatch(ack)?, None);
+        node.handle(msg(
+            "c1",
+            json!({ "type": "broadcast", "msg_id": 4, "message": 9 }),
+        ))?;
+        assert_eq!(broadcast.gossip(now)?, 2);
+        let gossiped: Vec<serde_json::Value> = rx
+            .try_iter()
+            .map(|m| m.body.extra["messages"].clone())
+            .collect();
+        assert_eq!(gossiped, vec![json!([9]), json!([9])]);
+        Ok(())
+    }
+
+    #[test]
+    fn messages_known_everywhere_are_kept_once() -> Result<()> {
+        // Tests that once every peer is known to have a message, it is dropped from the known
+        // sets of the peers, and still not gossiped again.
+        let (outbox, msg_ids, rx) = outbox();
+        let broadcast = Broadcast::new(outbox);
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2", "n3"])?;
+        let now = Instant::now();
+        node.handle(msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 2, "messages": [7, 8] }),
+        ))?;
+        assert_eq!(broadcast.gossip(now)?, 1);
+
+        let to_n3 = rx.try_recv()?;
+        node.dispatch(to_n3.reply_with(Body {
+            typ: "gossip_ok".into(),
+            ..Default::default()
+        }))?;
+        assert_eq!(broadcast.gossip(now)?, 0);
+
+        let known = broadcast.known.lock().unwrap();
+        assert_eq!(known.everywhere, [7, 8].into());
+        assert!(known.by_peer.values().all(|known| known.is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn batched_acks_ack_a_burst_of_gossip_at_once() -> Result<()> {
+        // Tests that gossip to a node acking in batches gets no reply each, and that the one
+        // ack it sends on its next round acks all of it.
+        let (outbox1, msg_ids1, rx1) = outbox();
+        let sender = Broadcast::new(outbox1.clone());
+        let node1 = node_with_outbox(&sender, msg_ids1, "n1", &["n1", "n2"])?;
+        let (outbox2, msg_ids2, rx2) = outbox();
+        let receiver = Broadcast::new(outbox2).with_batched_acks();
+        let node2 = node_with_outbox(&receiver, msg_ids2, "n2", &["n1", "n2"])?;
+
+        for message in [7, 8] {
+            sender.messages.lock().unwrap().insert(message);
+            assert_eq!(sender.gossip(Instant::now())?, 1);
+        }
+        for gossip in rx1.try_iter() {
+            assert_eq!(node2.dispatch(gossip)?, None);
+        }
+        assert_eq!(receiver.ack_gossip()?, 1);
+        assert_eq!(receiver.ack_gossip()?, 0);
+
+        let ack = rx2.try_recv()?;
+        assert_eq!(ack.body.extra["acks"].as_array().map(Vec::len), Some(2));
+        assert_eq!(node1.dispatch(ack)?, None);
+        assert!(outbox1.is_empty());
+        assert_eq!(receiver.messages(), vec![7, 8]);
+        Ok(())
+    }
+
+    #[test]
+    fn batched_acks_skip_gossip_below_watermark() -> Result<()> {
+        // Tests that gossip sent again before its ack made it is not acked twice once the
+        // sender's watermark is past it.
+        let (outbox, msg_ids, rx) = outbox();
+        let broadcast = Broadcast::new(outbox).with_batched_acks();
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2"])?;
+        node.handle(msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 2, "messages": [7] }),
+        ))?;
+        assert_eq!(broadcast.ack_gossip()?, 1);
+        rx.try_recv()?;
+
+        let mut resent = msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 2, "messages": [7] }),
+        );
+        watermark::stamp(&mut resent.body, 2);
+        node.handle(resent)?;
+        let mut gossip = msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 5, "messages": [8] }),
+        );
+        watermark::stamp(&mut gossip.body, 5);
+        node.handle(gossip)?;
+        assert_eq!(broadcast.ack_gossip()?, 1);
+
+        assert_eq!(rx.try_recv()?.body.extra["acks"], json!([5]));
+        Ok(())
+    }
+
+    #[test]
+    fn fanout_limits_neighbors_per_round() -> Result<()> {
+        // Tests that each round gossips to fanout neighbors, until every neighbor has acked.
+        let (outbox, _, rx) = outbox();
+        let broadcast = Broadcast::new(outbox.clone()).with_fanout(Fanout::Peers(1));
+        let ids: Vec<String> = ["n1", "n2", "n3", "n4"].map(String::from).into();
+        broadcast.init("n1", &ids);
+        broadcast.messages.lock().unwrap().insert(7);
+
+        let mut gossiped = Vec::new();
+        for _ in 0..3 {
+            assert_eq!(broadcast.gossip(Instant::now())?, 1);
+            let sent = rx.try_recv()?;
+            gossiped.push(sent.dest.clone());
+            outbox.ack(&sent.reply_with(Body::default()));
+        }
+        gossiped.sort();
+
+        assert_eq!(gossiped, vec!["n2", "n3", "n4"]);
+        assert_eq!(broadcast.gossip(Instant::now())?, 0);
+        assert_eq!(Fanout::Log.limit(5), Some(3));
+        assert_eq!("log".parse(), Ok(Fanout::Log));
+        assert_eq!("2".parse(), Ok(Fanout::Peers(2)));
+        assert!("some".parse::<Fanout>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn epidemic_gossip_spreads_by_push_and_pull() -> Result<()> {
+        // Tests that messages are pushed to random peers for a few rounds, and that pushed peers
+        // push back what they are spreading.
+        let (outbox, msg_ids, rx) = outbox();
+        let broadcast = Broadcast::new(outbox)
+            .with_mode(GossipMode::Epidemic)
+            .with_fanout(Fanout::Peers(2));
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2", "n3", "n4"])?;
+        node.handle(msg(
+            "c1",
+            json!({ "type": "broadcast", "msg_id": 2, "message": 7 }),
+        ))?;
+
+        node.handle(msg(
+            "n4",
+            json!({ "type": "gossip", "msg_id": 3, "messages": [8] }),
+        ))?;
+        node.handle(msg(
+            "n3",
+            json!({ "type": "gossip", "msg_id": 4, "messages": [9], "pull": true }),
+        ))?;
+        let pulled: Vec<(String, serde_json::Value)> = rx
+            .try_iter()
+            .map(|m| (m.dest, m.body.extra["messages"].clone()))
+            .collect();
+        assert_eq!(pulled, vec![("n4".to_string(), json!([7]))]);
+
+        // Spread for log2(4) + 2 rounds, to 2 of the 3 peers each time.
+        let mut rounds = 0;
+        while broadcast.gossip(Instant::now())? > 0 {
+            rounds += 1;
+        }
+        let pushed: Vec<Message> = rx.try_iter().collect();
+        assert_eq!(rounds, 4);
+        assert_eq!(pushed.len(), 8);
+        // Peers are not pushed the messages they gossiped.
+        for m in &pushed {
+            let expected = match m.dest.as_str() {
+                "n2" => json!([7, 8, 9]),
+                "n3" => json!([7, 8]),
+                "n4" => json!([7, 9]),
+                dest => panic!("pushed to {dest}"),
+            };
+            assert_eq!(m.body.extra["messages"], expected, "{m:?}");
+        }
+        assert_eq!(broadcast.messages(), vec![7, 8, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn epidemic_gossip_skips_peers_known_to_have_messages() -> Result<()> {
+        // Tests that a peer that acked a push, or gossiped the messages itself, is not pushed
+        // them again while they are spread.
+        let (outbox, msg_ids, rx) = outbox();
+        let broadcast = Broadcast::new(outbox.clone()).with_mode(GossipMode::Epidemic);
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2", "n3"])?;
+        node.handle(msg(
+            "c1",
+            json!({ "type": "broadcast", "msg_id": 2, "message": 7 }),
+        ))?;
+
+        assert_eq!(broadcast.gossip(Instant::now())?, 2);
+        for push in rx.try_iter().filter(|m| m.dest == "n2") {
+            outbox.ack(&msg(
+                "n2",
+                json!({ "type": "gossip_ok", "msg_id": 5, "in_reply_to": push.body.msg_id }),
+            ));
+        }
+        assert_eq!(broadcast.gossip(Instant::now())?, 1);
+        let pushed: Vec<String> = rx.try_iter().map(|m| m.dest).collect();
+        assert_eq!(pushed, vec!["n3"]);
+
+        node.handle(msg(
+            "n3",
+            json!({ "type": "gossip", "msg_id": 6, "messages": [7] }),
+        ))?;
+        // Still spreading 7 for another round, but both peers have it.
+        assert_eq!(broadcast.gossip(Instant::now())?, 0);
+        assert_eq!(rx.try_iter().count(), 0, "nothing is echoed back");
+        Ok(())
+    }
+
+    #[test]
+    fn anti_entropy_exchanges_missing_messages() -> Result<()> {
+        // Tests that a sync sends every message, that its peer sends back the ones missing from
+        // it, and that no other sync is sent until it is acked.
+        let (outbox, msg_ids, rx) = outbox();
+        let broadcast = Broadcast::new(outbox.clone());
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2"])?;
+        for (msg_id, message) in [(2, 7), (3, 8)] {
+            node.handle(msg(
+                "c1",
+                json!({ "type": "broadcast", "msg_id": msg_id, "message": message }),
+            ))?;
+        }
+
+        assert!(broadcast.anti_entropy(Instant::now())?);
+        assert!(
+            !broadcast.anti_entropy(Instant::now())?,
+            "sync is not acked"
+        );
+        let sync = rx.try_recv()?;
+        assert_eq!(
+            (sync.dest.as_str(), &sync.body.extra["messages"]),
+            ("n2", &json!([7, 8]))
+        );
+        outbox.ack(&sync.reply_with(Body::default()));
+        assert!(broadcast.anti_entropy(Instant::now())?);
+
+        let reply = node.handle(msg(
+            "n2",
+            json!({ "type": "sync", "msg_id": 4, "messages": [8, 9] }),
+        ))?;
+        assert_eq!(reply.body.typ, "sync_ok");
+        let sent: Vec<Message> = rx.try_iter().collect();
+        assert_eq!(
+            sent.last().map(|m| &m.body.extra["messages"]),
+            Some(&json!([7]))
+        );
+        assert_eq!(broadcast.messages(), vec![7, 8, 9]);
+        Ok(())
+    }
+
+    #[test]
+    fn digest_sync_exchanges_differing_buckets() -> Result<()> {
+        // Tests that large sets are synced by digest, and that each node ends up with the
+        // messages only the other had.
+        let ids = ["n1", "n2"];
+        let mut broadcasts = vec![];
+        for messages in [0..100, 1..101] {
+            let (outbox, msg_ids, rx) = outbox();
+            let broadcast = Broadcast::new(outbox);
+            broadcast.learn(messages);
+            broadcasts.push((broadcast, msg_ids, rx));
+        }
+        let mut nodes = vec![];
+        for ((broadcast, msg_ids, rx), id) in broadcasts.iter().zip(ids) {
+            let node = node_with_outbox(broadcast, msg_ids.clone(), id, &ids)?;
+            nodes.push((broadcast, node, rx));
+        }
+        let (n1, n2) = (&nodes[0], &nodes[1]);
+        assert!(n1.0.anti_entropy(Instant::now())?);
+        let sync = n1.2.try_recv()?;
+        assert!(!sync.body.extra.contains_key("messages"));
+        assert_eq!(n2.1.handle(sync)?.body.typ, "sync_ok");
+        let diff = n2.2.try_recv()?;
+        assert_eq!(diff.body.typ, "sync_diff");
+        assert!(diff.body.get_as::<Vec<u64>>("messages")?.len() < 100);
+        assert_eq!(n1.1.handle(diff)?.body.typ, "sync_diff_ok");
+        n2.1.handle(n1.2.try_recv()?)?;
+
+        assert_eq!(n1.0.messages(), (0..101).collect::<Vec<_>>());
+        assert_eq!(n2.0.messages(), (0..101).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn read_returns_every_message() -> Result<()> {
+        let (outbox, msg_ids, _rx) = outbox();
+        let broadcast = Broadcast::new(outbox);
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1"])?;
+        node.handle(msg(
+            "c1",
+            json!({ "type": "topology", "msg_id": 2, "topology": { "n1": [] } }),
+        ))?;
+
+        for (i, message) in [3, 1, 2].into_iter().enumerate() {
+            node.handle(msg(
+                "c1",
+                json!({ "type": "broadcast", "msg_id": i + 3, "message": message }),
+            ))?;
+        }
+        let reply = node.handle(msg("c1", json!({ "type": "read", "msg_id": 9 })))?;
+
+        assert_eq!(reply.body.extra["messages"], json!([1, 2, 3]));
+        Ok(())
+    }
+
+    /// Keeps messages in a sorted vec, like a store for range digests would.
+    #[derive(Debug, Default)]
+    struct SortedStore(Vec<u64>);
+
+    impl BroadcastStore for SortedStore {
+        fn insert(&mut self, message: u64) -> bool {
+            match self.0.binary_search(&message) {
+                Ok(_) => false,
+                Err(at) => {
+                    self.0.insert(at, message);
+                    true
+                }
+            }
+        }
+
+        fn contains(&self, message: u64) -> bool {
+            self.0.binary_search(&message).is_ok()
+        }
+
+        fn all(&self) -> BTreeSet<u64> {
+            self.0.iter().copied().collect()
+        }
+
+        fn missing_since(&self, known: &BTreeSet<u64>) -> Vec<u64> {
+            self.0
+                .iter()
+                .filter(|m| !known.contains(m))
+                .copied()
+                .collect()
+        }
+    }
+
+    #[test]
+    fn gossip_works_with_any_store() -> Result<()> {
+        let (outbox, msg_ids, rx) = outbox();
+        let broadcast = Broadcast::new(outbox).with_store(SortedStore::default());
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2"])?;
+        node.handle(msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 2, "messages": [3] }),
+        ))?;
+        for (i, message) in [5, 1, 3].into_iter().enumerate() {
+            node.handle(msg(
+                "c1",
+                json!({ "type": "broadcast", "msg_id": i + 3, "message": message }),
+            ))?;
+        }
+
+        broadcast.gossip(Instant::now())?;
+
+        let gossip: Vec<Message> = rx.try_iter().collect();
+        assert_eq!(gossip.len(), 1);
+        assert_eq!(gossip[0].body.extra["messages"], json!([1, 5]));
+        assert_eq!(broadcast.messages(), vec![1, 3, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn gossip_is_compressed_for_peers_that_accept_it() -> Result<()> {
+        let (outbox1, msg_ids1, rx) = outbox();
+        let ids = ["n1", "n2", "n3"];
+        let sender = Broadcast::new(outbox1).with_compression();
+        let sender_node = node_with_outbox(&sender, msg_ids1, "n1", &ids)?;
+        sender_node.handle(msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 2, "messages": [3], "accept_compressed": true }),
+        ))?;
+        sender_node.handle(msg(
+            "c1",
+            json!({ "type": "broadcast", "msg_id": 3, "message": 4 }),
+        ))?;
+
+        sender.gossip(Instant::now())?;
+
+        let gossip: Vec<Message> = rx.try_iter().collect();
+        let to = |dest: &str| gossip.iter().find(|m| m.dest == dest).unwrap().clone();
+        assert_eq!(to("n3").body.extra["messages"], json!([3, 4]));
+        let compressed = to("n2");
+        assert!(!compressed.body.extra.contains_key("messages"));
+        assert_eq!(compressed.body.extra["accept_compressed"], true);
+        let (outbox2, msg_ids2, _rx) = outbox();
+        let receiver = Broadcast::new(outbox2);
+        let receiver_node = node_with_outbox(&receiver, msg_ids2, "n2", &ids)?;
+        receiver_node.handle(compressed)?;
+        assert_eq!(receiver.messages(), vec![4]);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_gossip_is_rejected_for_a_retry() -> Result<()> {
+        let (outbox, msg_ids, _rx) = outbox();
+        let broadcast = Broadcast::new(outbox);
+        let node = node_with_outbox(&broadcast, msg_ids, "n1", &["n1", "n2"])?;
+        let checksum = checksum::of(&json!([1, 2]));
+
+        let e = node
+            .handle(msg(
+                "n2",
+                json!({ "type": "gossip", "msg_id": 2, "messages": [1], "checksum": checksum }),
+            ))
+            .unwrap_err();
+
+        assert_eq!(e.code(), TEMPORARILY_UNAVAILABLE, "{e}");
+        let packed = compress::pack(&json!([1, 2]));
+        let truncated = &packed[..packed.len() - 4];
+        let e = node
+            .handle(msg(
+                "n2",
+                json!({ "type": "gossip", "msg_id": 3, "compressed": truncated, "checksum": checksum }),
+            ))
+            .unwrap_err();
+        assert_eq!(e.code(), TEMPORARILY_UNAVAILABLE, "{e}");
+        assert!(broadcast.messages().is_empty());
+        node.handle(msg(
+            "n2",
+            json!({ "type": "gossip", "msg_id": 4, "messages": [1, 2], "checksum": checksum }),
+        ))?;
+        assert_eq!(broadcast.messages(), vec![1, 2]);
+        Ok(())
+    }
+}
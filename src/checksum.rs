@@ -0,0 +1,108 @@
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::{compress, error::NodeError, message::Body};
+
+/// Field the checksum of the payload of a message is in.
+pub const CHECKSUM_FIELD: &str = "checksum";
+
+/// CRC-32 (IEEE) of every byte value, for [`crc32`].
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE) of `data`, the checksum of zip and ethernet.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Checksum of `payload` serialized. Objects serialize with their keys sorted, so the sender
+/// and the receiver of a payload get the same checksum.
+pub fn of(payload: &Value) -> u32 {
+    crc32(payload.to_string().as_bytes())
+}
+
+/// Puts the checksum of `payload` in `body`.
+pub fn stamp(body: &mut Body, payload: &Value) {
+    body.extra.insert(CHECKSUM_FIELD.into(), of(payload).into());
+}
+
+/// Checks `payload`, recieved in `body`, against the checksum in `body`. Bodies without one,
+/// from nodes that do not send them, pass.
+///
+/// A payload that does not match was corrupted or truncated on the way, it fails with an
+/// Unavailable error so requests carrying it are answered with an error their sender retries.
+pub fn verify(body: &Body, payload: &Value) -> Result<()> {
+    let Some(expected) = body.extra.get(CHECKSUM_FIELD) else {
+        return Ok(());
+    };
+    let actual = of(payload);
+    if expected.as_u64() != Some(actual as u64) {
+        return Err(NodeError::Unavailable(format!(
+            "{} payload has checksum {actual}, expected {expected}",
+            body.typ
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// The payload in the field `name` of `body`, or in its compressed field, checked against the
+/// checksum in `body` like [`verify`] does.
+///
+/// A compressed payload of a body with a checksum that cannot be decoded was corrupted on the
+/// way too, it also fails with an Unavailable error.
+pub fn get(body: &Body, name: &str) -> Result<Value> {
+    let payload = compress::get(body, name).map_err(|e| match body.extra.get(CHECKSUM_FIELD) {
+        Some(_) => NodeError::Unavailable(format!("{} payload is corrupt: {e:#}", body.typ)).into(),
+        None => e,
+    })?;
+    verify(body, &payload)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::checksum::{crc32, stamp, verify};
+    use crate::error::NodeError;
+    use crate::message::Body;
+
+    #[test]
+    fn corrupted_payloads_fail_verification() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let payload = json!({ "messages": [1, 2, 3] });
+        let mut body = Body {
+            typ: "snapshot".into(),
+            ..Default::default()
+        };
+        assert!(verify(&body, &payload).is_ok(), "no checksum to verify");
+
+        stamp(&mut body, &payload);
+
+        assert!(verify(&body, &payload).is_ok());
+        let e = verify(&body, &json!({ "messages": [1, 2] })).unwrap_err();
+        assert!(
+            matches!(e.downcast_ref(), Some(NodeError::Unavailable(_))),
+            "{e:#}"
+        );
+    }
+}
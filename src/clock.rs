@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::message::Body;
+
+/// Body field Lamport timestamps are carried in, reserved for the clock.
+pub const LAMPORT_FIELD: &str = "lamport";
+
+/// Lamport clock, orders events so that an event that happened before another has a smaller
+/// timestamp.
+///
+/// The clock ticks on every local event and every send, and on every receive moves past the
+/// timestamp of the message received. Timestamps of different nodes can be equal, break ties by
+/// node ID for a total order, like [`LWWRegister`](crate::crdt::LWWRegister) does.
+///
+/// Give the clock to the node with [`Node::with_clock`](crate::node::Node::with_clock) to stamp
+/// every message the node sends and merge the timestamp of every message it receives.
+#[derive(Debug, Default)]
+pub struct LamportClock {
+    time: AtomicU64,
+}
+
+impl LamportClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The timestamp of the last event.
+    pub fn now(&self) -> u64 {
+        self.time.load(Ordering::SeqCst)
+    }
+
+    /// Ticks for a local event or a send, returns its timestamp.
+    pub fn tick(&self) -> u64 {
+        self.time.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Moves past `timestamp` of an event received, returns the timestamp of the receive.
+    pub fn observe(&self, timestamp: u64) -> u64 {
+        self.time.fetch_max(timestamp, Ordering::SeqCst);
+        self.tick()
+    }
+
+    /// Ticks for sending `body` and stores the timestamp in it, returns the timestamp.
+    pub fn stamp(&self, body: &mut Body) -> u64 {
+        let timestamp = self.tick();
+        body.extra.insert(LAMPORT_FIELD.into(), timestamp.into());
+        timestamp
+    }
+
+    /// Observes the timestamp of the received `body`, if it has one.
+    pub fn receive(&self, body: &Body) -> Option<u64> {
+        let timestamp = body.extra.get(LAMPORT_FIELD)?.as_u64()?;
+        Some(self.observe(timestamp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::{LamportClock, LAMPORT_FIELD};
+    use crate::message::Body;
+
+    #[test]
+    fn receive_happens_after_send() {
+        // Tests that a message received is after its send, on a clock that was behind.
+        let (n1, n2) = (LamportClock::new(), LamportClock::new());
+        for _ in 0..5 {
+            n1.tick();
+        }
+        let mut body = Body::default();
+
+        let sent = n1.stamp(&mut body);
+        let received = n2.receive(&body);
+        let unstamped = n2.receive(&Body::default());
+
+        assert_eq!(body.extra[LAMPORT_FIELD], 6);
+        assert_eq!((sent, received, unstamped), (6, Some(7), None));
+        assert_eq!(n1.observe(2), 7, "older timestamps still tick");
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{error::NodeError, message::Body};
+
+/// Field a message advertises it accepts compressed payloads in, so replies to it may have
+/// them.
+pub const ACCEPT_FIELD: &str = "accept_compressed";
+/// Field a compressed payload is in, base64, replacing the field it was compressed from.
+pub const COMPRESSED_FIELD: &str = "compressed";
+
+/// Whether `body`, a request, advertises it accepts compressed payloads.
+pub fn accepts(body: &Body) -> bool {
+    body.extra.get(ACCEPT_FIELD) == Some(&Value::Bool(true))
+}
+
+/// Advertises in `body` that compressed payloads are accepted.
+pub fn accept(body: &mut Body) {
+    body.extra.insert(ACCEPT_FIELD.into(), true.into());
+}
+
+/// Puts `value` in the field `name` of `body`, or compressed in the compressed field if
+/// `compressed`.
+pub fn insert(body: &mut Body, name: &str, value: Value, compressed: bool) {
+    match compressed {
+        true => body
+            .extra
+            .insert(COMPRESSED_FIELD.into(), pack(&value).into()),
+        false => body.extra.insert(name.into(), value),
+    };
+}
+
+/// The field `name` of `body`, or the payload in its compressed field if it has one.
+pub fn get<T: DeserializeOwned>(body: &Body, name: &str) -> Result<T> {
+    let Some(packed) = body.extra.get(COMPRESSED_FIELD) else {
+        return body.get_as(name);
+    };
+    let packed = packed
+        .as_str()
+        .ok_or_else(|| NodeError::Malformed(format!("{COMPRESSED_FIELD} is not a string")))?;
+    serde_json::from_value(unpack(packed)?)
+        .map_err(|e| NodeError::Malformed(format!("compressed {name} is invalid: {e}")).into())
+}
+
+/// Compresses `value` serialized, encoded base64.
+pub fn pack(value: &Value) -> String {
+    STANDARD.encode(compress(value.to_string().as_bytes()))
+}
+
+/// The value packed into `packed` by [`pack`].
+pub fn unpack(packed: &str) -> Result<Value> {
+    let compressed = STANDARD
+        .decode(packed)
+        .map_err(|e| NodeError::Malformed(format!("invalid base64: {e}")))?;
+    let json = decompress(&compressed)?;
+    serde_json::from_slice(&json)
+        .map_err(|e| NodeError::Malformed(format!("compressed payload is not JSON: {e}")).into())
+}
+
+/// Raw deflate compression of `data`.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    // Writing to a Vec cannot fail.
+    encoder.write_all(data).expect("write to Vec");
+    encoder.finish().expect("write to Vec")
+}
+
+/// The data compressed into `compressed` by [`compress`].
+pub fn decompress(compressed: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(compressed.len() * 2);
+    DeflateDecoder::new(compressed)
+        .read_to_end(&mut out)
+        .map_err(|e| NodeError::Malformed(format!("corrupt compressed payload: {e}")))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::compress::{compress, decompress, pack, unpack};
+
+    #[test]
+    fn payloads_survive_compression() -> Result<()> {
+        let logs: Vec<_> = (0..500)
+            .map(|i| json!({ "key": format!("k{}", i % 5), "offset": i, "msg": i * 7 }))
+            .collect();
+        let value = json!({ "logs": logs });
+        let packed = pack(&value);
+        assert!(
+            packed.len() < value.to_string().len() / 2,
+            "{}",
+            packed.len()
+        );
+        assert_eq!(unpack(&packed)?, value);
+
+        for data in [
+            &b""[..],
+            b"a",
+            b"aaaaaaaaaaaaaaaaaaaaaaaa",
+            b"abcabcabd\x00\xff",
+        ] {
+            assert_eq!(decompress(&compress(data))?, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_payloads_are_rejected() {
+        assert!(decompress(&[0xff, 9, 0, 0]).is_err());
+        assert!(unpack("Zm9v!").is_err());
+        assert!(unpack(&pack(&json!([1, 2, 3]))[4..]).is_err());
+    }
+}
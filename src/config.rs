@@ -0,0 +1,274 @@
+use std::{num::ParseIntError, time::Duration};
+
+use clap::Args;
+
+use crate::{
+    broadcast::{Fanout, GossipMode},
+    durability::Durability,
+    kafka::KafkaMode,
+    txn::Isolation,
+    unique_ids::IdScheme,
+};
+
+/// Tunables of a node, whatever its workload, with the defaults workloads are tested with.
+///
+/// Every tunable is a flag, and can also be set with the environment variable named after it,
+/// e.g. `--gossip-interval-ms` with `MAELSTROM_GOSSIP_INTERVAL_MS`, which is handy when
+/// Maelstrom starts the node. Durations are in milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct Config {
+    /// How often nodes gossip their state to their peers.
+    #[arg(
+        long = "gossip-interval-ms",
+        env = "MAELSTROM_GOSSIP_INTERVAL_MS",
+        default_value = "150",
+        value_parser = millis
+    )]
+    pub gossip_interval: Duration,
+    /// How many neighbors broadcast nodes gossip to each round: all, log (log2 of the cluster
+    /// size) or a number.
+    #[arg(long, env = "MAELSTROM_GOSSIP_FANOUT", default_value_t = Fanout::All)]
+    pub gossip_fanout: Fanout,
+    /// Who broadcast nodes gossip to, their neighbors or random peers.
+    #[arg(long, value_enum, env = "MAELSTROM_GOSSIP_MODE", default_value_t = GossipMode::Neighbors)]
+    pub gossip_mode: GossipMode,
+    /// Whether broadcast nodes ack the gossip of a neighbor once a round, in one ack, instead of
+    /// every gossip right away.
+    #[arg(long, env = "MAELSTROM_GOSSIP_BATCH_ACKS")]
+    pub gossip_batch_acks: bool,
+    /// How often broadcast nodes sync every message with a random peer, in case gossip was lost.
+    #[arg(
+        long = "anti-entropy-interval-ms",
+        env = "MAELSTROM_ANTI_ENTROPY_INTERVAL_MS",
+        default_value = "1000",
+        value_parser = millis
+    )]
+    pub anti_entropy_interval: Duration,
+    /// How long a node waits for a peer to ack a message before sending it again.
+    #[arg(
+        long = "retry-interval-ms",
+        env = "MAELSTROM_RETRY_INTERVAL_MS",
+        default_value = "200",
+        value_parser = millis
+    )]
+    pub retry_interval: Duration,
+    /// Whether messages are sent again after a timeout derived from the measured round trip
+    /// times of their dest, instead of the retry interval.
+    #[arg(long, env = "MAELSTROM_ADAPTIVE_RETRIES")]
+    pub adaptive_retries: bool,
+    /// Most messages a second sent through the outbox, gossip and retries included, if set.
+    /// Bursts of up to a second's worth are sent at once.
+    #[arg(long, env = "MAELSTROM_SEND_RATE")]
+    pub send_rate: Option<u32>,
+    /// How long RPCs, like requests to lin-kv, wait for a reply.
+    #[arg(
+        long = "rpc-timeout-ms",
+        env = "MAELSTROM_RPC_TIMEOUT_MS",
+        default_value = "1000",
+        value_parser = millis
+    )]
+    pub rpc_timeout: Duration,
+    /// How often heartbeats are sent, when they are.
+    #[arg(
+        long = "heartbeat-interval-ms",
+        env = "MAELSTROM_HEARTBEAT_INTERVAL_MS",
+        default_value = "500",
+        value_parser = millis
+    )]
+    pub heartbeat_interval: Duration,
+    /// How long a peer can go without answering heartbeats before it is suspected dead.
+    #[arg(
+        long = "heartbeat-timeout-ms",
+        env = "MAELSTROM_HEARTBEAT_TIMEOUT_MS",
+        default_value = "2000",
+        value_parser = millis
+    )]
+    pub heartbeat_timeout: Duration,
+    /// How many heartbeats in a row a peer can leave unanswered before it is unreachable.
+    #[arg(long, env = "MAELSTROM_HEARTBEAT_MISSED_LIMIT", default_value = "3")]
+    pub heartbeat_missed_limit: usize,
+    /// Isolation level of txn transactions.
+    #[arg(long, value_enum, env = "MAELSTROM_TXN_ISOLATION", default_value_t = Isolation::ReadCommitted)]
+    pub txn_isolation: Isolation,
+    /// Shard txn registers across nodes and commit transactions with two-phase commit, instead
+    /// of replicating every register to every node.
+    #[arg(long, env = "MAELSTROM_TXN_TWO_PHASE")]
+    pub txn_two_phase: bool,
+    /// How unique IDs are made, as hex flake IDs, as snowflake integers or out of blocks leased
+    /// from seq-kv.
+    #[arg(long, value_enum, env = "MAELSTROM_UNIQUE_ID_SCHEME", default_value_t = IdScheme::Hex)]
+    pub unique_id_scheme: IdScheme,
+    /// How kafka nodes share keys, by leases on logs in lin-kv or by owning them.
+    #[arg(long, value_enum, env = "MAELSTROM_KAFKA_MODE", default_value_t = KafkaMode::Leases)]
+    pub kafka_mode: KafkaMode,
+    /// Whether kafka owners drop the entries of their logs below the committed offset.
+    #[arg(long, env = "MAELSTROM_KAFKA_DROP_COMMITTED")]
+    pub kafka_drop_committed: bool,
+    /// Most entries kafka owners keep per log, dropping the oldest, if set.
+    #[arg(long, env = "MAELSTROM_KAFKA_MAX_LOG_ENTRIES")]
+    pub kafka_max_log_entries: Option<usize>,
+    /// How long a node leads a kafka key once it takes its lease.
+    #[arg(
+        long = "kafka-lease-ms",
+        env = "MAELSTROM_KAFKA_LEASE_MS",
+        default_value = "2000",
+        value_parser = millis
+    )]
+    pub kafka_lease: Duration,
+    /// Most messages a kafka poll returns per key, clients poll again for the rest.
+    #[arg(long, env = "MAELSTROM_KAFKA_POLL_LIMIT", default_value_t = 1000)]
+    pub kafka_poll_limit: usize,
+    /// With owners and a state dir, log every kafka send to a write-ahead log before acking it,
+    /// instead of saving snapshots of the logs.
+    #[arg(long, env = "MAELSTROM_KAFKA_WAL")]
+    pub kafka_wal: bool,
+    /// When state written to the state dir is synced to disk: never, every-write or every this
+    /// many milliseconds.
+    #[arg(long, env = "MAELSTROM_DURABILITY", default_value_t = Durability::Never)]
+    pub durability: Durability,
+    /// Whether a node asks a random peer for its whole workload state on start, to catch up
+    /// after a restart in one exchange instead of by gossip.
+    #[arg(long, env = "MAELSTROM_SNAPSHOT_ON_START")]
+    pub snapshot_on_start: bool,
+    /// Whether nodes compress snapshots and broadcast gossip for peers that accept it, and
+    /// advertise that they accept it.
+    #[arg(long, env = "MAELSTROM_COMPRESS_PAYLOADS")]
+    pub compress_payloads: bool,
+    /// Replies to the last this many requests are remembered and sent again to duplicates and
+    /// retries of them, by msg_id or idempotency key, instead of handling them again, if set.
+    #[arg(long, env = "MAELSTROM_DEDUP_CAPACITY")]
+    pub dedup_capacity: Option<usize>,
+    /// Replies to the last this many requests of every client are kept in its session and sent
+    /// again when the client retries a request with the same `request_id` after a timeout, for
+    /// exactly-once kv and kafka operations, if set.
+    #[arg(long, env = "MAELSTROM_SESSION_REPLIES")]
+    pub session_replies: Option<usize>,
+    /// Whether nodes send their peers low watermarks of the messages they acked, and forget
+    /// what they remember of requests of a peer below its watermark, for long runs.
+    #[arg(long, env = "MAELSTROM_WATERMARKS")]
+    pub watermarks: bool,
+    /// Handlers slower than this are logged as slow.
+    #[arg(
+        long = "slow-handler-ms",
+        env = "MAELSTROM_SLOW_HANDLER_MS",
+        default_value = "100",
+        value_parser = millis
+    )]
+    pub slow_handler: Duration,
+    /// Number of threads handling messages, by default one per core but at least 4, since
+    /// handlers may wait on RPCs whose replies are routed by other workers.
+    #[arg(long, env = "MAELSTROM_WORKERS")]
+    pub workers: Option<usize>,
+    /// Most handlers of a message type running at once, as `type=limit`, e.g. `txn=1` to
+    /// handle transactions one at a time while reads run on every worker.
+    #[arg(
+        long = "handler-limit",
+        env = "MAELSTROM_HANDLER_LIMITS",
+        value_delimiter = ',',
+        value_parser = type_limit
+    )]
+    pub handler_limits: Vec<(String, usize)>,
+    /// Most messages waiting to be handled, and most replies waiting to be written.
+    #[arg(long, env = "MAELSTROM_QUEUE_CAPACITY", default_value_t = 1024)]
+    pub queue_capacity: usize,
+    /// Messages larger than this many bytes are rejected as malformed, if set.
+    #[arg(long = "max-message-bytes", env = "MAELSTROM_MAX_MESSAGE_BYTES")]
+    pub max_message_size: Option<usize>,
+    /// Messages to peers whose body is larger than this many bytes are sent in frags of at
+    /// most this many bytes each, and put back together by the peer, if set.
+    #[arg(long = "fragment-bytes", env = "MAELSTROM_FRAGMENT_BYTES")]
+    pub fragment_size: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gossip_interval: Duration::from_millis(150),
+            gossip_fanout: Fanout::All,
+            gossip_mode: GossipMode::Neighbors,
+            gossip_batch_acks: false,
+            anti_entropy_interval: Duration::from_secs(1),
+            retry_interval: Duration::from_millis(200),
+            adaptive_retries: false,
+            send_rate: None,
+            rpc_timeout: Duration::from_secs(1),
+            heartbeat_interval: Duration::from_millis(500),
+            heartbeat_timeout: Duration::from_secs(2),
+            heartbeat_missed_limit: 3,
+            txn_isolation: Isolation::ReadCommitted,
+            txn_two_phase: false,
+            unique_id_scheme: IdScheme::Hex,
+            kafka_mode: KafkaMode::Leases,
+            kafka_drop_committed: false,
+            kafka_max_log_entries: None,
+            kafka_lease: Duration::from_secs(2),
+            kafka_poll_limit: 1000,
+            kafka_wal: false,
+            durability: Durability::Never,
+            snapshot_on_start: false,
+            compress_payloads: false,
+            dedup_capacity: None,
+            session_replies: None,
+            watermarks: false,
+            slow_handler: Duration::from_millis(100),
+            workers: None,
+            handler_limits: Vec::new(),
+            queue_capacity: 1024,
+            max_message_size: None,
+            fragment_size: None,
+        }
+    }
+}
+
+fn millis(arg: &str) -> Result<Duration, ParseIntError> {
+    Ok(Duration::from_millis(arg.parse()?))
+}
+
+fn type_limit(arg: &str) -> Result<(String, usize), String> {
+    let (typ, limit) = arg
+        .split_once('=')
+        .ok_or(format!("expected type=limit, got {arg}"))?;
+    let limit = limit
+        .parse()
+        .map_err(|e| format!("bad limit of {typ}: {e}"))?;
+    Ok((typ.to_string(), limit))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use crate::config::Config;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        config: Config,
+    }
+
+    #[test]
+    fn flags_default_to_default_config() {
+        let defaults = Args::parse_from(["node"]).config;
+        let set = Args::parse_from([
+            "node",
+            "--gossip-interval-ms",
+            "50",
+            "--workers",
+            "2",
+            "--handler-limit",
+            "txn=1,read=8",
+        ])
+        .config;
+
+        assert_eq!(defaults, Config::default());
+        assert_eq!(set.gossip_interval, Duration::from_millis(50));
+        assert_eq!(set.workers, Some(2));
+        assert_eq!(
+            set.handler_limits,
+            vec![("txn".to_string(), 1), ("read".to_string(), 8)]
+        );
+        assert!(Args::try_parse_from(["node", "--rpc-timeout-ms", "1s"]).is_err());
+    }
+}
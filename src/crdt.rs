@@ -0,0 +1,543 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A state based CRDT, that replicas converge on by sending each other the part of their state
+/// the other is missing, see [`Replicated`](crate::replicated::Replicated).
+pub trait Crdt: Clone + Default + Serialize + DeserializeOwned {
+    /// Merges `other` into this state.
+    fn merge(&mut self, other: &Self);
+
+    /// The part of this state a replica that has `known` is missing, None if it has all of it.
+    fn missing_for(&self, known: &Self) -> Option<Self>;
+}
+
+/// A grow-only counter (G-Counter).
+///
+/// Every node only ever increments its own entry, the value of the counter is the sum of all
+/// entries. Two counters are merged by taking the max of each node's entry, so merging is
+/// commutative, associative and idempotent and nodes can gossip their whole state in any order.
+///
+/// Serializes as a map from node ID to that node's count, e.g. `{"n1": 3, "n2": 5}`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(transparent)]
+pub struct GCounter {
+    // Per node counts, keyed by node ID.
+    counts: HashMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `delta` to the entry of `node_id`.
+    pub fn increment(&mut self, node_id: &str, delta: u64) {
+        *self.counts.entry(node_id.to_string()).or_default() += delta;
+    }
+
+    /// The current value of the counter, the sum of all node entries.
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Merges `other` into this counter, keeping the largest count seen for every node.
+    pub fn merge(&mut self, other: &GCounter) {
+        for (node_id, &count) in &other.counts {
+            let entry = self.counts.entry(node_id.clone()).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        GCounter::merge(self, other)
+    }
+
+    /// The whole counter, unless `known` is the same, as the entries cannot be merged apart.
+    fn missing_for(&self, known: &Self) -> Option<Self> {
+        (known != self).then(|| self.clone())
+    }
+}
+
+/// A grow-only set (G-Set).
+///
+/// Elements can only be added, merging two sets is their union.
+///
+/// Serializes as a JSON array of its elements.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(transparent, bound(deserialize = "T: Deserialize<'de> + Eq + Hash"))]
+pub struct GSet<T: Eq + Hash> {
+    elements: HashSet<T>,
+}
+
+impl<T: Eq + Hash> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> GSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `value` to the set, returns true if it was not already present.
+    pub fn insert(&mut self, value: T) -> bool {
+        self.elements.insert(value)
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.contains(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+
+    /// Merges `other` into this set (set union).
+    pub fn merge(&mut self, other: &GSet<T>) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+}
+
+impl<T: Eq + Hash + Clone + Serialize + DeserializeOwned> Crdt for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        GSet::merge(self, other)
+    }
+
+    /// The elements not in `known`.
+    fn missing_for(&self, known: &Self) -> Option<Self> {
+        let elements: HashSet<T> = self
+            .elements
+            .iter()
+            .filter(|&element| !known.contains(element))
+            .cloned()
+            .collect();
+        (!elements.is_empty()).then_some(Self { elements })
+    }
+}
+
+/// Uniquely identifies a single add operation on an [`ORSet`]: the node that did the add and that
+/// node's sequence number for it.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Tag(pub String, pub u64);
+
+/// An observed-remove set (OR-Set).
+///
+/// Every add is tagged with a unique [`Tag`], a remove only deletes the tags it has observed. So
+/// an add that is concurrent with a remove wins, and an element can be re-added after removal.
+/// Removed tags are kept as tombstones so that merging a stale replica does not resurrect them.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(
+    into = "ORSetRepr<T>",
+    from = "ORSetRepr<T>",
+    bound(
+        serialize = "T: Serialize + Eq + Hash + Clone",
+        deserialize = "T: Deserialize<'de> + Eq + Hash + Clone"
+    )
+)]
+pub struct ORSet<T: Eq + Hash> {
+    // Live tags of every element in the set.
+    adds: HashMap<T, HashSet<Tag>>,
+    // Tags that have been removed.
+    removed: HashSet<Tag>,
+    // Last sequence number used per node, used to create unique tags.
+    clocks: HashMap<String, u64>,
+}
+
+/// Wire representation of an [`ORSet`], elements are stored as a list so any serializable type
+/// can be used (JSON map keys must be strings).
+#[derive(Serialize, Deserialize)]
+struct ORSetRepr<T> {
+    adds: Vec<(T, Vec<Tag>)>,
+    removed: Vec<Tag>,
+    clocks: HashMap<String, u64>,
+}
+
+impl<T: Eq + Hash + Clone> From<ORSet<T>> for ORSetRepr<T> {
+    fn from(set: ORSet<T>) -> Self {
+        Self {
+            adds: set
+                .adds
+                .into_iter()
+                .map(|(value, tags)| (value, tags.into_iter().collect()))
+                .collect(),
+            removed: set.removed.into_iter().collect(),
+            clocks: set.clocks,
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> From<ORSetRepr<T>> for ORSet<T> {
+    fn from(repr: ORSetRepr<T>) -> Self {
+        Self {
+            adds: repr
+                .adds
+                .into_iter()
+                .map(|(value, tags)| (value, tags.into_iter().collect()))
+                .collect(),
+            removed: repr.removed.into_iter().collect(),
+            clocks: repr.clocks,
+        }
+    }
+}
+
+impl<T: Eq + Hash> Default for ORSet<T> {
+    fn default() -> Self {
+        Self {
+            adds: HashMap::new(),
+            removed: HashSet::new(),
+            clocks: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> ORSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `value` to the set on behalf of `node_id`.
+    pub fn insert(&mut self, node_id: &str, value: T) {
+        let seq = self.clocks.entry(node_id.to_string()).or_default();
+        *seq += 1;
+        let tag = Tag(node_id.to_string(), *seq);
+        self.adds.entry(value).or_default().insert(tag);
+    }
+
+    /// Removes `value` from the set, returns true if it was present.
+    ///
+    /// Only the adds observed by this replica are removed, a concurrent add on another replica
+    /// survives the merge.
+    pub fn remove(&mut self, value: &T) -> bool {
+        match self.adds.remove(value) {
+            Some(tags) => {
+                self.removed.extend(tags);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.adds.contains_key(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.adds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.adds.keys()
+    }
+
+    /// Merges `other` into this set.
+    pub fn merge(&mut self, other: &ORSet<T>) {
+        self.removed.extend(other.removed.iter().cloned());
+        for (value, tags) in &other.adds {
+            let live = self.adds.entry(value.clone()).or_default();
+            live.extend(tags.iter().cloned());
+        }
+        let removed = &self.removed;
+        self.adds.retain(|_, tags| {
+            tags.retain(|tag| !removed.contains(tag));
+            !tags.is_empty()
+        });
+        for (node_id, &seq) in &other.clocks {
+            let clock = self.clocks.entry(node_id.clone()).or_default();
+            *clock = (*clock).max(seq);
+        }
+    }
+}
+
+/// A last-writer-wins register (LWW-Register).
+///
+/// Every write carries a timestamp and the ID of the node doing the write, the write with the
+/// largest `(timestamp, node_id)` pair wins. Comparing node IDs breaks ties between writes that
+/// happen at the same timestamp on different nodes, so all replicas pick the same winner.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct LWWRegister<T> {
+    // Current value, None until the first write.
+    value: Option<T>,
+    // Timestamp of the write that produced the current value.
+    timestamp: u64,
+    // Node that did the write that produced the current value.
+    node_id: String,
+}
+
+impl<T> Default for LWWRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            timestamp: 0,
+            node_id: String::new(),
+        }
+    }
+}
+
+impl<T: Clone> LWWRegister<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes `value` at `timestamp` on behalf of `node_id`.
+    ///
+    /// Returns true if the write won, i.e. it is newer than the current value.
+    pub fn set(&mut self, value: T, timestamp: u64, node_id: &str) -> bool {
+        if self.value.is_some() && (timestamp, node_id) <= (self.timestamp, &self.node_id[..]) {
+            return false;
+        }
+        self.value = Some(value);
+        self.timestamp = timestamp;
+        self.node_id = node_id.to_string();
+        true
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    /// Timestamp of the current value.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Merges `other` into this register, keeping whichever write is newer.
+    pub fn merge(&mut self, other: &LWWRegister<T>) {
+        if let Some(value) = &other.value {
+            self.set(value.clone(), other.timestamp, &other.node_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::crdt::{GCounter, GSet, LWWRegister, ORSet};
+
+    #[test]
+    fn gcounter_starts_at_zero() {
+        let counter = GCounter::new();
+        assert_eq!(counter.value(), 0);
+    }
+
+    #[test]
+    fn gcounter_sums_increments() {
+        // Tests that the value is the sum of all increments across nodes.
+        let mut counter = GCounter::new();
+        counter.increment("n1", 3);
+        counter.increment("n2", 4);
+        counter.increment("n1", 1);
+
+        assert_eq!(counter.value(), 8);
+    }
+
+    #[test]
+    fn gcounter_merge_takes_max_per_node() {
+        // Tests that merging keeps the largest entry for each node rather than adding them.
+        let mut a = GCounter::new();
+        a.increment("n1", 5);
+        a.increment("n2", 1);
+        let mut b = GCounter::new();
+        b.increment("n1", 2);
+        b.increment("n2", 7);
+        b.increment("n3", 1);
+
+        a.merge(&b);
+
+        assert_eq!(a.value(), 5 + 7 + 1);
+    }
+
+    #[test]
+    fn gcounter_merge_is_idempotent_and_commutative() {
+        let mut a = GCounter::new();
+        a.increment("n1", 5);
+        let mut b = GCounter::new();
+        b.increment("n2", 3);
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), 8);
+    }
+
+    #[test]
+    fn gcounter_serde_roundtrip() -> Result<()> {
+        // Tests that a counter serializes as a plain node -> count map.
+        let mut counter = GCounter::new();
+        counter.increment("n1", 2);
+
+        let json = serde_json::to_value(&counter)?;
+        assert_eq!(json, serde_json::json!({"n1": 2}));
+
+        let parsed = serde_json::from_value::<GCounter>(json)?;
+        assert_eq!(parsed, counter);
+        Ok(())
+    }
+
+    #[test]
+    fn gset_merge_is_union() {
+        let mut a = GSet::new();
+        a.insert(1);
+        a.insert(2);
+        let mut b = GSet::new();
+        b.insert(2);
+        b.insert(3);
+
+        a.merge(&b);
+
+        let mut values: Vec<_> = a.iter().copied().collect();
+        values.sort();
+        assert_eq!(values, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn gset_serializes_as_array() -> Result<()> {
+        let mut set = GSet::new();
+        set.insert(7u64);
+
+        let json = serde_json::to_value(&set)?;
+        assert_eq!(json, serde_json::json!([7]));
+        assert_eq!(serde_json::from_value::<GSet<u64>>(json)?, set);
+        Ok(())
+    }
+
+    #[test]
+    fn orset_remove_then_add() {
+        // Tests that an element can be re-added after being removed.
+        let mut set = ORSet::new();
+        set.insert("n1", 1);
+        assert!(set.remove(&1));
+        assert!(!set.contains(&1));
+
+        set.insert("n1", 1);
+        assert!(set.contains(&1));
+    }
+
+    #[test]
+    fn orset_concurrent_add_wins() {
+        // Tests that a remove only deletes the adds it observed, so a concurrent add on
+        // another replica survives the merge.
+        let mut a = ORSet::new();
+        a.insert("n1", 1);
+        let mut b = a.clone();
+
+        a.remove(&1);
+        b.insert("n2", 1);
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+
+        assert!(ab.contains(&1));
+        assert_eq!(ab, ba);
+    }
+
+    #[test]
+    fn orset_merge_does_not_resurrect_removed() {
+        // Tests that merging a stale replica does not bring back removed elements.
+        let mut a = ORSet::new();
+        a.insert("n1", 1);
+        let stale = a.clone();
+
+        a.remove(&1);
+        a.merge(&stale);
+
+        assert!(!a.contains(&1));
+        assert!(a.is_empty());
+    }
+
+    #[test]
+    fn orset_serde_roundtrip() -> Result<()> {
+        let mut set = ORSet::new();
+        set.insert("n1", "a".to_string());
+        set.insert("n2", "b".to_string());
+        set.remove(&"b".to_string());
+
+        let json = serde_json::to_string(&set)?;
+        let parsed = serde_json::from_str::<ORSet<String>>(&json)?;
+
+        assert_eq!(parsed, set);
+        Ok(())
+    }
+
+    #[test]
+    fn lww_register_newer_write_wins() {
+        let mut register = LWWRegister::new();
+        assert_eq!(register.get(), None);
+
+        assert!(register.set("a", 1, "n1"));
+        assert!(register.set("b", 2, "n1"));
+        assert!(!register.set("c", 1, "n2"), "older write should lose");
+
+        assert_eq!(register.get(), Some(&"b"));
+        assert_eq!(register.timestamp(), 2);
+    }
+
+    #[test]
+    fn lww_register_ties_broken_by_node_id() {
+        // Tests that writes with the same timestamp are resolved the same way on every replica.
+        let mut a = LWWRegister::new();
+        a.set("from n1", 5, "n1");
+        let mut b = LWWRegister::new();
+        b.set("from n2", 5, "n2");
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+
+        assert_eq!(ab.get(), Some(&"from n2"));
+        assert_eq!(ab, ba);
+    }
+
+    #[test]
+    fn lww_register_merge_with_empty_is_noop() {
+        let mut register = LWWRegister::new();
+        register.set(3, 1, "n1");
+        let before = register.clone();
+
+        register.merge(&LWWRegister::new());
+
+        assert_eq!(register, before);
+    }
+
+    #[test]
+    fn lww_register_serde_roundtrip() -> Result<()> {
+        let mut register = LWWRegister::new();
+        register.set(42u64, 7, "n3");
+
+        let json = serde_json::to_value(&register)?;
+        assert_eq!(
+            json,
+            serde_json::json!({"value": 42, "timestamp": 7, "node_id": "n3"})
+        );
+        assert_eq!(serde_json::from_value::<LWWRegister<u64>>(json)?, register);
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::message::Message;
+
+/// Field of a request with the idempotency key its client gave it.
+pub const IDEMPOTENCY_KEY_FIELD: &str = "idempotency_key";
+
+/// Remembers the replies to the most recent requests, keyed by (src, msg_id), so a request
+/// recieved again can get the same reply without running its handler twice.
+///
+/// Replies can also be keyed by the idempotency key of their request instead, so a retry of the
+/// same operation is collapsed even when it is a new request, e.g. sent again by a client with a
+/// new msg_id or to another node. Holds at most `capacity` replies, the oldest is forgotten
+/// first.
+#[derive(Debug, Clone, Default)]
+pub struct Dedup {
+    replies: HashMap<Key, Message>,
+    // Keys of `replies` from oldest to newest.
+    order: VecDeque<Key>,
+    capacity: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Request(String, u64),
+    Idempotent(String),
+}
+
+impl Dedup {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// The reply sent to the request `msg_id` from `src`, if it is remembered.
+    pub fn get(&self, src: &str, msg_id: u64) -> Option<&Message> {
+        self.replies.get(&Key::Request(src.to_string(), msg_id))
+    }
+
+    /// Remembers `reply` as the reply to request `msg_id` from `src`.
+    pub fn insert(&mut self, src: &str, msg_id: u64, reply: Message) {
+        self.remember(Key::Request(src.to_string(), msg_id), reply);
+    }
+
+    /// The reply sent to the operation with the idempotency key `key`, if it is remembered.
+    pub fn get_key(&self, key: &str) -> Option<&Message> {
+        self.replies.get(&Key::Idempotent(key.to_string()))
+    }
+
+    /// Remembers `reply` as the reply to the operation with the idempotency key `key`.
+    pub fn insert_key(&mut self, key: &str, reply: Message) {
+        self.remember(Key::Idempotent(key.to_string()), reply);
+    }
+
+    fn remember(&mut self, key: Key, reply: Message) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.replies.insert(key.clone(), reply).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forgets the replies to the requests from `src` with a msg_id below `watermark`, which
+    /// `src` does not send again, see [`watermark`](crate::watermark). Returns how many.
+    pub fn prune(&mut self, src: &str, watermark: u64) -> usize {
+        let stale = |key: &Key| matches!(key, Key::Request(from, msg_id) if from == src && *msg_id < watermark);
+        let before = self.replies.len();
+        self.replies.retain(|key, _| !stale(key));
+        self.order.retain(|key| !stale(key));
+        before - self.replies.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.replies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replies.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dedup::Dedup;
+    use crate::message::Message;
+
+    fn reply(msg_id: u64) -> Message {
+        let mut reply = Message::default();
+        reply.body.in_reply_to = msg_id;
+        reply
+    }
+
+    #[test]
+    fn remembers_replies_by_src_and_msg_id() {
+        let mut dedup = Dedup::new(10);
+
+        dedup.insert("c1", 1, reply(1));
+
+        assert_eq!(dedup.get("c1", 1), Some(&reply(1)));
+        assert_eq!(dedup.get("c2", 1), None);
+        assert_eq!(dedup.get("c1", 2), None);
+    }
+
+    #[test]
+    fn forgets_oldest_past_capacity() {
+        let mut dedup = Dedup::new(2);
+
+        dedup.insert("c1", 1, reply(1));
+        dedup.insert("c1", 2, reply(2));
+        dedup.insert("c1", 3, reply(3));
+
+        assert_eq!(dedup.len(), 2);
+        assert_eq!(dedup.get("c1", 1), None);
+        assert!(dedup.get("c1", 2).is_some() && dedup.get("c1", 3).is_some());
+    }
+
+    #[test]
+    fn remembers_replies_by_idempotency_key() {
+        let mut dedup = Dedup::new(2);
+
+        dedup.insert_key("broadcast:7", reply(1));
+        dedup.insert("c1", 2, reply(2));
+
+        assert_eq!(dedup.get_key("broadcast:7"), Some(&reply(1)));
+        assert_eq!(dedup.get_key("broadcast:8"), None);
+        dedup.insert("c1", 3, reply(3));
+        assert_eq!(dedup.get_key("broadcast:7"), None);
+    }
+
+    #[test]
+    fn prunes_replies_below_watermark() {
+        let mut dedup = Dedup::new(10);
+        dedup.insert("n2", 1, reply(1));
+        dedup.insert("n2", 5, reply(5));
+        dedup.insert("n3", 1, reply(1));
+        dedup.insert_key("broadcast:7", reply(2));
+
+        assert_eq!(dedup.prune("n2", 5), 1);
+
+        assert_eq!(dedup.get("n2", 1), None);
+        assert!(dedup.get("n2", 5).is_some() && dedup.get("n3", 1).is_some());
+        assert_eq!(dedup.len(), 3);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// Bucket-hash digest of a set, to find where two large sets differ without sending them.
+///
+/// Items are spread over a fixed number of buckets by their hash, and the digest has a hash of
+/// every bucket, which does not depend on the order items were added in. Two sets with the same
+/// items have the same digest, buckets whose hashes differ are the only ones whose items need
+/// to be exchanged to reconcile the sets. Every node must hash items the same way, so they must
+/// all run the same build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    buckets: Vec<u64>,
+}
+
+impl Digest {
+    /// Digest of `items`, in `buckets` buckets.
+    pub fn new<'a, T: Hash + 'a>(items: impl IntoIterator<Item = &'a T>, buckets: usize) -> Self {
+        let mut digest = Self {
+            buckets: vec![0; buckets.max(1)],
+        };
+        for item in items {
+            let hash = hash(item);
+            let bucket = digest.bucket_of_hash(hash);
+            digest.buckets[bucket] ^= hash;
+        }
+        digest
+    }
+
+    /// Digest made of the bucket hashes of another, as sent in a message.
+    pub fn from_buckets(buckets: Vec<u64>) -> Self {
+        if buckets.is_empty() {
+            return Self { buckets: vec![0] };
+        }
+        Self { buckets }
+    }
+
+    /// The hash of every bucket.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// The bucket `item` is in.
+    pub fn bucket_of<T: Hash>(&self, item: &T) -> usize {
+        self.bucket_of_hash(hash(item))
+    }
+
+    /// Buckets whose hashes differ between this digest and `other`, every bucket if they do not
+    /// have as many.
+    pub fn diff(&self, other: &Digest) -> Vec<usize> {
+        if self.buckets.len() != other.buckets.len() {
+            return (0..self.buckets.len()).collect();
+        }
+        (0..self.buckets.len())
+            .filter(|&i| self.buckets[i] != other.buckets[i])
+            .collect()
+    }
+
+    fn bucket_of_hash(&self, hash: u64) -> usize {
+        (hash % self.buckets.len() as u64) as usize
+    }
+}
+
+fn hash<T: Hash>(item: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::digest::Digest;
+
+    #[test]
+    fn diff_finds_buckets_of_differing_items() {
+        let ours = Digest::new(&[1u64, 2, 3, 4], 16);
+        let theirs = Digest::new(&[4u64, 3, 2, 1, 5], 16);
+
+        assert_eq!(ours, Digest::new(&[4u64, 2, 3, 1], 16));
+        assert_eq!(ours.diff(&theirs), vec![ours.bucket_of(&5u64)]);
+        let received = Digest::from_buckets(theirs.buckets().to_vec());
+        assert_eq!(received.diff(&theirs), Vec::<usize>::new());
+        assert_eq!(ours.diff(&Digest::new(&[1u64], 4)).len(), 16);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    fmt,
+    fs::File,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+/// When state written to files is synced to disk, trading write latency for how much of it
+/// survives the machine crashing, not only the process.
+///
+/// Written files are always handed to the OS before a reply is sent, which is enough to survive
+/// Maelstrom killing the process. Syncing also survives losing the page cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    // Never, the OS writes files back when it sees fit.
+    #[default]
+    Never,
+    // After every write, before it is acked.
+    EveryWrite,
+    // After the first write once this long has passed since the last sync, so at most the
+    // writes of one interval are lost.
+    Interval(Duration),
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses "never", "every-write" or an interval in milliseconds.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Durability::Never),
+            "every-write" => Ok(Durability::EveryWrite),
+            millis => millis
+                .parse()
+                .map(|millis| Durability::Interval(Duration::from_millis(millis)))
+                .map_err(|e| format!("durability must be never, every-write or milliseconds: {e}")),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Never => write!(f, "never"),
+            Durability::EveryWrite => write!(f, "every-write"),
+            Durability::Interval(interval) => write!(f, "{}", interval.as_millis()),
+        }
+    }
+}
+
+/// Syncs the files written by a persistence layer as its [`Durability`] says.
+#[derive(Debug, Default)]
+pub(crate) struct Syncer {
+    durability: Durability,
+    // When a file was last synced.
+    last: Mutex<Option<Instant>>,
+}
+
+impl Syncer {
+    pub(crate) fn new(durability: Durability) -> Self {
+        Self {
+            durability,
+            last: Mutex::default(),
+        }
+    }
+
+    /// Whether a write at `now` is synced, and if so counts it as the last sync.
+    fn due(&self, now: Instant) -> bool {
+        let interval = match self.durability {
+            Durability::Never => return false,
+            Durability::EveryWrite => Duration::ZERO,
+            Durability::Interval(interval) => interval,
+        };
+        let mut last = self.last.lock().unwrap();
+        if last.is_some_and(|last| now.saturating_duration_since(last) < interval) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    }
+
+    /// Syncs the data of `file`, just written, if it is due.
+    pub(crate) fn written(&self, file: &File) -> Result<()> {
+        if self.due(Instant::now()) {
+            file.sync_data().context("cannot sync to disk")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::durability::{Durability, Syncer};
+
+    #[test]
+    fn syncs_follow_durability() {
+        let now = Instant::now();
+        let at = |millis| now + Duration::from_millis(millis);
+        let syncs = |durability: &str| {
+            let syncer = Syncer::new(durability.parse().unwrap());
+            [0, 10, 60, 100, 130].map(|millis| syncer.due(at(millis)))
+        };
+
+        assert_eq!(syncs("never"), [false; 5]);
+        assert_eq!(syncs("every-write"), [true; 5]);
+        assert_eq!(syncs("50"), [true, false, true, false, true]);
+        assert_eq!(
+            Durability::Interval(Duration::from_millis(50)).to_string(),
+            "50"
+        );
+        assert!("sometimes".parse::<Durability>().is_err());
+    }
+}
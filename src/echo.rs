@@ -0,0 +1,82 @@
+use anyhow::Result;
+
+use crate::{
+    error::NodeError,
+    message::{Body, Message},
+    runtime::{Ctx, Service},
+};
+
+/// The echo workload, which replies to every `echo` with its own body, run by the
+/// [`runtime`](crate::runtime).
+#[derive(Debug, Default)]
+pub struct Echo;
+
+impl Service for Echo {
+    fn init(&mut self, _: &mut Ctx) {}
+
+    fn handle(&mut self, ctx: &mut Ctx, msg: Message) -> Result<()> {
+        if msg.body.typ != "echo" {
+            return Err(NodeError::NoHandler(msg.body.typ).into());
+        }
+        let body = Body {
+            extra: msg.body.extra.clone(),
+            ..crate::body!("echo_ok")
+        };
+        ctx.reply(&msg, body);
+        Ok(())
+    }
+
+    fn tick(&mut self, _: &mut Ctx) {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, time::Duration};
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::echo::Echo;
+    use crate::message::Message;
+    use crate::replay::SharedOutput;
+    use crate::runtime::run;
+    use crate::transport::StdioTransport;
+
+    #[test]
+    fn echo_replies_with_body() -> Result<()> {
+        let input = [
+            json!({
+                "src": "c0", "dest": "n1",
+                "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }
+            }),
+            json!({
+                "src": "c1", "dest": "n1",
+                "body": { "type": "echo", "msg_id": 2, "echo": "hi" }
+            }),
+            json!({ "src": "c1", "dest": "n1", "body": { "type": "read", "msg_id": 3 } }),
+        ]
+        .map(|msg| format!("{msg}\n"))
+        .concat();
+        let output = SharedOutput::default();
+
+        run(
+            &mut Echo,
+            StdioTransport::from_io(Cursor::new(input), output.clone()),
+            Duration::from_secs(60),
+        )?;
+
+        let replies = String::from_utf8(output.contents())?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Message>, _>>()?;
+        assert_eq!(replies.len(), 3);
+        assert_eq!(
+            (replies[1].dest.as_str(), replies[1].body.in_reply_to),
+            ("c1", 2)
+        );
+        assert_eq!(replies[1].body.typ, "echo_ok");
+        assert_eq!(replies[1].body.extra["echo"], "hi");
+        assert_eq!(replies[2].body.extra["code"], 10, "no handler for read");
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+
+use crate::message::{
+    CRASH, MALFORMED_REQUEST, NOT_SUPPORTED, TEMPORARILY_UNAVAILABLE, TIMEOUT, TXN_CONFLICT,
+};
+
+/// Why a node failed to handle a message, each kind answered with its Maelstrom error code.
+///
+/// Handlers return [`anyhow::Error`]s, a `NodeError` returned by a handler, through `?` or
+/// [`anyhow::Error::new`], keeps its kind, as does an error caused by one, like a
+/// [`KvError`](crate::kv::KvError) of an RPC that timed out. Any other error is a
+/// [`NodeError::Handler`] error.
+#[derive(Debug)]
+pub enum NodeError {
+    // A message other than init arrived before the node was initialized.
+    NotReady(String),
+    // No handler is registered for the message's type.
+    NoHandler(String),
+    // The message is not a valid request of its type.
+    Malformed(String),
+    // Something the request waited on did not answer in time.
+    Timeout(String),
+    // The request cannot be served right now, but may be later.
+    Unavailable(String),
+    // The transaction of the request was aborted because it conflicts with another one.
+    TxnConflict(String),
+    // Any other failure of the handler, it may or may not have taken effect.
+    Handler(anyhow::Error),
+}
+
+impl NodeError {
+    /// The Maelstrom error code requests failing with this error are answered with.
+    pub fn code(&self) -> u64 {
+        match self {
+            Self::NotReady(_) | Self::Unavailable(_) => TEMPORARILY_UNAVAILABLE,
+            Self::NoHandler(_) => NOT_SUPPORTED,
+            Self::Malformed(_) => MALFORMED_REQUEST,
+            Self::Timeout(_) => TIMEOUT,
+            Self::TxnConflict(_) => TXN_CONFLICT,
+            Self::Handler(_) => CRASH,
+        }
+    }
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotReady(text) => write!(f, "Not Ready: {text}"),
+            Self::NoHandler(typ) => {
+                write!(f, "UnimplementedError: No handler for message type {typ}")
+            }
+            Self::Malformed(text) => write!(f, "MalformedRequest: {text}"),
+            Self::Timeout(text) => write!(f, "Timeout: {text}"),
+            Self::Unavailable(text) => write!(f, "Unavailable: {text}"),
+            Self::TxnConflict(text) => write!(f, "TxnConflict: {text}"),
+            Self::Handler(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+impl From<anyhow::Error> for NodeError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        // Display of the outermost error, errors wrapping another usually show it already.
+        let text = e.to_string();
+        let cause = e
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<NodeError>());
+        match cause {
+            Some(Self::NotReady(_)) => Self::NotReady(text),
+            Some(Self::NoHandler(typ)) => Self::NoHandler(typ.clone()),
+            Some(Self::Malformed(_)) => Self::Malformed(text),
+            Some(Self::Timeout(_)) => Self::Timeout(text),
+            Some(Self::Unavailable(_)) => Self::Unavailable(text),
+            Some(Self::TxnConflict(_)) => Self::TxnConflict(text),
+            Some(Self::Handler(_)) | None => Self::Handler(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{anyhow, Context};
+
+    use crate::error::NodeError;
+    use crate::kv::KvError;
+
+    #[test]
+    fn handler_errors_keep_their_kind() {
+        let malformed = anyhow::Error::new(NodeError::Malformed("no key".into()));
+        let wrapped = Err::<(), _>(NodeError::Timeout("no reply".into()))
+            .context("reading key")
+            .unwrap_err();
+
+        assert_eq!(NodeError::from(malformed).code(), 12);
+        assert_eq!(NodeError::from(wrapped).code(), 0);
+        let rpc = KvError::Rpc(NodeError::Timeout("no reply".into()).into());
+
+        assert_eq!(NodeError::from(anyhow::Error::new(rpc)).code(), 0);
+        let other = NodeError::from(anyhow!("disk full"));
+        assert_eq!(
+            (other.code(), other.to_string()),
+            (13, "disk full".to_string())
+        );
+    }
+}
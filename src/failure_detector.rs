@@ -0,0 +1,217 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Decides which peers are suspected dead from when they were heard from.
+///
+/// Detectors do not keep time themselves, they are told when peers are heard from and asked
+/// about a given time, see [`Heartbeats`](crate::heartbeat::Heartbeats).
+pub trait FailureDetector: fmt::Debug {
+    /// Records that `peer` was heard from at `now`.
+    fn heard_from(&mut self, peer: &str, now: Instant);
+
+    /// Whether `peer` is suspected dead at `now`. Peers never heard from are not suspected.
+    fn is_suspected(&self, peer: &str, now: Instant) -> bool;
+}
+
+/// Suspects peers that have not been heard from for a fixed timeout.
+#[derive(Debug)]
+pub struct TimeoutDetector {
+    timeout: Duration,
+    last_seen: HashMap<String, Instant>,
+}
+
+impl TimeoutDetector {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_seen: HashMap::new(),
+        }
+    }
+}
+
+impl FailureDetector for TimeoutDetector {
+    fn heard_from(&mut self, peer: &str, now: Instant) {
+        let seen = self.last_seen.entry(peer.to_string()).or_insert(now);
+        *seen = (*seen).max(now);
+    }
+
+    fn is_suspected(&self, peer: &str, now: Instant) -> bool {
+        self.last_seen
+            .get(peer)
+            .is_some_and(|&seen| now.saturating_duration_since(seen) > self.timeout)
+    }
+}
+
+/// Configuration of [`PhiAccrualDetector`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhiConfig {
+    // Peers are suspected once phi goes over this, a phi of 1 means a 10% chance the peer is
+    // wrongly suspected, 2 a 1% chance and so on.
+    pub threshold: f64,
+    // How many of the last intervals between hearing from a peer are kept.
+    pub window: usize,
+    // Lower bound of the standard deviation of intervals, so peers heard from very regularly
+    // are not suspected after the slightest delay.
+    pub min_std_dev: Duration,
+    // Delay on top of the usual intervals that is not suspicious, like a GC pause.
+    pub acceptable_pause: Duration,
+    // Interval expected before a peer has been heard from twice.
+    pub first_interval: Duration,
+}
+
+impl Default for PhiConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 8.0,
+            window: 100,
+            min_std_dev: Duration::from_millis(100),
+            acceptable_pause: Duration::ZERO,
+            first_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// The phi accrual failure detector of Hayashibara et al., as used by Akka and Cassandra.
+///
+/// Instead of a fixed timeout, it learns the distribution of the intervals between hearing from
+/// every peer, and suspects a peer once the time since it was last heard from is unlikely
+/// enough under that distribution. Suspicion so adapts to the latency of the network, a peer
+/// behind a slow link is given more time than one behind a fast link.
+#[derive(Debug)]
+pub struct PhiAccrualDetector {
+    config: PhiConfig,
+    peers: HashMap<String, Arrivals>,
+}
+
+/// When a peer was last heard from and the last intervals between hearing from it, in
+/// milliseconds.
+#[derive(Debug)]
+struct Arrivals {
+    last: Instant,
+    intervals: VecDeque<f64>,
+}
+
+impl PhiAccrualDetector {
+    pub fn new(config: PhiConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// The suspicion level of `peer` at `now`, 0 for peers never heard from.
+    pub fn phi(&self, peer: &str, now: Instant) -> f64 {
+        let Some(arrivals) = self.peers.get(peer) else {
+            return 0.0;
+        };
+        let n = arrivals.intervals.len() as f64;
+        let mean = arrivals.intervals.iter().sum::<f64>() / n;
+        let variance = arrivals
+            .intervals
+            .iter()
+            .map(|i| (i - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let std_dev = variance.sqrt().max(millis(self.config.min_std_dev));
+        let mean = mean + millis(self.config.acceptable_pause);
+        let elapsed = millis(now.saturating_duration_since(arrivals.last));
+        phi(elapsed, mean, std_dev)
+    }
+}
+
+impl FailureDetector for PhiAccrualDetector {
+    fn heard_from(&mut self, peer: &str, now: Instant) {
+        let Some(arrivals) = self.peers.get_mut(peer) else {
+            // Start from intervals with the mean and a standard deviation a quarter of the
+            // first interval, as Akka does.
+            let first = millis(self.config.first_interval);
+            self.peers.insert(
+                peer.to_string(),
+                Arrivals {
+                    last: now,
+                    intervals: VecDeque::from([first * 0.75, first * 1.25]),
+                },
+            );
+            return;
+        };
+        if now <= arrivals.last {
+            return;
+        }
+        if arrivals.intervals.len() >= self.config.window.max(1) {
+            arrivals.intervals.pop_front();
+        }
+        arrivals
+            .intervals
+            .push_back(millis(now.duration_since(arrivals.last)));
+        arrivals.last = now;
+    }
+
+    fn is_suspected(&self, peer: &str, now: Instant) -> bool {
+        self.phi(peer, now) > self.config.threshold
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// -log10 of the probability that an interval is longer than `elapsed`, for intervals normally
+/// distributed with `mean` and `std_dev`, using the logistic approximation of the normal CDF of
+/// Akka.
+fn phi(elapsed: f64, mean: f64, std_dev: f64) -> f64 {
+    let y = (elapsed - mean) / std_dev;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::failure_detector::{
+        FailureDetector, PhiAccrualDetector, PhiConfig, TimeoutDetector,
+    };
+
+    #[test]
+    fn timeout_suspects_silent_peers() {
+        let mut detector = TimeoutDetector::new(Duration::from_millis(100));
+        let start = Instant::now();
+        detector.heard_from("n2", start);
+
+        assert!(!detector.is_suspected("n2", start + Duration::from_millis(100)));
+        assert!(detector.is_suspected("n2", start + Duration::from_millis(101)));
+        assert!(!detector.is_suspected("n3", start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn phi_adapts_to_intervals() {
+        // Tests that a silence suspicious for a peer heard from every 100ms is not for one heard
+        // from every second.
+        let config = PhiConfig {
+            threshold: 3.0,
+            min_std_dev: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut detector = PhiAccrualDetector::new(config);
+        let start = Instant::now();
+        for i in 0..50 {
+            detector.heard_from("fast", start + Duration::from_millis(100 * i));
+            detector.heard_from("slow", start + Duration::from_millis(1000 * i));
+        }
+        let at = |last: u64, millis: u64| start + Duration::from_millis(last + millis);
+
+        assert!(detector.phi("fast", at(4900, 100)) < 1.0);
+        assert!(detector.is_suspected("fast", at(4900, 600)));
+        assert!(!detector.is_suspected("slow", at(49000, 600)));
+        assert!(detector.is_suspected("slow", at(49000, 2000)));
+        assert!(detector.phi("fast", at(4900, 300)) < detector.phi("fast", at(4900, 400)));
+        assert_eq!(detector.phi("unknown", at(0, 0)), 0.0);
+    }
+}
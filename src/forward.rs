@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::message::{Body, Message, MsgIds};
+
+/// Forwards requests to other nodes and relays their replies back to the original requesters,
+/// e.g. writes forwarded to the leader of their key.
+///
+/// Give the forwarder to the node with
+/// [`Node::with_forwarder`](crate::node::Node::with_forwarder), the node then relays replies to
+/// forwarded requests itself, instead of handling them.
+#[derive(Debug, Default)]
+pub struct Forwarder {
+    msg_ids: Arc<MsgIds>,
+    // Who a request was forwarded to and the request it forwards, with its body left out,
+    // keyed by the msg_id it was forwarded with.
+    forwarded: Mutex<HashMap<u64, (String, Message)>>,
+}
+
+impl Forwarder {
+    /// Creates a forwarder that numbers the replies it relays with `msg_ids`.
+    pub fn new(msg_ids: Arc<MsgIds>) -> Self {
+        Self {
+            msg_ids,
+            ..Default::default()
+        }
+    }
+
+    /// Forwards `request` to `dest`, returns the request to send it, from this node with
+    /// `msg_id`. The reply of `dest` is relayed to the sender of `request`, as a reply to it.
+    ///
+    /// Forwarded requests are forgotten once relayed, a request `dest` never answers is
+    /// remembered until the forwarder is dropped.
+    pub fn forward(&self, request: Message, dest: &str, msg_id: u64) -> Message {
+        let Message {
+            src,
+            dest: to,
+            body,
+        } = request;
+        let header = Message {
+            src,
+            dest: to.clone(),
+            body: Body {
+                msg_id: body.msg_id,
+                ..Default::default()
+            },
+        };
+        self.forwarded
+            .lock()
+            .unwrap()
+            .insert(msg_id, (dest.to_string(), header));
+        Message {
+            src: to,
+            dest: dest.to_string(),
+            body: Body { msg_id, ..body },
+        }
+    }
+
+    /// The reply to relay to the original requester, if `reply` answers a forwarded request.
+    pub fn relay(&self, reply: &Message) -> Option<Message> {
+        let mut forwarded = self.forwarded.lock().unwrap();
+        match forwarded.get(&reply.body.in_reply_to) {
+            Some((dest, _)) if *dest == reply.src => {
+                let (_, request) = forwarded.remove(&reply.body.in_reply_to)?;
+                Some(request.reply_with(Body {
+                    msg_id: self.msg_ids.next(),
+                    ..reply.body.clone()
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Number of forwarded requests waiting for a reply.
+    pub fn len(&self) -> usize {
+        self.forwarded.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::forward::Forwarder;
+    use crate::message::{Message, MsgIds};
+
+    fn msg(value: serde_json::Value) -> Message {
+        serde_json::from_value(value).expect("invalid message json.")
+    }
+
+    #[test]
+    fn relays_replies_to_requester() -> Result<()> {
+        let forwarder = Forwarder::new(Arc::new(MsgIds::new()));
+        let request = msg(json!({
+            "src": "c1", "dest": "n1", "body": { "type": "send", "msg_id": 4, "msg": 7 }
+        }));
+
+        let forward = forwarder.forward(request, "n2", 10);
+        let reply = msg(json!({
+            "src": "n2", "dest": "n1",
+            "body": { "type": "send_ok", "msg_id": 3, "in_reply_to": 10, "offset": 1 }
+        }));
+        let mut other = reply.clone();
+        other.src = "n3".into();
+
+        assert_eq!(
+            forward,
+            msg(json!({
+                "src": "n1", "dest": "n2", "body": { "type": "send", "msg_id": 10, "msg": 7 }
+            }))
+        );
+        assert_eq!(forwarder.relay(&other), None);
+        assert_eq!(
+            forwarder.relay(&reply),
+            Some(msg(json!({
+                "src": "n1", "dest": "c1",
+                "body": { "type": "send_ok", "msg_id": 0, "in_reply_to": 4, "offset": 1 }
+            })))
+        );
+        assert!(forwarder.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::{
+    error::NodeError,
+    message::{Body, Message, MsgIds},
+};
+
+/// How long the frags of a message wait for the rest of them before they are dropped.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most frags a message is split into, unless the max message size limits them further, see
+/// [`Fragments::with_max_message_size`]. Frags of a message with more are malformed.
+const MAX_FRAGS: usize = 1 << 16;
+
+/// Splits messages between nodes that are too large into `frag` messages, and puts them back
+/// together on the receiver, see [`Node::with_fragments`](crate::node::Node::with_fragments).
+///
+/// A message whose serialized body is longer than the fragment size is sent as frags with an
+/// `id`, unique to the sender, the `index` of the frag, the `total` of frags and a `chunk` of
+/// the body at most the fragment size long. Once every frag of a message is recieved the
+/// message is handled as if it was sent whole. Frags are sent once: a message missing a frag is
+/// dropped after a timeout, messages sent until acked are sent again whole. Messages to clients
+/// and Maelstrom services, which cannot reassemble them, are never split.
+///
+/// A message is split into at most [`MAX_FRAGS`] frags, frags claiming a larger `total` are
+/// rejected as malformed, so a peer cannot make this node reserve room for any number of them.
+#[derive(Debug)]
+pub struct Fragments {
+    max_size: usize,
+    // Most frags of a message.
+    max_frags: usize,
+    // Source of frag ids, from the clock so they are not reused after a restart.
+    ids: MsgIds,
+    // Frags of messages not recieved whole yet, by sender and frag id.
+    partial: Mutex<HashMap<(String, u64), Partial>>,
+}
+
+#[derive(Debug)]
+struct Partial {
+    chunks: Vec<Option<String>>,
+    missing: usize,
+    started: Instant,
+}
+
+impl Fragments {
+    /// Splits messages whose body is longer than `max_size` bytes serialized.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size: max_size.max(1),
+            max_frags: MAX_FRAGS,
+            ids: MsgIds::epoch_from_clock(),
+            partial: Mutex::default(),
+        }
+    }
+
+    /// Accepts only as many frags of a message as a message of `max_message_size` bytes takes,
+    /// if set, larger messages are rejected once reassembled anyway.
+    pub fn with_max_message_size(mut self, max_message_size: Option<usize>) -> Self {
+        if let Some(size) = max_message_size {
+            self.max_frags = size.div_ceil(self.max_size).clamp(1, MAX_FRAGS);
+        }
+        self
+    }
+
+    /// The messages to send for `msg`: `msg` itself, or its frags if it is too large.
+    pub fn split(&self, msg: Message) -> Vec<Message> {
+        if !msg.dest.starts_with('n') || msg.body.typ == "frag" {
+            return vec![msg];
+        }
+        let Ok(json) = serde_json::to_string(&msg.body) else {
+            return vec![msg];
+        };
+        if json.len() <= self.max_size {
+            return vec![msg];
+        }
+
+        let mut chunks = Vec::new();
+        let mut rest = json.as_str();
+        while !rest.is_empty() {
+            let mut end = self.max_size.min(rest.len());
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            // A character longer than the fragment size still goes in a chunk of its own.
+            if end == 0 {
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            let (chunk, tail) = rest.split_at(end);
+            chunks.push(chunk);
+            rest = tail;
+        }
+        if chunks.len() > self.max_frags {
+            warn!(
+                typ = %msg.body.typ,
+                bytes = json.len(),
+                "message is too large to split, sending it whole"
+            );
+            return vec![msg];
+        }
+        let id = self.ids.next_request();
+        debug!(
+            id,
+            typ = %msg.body.typ,
+            bytes = json.len(),
+            frags = chunks.len(),
+            "splitting message"
+        );
+        let total = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut body = Body {
+                    typ: "frag".to_string(),
+                    ..Default::default()
+                };
+                if let serde_json::Value::Object(extra) = json!({
+                    "id": id,
+                    "index": index,
+                    "total": total,
+                    "chunk": chunk,
+                }) {
+                    body.extra = extra;
+                }
+                Message {
+                    src: msg.src.clone(),
+                    dest: msg.dest.clone(),
+                    body,
+                }
+            })
+            .collect()
+    }
+
+    /// Takes in the `frag` message `frag` recieved at `now`, returns the message it is part of
+    /// once every frag of it was recieved. Partial messages older than the timeout are dropped.
+    pub fn reassemble(&self, frag: &Message, now: Instant) -> Result<Option<Message>> {
+        let body = &frag.body;
+        let (id, index, total) = (
+            body.get_u64("id")?,
+            body.get_u64("index")? as usize,
+            body.get_u64("total")? as usize,
+        );
+        let chunk = body.get_str("chunk")?;
+        if index >= total || total > self.max_frags {
+            return Err(NodeError::Malformed(format!(
+                "frag {index} of {total} of message {id} from {}, at most {} frags",
+                frag.src, self.max_frags
+            ))
+            .into());
+        }
+
+        let mut partial = self.partial.lock().unwrap();
+        partial.retain(|(src, id), partial| {
+            let expired = now.saturating_duration_since(partial.started) > REASSEMBLY_TIMEOUT;
+            if expired {
+                warn!(
+                    src,
+                    id,
+                    missing = partial.missing,
+                    "dropping partial message"
+                );
+            }
+            !expired
+        });
+        let key = (frag.src.clone(), id);
+        let message = partial.entry(key.clone()).or_insert_with(|| Partial {
+            chunks: vec![None; total],
+            missing: total,
+            started: now,
+        });
+        if message.chunks.len() != total {
+            return Err(NodeError::Malformed(format!(
+                "frag of message {id} from {} has {total} frags, not {}",
+                frag.src,
+                message.chunks.len()
+            ))
+            .into());
+        }
+        if message.chunks[index].is_none() {
+            message.chunks[index] = Some(chunk.to_string());
+            message.missing -= 1;
+        }
+        if message.missing > 0 {
+            return Ok(None);
+        }
+
+        let message = partial.remove(&key).expect("entry was just found");
+        let json: String = message.chunks.into_iter().flatten().collect();
+        let body = serde_json::from_str(&json).map_err(|e| {
+            NodeError::Malformed(format!(
+                "cannot reassemble message {id} from {}: {e}",
+                frag.src
+            ))
+        })?;
+        Ok(Some(Message {
+            src: frag.src.clone(),
+            dest: frag.dest.clone(),
+            body,
+        }))
+    }
+
+    /// Number of messages some frags of which were recieved, but not all.
+    pub fn partial(&self) -> usize {
+        self.partial.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::frag::Fragments;
+    use crate::message::{Body, Message};
+
+    fn message(dest: &str, value: &str) -> Message {
+        let mut body = Body {
+            typ: "gossip".into(),
+            msg_id: 7,
+            ..Default::default()
+        };
+        body.extra.insert("value".into(), json!(value));
+        Message {
+            src: "n1".into(),
+            dest: dest.into(),
+            body,
+        }
+    }
+
+    #[test]
+    fn large_messages_are_split_and_reassembled() -> Result<()> {
+        let fragments = Fragments::new(16);
+        let msg = message("n2", "a long value, with ünïcode in it");
+        let now = Instant::now();
+
+        let mut frags = fragments.split(msg.clone());
+        assert!(frags.len() > 2, "{frags:?}");
+        for frag in &frags {
+            assert_eq!(frag.body.typ, "frag");
+            assert!(frag.body.get_str("chunk")?.len() <= 16, "{frag:?}");
+        }
+        // Frags can arrive in any order, and more than once.
+        let last = frags.pop().unwrap();
+        frags.reverse();
+        for frag in &frags {
+            assert_eq!(fragments.reassemble(frag, now)?, None);
+        }
+        assert_eq!(fragments.reassemble(&frags[0], now)?, None);
+        assert_eq!(fragments.reassemble(&last, now)?, Some(msg));
+        assert_eq!(fragments.partial(), 0);
+
+        assert_eq!(Fragments::new(1024).split(message("n2", "short")).len(), 1);
+        assert_eq!(
+            fragments
+                .split(message("c1", "a long reply to a client"))
+                .len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn partial_messages_expire() -> Result<()> {
+        let fragments = Fragments::new(16);
+        let now = Instant::now();
+        let first = fragments.split(message("n2", "a long value that is split"));
+        let second = fragments.split(message("n2", "another value that is split"));
+
+        assert_eq!(fragments.reassemble(&first[0], now)?, None);
+        let later = now + Duration::from_secs(11);
+        assert_eq!(fragments.reassemble(&second[0], later)?, None);
+
+        assert_eq!(fragments.partial(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn frags_of_too_many_are_rejected() -> Result<()> {
+        let fragments = Fragments::new(16).with_max_message_size(Some(64));
+        let mut frag = fragments.split(message("n2", "a long value that is split"))[0].clone();
+
+        frag.body.extra.insert("total".into(), json!(u64::MAX));
+        assert!(fragments.reassemble(&frag, Instant::now()).is_err());
+        frag.body.extra.insert("total".into(), json!(5));
+        assert!(fragments.reassemble(&frag, Instant::now()).is_err());
+        assert_eq!(fragments.partial(), 0);
+
+        let whole = fragments.split(message("n2", &"x".repeat(100)));
+        assert_eq!(whole.len(), 1, "too large to split");
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{crdt::GCounter, message::Message, node::Handler, replicated::Replicated, reply};
+
+/// Grow-only counter workload (g-counter), backed by a [`GCounter`] CRDT.
+///
+/// An `add` only increments this node's entry, and is acked right away without talking to any
+/// other node. The whole counter is sent to the other nodes in a `merge` on a timer, see
+/// [`Replicated::sync`].
+pub type Counter = Replicated<GCounter>;
+
+/// Returns the handlers of the g-counter workload, backed by `counter`.
+pub fn handlers(counter: &Counter) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert("add".into(), Box::new(|msg, id| counter.add(msg, id)));
+    funs.insert("read".into(), Box::new(|msg, id| counter.read(msg, id)));
+    funs.insert("merge".into(), Box::new(|msg, id| counter.merge(msg, id)));
+    funs
+}
+
+impl Counter {
+    /// The value of the counter, as seen by this node.
+    pub fn value(&self) -> u64 {
+        self.state().value()
+    }
+
+    /// Handles an `add`, increments this node's entry by `delta`.
+    fn add(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let delta = msg.body.get_u64("delta")?;
+        let node_id = self.node_id();
+        self.state().increment(&node_id, delta);
+        Ok(reply!(msg, msg_id, "add_ok", {}))
+    }
+
+    fn read(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        Ok(reply!(msg, msg_id, "read_ok", { "value": self.value() }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::g_counter::{handlers, Counter};
+    use crate::message::{Body, Message, MsgIds};
+    use crate::node::Node;
+    use crate::outbox::Outbox;
+
+    fn msg(src: &str, dest: &str, body: serde_json::Value) -> Message {
+        serde_json::from_value(json!({ "src": src, "dest": dest, "body": body }))
+            .expect("invalid message json.")
+    }
+
+    #[test]
+    fn counters_converge_after_merging() -> Result<()> {
+        // Tests that adds on two nodes are counted by both once they exchange merges.
+        let mut nodes = vec![];
+        for node_id in ["n1", "n2"] {
+            let (tx, rx) = mpsc::channel();
+            let outbox = Arc::new(Outbox::new(
+                tx,
+                Arc::new(MsgIds::new()),
+                Duration::from_secs(1),
+            ));
+            nodes.push((Counter::new(outbox), rx, node_id));
+        }
+        let nodes: Vec<_> = nodes
+            .iter()
+            .map(|(counter, rx, node_id)| -> Result<_> {
+                let node = Node::with_init_handler(
+                    handlers(counter),
+                    Box::new(|id, ids| counter.init(id, ids)),
+                )?;
+                node.handle(msg(
+                    "c0",
+                    node_id,
+                    json!({ "type": "init", "msg_id": 1, "node_id": node_id, "node_ids": ["n1", "n2"] }),
+                ))?;
+                Ok((node, counter, rx))
+            })
+            .collect::<Result<_>>()?;
+
+        nodes[0].0.handle(msg(
+            "c1",
+            "n1",
+            json!({ "type": "add", "msg_id": 2, "delta": 3 }),
+        ))?;
+        nodes[1].0.handle(msg(
+            "c1",
+            "n2",
+            json!({ "type": "add", "msg_id": 2, "delta": 4 }),
+        ))?;
+        assert_eq!(nodes[0].2.try_iter().count(), 0, "adds send nothing");
+        for (_, counter, _) in &nodes {
+            assert_eq!(counter.sync(Instant::now())?, 1);
+        }
+        for (from, to) in [(0, 1), (1, 0)] {
+            for merge in nodes[from].2.try_iter() {
+                assert_eq!(merge.body.typ, "merge");
+                nodes[to].0.handle(merge)?;
+            }
+        }
+
+        assert_eq!(nodes[0].1.value(), 7);
+        let read = nodes[1]
+            .0
+            .handle(msg("c1", "n2", json!({ "type": "read", "msg_id": 3 })))?;
+        assert_eq!(read.body.extra["value"], 7);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_skips_peers_with_the_counter() -> Result<()> {
+        // Tests that a peer is sent the counter once per change, after it acked the last one.
+        let (tx, rx) = mpsc::channel();
+        let msg_ids = Arc::new(MsgIds::new());
+        let outbox = Arc::new(Outbox::new(tx, msg_ids.clone(), Duration::from_secs(1)));
+        let counter = Counter::new(outbox.clone());
+        let node = Node::with_init_handler(
+            handlers(&counter),
+            Box::new(|id, ids| counter.init(id, ids)),
+        )?
+        .with_msg_ids(msg_ids)
+        .with_outbox(outbox);
+        node.handle(msg(
+            "c0",
+            "n1",
+            json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] }),
+        ))?;
+        let now = Instant::now();
+        let add = |msg_id: u64| {
+            node.handle(msg(
+                "c1",
+                "n1",
+                json!({ "type": "add", "msg_id": msg_id, "delta": 1 }),
+            ))
+        };
+
+        add(2)?;
+        assert_eq!(counter.sync(now)?, 1);
+        add(3)?;
+        assert_eq!(counter.sync(now)?, 0, "merge is waiting for its ack");
+        let merge = rx.try_recv()?;
+        node.dispatch(merge.reply_with(Body {
+            typ: "merge_ok".into(),
+            ..Default::default()
+        }))?;
+        assert_eq!(counter.sync(now)?, 1, "counter changed since the ack");
+        let merge = rx.try_recv()?;
+        node.dispatch(merge.reply_with(Body {
+            typ: "merge_ok".into(),
+            ..Default::default()
+        }))?;
+
+        assert_eq!(counter.sync(now)?, 0);
+        Ok(())
+    }
+}
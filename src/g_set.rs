@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{crdt::GSet, message::Message, node::Handler, replicated::Replicated, reply};
+
+/// Grow-only set workload (g-set), backed by a [`GSet`] CRDT.
+///
+/// An `add` inserts the element in this node's set, and is acked right away without talking to
+/// any other node. The elements every peer is not known to have are sent to it in a `merge` on
+/// a timer, see [`Replicated::sync`].
+pub type Set = Replicated<GSet<u64>>;
+
+/// Returns the handlers of the g-set workload, backed by `set`.
+pub fn handlers(set: &Set) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert("add".into(), Box::new(|msg, id| set.add(msg, id)));
+    funs.insert("read".into(), Box::new(|msg, id| set.read(msg, id)));
+    funs.insert("merge".into(), Box::new(|msg, id| set.merge(msg, id)));
+    funs
+}
+
+impl Set {
+    /// Every element of the set as seen by this node, in order.
+    pub fn elements(&self) -> Vec<u64> {
+        let mut elements: Vec<u64> = self.state().iter().copied().collect();
+        elements.sort();
+        elements
+    }
+
+    /// Handles an `add`, inserts `element` in this node's set.
+    fn add(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let element = msg.body.get_u64("element")?;
+        self.state().insert(element);
+        Ok(reply!(msg, msg_id, "add_ok", {}))
+    }
+
+    fn read(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        Ok(reply!(msg, msg_id, "read_ok", { "value": self.elements() }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::g_set::{handlers, Set};
+    use crate::message::{Body, Message, MsgIds};
+    use crate::node::Node;
+    use crate::outbox::Outbox;
+
+    fn msg(src: &str, dest: &str, body: serde_json::Value) -> Message {
+        serde_json::from_value(json!({ "src": src, "dest": dest, "body": body }))
+            .expect("invalid message json.")
+    }
+
+    #[test]
+    fn sync_sends_only_missing_elements() -> Result<()> {
+        // Tests that a peer is sent the elements it does not have once it acked the last
+        // merge, and that the elements it sent are not sent back.
+        let (tx, rx) = mpsc::channel();
+        let msg_ids = Arc::new(MsgIds::new());
+        let outbox = Arc::new(Outbox::new(tx, msg_ids.clone(), Duration::from_secs(1)));
+        let set = Set::new(outbox.clone());
+        let node = Node::with_init_handler(handlers(&set), Box::new(|id, ids| set.init(id, ids)))?
+            .with_msg_ids(msg_ids)
+            .with_outbox(outbox);
+        node.handle(msg(
+            "c0",
+            "n1",
+            json!({ "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] }),
+        ))?;
+        let now = Instant::now();
+        for (msg_id, element) in [(2, 1), (3, 2)] {
+            node.handle(msg(
+                "c1",
+                "n1",
+                json!({ "type": "add", "msg_id": msg_id, "element": element }),
+            ))?;
+        }
+
+        assert_eq!(set.sync(now)?, 1);
+        let merge = rx.try_recv()?;
+        assert_eq!(merge.body.get_as::<Vec<u64>>("delta")?.len(), 2);
+        node.handle(msg(
+            "n2",
+            "n1",
+            json!({ "type": "merge", "msg_id": 1, "delta": [3] }),
+        ))?;
+        node.dispatch(merge.reply_with(Body {
+            typ: "merge_ok".into(),
+            ..Default::default()
+        }))?;
+        assert_eq!(set.sync(now)?, 0, "n2 has every element");
+        node.handle(msg(
+            "c1",
+            "n1",
+            json!({ "type": "add", "msg_id": 4, "element": 4 }),
+        ))?;
+        assert_eq!(set.sync(now)?, 1);
+
+        assert_eq!(rx.try_recv()?.body.extra["delta"], json!([4]));
+        let read = node.handle(msg("c1", "n1", json!({ "type": "read", "msg_id": 5 })))?;
+        assert_eq!(read.body.extra["value"], json!([1, 2, 3, 4]));
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tracing::{info, warn};
+
+use crate::{
+    failure_detector::{FailureDetector, TimeoutDetector},
+    message::{Body, Message},
+};
+
+/// Configuration of [`Heartbeats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    // How often a heartbeat is sent to every peer.
+    pub interval: Duration,
+    // How long since a peer was last heard from before it is suspected dead, unless another
+    // failure detector is used.
+    pub timeout: Duration,
+    // How many heartbeats in a row a peer can leave unanswered before it is unreachable.
+    pub missed_limit: usize,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(500),
+            timeout: Duration::from_secs(2),
+            missed_limit: 3,
+        }
+    }
+}
+
+/// A change of reachability of a peer, see [`Heartbeats::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer left the limit of heartbeats in a row unanswered.
+    Unreachable(String),
+    /// An unreachable peer was heard from again.
+    Recovered(String),
+}
+
+/// Tracks which peers are alive by sending them heartbeats, which they answer with a
+/// `heartbeat_ok`.
+///
+/// Every message from a peer, not only its `heartbeat_ok`, shows it is alive, so the node must
+/// let the heartbeats observe every message it receives, see
+/// [`Node::with_heartbeats`](crate::node::Node::with_heartbeats). Like the
+/// [`Outbox`](crate::outbox::Outbox) it does not keep time itself, [`Heartbeats::tick`] must be
+/// called regularly with the current time.
+///
+/// Which peers are suspected dead is up to a [`FailureDetector`], by default one that suspects
+/// peers not heard from for the timeout. Independently of it, a peer that leaves the missed
+/// limit of heartbeats in a row unanswered is unreachable, likely partitioned away, until it is
+/// heard from again, and subscribers are told of both, e.g. to buffer writes to the peer.
+#[derive(Debug)]
+pub struct Heartbeats {
+    sender: Sender<Message>,
+    config: HeartbeatConfig,
+    // ID of this node, set on init.
+    node_id: Mutex<String>,
+    // Every other node, in order, set on init.
+    peers: Mutex<Vec<String>>,
+    detector: Mutex<Box<dyn FailureDetector + Send>>,
+    // When heartbeats were last sent.
+    last_sent: Mutex<Option<Instant>>,
+    // Heartbeats every peer left unanswered in a row since it was last heard from.
+    missed: Mutex<BTreeMap<String, usize>>,
+    subscribers: Mutex<Vec<Sender<PeerEvent>>>,
+}
+
+impl Heartbeats {
+    /// Creates heartbeats that are sent to `sender`.
+    pub fn new(sender: Sender<Message>, config: HeartbeatConfig) -> Self {
+        Self {
+            sender,
+            detector: Mutex::new(Box::new(TimeoutDetector::new(config.timeout))),
+            config,
+            node_id: Mutex::default(),
+            peers: Mutex::default(),
+            last_sent: Mutex::default(),
+            missed: Mutex::default(),
+            subscribers: Mutex::default(),
+        }
+    }
+
+    /// Returns a receiver of every [`PeerEvent`] from now on.
+    pub fn subscribe(&self) -> Receiver<PeerEvent> {
+        let (sender, events) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        events
+    }
+
+    /// Sends `event` to every subscriber, forgetting those whose receiver was dropped.
+    fn publish(&self, event: PeerEvent) {
+        info!(?event, "peer reachability changed");
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Decides which peers are suspected dead with `detector` instead of the timeout.
+    pub fn with_detector(self, detector: impl FailureDetector + Send + 'static) -> Self {
+        *self.detector.lock().unwrap() = Box::new(detector);
+        self
+    }
+
+    /// Starts tracking every other node, meant to be used as (part of) the node's init handler.
+    ///
+    /// Peers count as heard from at `now`, so they are alive until the detector suspects them.
+    pub fn init(&self, node_id: &str, node_ids: &[String], now: Instant) {
+        *self.node_id.lock().unwrap() = node_id.to_string();
+        let mut peers: Vec<String> = node_ids
+            .iter()
+            .filter(|&id| id != node_id)
+            .cloned()
+            .collect();
+        peers.sort();
+        let mut detector = self.detector.lock().unwrap();
+        for peer in &peers {
+            detector.heard_from(peer, now);
+        }
+        *self.peers.lock().unwrap() = peers;
+    }
+
+    /// Records that `msg` was just received from its src, returns whether it is a heartbeat
+    /// reply, which needs no handling.
+    pub fn observe(&self, msg: &Message, now: Instant) -> bool {
+        if !self.peers.lock().unwrap().contains(&msg.src) {
+            return false;
+        }
+        let mut detector = self.detector.lock().unwrap();
+        if detector.is_suspected(&msg.src, now) {
+            info!(peer = %msg.src, "peer is alive again");
+        }
+        detector.heard_from(&msg.src, now);
+        drop(detector);
+        let missed = self.missed.lock().unwrap().insert(msg.src.clone(), 0);
+        if missed.is_some_and(|missed| missed >= self.config.missed_limit) {
+            self.publish(PeerEvent::Recovered(msg.src.clone()));
+        }
+        msg.body.typ == "heartbeat_ok"
+    }
+
+    /// Sends a heartbeat to every peer if one is due at `now`. Peers that did not answer the
+    /// last one missed it, the ones reaching the missed limit are unreachable.
+    pub fn tick(&self, now: Instant) -> Result<()> {
+        let mut last_sent = self.last_sent.lock().unwrap();
+        if last_sent.is_some_and(|sent| now.saturating_duration_since(sent) < self.config.interval)
+        {
+            return Ok(());
+        }
+        let first = last_sent.replace(now).is_none();
+
+        let node_id = self.node_id.lock().unwrap().clone();
+        let peers = self.peers.lock().unwrap().clone();
+        if !first {
+            let mut missed = self.missed.lock().unwrap();
+            for peer in &peers {
+                let missed = missed.entry(peer.clone()).or_default();
+                *missed += 1;
+                if *missed == self.config.missed_limit {
+                    self.publish(PeerEvent::Unreachable(peer.clone()));
+                }
+            }
+        }
+        for peer in &peers {
+            self.sender
+                .send(Message {
+                    src: node_id.clone(),
+                    dest: peer.clone(),
+                    body: Body {
+                        typ: "heartbeat".to_string(),
+                        ..Default::default()
+                    },
+                })
+                .map_err(|_| anyhow!("Unavailable: heartbeat receiver dropped"))?;
+        }
+        Ok(())
+    }
+
+    /// Calls [`Heartbeats::tick`] as often as heartbeats are due from a background thread,
+    /// until the receiver of the heartbeats is dropped.
+    pub fn tick_every_interval(self: &Arc<Self>) -> thread::JoinHandle<()> {
+        let heartbeats = Arc::clone(self);
+        thread::spawn(move || loop {
+            if let Err(e) = heartbeats.tick(Instant::now()) {
+                warn!("stopped sending heartbeats: {e:#}");
+                return;
+            }
+            thread::sleep(heartbeats.config.interval);
+        })
+    }
+
+    /// Peers not suspected dead at `now`, in order.
+    pub fn alive_peers(&self, now: Instant) -> Vec<String> {
+        let detector = self.detector.lock().unwrap();
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|peer| !detector.is_suspected(peer, now))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the peer `peer` left the missed limit of heartbeats in a row unanswered.
+    pub fn is_unreachable(&self, peer: &str) -> bool {
+        let missed = self.missed.lock().unwrap();
+        missed
+            .get(peer)
+            .is_some_and(|&missed| missed >= self.config.missed_limit)
+    }
+
+    /// Whether the peer `peer` is suspected dead at `now`. Nodes that are not peers, like
+    /// clients and services, are never suspected.
+    pub fn is_suspected(&self, peer: &str, now: Instant) -> bool {
+        self.detector.lock().unwrap().is_suspected(peer, now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+
+    use crate::failure_detector::{PhiAccrualDetector, PhiConfig};
+    use crate::heartbeat::{HeartbeatConfig, Heartbeats, PeerEvent};
+    use crate::message::{Body, Message};
+
+    fn heartbeats() -> (Heartbeats, mpsc::Receiver<Message>, Instant) {
+        let (tx, rx) = mpsc::channel();
+        let heartbeats = Heartbeats::new(
+            tx,
+            HeartbeatConfig {
+                interval: Duration::from_millis(100),
+                timeout: Duration::from_millis(500),
+                missed_limit: 3,
+            },
+        );
+        let start = Instant::now();
+        let ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+        heartbeats.init("n1", &ids, start);
+        (heartbeats, rx, start)
+    }
+
+    fn from(src: &str, typ: &str) -> Message {
+        Message {
+            src: src.into(),
+            dest: "n1".into(),
+            body: Body {
+                typ: typ.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn silent_peers_are_suspected() {
+        // Tests that a peer is alive while it is heard from, by any message, and suspected
+        // once it has been silent for the timeout.
+        let (heartbeats, _rx, start) = heartbeats();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert!(heartbeats.observe(&from("n2", "heartbeat_ok"), at(400)));
+        assert!(!heartbeats.observe(&from("n3", "broadcast"), at(300)));
+
+        assert_eq!(heartbeats.alive_peers(at(500)), vec!["n2", "n3"]);
+        assert_eq!(heartbeats.alive_peers(at(850)), vec!["n2"]);
+        assert!(heartbeats.alive_peers(at(1000)).is_empty());
+        assert!(heartbeats.is_suspected("n3", at(850)));
+        assert!(!heartbeats.is_suspected("c1", at(1000)));
+    }
+
+    #[test]
+    fn detector_decides_suspicion() {
+        // Tests that a phi accrual detector, which learned that peers answer every 100ms,
+        // suspects them well before the timeout.
+        let (heartbeats, _rx, start) = heartbeats();
+        let heartbeats = heartbeats.with_detector(PhiAccrualDetector::new(PhiConfig {
+            threshold: 3.0,
+            min_std_dev: Duration::from_millis(10),
+            first_interval: Duration::from_millis(100),
+            ..Default::default()
+        }));
+        let ids: Vec<String> = ["n1", "n2", "n3"].map(String::from).to_vec();
+        heartbeats.init("n1", &ids, start);
+        let at = |millis| start + Duration::from_millis(millis);
+
+        for millis in (100..=1000).step_by(100) {
+            heartbeats.observe(&from("n2", "heartbeat_ok"), at(millis));
+            heartbeats.observe(&from("n3", "heartbeat_ok"), at(millis.min(500)));
+        }
+
+        assert_eq!(heartbeats.alive_peers(at(1000)), vec!["n2"]);
+    }
+
+    #[test]
+    fn heartbeats_are_sent_every_interval() -> Result<()> {
+        let (heartbeats, rx, start) = heartbeats();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        heartbeats.tick(at(0))?;
+        heartbeats.tick(at(50))?;
+        heartbeats.tick(at(100))?;
+
+        let sent: Vec<Message> = rx.try_iter().collect();
+        let dests: Vec<&str> = sent.iter().map(|m| m.dest.as_str()).collect();
+        assert_eq!(dests, vec!["n2", "n3", "n2", "n3"]);
+        assert!(sent.iter().all(|m| m.body.typ == "heartbeat"));
+        Ok(())
+    }
+
+    #[test]
+    fn peers_missing_heartbeats_are_unreachable_until_heard_from() -> Result<()> {
+        let (heartbeats, _rx, start) = heartbeats();
+        let events = heartbeats.subscribe();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        for millis in (0..=300).step_by(100) {
+            heartbeats.tick(at(millis))?;
+            heartbeats.observe(&from("n3", "heartbeat_ok"), at(millis));
+        }
+        assert!(heartbeats.is_unreachable("n2"));
+        assert!(!heartbeats.is_unreachable("n3"));
+        heartbeats.tick(at(400))?;
+        heartbeats.observe(&from("n2", "broadcast"), at(450));
+
+        let events: Vec<PeerEvent> = events.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                PeerEvent::Unreachable("n2".into()),
+                PeerEvent::Recovered("n2".into())
+            ]
+        );
+        assert!(!heartbeats.is_unreachable("n2"));
+        Ok(())
+    }
+}
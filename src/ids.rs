@@ -0,0 +1,173 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Start of the timestamps of IDs, 2024-01-01T00:00:00Z, so the 42 bits of milliseconds of 64
+/// bit IDs last for over a century.
+const EPOCH: Duration = Duration::from_secs(1_704_067_200);
+
+/// Bits of the node hash and of the counter of 64 bit IDs, the timestamp takes the rest.
+const NODE_BITS_64: u32 = 10;
+const SEQ_BITS_64: u32 = 12;
+
+/// Bits of the timestamp of snowflake IDs, one less than of 64 bit IDs so snowflake IDs are
+/// positive as signed 64 bit integers. 41 bits of milliseconds last for 69 years.
+const MILLIS_BITS_SNOWFLAKE: u32 = 41;
+
+/// Bits of the node hash and of the counter of 128 bit IDs, the timestamp takes the top 64.
+const NODE_BITS_128: u32 = 32;
+const SEQ_BITS_128: u32 = 32;
+
+/// Generates flake IDs: a millisecond timestamp, a hash of the node ID and a per node counter,
+/// from the most to the least significant bits.
+///
+/// IDs of a generator only ever go up, even if the system clock goes back: the timestamp of an
+/// ID is never before the one of the previous ID, and when the counter of a millisecond runs
+/// out the next millisecond is used early. IDs of different nodes differ by the node hash, 10
+/// bits for 64 bit IDs and 32 bits for 128 bit IDs, so prefer 128 bit IDs when more than a few
+/// nodes generate them, or give every node its own index with [`FlakeIds::with_node_index`].
+#[derive(Debug)]
+pub struct FlakeIds {
+    // FNV-1a hash of the node ID, or its index.
+    node: u64,
+    // (timestamp, counter) of the last ID.
+    last: Mutex<(u64, u64)>,
+}
+
+impl FlakeIds {
+    /// Creates a generator of IDs for the node `node_id`.
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node: fnv1a(node_id.as_bytes()),
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Creates a generator of IDs for the node at `index` among the nodes of the cluster, whose
+    /// 64 bit and snowflake IDs never collide with the ones of other nodes with an index below
+    /// 1024.
+    pub fn with_node_index(index: usize) -> Self {
+        Self {
+            node: index as u64,
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Returns a new snowflake ID, a 64 bit ID whose top bit is zero: 41 bits of milliseconds
+    /// since 2024, 10 bits of node hash or index and a 12 bit counter.
+    pub fn next_snowflake(&self) -> u64 {
+        let (millis, seq) = self.next_at(now_millis(), SEQ_BITS_64);
+        let millis = millis & mask(MILLIS_BITS_SNOWFLAKE);
+        let node = self.node & mask(NODE_BITS_64);
+        (millis << (NODE_BITS_64 + SEQ_BITS_64)) | (node << SEQ_BITS_64) | seq
+    }
+
+    /// Returns a new 64 bit ID: 42 bits of milliseconds since 2024, 10 bits of node hash and a
+    /// 12 bit counter.
+    pub fn next_u64(&self) -> u64 {
+        let (millis, seq) = self.next_at(now_millis(), SEQ_BITS_64);
+        let node = self.node & mask(NODE_BITS_64);
+        (millis << (NODE_BITS_64 + SEQ_BITS_64)) | (node << SEQ_BITS_64) | seq
+    }
+
+    /// Returns a new 128 bit ID: 64 bits of milliseconds since 2024, 32 bits of node hash and a
+    /// 32 bit counter.
+    pub fn next_u128(&self) -> u128 {
+        let (millis, seq) = self.next_at(now_millis(), SEQ_BITS_128);
+        let node = (self.node & mask(NODE_BITS_128)) as u128;
+        ((millis as u128) << (NODE_BITS_128 + SEQ_BITS_128)) | (node << SEQ_BITS_128) | seq as u128
+    }
+
+    /// The (timestamp, counter) of the next ID at `millis`, with a counter of `seq_bits`.
+    fn next_at(&self, millis: u64, seq_bits: u32) -> (u64, u64) {
+        let mut last = self.last.lock().unwrap();
+        let (last_millis, last_seq) = *last;
+        let next = if millis > last_millis {
+            (millis, 0)
+        } else if last_seq < mask(seq_bits) {
+            (last_millis, last_seq + 1)
+        } else {
+            (last_millis + 1, 0)
+        };
+        *last = next;
+        next
+    }
+}
+
+fn mask(bits: u32) -> u64 {
+    (1 << bits) - 1
+}
+
+/// Milliseconds since [`EPOCH`].
+fn now_millis() -> u64 {
+    let since_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_unix.saturating_sub(EPOCH).as_millis() as u64
+}
+
+/// 64 bit FNV-1a, stable across processes and Rust versions unlike the std hashers.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::ids::{FlakeIds, SEQ_BITS_64};
+
+    #[test]
+    fn ids_go_up() {
+        let ids = FlakeIds::new("n1");
+
+        let generated: Vec<u64> = (0..10_000).map(|_| ids.next_u64()).collect();
+        let generated_128: Vec<u128> = (0..10_000).map(|_| ids.next_u128()).collect();
+
+        assert!(generated.windows(2).all(|w| w[0] < w[1]));
+        assert!(generated_128.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn clock_going_back_keeps_ids_going_up() {
+        // Tests that the timestamp never goes back and a full counter moves to the next
+        // millisecond.
+        let ids = FlakeIds::new("n1");
+
+        assert_eq!(ids.next_at(100, SEQ_BITS_64), (100, 0));
+        assert_eq!(ids.next_at(90, SEQ_BITS_64), (100, 1));
+        *ids.last.lock().unwrap() = (100, 4095);
+        assert_eq!(ids.next_at(100, SEQ_BITS_64), (101, 0));
+        assert_eq!(ids.next_at(100, SEQ_BITS_64), (101, 1));
+        assert_eq!(ids.next_at(200, SEQ_BITS_64), (200, 0));
+    }
+
+    #[test]
+    fn snowflakes_are_positive_and_differ_by_node_index() {
+        let nodes: Vec<FlakeIds> = (0..3).map(FlakeIds::with_node_index).collect();
+
+        let generated: Vec<u64> = nodes.iter().map(|ids| ids.next_snowflake()).collect();
+
+        assert!(generated.iter().all(|&id| (id as i64) > 0));
+        let indexes: Vec<u64> = generated.iter().map(|id| (id >> 12) & 1023).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn nodes_generate_different_ids() {
+        let nodes: Vec<FlakeIds> = ["n0", "n1", "n2", "n3", "n4"]
+            .into_iter()
+            .map(FlakeIds::new)
+            .collect();
+
+        let generated: HashSet<u128> = nodes
+            .iter()
+            .flat_map(|ids| (0..1000).map(|_| ids.next_u128()).collect::<Vec<_>>())
+            .collect();
+
+        assert_eq!(generated.len(), 5000);
+    }
+}
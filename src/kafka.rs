@@ -0,0 +1,1498 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{mpsc::Sender, Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::debug;
+
+use crate::{
+    body,
+    message::{Body, Message, KEY_DOES_NOT_EXIST, PRECONDITION_FAILED},
+    node::{no_reply, Handler},
+    persistence::Persist,
+    reply,
+    wal::Wal,
+};
+
+/// Name of Maelstrom's linearizable key value service.
+const LIN_KV: &str = "lin-kv";
+
+/// lin-kv key that holds the committed offsets of every key.
+const COMMITTED_KEY: &str = "committed-offsets";
+
+/// How long before its lease expires a leader stops using it, so nodes whose clocks are slightly
+/// apart do not both lead a key.
+const LEASE_MARGIN: Duration = Duration::from_millis(100);
+
+/// Kafka-style replicated log workload.
+///
+/// The log of every key is stored in lin-kv as a JSON array, so every node can serve `send` and
+/// `poll` for any key. A `send` reads the current log and appends to it with a `cas`, the offset of
+/// a message is its index in the log. Lost races (precondition failed) are retried from the read.
+///
+/// With [`Kafka::with_leases`] a node takes a lease on a key in lin-kv before appending to its
+/// log, and while it holds the lease it appends without reading the log first, from the log it
+/// last appended. Sends for the key to other nodes are forwarded to it, so a single node appends
+/// to every key and appends do not race. Appends are still a cas, so a node that lost its lease
+/// without knowing cannot corrupt the log, it fails the cas and retries from the read. With
+/// [`Kafka::with_batched_appends`] the leader assigns offsets itself and has at most one cas in
+/// flight per key, the sends that arrive meanwhile are appended together by the next one, so
+/// there is a cas per batch of sends instead of one per send.
+///
+/// With [`Kafka::with_owners`] every key is owned by one node instead, picked by consistent
+/// hashing of the key over the nodes of the cluster. The owner keeps the log of its keys in
+/// memory and appends without talking to lin-kv, other nodes forward sends and polls of the key
+/// to it. With [`Kafka::recover_from`] owners append every send to a write-ahead log before
+/// acking it, and the logs they own are replayed from it when they start again.
+///
+/// Committed offsets are kept in lin-kv too, all of them under one key, so every node lists the
+/// same offsets and they outlive the nodes that committed them. A commit reads them and raises
+/// them with a cas, retried from the read when it loses a race.
+///
+/// Since handlers can only produce one message, talking to lin-kv is a chain of handlers: every
+/// request to lin-kv is remembered in `pending` under the msg_id it was sent with, and the
+/// `read_ok`/`cas_ok`/`error` handlers continue the client operation that is waiting on it.
+#[derive(Debug, Default)]
+pub struct Kafka {
+    // Client operations waiting on a lin-kv reply, keyed by the msg_id of the lin-kv request.
+    pending: Mutex<HashMap<u64, Pending>>,
+    // Committed offsets per key, as last read from or written to lin-kv.
+    committed: Mutex<HashMap<String, u64>>,
+    // How long leases last, None to not use leases.
+    lease: Option<Duration>,
+    // Leader of every key as last seen by this node.
+    leaders: Mutex<HashMap<String, Leader>>,
+    // Most messages a poll returns per key, None to return all of them.
+    poll_limit: Option<usize>,
+    // Whether keys are owned by a node, which keeps their logs in `logs`.
+    owned: bool,
+    // Every node in the cluster, set on init.
+    nodes: Mutex<Vec<String>>,
+    // Logs of the keys this node owns.
+    logs: Mutex<HashMap<String, Log>>,
+    // Which entries of the logs it owns this node drops.
+    retention: Retention,
+    // Where the sends to the keys this node owns are logged before they are acked, if anywhere.
+    wal: OnceLock<Wal>,
+    // Where the replies to the sends of a batch are sent, if appends are batched.
+    outbox: Option<Sender<Message>>,
+    // Sends waiting for the append in flight to their key, keyed by key. A key is in it while an
+    // append to it is in flight, when appends are batched.
+    batches: Mutex<HashMap<String, Vec<Message>>>,
+}
+
+/// Which entries of the logs they own nodes drop, so that logs do not grow forever. Polls from
+/// an offset that was dropped return the entries from the first one left.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Drop the entries below the committed offset of their key.
+    pub committed: bool,
+    /// Keep at most this many entries per key, dropping the oldest.
+    pub max_entries: Option<usize>,
+}
+
+/// The entries of a log from the offset `start`, the ones before are dropped.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+struct Log {
+    start: u64,
+    entries: VecDeque<Value>,
+}
+
+impl Log {
+    /// Offset of the next entry appended.
+    fn end(&self) -> u64 {
+        self.start + self.entries.len() as u64
+    }
+
+    /// Drops the entries below `offset`.
+    fn drop_below(&mut self, offset: u64) {
+        let offset = offset.clamp(self.start, self.end());
+        self.entries.drain(..(offset - self.start) as usize);
+        self.start = offset;
+    }
+}
+
+impl From<Vec<Value>> for Log {
+    fn from(entries: Vec<Value>) -> Self {
+        Self {
+            start: 0,
+            entries: entries.into(),
+        }
+    }
+}
+
+/// How the nodes of the kafka workload share keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KafkaMode {
+    // Logs are in lin-kv, the node holding the lease of a key appends to it.
+    #[default]
+    Leases,
+    // Every key is owned by one node, which keeps its log.
+    Owners,
+}
+
+/// The lease of a key, with the log this node last appended to it when it holds the lease.
+type Leader = (Lease, Option<Vec<Value>>);
+
+/// A lease on a key, as kept in lin-kv.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+struct Lease {
+    // The node leading the key.
+    node: String,
+    // When the lease expires, in milliseconds since the Unix epoch.
+    expires: u64,
+}
+
+/// A client operation waiting on lin-kv.
+#[derive(Debug)]
+enum Pending {
+    // Sends waiting for the current log of their key.
+    ReadLog {
+        requests: Vec<Message>,
+    },
+    // Sends waiting for the cas that appends their messages from `offset` on, making the log
+    // `log`.
+    AppendLog {
+        requests: Vec<Message>,
+        offset: u64,
+        log: Vec<Value>,
+    },
+    // A `send` waiting for the lease of its key.
+    ReadLease {
+        request: Message,
+    },
+    // A `send` waiting for the cas that takes or renews `lease`.
+    TakeLease {
+        request: Message,
+        lease: Lease,
+    },
+    // A `send` forwarded to the leader of its key.
+    Forwarded {
+        request: Message,
+    },
+    // A `commit_offsets` waiting for the committed offsets, to raise them.
+    ReadCommitted {
+        request: Message,
+    },
+    // A `commit_offsets` waiting for the cas that raises the committed offsets to `offsets`.
+    CasCommitted {
+        request: Message,
+        offsets: BTreeMap<String, u64>,
+    },
+    // A `list_committed_offsets` waiting for the committed offsets.
+    ListCommitted {
+        request: Message,
+    },
+    // A `poll` waiting for the log of `remaining[0]`, `msgs` has the messages polled so far and
+    // `next` the offsets to poll the keys cut short by the poll limit from.
+    Poll {
+        request: Message,
+        remaining: Vec<(String, u64)>,
+        msgs: Map<String, Value>,
+        next: Map<String, Value>,
+    },
+}
+
+/// Returns the handlers of the kafka workload, backed by `kafka`.
+pub fn handlers(kafka: &Kafka) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert("send".into(), Box::new(|msg, id| kafka.send(msg, id)));
+    funs.insert("poll".into(), Box::new(|msg, id| kafka.poll(msg, id)));
+    funs.insert(
+        "commit_offsets".into(),
+        Box::new(|msg, id| kafka.commit_offsets(msg, id)),
+    );
+    funs.insert(
+        "list_committed_offsets".into(),
+        Box::new(|msg, id| kafka.list_committed_offsets(msg, id)),
+    );
+    funs.insert("read_ok".into(), Box::new(|msg, id| kafka.read_ok(msg, id)));
+    funs.insert("cas_ok".into(), Box::new(|msg, id| kafka.cas_ok(msg, id)));
+    funs.insert(
+        "send_ok".into(),
+        Box::new(|msg, id| kafka.forwarded_ok(msg, id)),
+    );
+    funs.insert(
+        "poll_ok".into(),
+        Box::new(|msg, id| kafka.owner_poll_ok(msg, id)),
+    );
+    funs.insert("error".into(), Box::new(|msg, id| kafka.kv_error(msg, id)));
+    funs
+}
+
+impl Kafka {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a kafka where nodes take leases of `lease` on keys before appending to them.
+    pub fn with_leases(lease: Duration) -> Self {
+        Self {
+            lease: Some(lease),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a kafka where every key is owned by one node, which keeps its log in memory.
+    pub fn with_owners() -> Self {
+        Self {
+            owned: true,
+            ..Default::default()
+        }
+    }
+
+    /// Batches the appends of the keys this node leads: sends that arrive while an append to
+    /// their key is in flight are appended together by the next one, and the replies to the
+    /// sends of a batch but one are sent to `outbox`.
+    pub fn with_batched_appends(mut self, outbox: Sender<Message>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Drops the entries of the logs this node owns that `retention` says to.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Remembers the nodes of the cluster, which own the keys, meant to be used as the node's
+    /// init handler.
+    pub fn init(&self, _node_id: &str, node_ids: &[String]) {
+        *self.nodes.lock().unwrap() = node_ids.to_vec();
+    }
+
+    /// Replays the sends in `wal` into the logs this node owns, and logs every send to the keys
+    /// it owns there from now on, before acking it. Meant to be called on init, as the log is
+    /// usually per node.
+    pub fn recover_from(&self, wal: Wal) -> Result<()> {
+        let mut logs = self.logs.lock().unwrap();
+        for entry in wal.replay()? {
+            let key = entry["key"]
+                .as_str()
+                .ok_or_else(|| anyhow!("wal entry without key: {entry}"))?;
+            let log = logs.entry(key.to_string()).or_default();
+            log.entries.push_back(entry["msg"].clone());
+            self.compact(key, log);
+        }
+        self.wal
+            .set(wal)
+            .map_err(|_| anyhow!("kafka wal is already set"))
+    }
+
+    /// Node that owns `key`, None if keys are not owned. The one whose hash with the key is
+    /// highest, so that only the keys of a node move when it leaves.
+    fn owner(&self, key: &str) -> Option<String> {
+        if !self.owned {
+            return None;
+        }
+        self.nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .max_by_key(|node| {
+                let mut hasher = DefaultHasher::new();
+                (node, key).hash(&mut hasher);
+                hasher.finish()
+            })
+            .cloned()
+    }
+
+    /// Returns at most `limit` messages per key from a poll, the reply has the offsets to poll
+    /// the keys with more messages from in `next_offsets`.
+    pub fn with_poll_limit(mut self, limit: usize) -> Self {
+        self.poll_limit = Some(limit);
+        self
+    }
+
+    /// Handles a `send`, from a client or forwarded by another node.
+    ///
+    /// Without leases, or when forwarded to a node that is not the leader, it starts by reading
+    /// the log of the key from lin-kv. With leases, the leader appends to the log it last
+    /// appended, renewing its lease first once it is half over, and other nodes forward the
+    /// send to the leader they know, or read the lease to learn or take it.
+    ///
+    /// With owners, the owner appends to the log it keeps and other nodes forward the send to
+    /// it.
+    fn send(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        if let Some(owner) = self.owner(msg.body.get_str("key")?) {
+            if owner != msg.dest {
+                return self.forward(msg, &owner, msg_id);
+            }
+            let entry: Value = msg.body.get_as("msg")?;
+            let key = msg.body.get_str("key")?;
+            let mut logs = self.logs.lock().unwrap();
+            if let Some(wal) = self.wal.get() {
+                wal.append(&json!({ "key": key, "msg": entry }))
+                    .map_err(|e| anyhow!("Internal: cannot log send: {e:#}"))?;
+            }
+            let log = logs.entry(key.to_string()).or_default();
+            log.entries.push_back(entry);
+            let offset = log.end() - 1;
+            self.compact(key, log);
+            return Ok(reply!(msg, msg_id, "send_ok", { "offset": offset }));
+        }
+        let Some(duration) = self.lease else {
+            return self.read_log(vec![msg], msg_id);
+        };
+        let key = msg.body.get_str("key")?.to_string();
+        let now = now_millis();
+        let leader = self.leaders.lock().unwrap().get(&key).cloned();
+
+        match leader {
+            Some((lease, log))
+                if lease.node == msg.dest && lease.expires > now + millis(LEASE_MARGIN) =>
+            {
+                if lease.expires < now + millis(duration) / 2 {
+                    let renewed = Lease {
+                        node: msg.dest.clone(),
+                        expires: now + millis(duration),
+                    };
+                    return self.take_lease(msg, Some(lease), renewed, msg_id);
+                }
+                self.lead_append(msg, log, msg_id)
+            }
+            _ if from_node(&msg) => self.read_log(vec![msg], msg_id),
+            Some((lease, _)) if lease.node != msg.dest && lease.expires > now => {
+                self.forward(msg, &lease.node, msg_id)
+            }
+            _ => {
+                let read = kv_request(&msg, msg_id, "read", json!({ "key": lease_key(&key) }));
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .insert(msg_id, Pending::ReadLease { request: msg });
+                Ok(read)
+            }
+        }
+    }
+
+    /// Reads the log of the key of the sends in `requests`, all to the same key, from lin-kv,
+    /// to append to it.
+    fn read_log(&self, requests: Vec<Message>, msg_id: u64) -> Result<Message> {
+        let request = requests
+            .first()
+            .ok_or_else(|| anyhow!("Internal: no sends to read the log for"))?;
+        let key = request.body.get_str("key")?;
+        let read = kv_request(request, msg_id, "read", json!({ "key": log_key(key) }));
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(msg_id, Pending::ReadLog { requests });
+        Ok(read)
+    }
+
+    /// Appends the message of `request` to `log`, the log this node last appended to a key it
+    /// leads, or to the log read first if it does not know it. With batched appends, waits for
+    /// the append in flight to the key, if any.
+    fn lead_append(
+        &self,
+        request: Message,
+        log: Option<Vec<Value>>,
+        msg_id: u64,
+    ) -> Result<Message> {
+        if self.outbox.is_some() {
+            let key = request.body.get_str("key")?.to_string();
+            let mut batches = self.batches.lock().unwrap();
+            if let Some(batch) = batches.get_mut(&key) {
+                batch.push(request);
+                return Ok(no_reply());
+            }
+            batches.insert(key, Vec::new());
+        }
+        match log {
+            Some(log) => self.append(vec![request], log, msg_id),
+            None => self.read_log(vec![request], msg_id),
+        }
+    }
+
+    /// Appends the messages of the sends in `requests`, all to the same key, to `log` with a cas
+    /// against lin-kv, along with the sends waiting for an append to the key.
+    fn append(&self, mut requests: Vec<Message>, log: Vec<Value>, msg_id: u64) -> Result<Message> {
+        let request = requests
+            .first()
+            .ok_or_else(|| anyhow!("Internal: no sends to append"))?;
+        let key = request.body.get_str("key")?.to_string();
+        if let Some(batch) = self.batches.lock().unwrap().get_mut(&key) {
+            requests.append(batch);
+        }
+
+        let mut appended = log.clone();
+        for request in &requests {
+            appended.push(request.body.get_as("msg")?);
+        }
+        let cas = kv_request(
+            &requests[0],
+            msg_id,
+            "cas",
+            json!({
+                "key": log_key(&key),
+                "from": log,
+                "to": appended,
+                "create_if_not_exists": true,
+            }),
+        );
+        let offset = log.len() as u64;
+        // The next append of the leader goes after this one, if this one fails so does the
+        // next and both are retried from the read.
+        if let Some((lease, log)) = self.leaders.lock().unwrap().get_mut(&key) {
+            if lease.node == requests[0].dest {
+                *log = Some(appended.clone());
+            }
+        }
+        self.pending.lock().unwrap().insert(
+            msg_id,
+            Pending::AppendLog {
+                requests,
+                offset,
+                log: appended,
+            },
+        );
+        Ok(cas)
+    }
+
+    /// Handles the end of the append in flight to `key` that made its log `log`: appends the
+    /// sends that waited for it, if any, and returns the cas that does.
+    fn appended(&self, key: &str, log: Vec<Value>, msg_id: u64) -> Result<Option<Message>> {
+        let mut batches = self.batches.lock().unwrap();
+        match batches.remove(key) {
+            Some(batch) if !batch.is_empty() => {
+                batches.insert(key.to_string(), Vec::new());
+                drop(batches);
+                self.append(batch, log, msg_id).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the first of `msgs` and sends the others to the outbox, there is only one unless
+    /// appends are batched.
+    fn send_all(&self, msgs: Vec<Message>) -> Result<Message> {
+        let mut msgs = msgs.into_iter();
+        let first = msgs.next().unwrap_or_else(no_reply);
+        for msg in msgs {
+            let Some(outbox) = &self.outbox else {
+                return Err(anyhow!("Internal: no outbox to send {msg:?} to"));
+            };
+            outbox.send(msg)?;
+        }
+        Ok(first)
+    }
+
+    /// Sets the lease of the key of `request` from `from` to `to` with a cas against lin-kv,
+    /// `from` None when there is no lease yet.
+    fn take_lease(
+        &self,
+        request: Message,
+        from: Option<Lease>,
+        to: Lease,
+        msg_id: u64,
+    ) -> Result<Message> {
+        let key = request.body.get_str("key")?;
+        let cas = kv_request(
+            &request,
+            msg_id,
+            "cas",
+            json!({
+                "key": lease_key(key),
+                "from": from,
+                "to": to,
+                "create_if_not_exists": from.is_none(),
+            }),
+        );
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(msg_id, Pending::TakeLease { request, lease: to });
+        Ok(cas)
+    }
+
+    /// Continues a `send` with the `lease` of its key read from lin-kv, None if there is none.
+    fn lease_read(&self, request: Message, lease: Option<Lease>, msg_id: u64) -> Result<Message> {
+        let key = request.body.get_str("key")?.to_string();
+        let now = now_millis();
+        match lease {
+            Some(lease) if lease.node != request.dest && lease.expires > now => {
+                let leader = lease.node.clone();
+                self.leaders.lock().unwrap().insert(key, (lease, None));
+                self.forward(request, &leader, msg_id)
+            }
+            from => {
+                let to = Lease {
+                    node: request.dest.clone(),
+                    expires: now + millis(self.lease.unwrap_or_default()),
+                };
+                self.take_lease(request, from, to, msg_id)
+            }
+        }
+    }
+
+    /// Forwards the client `send` in `request` to `leader`.
+    fn forward(&self, request: Message, leader: &str, msg_id: u64) -> Result<Message> {
+        let forward = Message {
+            src: request.dest.clone(),
+            dest: leader.to_string(),
+            body: Body {
+                msg_id,
+                extra: request.body.extra.clone(),
+                ..body!("send")
+            },
+        };
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(msg_id, Pending::Forwarded { request });
+        Ok(forward)
+    }
+
+    /// Handles the `send_ok` of the leader a send was forwarded to.
+    fn forwarded_ok(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        match self.take_pending(&msg)? {
+            Pending::Forwarded { request } => {
+                let offset = msg.body.get_u64("offset")?;
+                Ok(reply!(request, msg_id, "send_ok", { "offset": offset }))
+            }
+            pending => Err(anyhow!("unexpected send_ok {:?} for {:?}", msg, pending)),
+        }
+    }
+
+    /// Forgets that `node` leads `key`, after it failed to.
+    fn forget_leader(&self, key: &str, node: &str) {
+        let mut leaders = self.leaders.lock().unwrap();
+        if leaders
+            .get(key)
+            .is_some_and(|(lease, _)| lease.node == node)
+        {
+            leaders.remove(key);
+        }
+    }
+
+    /// Handles a client `poll`, reads the logs of the requested keys one after the other.
+    fn poll(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let remaining = msg
+            .body
+            .get_as::<BTreeMap<String, u64>>("offsets")?
+            .into_iter()
+            .collect();
+        self.poll_next(msg, remaining, Map::new(), Map::new(), msg_id)
+    }
+
+    /// Reads the log of the next key to poll, or replies to the client if all keys are done.
+    /// With owners, the log is the one this node keeps, or polled from the owner of the key.
+    fn poll_next(
+        &self,
+        request: Message,
+        remaining: Vec<(String, u64)>,
+        msgs: Map<String, Value>,
+        next: Map<String, Value>,
+        msg_id: u64,
+    ) -> Result<Message> {
+        let Some((key, from)) = remaining.first() else {
+            let mut reply = json!({ "msgs": msgs });
+            if !next.is_empty() {
+                reply["next_offsets"] = next.into();
+            }
+            return Ok(reply!(request, msg_id, "poll_ok", reply));
+        };
+
+        let read = match self.owner(key) {
+            Some(owner) if owner == request.dest => {
+                let log = self.logs.lock().unwrap().get(key).cloned();
+                let log = log.unwrap_or_default();
+                return self.poll_continue(request, remaining, msgs, next, log, msg_id);
+            }
+            Some(owner) => Message {
+                src: request.dest.clone(),
+                dest: owner,
+                body: Body {
+                    msg_id,
+                    ..body!("poll", { "offsets": { key: from } })
+                },
+            },
+            None => kv_request(&request, msg_id, "read", json!({ "key": log_key(key) })),
+        };
+        self.pending.lock().unwrap().insert(
+            msg_id,
+            Pending::Poll {
+                request,
+                remaining,
+                msgs,
+                next,
+            },
+        );
+        Ok(read)
+    }
+
+    /// Handles the `poll_ok` of the owner of the key a poll was waiting on, and moves on to the
+    /// next key.
+    fn owner_poll_ok(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let Pending::Poll {
+            request,
+            mut remaining,
+            mut msgs,
+            mut next,
+        } = self.take_pending(&msg)?
+        else {
+            return Err(anyhow!("unexpected poll_ok {:?}", msg));
+        };
+        let (key, _) = remaining.remove(0);
+        if let Some(entries) = msg.body.extra.get("msgs").and_then(|m| m.get(&key)) {
+            msgs.insert(key.clone(), entries.clone());
+        }
+        if let Some(offset) = msg.body.extra.get("next_offsets").and_then(|n| n.get(&key)) {
+            next.insert(key, offset.clone());
+        }
+        self.poll_next(request, remaining, msgs, next, msg_id)
+    }
+
+    /// Handles a client `commit_offsets`, reads the committed offsets from lin-kv to raise them.
+    fn commit_offsets(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        msg.body.get_as::<BTreeMap<String, u64>>("offsets")?;
+        Ok(self.read_committed(msg, msg_id, true))
+    }
+
+    /// Handles a client `list_committed_offsets`, reads the committed offsets from lin-kv.
+    fn list_committed_offsets(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        msg.body.get_as::<Vec<String>>("keys")?;
+        Ok(self.read_committed(msg, msg_id, false))
+    }
+
+    /// Reads the committed offsets from lin-kv for `request`, a commit or a list.
+    fn read_committed(&self, request: Message, msg_id: u64, commit: bool) -> Message {
+        let read = kv_request(&request, msg_id, "read", json!({ "key": COMMITTED_KEY }));
+        let pending = if commit {
+            Pending::ReadCommitted { request }
+        } else {
+            Pending::ListCommitted { request }
+        };
+        self.pending.lock().unwrap().insert(msg_id, pending);
+        read
+    }
+
+    /// Raises the committed `offsets` read from lin-kv, None if there are none yet, to the
+    /// offsets of the `commit_offsets` in `request`, with a cas against lin-kv.
+    fn raise_committed(
+        &self,
+        request: Message,
+        offsets: Option<BTreeMap<String, u64>>,
+        msg_id: u64,
+    ) -> Result<Message> {
+        let commits: BTreeMap<String, u64> = request.body.get_as("offsets")?;
+        let mut raised = offsets.clone().unwrap_or_default();
+        for (key, offset) in commits {
+            let entry = raised.entry(key).or_default();
+            *entry = (*entry).max(offset);
+        }
+        if offsets.as_ref() == Some(&raised) {
+            return Ok(self.committed_ok(request, raised, msg_id));
+        }
+        let cas = kv_request(
+            &request,
+            msg_id,
+            "cas",
+            json!({
+                "key": COMMITTED_KEY,
+                "from": offsets.unwrap_or_default(),
+                "to": raised,
+                "create_if_not_exists": true,
+            }),
+        );
+        self.pending.lock().unwrap().insert(
+            msg_id,
+            Pending::CasCommitted {
+                request,
+                offsets: raised,
+            },
+        );
+        Ok(cas)
+    }
+
+    /// Drops the entries of `log`, the log of `key`, that the retention says to.
+    fn compact(&self, key: &str, log: &mut Log) {
+        if self.retention.committed {
+            if let Some(&offset) = self.committed.lock().unwrap().get(key) {
+                log.drop_below(offset);
+            }
+        }
+        if let Some(max) = self.retention.max_entries {
+            log.drop_below(log.end().saturating_sub(max as u64));
+        }
+    }
+
+    /// Remembers the committed `offsets` in lin-kv and answers the `commit_offsets` in `request`.
+    fn committed_ok(
+        &self,
+        request: Message,
+        offsets: BTreeMap<String, u64>,
+        msg_id: u64,
+    ) -> Message {
+        self.remember_committed(&offsets);
+        if self.retention.committed {
+            let mut logs = self.logs.lock().unwrap();
+            for key in offsets.keys() {
+                if let Some(log) = logs.get_mut(key) {
+                    self.compact(key, log);
+                }
+            }
+        }
+        reply!(request, msg_id, "commit_offsets_ok", {})
+    }
+
+    /// Answers the `list_committed_offsets` in `request` with the committed `offsets` read from
+    /// lin-kv.
+    fn list_committed(
+        &self,
+        request: Message,
+        offsets: BTreeMap<String, u64>,
+        msg_id: u64,
+    ) -> Result<Message> {
+        let keys: Vec<String> = request.body.get_as("keys")?;
+        self.remember_committed(&offsets);
+
+        let committed = self.committed.lock().unwrap();
+        let offsets: Map<String, Value> = keys
+            .into_iter()
+            .filter_map(|k| committed.get(&k).map(|offset| (k, (*offset).into())))
+            .collect();
+        Ok(reply!(request, msg_id, "list_committed_offsets_ok", { "offsets": offsets }))
+    }
+
+    /// Raises the committed offsets this node knows to `offsets`, committed offsets never go
+    /// back.
+    fn remember_committed(&self, offsets: &BTreeMap<String, u64>) {
+        let mut committed = self.committed.lock().unwrap();
+        for (key, offset) in offsets {
+            let entry = committed.entry(key.clone()).or_default();
+            *entry = (*entry).max(*offset);
+        }
+    }
+
+    /// Takes the operation waiting on the lin-kv request that `msg` replies to.
+    fn take_pending(&self, msg: &Message) -> Result<Pending> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&msg.body.in_reply_to)
+            .ok_or(anyhow!("no pending lin-kv request for reply {:?}", msg))
+    }
+
+    fn read_ok(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let log = msg
+            .body
+            .extra
+            .get("value")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        match self.take_pending(&msg)? {
+            Pending::ReadLog { requests } => self.append(requests, log, msg_id),
+            Pending::ReadLease { request } => {
+                let lease = msg.body.get_as("value")?;
+                self.lease_read(request, Some(lease), msg_id)
+            }
+            Pending::ReadCommitted { request } => {
+                let offsets = msg.body.get_as("value")?;
+                self.raise_committed(request, Some(offsets), msg_id)
+            }
+            Pending::ListCommitted { request } => {
+                let offsets = msg.body.get_as("value")?;
+                self.list_committed(request, offsets, msg_id)
+            }
+            Pending::Poll {
+                request,
+                remaining,
+                msgs,
+                next,
+            } => self.poll_continue(request, remaining, msgs, next, log.into(), msg_id),
+            pending => Err(anyhow!("unexpected read_ok {:?} for {:?}", msg, pending)),
+        }
+    }
+
+    /// Adds the polled entries of `log` for the current key, at most the poll limit of them, and
+    /// moves on to the next one.
+    fn poll_continue(
+        &self,
+        request: Message,
+        mut remaining: Vec<(String, u64)>,
+        mut msgs: Map<String, Value>,
+        mut next: Map<String, Value>,
+        log: Log,
+        msg_id: u64,
+    ) -> Result<Message> {
+        let (key, from) = remaining.remove(0);
+        let limit = self.poll_limit.unwrap_or(usize::MAX);
+        let from = from.max(log.start);
+        if log.end().saturating_sub(from) > limit as u64 {
+            next.insert(key.clone(), (from + limit as u64).into());
+        }
+        let entries: Vec<Value> = log
+            .entries
+            .into_iter()
+            .zip(log.start..)
+            .skip((from - log.start) as usize)
+            .take(limit)
+            .map(|(entry, offset)| json!([offset, entry]))
+            .collect();
+        if !entries.is_empty() {
+            msgs.insert(key, entries.into());
+        }
+        self.poll_next(request, remaining, msgs, next, msg_id)
+    }
+
+    fn cas_ok(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        match self.take_pending(&msg)? {
+            Pending::AppendLog {
+                requests,
+                offset,
+                log,
+            } => {
+                let key = requests[0].body.get_str("key")?.to_string();
+                let next = self.appended(&key, log, msg_id)?;
+                let reply_id = if next.is_some() { 0 } else { msg_id };
+                let replies = requests.iter().zip(offset..).map(
+                    |(request, offset)| reply!(request, reply_id, "send_ok", { "offset": offset }),
+                );
+                self.send_all(next.into_iter().chain(replies).collect())
+            }
+            Pending::TakeLease { request, lease } => {
+                let key = request.body.get_str("key")?.to_string();
+                let mut leaders = self.leaders.lock().unwrap();
+                let log = match leaders.remove(&key) {
+                    Some((held, log)) if held.node == lease.node => log,
+                    _ => None,
+                };
+                leaders.insert(key, (lease, log));
+                drop(leaders);
+                self.send(request, msg_id)
+            }
+            Pending::CasCommitted { request, offsets } => {
+                Ok(self.committed_ok(request, offsets, msg_id))
+            }
+            pending => Err(anyhow!("unexpected cas_ok {:?} for {:?}", msg, pending)),
+        }
+    }
+
+    /// Handles an error from lin-kv.
+    ///
+    /// A missing key is an empty log or no lease, a failed cas means another node appended or
+    /// took the lease first so the send is retried. Other errors, and errors of the leader a send
+    /// was forwarded to, are passed on to the client.
+    fn kv_error(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let code = msg.body.get_u64("code")?;
+
+        match (self.take_pending(&msg)?, code) {
+            (Pending::ReadLog { requests }, KEY_DOES_NOT_EXIST) => {
+                self.append(requests, vec![], msg_id)
+            }
+            (Pending::AppendLog { mut requests, .. }, PRECONDITION_FAILED) => {
+                debug!("log changed while appending, retrying send");
+                let key = requests[0].body.get_str("key")?.to_string();
+                if let Some((_, log)) = self.leaders.lock().unwrap().get_mut(&key) {
+                    *log = None;
+                }
+                // A batch is retried from the read, sends that arrive meanwhile wait for it.
+                if requests.len() > 1 || self.batches.lock().unwrap().contains_key(&key) {
+                    return self.read_log(requests, msg_id);
+                }
+                self.send(requests.remove(0), msg_id)
+            }
+            (Pending::ReadLease { request }, KEY_DOES_NOT_EXIST) => {
+                self.lease_read(request, None, msg_id)
+            }
+            (Pending::TakeLease { request, .. }, PRECONDITION_FAILED | KEY_DOES_NOT_EXIST) => {
+                debug!("lease changed while taking it, retrying send");
+                self.forget_leader(request.body.get_str("key")?, &request.dest);
+                self.send(request, msg_id)
+            }
+            (Pending::ReadCommitted { request }, KEY_DOES_NOT_EXIST) => {
+                self.raise_committed(request, None, msg_id)
+            }
+            (Pending::CasCommitted { request, .. }, PRECONDITION_FAILED) => {
+                debug!("committed offsets changed while committing, retrying commit");
+                Ok(self.read_committed(request, msg_id, true))
+            }
+            (Pending::ListCommitted { request }, KEY_DOES_NOT_EXIST) => {
+                self.list_committed(request, BTreeMap::new(), msg_id)
+            }
+            (Pending::Forwarded { request }, _) => {
+                self.forget_leader(request.body.get_str("key")?, &msg.src);
+                let error = Value::Object(msg.body.extra);
+                Ok(reply!(request, msg_id, "error", error))
+            }
+            (
+                Pending::Poll {
+                    request,
+                    remaining,
+                    msgs,
+                    next,
+                },
+                KEY_DOES_NOT_EXIST,
+            ) => self.poll_continue(request, remaining, msgs, next, Log::default(), msg_id),
+            (Pending::ReadLog { requests } | Pending::AppendLog { requests, .. }, _) => {
+                let key = requests[0].body.get_str("key")?.to_string();
+                // The sends waiting for the failed append were not part of it, they go on.
+                let next = {
+                    let mut batches = self.batches.lock().unwrap();
+                    match batches.remove(&key) {
+                        Some(batch) if !batch.is_empty() => {
+                            batches.insert(key, Vec::new());
+                            Some(batch)
+                        }
+                        _ => None,
+                    }
+                };
+                let next = next.map(|batch| self.read_log(batch, msg_id)).transpose()?;
+                let reply_id = if next.is_some() { 0 } else { msg_id };
+                let error = Value::Object(msg.body.extra);
+                let errors = requests
+                    .iter()
+                    .map(|request| reply!(request, reply_id, "error", error));
+                self.send_all(next.into_iter().chain(errors).collect())
+            }
+            (
+                Pending::ReadLease { request }
+                | Pending::TakeLease { request, .. }
+                | Pending::ReadCommitted { request }
+                | Pending::CasCommitted { request, .. }
+                | Pending::ListCommitted { request }
+                | Pending::Poll { request, .. },
+                _,
+            ) => {
+                let error = Value::Object(msg.body.extra);
+                Ok(reply!(request, msg_id, "error", error))
+            }
+        }
+    }
+}
+
+impl Persist for Kafka {
+    /// The committed offsets last known and the logs of the keys this node owns, other logs are
+    /// in lin-kv and operations waiting on lin-kv are lost like their clients' requests.
+    fn snapshot(&self) -> Value {
+        json!({
+            "committed": *self.committed.lock().unwrap(),
+            "logs": *self.logs.lock().unwrap(),
+        })
+    }
+
+    fn restore(&self, snapshot: Value) -> Result<()> {
+        *self.committed.lock().unwrap() = serde_json::from_value(snapshot["committed"].clone())?;
+        *self.logs.lock().unwrap() = serde_json::from_value(snapshot["logs"].clone())?;
+        Ok(())
+    }
+}
+
+/// lin-kv key that holds the log of `key`.
+fn log_key(key: &str) -> String {
+    format!("log-{key}")
+}
+
+/// lin-kv key that holds the lease of `key`.
+fn lease_key(key: &str) -> String {
+    format!("lease-{key}")
+}
+
+/// Whether `msg` comes from another node, Maelstrom names nodes n1, n2... and clients c1, c2...
+fn from_node(msg: &Message) -> bool {
+    msg.src.starts_with('n')
+}
+
+/// Milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    millis(now.unwrap_or_default())
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+/// Builds a request to lin-kv on behalf of the client `request`.
+fn kv_request(request: &Message, msg_id: u64, typ: &str, extra: Value) -> Message {
+    Message {
+        src: request.dest.clone(),
+        dest: LIN_KV.to_string(),
+        body: Body {
+            msg_id,
+            ..body!(typ, extra)
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc, time::Duration};
+
+    use anyhow::Result;
+    use serde_json::{json, Value};
+
+    use crate::kafka::{handlers, Kafka, Retention};
+    use crate::message::Message;
+    use crate::node::{no_reply, Node};
+    use crate::wal::Wal;
+
+    fn message(value: Value) -> Message {
+        serde_json::from_value(value).expect("invalid message json.")
+    }
+
+    fn init_node(kafka: &Kafka) -> Result<Node<'_>> {
+        let node = Node::new(handlers(kafka))?;
+        node.handle(message(json!({
+            "src": "c0", "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"] }
+        })))?;
+        Ok(node)
+    }
+
+    fn send(key: &str, msg: u64) -> Message {
+        message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "send", "msg_id": 7, "key": key, "msg": msg }
+        }))
+    }
+
+    /// A reply from lin-kv to `request`.
+    fn kv_reply(request: &Message, body: Value) -> Message {
+        let mut reply = message(json!({ "src": "lin-kv", "dest": "n1", "body": body }));
+        reply.body.in_reply_to = request.body.msg_id;
+        reply
+    }
+
+    #[test]
+    fn send_appends_to_log_in_lin_kv() -> Result<()> {
+        // Tests that a send reads the log, appends to it with a cas and replies with the offset.
+        let kafka = Kafka::new();
+        let node = init_node(&kafka)?;
+
+        let read = node.handle(send("k1", 42))?;
+        assert_eq!(read.dest, "lin-kv");
+        assert_eq!(read.body.typ, "read");
+        assert_eq!(read.body.extra["key"], "log-k1");
+
+        let cas = node.handle(kv_reply(
+            &read,
+            json!({ "type": "read_ok", "value": [10, 11] }),
+        ))?;
+        assert_eq!(cas.body.typ, "cas");
+        assert_eq!(cas.body.extra["from"], json!([10, 11]));
+        assert_eq!(cas.body.extra["to"], json!([10, 11, 42]));
+
+        let reply = node.handle(kv_reply(&cas, json!({ "type": "cas_ok" })))?;
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.typ, "send_ok");
+        assert_eq!(reply.body.in_reply_to, 7);
+        assert_eq!(reply.body.extra["offset"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn retried_send_gets_original_send_ok() -> Result<()> {
+        // Tests that a client sending a send again gets its send_ok, not the lin-kv request the
+        // send started with, and the message is appended once.
+        let kafka = Kafka::new();
+        let node = init_node(&kafka)?.with_dedup(10).with_sessions(10);
+        let send = |msg_id| {
+            let mut send = send("k1", 42);
+            send.body.msg_id = msg_id;
+            send.body.extra.insert("request_id".into(), json!("r1"));
+            send
+        };
+
+        let read = node.handle(send(7))?;
+        let cas = node.handle(kv_reply(&read, json!({ "type": "read_ok", "value": [] })))?;
+        let reply = node.handle(kv_reply(&cas, json!({ "type": "cas_ok" })))?;
+        assert_eq!(node.handle(send(7))?, reply);
+
+        let retry = node.handle(send(8))?;
+        assert_eq!(
+            (
+                retry.dest.as_str(),
+                retry.body.typ.as_str(),
+                retry.body.in_reply_to
+            ),
+            ("c1", "send_ok", 8)
+        );
+        assert_eq!(retry.body.extra["offset"], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn send_to_missing_key_creates_log() -> Result<()> {
+        let kafka = Kafka::new();
+        let node = init_node(&kafka)?;
+
+        let read = node.handle(send("k1", 42))?;
+        let cas = node.handle(kv_reply(&read, json!({ "type": "error", "code": 20 })))?;
+
+        assert_eq!(cas.body.typ, "cas");
+        assert_eq!(cas.body.extra["from"], json!([]));
+        assert_eq!(cas.body.extra["to"], json!([42]));
+        assert_eq!(cas.body.extra["create_if_not_exists"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn send_retries_when_cas_fails() -> Result<()> {
+        // Tests that losing the cas race to another node re-reads the log and retries.
+        let kafka = Kafka::new();
+        let node = init_node(&kafka)?;
+
+        let read = node.handle(send("k1", 42))?;
+        let cas = node.handle(kv_reply(&read, json!({ "type": "read_ok", "value": [] })))?;
+        let retry = node.handle(kv_reply(&cas, json!({ "type": "error", "code": 22 })))?;
+        assert_eq!(retry.body.typ, "read");
+
+        let cas = node.handle(kv_reply(&retry, json!({ "type": "read_ok", "value": [1] })))?;
+        let reply = node.handle(kv_reply(&cas, json!({ "type": "cas_ok" })))?;
+
+        assert_eq!(reply.body.typ, "send_ok");
+        assert_eq!(reply.body.extra["offset"], 1);
+        Ok(())
+    }
+
+    #[test]
+    fn poll_reads_every_key() -> Result<()> {
+        // Tests that a poll reads the logs of all keys and returns entries from the offsets.
+        let kafka = Kafka::new();
+        let node = init_node(&kafka)?;
+
+        let poll = message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "poll", "msg_id": 3, "offsets": { "a": 1, "b": 0 } }
+        }));
+        let read_a = node.handle(poll)?;
+        assert_eq!(read_a.body.extra["key"], "log-a");
+        let read_b = node.handle(kv_reply(
+            &read_a,
+            json!({ "type": "read_ok", "value": [5, 6, 7] }),
+        ))?;
+        assert_eq!(read_b.body.extra["key"], "log-b");
+        let reply = node.handle(kv_reply(&read_b, json!({ "type": "error", "code": 20 })))?;
+
+        assert_eq!(reply.body.typ, "poll_ok");
+        assert_eq!(reply.body.in_reply_to, 3);
+        assert_eq!(reply.body.extra["msgs"], json!({ "a": [[1, 6], [2, 7]] }));
+        Ok(())
+    }
+
+    #[test]
+    fn poll_is_limited_per_key() -> Result<()> {
+        // Tests that a poll returns at most the limit per key, and the offsets to poll the rest
+        // from.
+        let kafka = Kafka::new().with_poll_limit(2);
+        let node = init_node(&kafka)?;
+
+        let poll = message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "poll", "msg_id": 3, "offsets": { "a": 1, "b": 0 } }
+        }));
+        let read_a = node.handle(poll)?;
+        let read_b = node.handle(kv_reply(
+            &read_a,
+            json!({ "type": "read_ok", "value": [5, 6, 7, 8] }),
+        ))?;
+        let reply = node.handle(kv_reply(
+            &read_b,
+            json!({ "type": "read_ok", "value": [1, 2] }),
+        ))?;
+
+        assert_eq!(
+            reply.body.extra["msgs"],
+            json!({ "a": [[1, 6], [2, 7]], "b": [[0, 1], [1, 2]] })
+        );
+        assert_eq!(reply.body.extra["next_offsets"], json!({ "a": 3 }));
+        Ok(())
+    }
+
+    #[test]
+    fn owners_append_and_serve_their_keys() -> Result<()> {
+        // Tests that the owner of a key appends to it without lin-kv, and that other nodes
+        // forward sends and polls of it to the owner.
+        let (n1, n2) = (Kafka::with_owners(), Kafka::with_owners());
+        let ids = ["n1".to_string(), "n2".to_string()];
+        n1.init("n1", &ids);
+        n2.init("n2", &ids);
+        let key = (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| n1.owner(key).as_deref() == Some("n2"))
+            .unwrap();
+        let (n1, n2) = (init_node(&n1)?, init_node(&n2)?);
+        let to_n2 = |mut msg: Message| {
+            msg.dest = "n2".into();
+            msg
+        };
+
+        let forwarded = n1.handle(send(&key, 5))?;
+        assert_eq!(forwarded.dest, "n2");
+        let sent = n2.handle(forwarded)?;
+        assert_eq!(sent.body.extra["offset"], 0);
+        assert_eq!(n1.handle(sent)?.body.typ, "send_ok");
+        assert_eq!(n2.handle(to_n2(send(&key, 6)))?.body.extra["offset"], 1);
+
+        let poll = n1.handle(message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "poll", "msg_id": 3, "offsets": { (key.clone()): 1 } }
+        })))?;
+        assert_eq!(poll.dest, "n2");
+        let reply = n1.handle(n2.handle(poll)?)?;
+        assert_eq!(reply.body.typ, "poll_ok");
+        assert_eq!(reply.body.extra["msgs"], json!({ (key): [[1, 6]] }));
+        Ok(())
+    }
+
+    #[test]
+    fn owned_logs_are_replayed_from_wal() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("maelstrom-kafka-wal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let ids = ["n1".to_string()];
+        let kafka = Kafka::with_owners();
+        kafka.init("n1", &ids);
+        kafka.recover_from(Wal::open(&dir)?)?;
+        let node = init_node(&kafka)?;
+        node.handle(send("a", 5))?;
+        node.handle(send("b", 6))?;
+        assert_eq!(node.handle(send("a", 7))?.body.extra["offset"], 1);
+
+        let restarted = Kafka::with_owners();
+        restarted.init("n1", &ids);
+        restarted.recover_from(Wal::open(&dir)?)?;
+        let node = init_node(&restarted)?;
+        assert_eq!(node.handle(send("a", 8))?.body.extra["offset"], 2);
+        let poll = node.handle(message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "poll", "msg_id": 3, "offsets": { "a": 0, "b": 0 } }
+        })))?;
+        assert_eq!(
+            poll.body.extra["msgs"],
+            json!({ "a": [[0, 5], [1, 7], [2, 8]], "b": [[0, 6]] })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn owners_drop_entries_past_retention() -> Result<()> {
+        let kafka = Kafka::with_owners().with_retention(Retention {
+            committed: true,
+            max_entries: Some(3),
+        });
+        kafka.init("n1", &["n1".to_string()]);
+        let node = init_node(&kafka)?;
+        for msg in 0..4 {
+            node.handle(send("a", msg))?;
+        }
+        kafka.committed.lock().unwrap().insert("a".into(), 2);
+        node.handle(send("a", 4))?;
+
+        let reply = node.handle(message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "poll", "msg_id": 3, "offsets": { "a": 0 } }
+        })))?;
+        assert_eq!(
+            reply.body.extra["msgs"],
+            json!({ "a": [[2, 2], [3, 3], [4, 4]] })
+        );
+        let log = &kafka.logs.lock().unwrap()["a"];
+        assert_eq!((log.start, log.entries.len()), (2, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn committed_offsets_are_listed() -> Result<()> {
+        // Tests that commits raise the committed offsets in lin-kv, retrying lost races, and
+        // that lists read them from lin-kv.
+        let kafka = Kafka::new();
+        let node = init_node(&kafka)?;
+
+        let read = node.handle(message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "commit_offsets", "msg_id": 1, "offsets": { "a": 2, "b": 4 } }
+        })))?;
+        assert_eq!(read.body.extra["key"], "committed-offsets");
+        let cas = node.handle(kv_reply(&read, json!({ "type": "error", "code": 20 })))?;
+        assert_eq!(cas.body.extra["to"], json!({ "a": 2, "b": 4 }));
+        let read = node.handle(kv_reply(&cas, json!({ "type": "error", "code": 22 })))?;
+        let cas = node.handle(kv_reply(
+            &read,
+            json!({ "type": "read_ok", "value": { "a": 3 } }),
+        ))?;
+        assert_eq!(
+            (&cas.body.extra["from"], &cas.body.extra["to"]),
+            (&json!({ "a": 3 }), &json!({ "a": 3, "b": 4 }))
+        );
+        let reply = node.handle(kv_reply(&cas, json!({ "type": "cas_ok" })))?;
+        assert_eq!(reply.body.typ, "commit_offsets_ok");
+
+        let read = node.handle(message(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "list_committed_offsets", "msg_id": 2, "keys": ["a", "c", "d"] }
+        })))?;
+        let reply = node.handle(kv_reply(
+            &read,
+            json!({ "type": "read_ok", "value": { "a": 3, "b": 4, "c": 1 } }),
+        ))?;
+        assert_eq!(reply.body.typ, "list_committed_offsets_ok");
+        assert_eq!(reply.body.extra["offsets"], json!({ "a": 3, "c": 1 }));
+        Ok(())
+    }
+
+    #[test]
+    fn unexpected_kv_reply_is_error() -> Result<()> {
+        let kafka = Kafka::new();
+        let node = init_node(&kafka)?;
+
+        let result = node.handle(message(json!({
+            "src": "lin-kv", "dest": "n1",
+            "body": { "type": "cas_ok", "in_reply_to": 99 }
+        })));
+
+        assert!(
+            result
+                .as_ref()
+                .is_err_and(|e| e.to_string().contains("no pending lin-kv request")),
+            "expected failure for unknown reply, got {:?}",
+            result
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn leader_appends_without_reading() -> Result<()> {
+        // Tests that a node takes the lease of a missing key, and then appends to the log it
+        // last appended without reading it.
+        let kafka = Kafka::with_leases(Duration::from_secs(60));
+        let node = init_node(&kafka)?;
+
+        let read_lease = node.handle(send("k1", 42))?;
+        assert_eq!(read_lease.body.typ, "read");
+        assert_eq!(read_lease.body.extra["key"], "lease-k1");
+        let take = node.handle(kv_reply(
+            &read_lease,
+            json!({ "type": "error", "code": 20, "text": "not found" }),
+        ))?;
+        assert_eq!(take.body.typ, "cas");
+        assert_eq!(take.body.extra["key"], "lease-k1");
+        assert_eq!(take.body.extra["to"]["node"], "n1");
+        assert_eq!(take.body.extra["create_if_not_exists"], true);
+
+        let read = node.handle(kv_reply(&take, json!({ "type": "cas_ok" })))?;
+        assert_eq!(read.body.extra["key"], "log-k1");
+        let cas = node.handle(kv_reply(
+            &read,
+            json!({ "type": "error", "code": 20, "text": "not found" }),
+        ))?;
+        node.handle(kv_reply(&cas, json!({ "type": "cas_ok" })))?;
+
+        let cas = node.handle(send("k1", 43))?;
+        assert_eq!(cas.body.typ, "cas");
+        assert_eq!(cas.body.extra["from"], json!([42]));
+        assert_eq!(cas.body.extra["to"], json!([42, 43]));
+        let reply = node.handle(kv_reply(&cas, json!({ "type": "cas_ok" })))?;
+        assert_eq!(reply.body.extra["offset"], 1);
+
+        // A failed cas means the log changed under the leader, it reads it again.
+        let cas = node.handle(send("k1", 44))?;
+        let read = node.handle(kv_reply(
+            &cas,
+            json!({ "type": "error", "code": 22, "text": "changed" }),
+        ))?;
+        assert_eq!(read.body.typ, "read");
+        assert_eq!(read.body.extra["key"], "log-k1");
+        Ok(())
+    }
+
+    #[test]
+    fn leader_batches_appends() -> Result<()> {
+        // Tests that the sends a leader gets while it appends are appended together by one cas
+        // once the append is done, and all get their offset.
+        let (tx, rx) = mpsc::channel();
+        let kafka = Kafka::with_leases(Duration::from_secs(60)).with_batched_appends(tx);
+        let node = init_node(&kafka)?;
+        let read_lease = node.handle(send("k1", 42))?;
+        let take = node.handle(kv_reply(
+            &read_lease,
+            json!({ "type": "error", "code": 20 }),
+        ))?;
+        let read = node.handle(kv_reply(&take, json!({ "type": "cas_ok" })))?;
+        let cas = node.handle(kv_reply(&read, json!({ "type": "read_ok", "value": [] })))?;
+        assert_eq!(cas.body.extra["to"], json!([42]));
+
+        assert_eq!(node.handle(send("k1", 43))?, no_reply());
+        assert_eq!(node.handle(send("k1", 44))?, no_reply());
+        let batch = node.handle(kv_reply(&cas, json!({ "type": "cas_ok" })))?;
+        assert_eq!(batch.body.typ, "cas");
+        assert_eq!(batch.body.extra["from"], json!([42]));
+        assert_eq!(batch.body.extra["to"], json!([42, 43, 44]));
+        assert_eq!(rx.try_recv()?.body.extra["offset"], 0);
+
+        let reply = node.handle(kv_reply(&batch, json!({ "type": "cas_ok" })))?;
+        let offsets = [reply, rx.try_recv()?].map(|r| r.body.extra["offset"].clone());
+        assert_eq!(offsets, [json!(1), json!(2)]);
+        assert!(rx.try_recv().is_err());
+
+        // With no append in flight, the next send is appended right away.
+        let cas = node.handle(send("k1", 45))?;
+        assert_eq!(cas.body.extra["to"], json!([42, 43, 44, 45]));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_batch_is_retried_from_read() -> Result<()> {
+        let (tx, _rx) = mpsc::channel();
+        let kafka = Kafka::with_leases(Duration::from_secs(60)).with_batched_appends(tx);
+        let node = init_node(&kafka)?;
+        let read_lease = node.handle(send("k1", 42))?;
+        let take = node.handle(kv_reply(
+            &read_lease,
+            json!({ "type": "error", "code": 20 }),
+        ))?;
+        let read = node.handle(kv_reply(&take, json!({ "type": "cas_ok" })))?;
+        let cas = node.handle(kv_reply(&read, json!({ "type": "read_ok", "value": [] })))?;
+        node.handle(send("k1", 43))?;
+
+        let read = node.handle(kv_reply(&cas, json!({ "type": "error", "code": 22 })))?;
+        assert_eq!(node.handle(send("k1", 44))?, no_reply());
+        let cas = node.handle(kv_reply(&read, json!({ "type": "read_ok", "value": [7] })))?;
+
+        assert_eq!(cas.body.extra["to"], json!([7, 42, 43, 44]));
+        Ok(())
+    }
+
+    #[test]
+    fn sends_are_forwarded_to_leader() -> Result<()> {
+        // Tests that a send to a node that does not lead its key is forwarded to the leader,
+        // whose reply goes to the client.
+        let kafka = Kafka::with_leases(Duration::from_secs(60));
+        let node = init_node(&kafka)?;
+        let lease = json!({ "node": "n2", "expires": u64::MAX });
+
+        let read_lease = node.handle(send("k1", 42))?;
+        let forward = node.handle(kv_reply(
+            &read_lease,
+            json!({ "type": "read_ok", "value": lease }),
+        ))?;
+        assert_eq!(forward.dest, "n2");
+        assert_eq!(forward.body.typ, "send");
+        assert_eq!(forward.body.extra["msg"], 42);
+
+        let mut send_ok = message(json!({
+            "src": "n2", "dest": "n1", "body": { "type": "send_ok", "offset": 3 }
+        }));
+        send_ok.body.in_reply_to = forward.body.msg_id;
+        let reply = node.handle(send_ok)?;
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.typ, "send_ok");
+        assert_eq!(reply.body.in_reply_to, 7);
+        assert_eq!(reply.body.extra["offset"], 3);
+
+        // The leader is remembered, the next send is forwarded right away.
+        let forward = node.handle(send("k1", 43))?;
+        assert_eq!(forward.dest, "n2");
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    message::{Body, KEY_DOES_NOT_EXIST, PRECONDITION_FAILED},
+    rpc,
+    rpc::Rpc,
+};
+
+/// Error of a key value store operation.
+#[derive(Debug)]
+pub enum KvError {
+    // The key has no value (code 20).
+    KeyDoesNotExist(String),
+    // A cas found a different value than expected (code 22).
+    PreconditionFailed(String),
+    // Any other error reply from the store.
+    Other { code: u64, text: String },
+    // The request could not be sent, no reply was recieved or the reply was malformed.
+    Rpc(anyhow::Error),
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist(text) => write!(f, "KeyDoesNotExist: {text}"),
+            KvError::PreconditionFailed(text) => write!(f, "PreconditionFailed: {text}"),
+            KvError::Other { code, text } => write!(f, "KvError {code}: {text}"),
+            KvError::Rpc(e) => write!(f, "RpcError: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvError::Rpc(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A key value store, as provided by Maelstrom's seq-kv, lin-kv and lww-kv services.
+pub trait Kv {
+    /// Reads the value of `key`.
+    fn read<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvError>;
+
+    /// Sets `key` to `value`.
+    fn write<T: Serialize>(&self, key: &str, value: &T) -> Result<(), KvError>;
+
+    /// Sets `key` to `to` if its value is `from`. If the key does not exist it is created with
+    /// `to` when `create_if_not_exists` is set, otherwise the cas fails.
+    fn cas<T: Serialize>(
+        &self,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError>;
+}
+
+/// Client of one of Maelstrom's key value services.
+#[derive(Debug)]
+pub struct KvClient<R> {
+    // Name of the service node, e.g. "seq-kv".
+    service: String,
+    rpc: R,
+}
+
+impl<R: Rpc> KvClient<R> {
+    pub fn new(service: &str, rpc: R) -> Self {
+        Self {
+            service: service.to_string(),
+            rpc,
+        }
+    }
+
+    /// Client of the sequentially consistent store.
+    pub fn seq_kv(rpc: R) -> Self {
+        Self::new("seq-kv", rpc)
+    }
+
+    /// Client of the linearizable store.
+    pub fn lin_kv(rpc: R) -> Self {
+        Self::new("lin-kv", rpc)
+    }
+
+    /// Client of the last-write-wins store.
+    pub fn lww_kv(rpc: R) -> Self {
+        Self::new("lww-kv", rpc)
+    }
+}
+
+impl<R: Rpc> Kv for KvClient<R> {
+    fn read<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvError> {
+        let mut reply = extra_of(rpc!(self.rpc, &self.service, "read", { "key": key }))?;
+        let value = reply.remove("value").unwrap_or_default();
+        serde_json::from_value(value).map_err(|e| KvError::Rpc(e.into()))
+    }
+
+    fn write<T: Serialize>(&self, key: &str, value: &T) -> Result<(), KvError> {
+        extra_of(rpc!(self.rpc, &self.service, "write", { "key": key, "value": value }))?;
+        Ok(())
+    }
+
+    fn cas<T: Serialize>(
+        &self,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError> {
+        let reply = rpc!(self.rpc, &self.service, "cas", {
+            "key": key,
+            "from": from,
+            "to": to,
+            "create_if_not_exists": create_if_not_exists,
+        });
+        extra_of(reply)?;
+        Ok(())
+    }
+}
+
+/// In-memory key value store with the semantics of Maelstrom's lin-kv, for testing workloads
+/// without Maelstrom.
+#[derive(Debug, Default)]
+pub struct MemoryKv {
+    store: Mutex<HashMap<String, Value>>,
+}
+
+impl MemoryKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Kv for MemoryKv {
+    fn read<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvError> {
+        let value = self
+            .store
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| KvError::KeyDoesNotExist(format!("key {key} does not exist")))?;
+        serde_json::from_value(value).map_err(|e| KvError::Rpc(e.into()))
+    }
+
+    fn write<T: Serialize>(&self, key: &str, value: &T) -> Result<(), KvError> {
+        let value = serde_json::to_value(value).map_err(|e| KvError::Rpc(e.into()))?;
+        self.store.lock().unwrap().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn cas<T: Serialize>(
+        &self,
+        key: &str,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError> {
+        let from = serde_json::to_value(from).map_err(|e| KvError::Rpc(e.into()))?;
+        let to = serde_json::to_value(to).map_err(|e| KvError::Rpc(e.into()))?;
+
+        let mut store = self.store.lock().unwrap();
+        match store.get_mut(key) {
+            None if create_if_not_exists => {
+                store.insert(key.to_string(), to);
+                Ok(())
+            }
+            None => Err(KvError::KeyDoesNotExist(format!(
+                "key {key} does not exist"
+            ))),
+            Some(current) if *current != from => Err(KvError::PreconditionFailed(format!(
+                "expected {from} but had {current} for key {key}"
+            ))),
+            Some(current) => {
+                *current = to;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: Kv> Kv for Arc<T> {
+    fn read<V: DeserializeOwned>(&self, key: &str) -> Result<V, KvError> {
+        T::read(self, key)
+    }
+
+    fn write<V: Serialize>(&self, key: &str, value: &V) -> Result<(), KvError> {
+        T::write(self, key, value)
+    }
+
+    fn cas<V: Serialize>(
+        &self,
+        key: &str,
+        from: &V,
+        to: &V,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError> {
+        T::cas(self, key, from, to, create_if_not_exists)
+    }
+}
+
+/// The extra fields of the `reply` of a service, an `error` reply is mapped to a [`KvError`].
+fn extra_of(reply: anyhow::Result<Body>) -> Result<Map<String, Value>, KvError> {
+    let reply = reply.map_err(KvError::Rpc)?;
+    if reply.typ == "error" {
+        return Err(reply_error(&reply.extra));
+    }
+    Ok(reply.extra)
+}
+
+/// Maps the fields of an `error` reply to a [`KvError`].
+fn reply_error(extra: &Map<String, Value>) -> KvError {
+    let code = extra
+        .get("code")
+        .and_then(|c| c.as_u64())
+        .unwrap_or_default();
+    let text = extra
+        .get("text")
+        .and_then(|t| t.as_str())
+        .unwrap_or_default()
+        .to_string();
+    match code {
+        KEY_DOES_NOT_EXIST => KvError::KeyDoesNotExist(text),
+        PRECONDITION_FAILED => KvError::PreconditionFailed(text),
+        code => KvError::Other { code, text },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::kv::{Kv, KvClient, KvError, MemoryKv};
+    use crate::lin_kv::LinKv;
+    use crate::message::Body;
+    use crate::rpc::Rpc;
+
+    /// Serves requests with an in-process lin-kv, recording where they were sent.
+    #[derive(Default)]
+    struct LocalRpc {
+        kv: LinKv,
+        dests: RefCell<Vec<String>>,
+    }
+
+    impl Rpc for &LocalRpc {
+        fn call(&self, dest: &str, body: Body) -> Result<Body> {
+            self.dests.borrow_mut().push(dest.to_string());
+            self.kv.apply(&body)
+        }
+    }
+
+    /// Checks the behaviour every Kv implementation must have.
+    fn check_kv(kv: &impl Kv) -> Result<()> {
+        assert!(matches!(
+            kv.read::<u64>("a"),
+            Err(KvError::KeyDoesNotExist(_))
+        ));
+
+        kv.write("a", &vec![1, 2])?;
+        assert_eq!(kv.read::<Vec<u64>>("a")?, vec![1, 2]);
+
+        assert!(matches!(
+            kv.cas("a", &vec![1], &vec![3], false),
+            Err(KvError::PreconditionFailed(_))
+        ));
+        kv.cas("a", &vec![1, 2], &vec![3], false)?;
+        assert_eq!(kv.read::<Vec<u64>>("a")?, vec![3]);
+
+        assert!(matches!(
+            kv.cas("b", &0, &1, false),
+            Err(KvError::KeyDoesNotExist(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_kv_semantics() -> Result<()> {
+        check_kv(&MemoryKv::new())
+    }
+
+    #[test]
+    fn memory_kv_cas_creates_missing_key() -> Result<()> {
+        let kv = MemoryKv::new();
+
+        kv.cas("counter", &0, &5, true)?;
+
+        assert_eq!(kv.read::<u64>("counter")?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn client_semantics() -> Result<()> {
+        // Tests the client against a lin-kv served in process.
+        let rpc = LocalRpc::default();
+        check_kv(&KvClient::seq_kv(&rpc))?;
+
+        assert!(rpc.dests.borrow().iter().all(|d| d == "seq-kv"));
+        Ok(())
+    }
+
+    #[test]
+    fn client_maps_error_codes() {
+        struct ErrorRpc;
+        impl Rpc for ErrorRpc {
+            fn call(&self, _: &str, _: Body) -> Result<Body> {
+                let mut body = Body {
+                    typ: "error".into(),
+                    ..Default::default()
+                };
+                body.extra.insert("code".into(), json!(11));
+                body.extra
+                    .insert("text".into(), json!("temporarily unavailable"));
+                Ok(body)
+            }
+        }
+
+        let result = KvClient::lin_kv(ErrorRpc).read::<u64>("a");
+
+        assert!(
+            matches!(result, Err(KvError::Other { code: 11, .. })),
+            "expected code 11 error got {:?}",
+            result
+        );
+    }
+}
@@ -0,0 +1,57 @@
+// Lets the code derived for this crate's own types name it as `::maelstrom`.
+extern crate self as maelstrom;
+
+pub mod body;
+pub mod broadcast;
+pub mod checksum;
+pub mod clock;
+pub mod compress;
+pub mod config;
+pub mod crdt;
+pub mod dedup;
+pub mod digest;
+pub mod durability;
+pub mod echo;
+pub mod error;
+pub mod failure_detector;
+pub mod forward;
+pub mod frag;
+pub mod g_counter;
+pub mod g_set;
+pub mod heartbeat;
+pub mod ids;
+pub mod kafka;
+pub mod kv;
+pub mod lin_kv;
+pub mod list_append;
+pub mod logging;
+pub mod macros;
+pub mod message;
+pub mod metrics;
+pub mod mvcc;
+pub mod network;
+pub mod node;
+pub mod outbox;
+pub mod peers;
+pub mod persistence;
+pub mod quorum;
+pub mod raft;
+pub mod rate_limit;
+pub mod replay;
+pub mod replicated;
+pub mod rpc;
+pub mod rtt;
+pub mod runtime;
+pub mod sequencer;
+pub mod sessions;
+pub mod storage;
+pub mod trace;
+pub mod transport;
+pub mod two_phase;
+pub mod txn;
+pub mod unique_ids;
+pub mod validate;
+pub mod wal;
+pub mod watermark;
+pub mod workload;
+pub mod writer;
@@ -0,0 +1,270 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::{
+    body,
+    message::{Body, Message, KEY_DOES_NOT_EXIST, MALFORMED_REQUEST, PRECONDITION_FAILED},
+    node::Handler,
+    raft::StateMachine,
+};
+
+/// Linearizable key value store workload (lin-kv), served by this node.
+///
+/// A single node applies every operation in the order it recieves them, which is trivially
+/// linearizable. Keys and values can be any JSON value.
+#[derive(Debug, Default)]
+pub struct LinKv {
+    // Value of every key, keyed by the JSON text of the key.
+    store: Mutex<HashMap<String, Value>>,
+}
+
+/// Returns the handlers of the lin-kv workload, backed by `kv`.
+pub fn handlers(kv: &LinKv) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    for typ in ["read", "write", "cas"] {
+        funs.insert(typ.into(), Box::new(|msg, id| kv.handle(msg, id)));
+    }
+    funs
+}
+
+impl LinKv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies the read, write or cas operation in `op`, returns the body of the reply.
+    ///
+    /// Failed operations are not errors, they produce an `error` reply body with the Maelstrom
+    /// error code.
+    pub fn apply(&self, op: &Body) -> Result<Body> {
+        match op.typ.as_str() {
+            "read" => self.read(op),
+            "write" => self.write(op),
+            "cas" => self.cas(op),
+            typ => Err(anyhow!("lin-kv cannot apply {typ}: {:?}", op)),
+        }
+    }
+
+    fn handle(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let mut body = self.apply(&msg.body)?;
+        body.msg_id = msg_id;
+        Ok(msg.reply_with(body))
+    }
+
+    fn read(&self, op: &Body) -> Result<Body> {
+        let key = field(op, "key")?.to_string();
+        let reply = match self.store.lock().unwrap().get(&key) {
+            Some(value) => body!("read_ok", { "value": value }),
+            None => not_found(&key),
+        };
+        Ok(reply)
+    }
+
+    fn write(&self, op: &Body) -> Result<Body> {
+        let key = field(op, "key")?.to_string();
+        let value = field(op, "value")?.clone();
+        self.store.lock().unwrap().insert(key, value);
+        Ok(body!("write_ok", {}))
+    }
+
+    /// Sets `key` to `to` if its current value is `from`. A missing key is created with `to`
+    /// if `create_if_not_exists` is set, as in Maelstrom's lin-kv.
+    fn cas(&self, op: &Body) -> Result<Body> {
+        let key = field(op, "key")?.to_string();
+        let from = field(op, "from")?;
+        let to = field(op, "to")?;
+        let create = op
+            .extra
+            .get("create_if_not_exists")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+
+        let mut store = self.store.lock().unwrap();
+        let reply = match store.get_mut(&key) {
+            None if create => {
+                store.insert(key, to.clone());
+                body!("cas_ok", {})
+            }
+            None => not_found(&key),
+            Some(current) if current != from => error(
+                PRECONDITION_FAILED,
+                &format!("expected {from} but had {current} for key {key}"),
+            ),
+            Some(current) => {
+                *current = to.clone();
+                body!("cas_ok", {})
+            }
+        };
+        Ok(reply)
+    }
+}
+
+/// Lets Raft replicate the store, commands are the bodies of read/write/cas requests and results
+/// are the bodies of their replies.
+impl StateMachine for LinKv {
+    fn apply(&mut self, command: &Value) -> Value {
+        let reply = serde_json::from_value::<Body>(command.clone())
+            .map_err(anyhow::Error::from)
+            .and_then(|op| LinKv::apply(self, &op))
+            .unwrap_or_else(|e| error(MALFORMED_REQUEST, &e.to_string()));
+        serde_json::to_value(reply).unwrap_or_default()
+    }
+
+    fn snapshot(&self) -> Value {
+        serde_json::to_value(&*self.store.lock().unwrap()).unwrap_or_default()
+    }
+
+    fn restore(&mut self, snapshot: &Value) -> Result<()> {
+        *self.store.lock().unwrap() = serde_json::from_value(snapshot.clone())?;
+        Ok(())
+    }
+}
+
+fn field<'a>(op: &'a Body, name: &str) -> Result<&'a Value> {
+    op.extra
+        .get(name)
+        .ok_or(anyhow!("{} has no {name} field: {:?}", op.typ, op))
+}
+
+fn not_found(key: &str) -> Body {
+    error(KEY_DOES_NOT_EXIST, &format!("key {key} does not exist"))
+}
+
+fn error(code: u64, text: &str) -> Body {
+    body!("error", { "code": code, "text": text })
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use serde_json::{json, Value};
+
+    use crate::lin_kv::{handlers, LinKv};
+    use crate::message::Message;
+    use crate::node::Node;
+    use crate::raft::StateMachine;
+
+    fn init_node(kv: &LinKv) -> Result<Node<'_>> {
+        let node = Node::new(handlers(kv))?;
+        node.handle(request(json!({
+            "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]
+        })))?;
+        Ok(node)
+    }
+
+    fn request(body: Value) -> Message {
+        serde_json::from_value(json!({ "src": "c1", "dest": "n1", "body": body }))
+            .expect("invalid message json.")
+    }
+
+    #[test]
+    fn read_missing_key_is_error_20() -> Result<()> {
+        let kv = LinKv::new();
+        let node = init_node(&kv)?;
+
+        let reply = node.handle(request(json!({ "type": "read", "msg_id": 2, "key": 1 })))?;
+
+        assert_eq!(reply.body.typ, "error");
+        assert_eq!(reply.body.in_reply_to, 2);
+        assert_eq!(reply.body.extra["code"], 20);
+        Ok(())
+    }
+
+    #[test]
+    fn read_returns_written_value() -> Result<()> {
+        let kv = LinKv::new();
+        let node = init_node(&kv)?;
+
+        let reply = node.handle(request(json!({
+            "type": "write", "msg_id": 2, "key": 1, "value": 5
+        })))?;
+        assert_eq!(reply.body.typ, "write_ok");
+        let reply = node.handle(request(json!({ "type": "read", "msg_id": 3, "key": 1 })))?;
+
+        assert_eq!(reply.body.typ, "read_ok");
+        assert_eq!(reply.body.extra["value"], 5);
+        Ok(())
+    }
+
+    #[test]
+    fn cas_replaces_matching_value() -> Result<()> {
+        let kv = LinKv::new();
+        let node = init_node(&kv)?;
+        node.handle(request(
+            json!({ "type": "write", "msg_id": 2, "key": "k", "value": 1 }),
+        ))?;
+
+        let reply = node.handle(request(json!({
+            "type": "cas", "msg_id": 3, "key": "k", "from": 1, "to": 2
+        })))?;
+        assert_eq!(reply.body.typ, "cas_ok");
+        let reply = node.handle(request(json!({ "type": "read", "msg_id": 4, "key": "k" })))?;
+
+        assert_eq!(reply.body.extra["value"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cas_creates_missing_key_if_asked() -> Result<()> {
+        let kv = LinKv::new();
+        let node = init_node(&kv)?;
+
+        let reply = node.handle(request(json!({
+            "type": "cas", "msg_id": 2, "key": "k", "from": 1, "to": 2,
+            "create_if_not_exists": true
+        })))?;
+        assert_eq!(reply.body.typ, "cas_ok");
+        let reply = node.handle(request(json!({
+            "type": "cas", "msg_id": 3, "key": "k", "from": 1, "to": 3,
+            "create_if_not_exists": true
+        })))?;
+        assert_eq!(reply.body.extra["code"], 22);
+        let reply = node.handle(request(json!({ "type": "read", "msg_id": 4, "key": "k" })))?;
+
+        assert_eq!(reply.body.extra["value"], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cas_errors() -> Result<()> {
+        // Tests that cas fails with 20 on a missing key and 22 on a value mismatch.
+        let kv = LinKv::new();
+        let node = init_node(&kv)?;
+
+        let reply = node.handle(request(json!({
+            "type": "cas", "msg_id": 2, "key": 1, "from": 1, "to": 2
+        })))?;
+        assert_eq!(reply.body.extra["code"], 20);
+
+        node.handle(request(
+            json!({ "type": "write", "msg_id": 3, "key": 1, "value": 3 }),
+        ))?;
+        let reply = node.handle(request(json!({
+            "type": "cas", "msg_id": 4, "key": 1, "from": 1, "to": 2
+        })))?;
+        assert_eq!(reply.body.typ, "error");
+        assert_eq!(reply.body.extra["code"], 22);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_restore_roundtrip() -> Result<()> {
+        // Tests that restoring a snapshot into another store gives the same contents.
+        let mut kv = LinKv::new();
+        StateMachine::apply(&mut kv, &json!({ "type": "write", "key": 1, "value": 2 }));
+        StateMachine::apply(
+            &mut kv,
+            &json!({ "type": "write", "key": "a", "value": [3] }),
+        );
+
+        let mut restored = LinKv::new();
+        restored.restore(&kv.snapshot())?;
+
+        let read = StateMachine::apply(&mut restored, &json!({ "type": "read", "key": "a" }));
+        assert_eq!(read["value"], json!([3]));
+        assert_eq!(restored.snapshot(), kv.snapshot());
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    error::NodeError,
+    ids::FlakeIds,
+    kv::{Kv, KvError},
+    message::{Message, TXN_CONFLICT},
+    node::Handler,
+    reply,
+};
+
+/// Key of the root in the store, the map from every list key to the thunk holding its value.
+const ROOT: &str = "root";
+
+/// A single operation of a list-append transaction, either a read `["r", key, null]` or an
+/// append `["append", key, value]`. Reads have the list filled in when the transaction commits.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ListOp(pub String, pub u64, pub Value);
+
+impl ListOp {
+    /// Whether the op is a read, or an append with a value.
+    fn is_valid(&self) -> bool {
+        match self.0.as_str() {
+            "r" => true,
+            "append" => !self.2.is_null(),
+            _ => false,
+        }
+    }
+}
+
+/// Transactional list workload (txn-list-append), the state kept in a key value store such as
+/// lin-kv, in the style of Datomic.
+///
+/// Lists are never changed in place. The value of every list is stored in a thunk, a key that
+/// is written once, and a root key maps every list key to the thunk holding its current value.
+/// A transaction reads the root, applies its ops to the lists it points to, writes a thunk for
+/// every list it appended to, and commits by a compare-and-set of the root. Every committed
+/// transaction so replaced the root another committed transaction left, which makes
+/// transactions serializable. If the root changed since it was read the transaction is aborted
+/// with a txn-conflict (30) error, and the client may retry it.
+#[derive(Debug)]
+pub struct ListAppend<K> {
+    kv: K,
+    // Names new thunks, set on init.
+    ids: OnceLock<FlakeIds>,
+    // Values of thunks already read, thunks never change.
+    thunks: Mutex<HashMap<String, Vec<Value>>>,
+}
+
+/// Returns the handlers of the txn-list-append workload, backed by `store`.
+pub fn handlers<K: Kv + Sync>(store: &ListAppend<K>) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert("txn".into(), Box::new(|msg, id| store.txn(msg, id)));
+    funs
+}
+
+impl<K: Kv> ListAppend<K> {
+    /// Creates a list store kept in `kv`.
+    pub fn new(kv: K) -> Self {
+        Self {
+            kv,
+            ids: OnceLock::new(),
+            thunks: Mutex::default(),
+        }
+    }
+
+    /// Starts naming thunks after this node, meant to be used as the node's init handler.
+    pub fn init(&self, node_id: &str, _node_ids: &[String]) {
+        self.ids.get_or_init(|| FlakeIds::new(node_id));
+    }
+
+    /// Applies all the ops of `ops` in order as one transaction, returns the completed ops.
+    ///
+    /// Fails with an error starting with "TxnConflict" when another transaction committed
+    /// first, then no op is applied.
+    pub fn apply(&self, ops: Vec<ListOp>) -> Result<Vec<ListOp>> {
+        let ids = self
+            .ids
+            .get()
+            .ok_or(NodeError::NotReady("node not initialized".into()))?;
+        if let Some(invalid) = ops.iter().find(|op| !op.is_valid()) {
+            return Err(NodeError::Malformed(format!("invalid micro-op {:?}", invalid)).into());
+        }
+
+        let root: BTreeMap<u64, String> = match self.kv.read(ROOT) {
+            Ok(root) => root,
+            Err(KvError::KeyDoesNotExist(_)) => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        // Lists the transaction read or appended to.
+        let mut lists: BTreeMap<u64, Vec<Value>> = BTreeMap::new();
+        let mut appended = BTreeSet::new();
+        let mut completed = Vec::with_capacity(ops.len());
+        for ListOp(op, key, value) in ops {
+            let list = match lists.entry(key) {
+                Entry::Occupied(list) => list.into_mut(),
+                Entry::Vacant(list) => list.insert(match root.get(&key) {
+                    Some(thunk) => self.thunk(thunk)?,
+                    None => Vec::new(),
+                }),
+            };
+            if op == "append" {
+                list.push(value.clone());
+                appended.insert(key);
+                completed.push(ListOp(op, key, value));
+            } else {
+                completed.push(ListOp(op, key, json!(list)));
+            }
+        }
+        if appended.is_empty() {
+            return Ok(completed);
+        }
+
+        let mut new_root = root.clone();
+        for key in appended {
+            let thunk = format!("{:032x}", ids.next_u128());
+            self.kv.write(&thunk, &lists[&key])?;
+            self.thunks
+                .lock()
+                .unwrap()
+                .insert(thunk.clone(), lists[&key].clone());
+            new_root.insert(key, thunk);
+        }
+        match self.kv.cas(ROOT, &root, &new_root, true) {
+            Ok(()) => Ok(completed),
+            Err(KvError::PreconditionFailed(_)) => Err(anyhow!(
+                "TxnConflict: another transaction committed since the root was read"
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The list in `thunk`, from the store the first time it is read.
+    fn thunk(&self, thunk: &str) -> Result<Vec<Value>> {
+        if let Some(list) = self.thunks.lock().unwrap().get(thunk) {
+            return Ok(list.clone());
+        }
+        let list: Vec<Value> = self.kv.read(thunk)?;
+        self.thunks
+            .lock()
+            .unwrap()
+            .insert(thunk.to_string(), list.clone());
+        Ok(list)
+    }
+
+    /// Handles a `txn` message, a conflict is replied with a txn-conflict error.
+    fn txn(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let ops: Vec<ListOp> = msg.body.get_as("txn")?;
+
+        match self.apply(ops) {
+            Ok(completed) => Ok(reply!(msg, msg_id, "txn_ok", { "txn": completed })),
+            Err(e) if e.to_string().starts_with("TxnConflict") => Ok(reply!(
+                msg,
+                msg_id,
+                "error",
+                { "code": TXN_CONFLICT, "text": e.to_string() }
+            )),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use anyhow::Result;
+    use serde::{de::DeserializeOwned, Serialize};
+    use serde_json::{json, Value};
+
+    use crate::kv::{Kv, KvError, MemoryKv};
+    use crate::list_append::{handlers, ListAppend, ListOp};
+    use crate::message::Message;
+    use crate::node::Node;
+
+    fn msg(body: Value) -> Message {
+        serde_json::from_value(json!({ "src": "c1", "dest": "n1", "body": body }))
+            .expect("invalid message json.")
+    }
+
+    fn op(op: &str, key: u64, value: Value) -> ListOp {
+        ListOp(op.to_string(), key, value)
+    }
+
+    fn store(node_id: &str) -> ListAppend<MemoryKv> {
+        let store = ListAppend::new(MemoryKv::new());
+        store.init(node_id, &[]);
+        store
+    }
+
+    #[test]
+    fn reads_see_committed_appends() -> Result<()> {
+        // Tests that reads see appends of earlier transactions and earlier ops of their own.
+        let store = store("n1");
+
+        store.apply(vec![op("append", 1, json!(3)), op("append", 2, json!(4))])?;
+        let completed = store.apply(vec![
+            op("r", 1, Value::Null),
+            op("append", 1, json!(5)),
+            op("r", 1, Value::Null),
+            op("r", 9, Value::Null),
+        ])?;
+
+        assert_eq!(
+            completed,
+            vec![
+                op("r", 1, json!([3])),
+                op("append", 1, json!(5)),
+                op("r", 1, json!([3, 5])),
+                op("r", 9, json!([])),
+            ]
+        );
+        assert_eq!(store.apply(vec![op("r", 2, Value::Null)])?[0].2, json!([4]));
+        Ok(())
+    }
+
+    /// Store where another node's transaction commits right before the first root swap.
+    struct Racing<'a> {
+        kv: &'a MemoryKv,
+        racer: Option<Box<ListAppend<Racing<'a>>>>,
+        raced: AtomicBool,
+    }
+
+    impl Kv for Racing<'_> {
+        fn read<T: DeserializeOwned>(&self, key: &str) -> Result<T, KvError> {
+            self.kv.read(key)
+        }
+
+        fn write<T: Serialize>(&self, key: &str, value: &T) -> Result<(), KvError> {
+            self.kv.write(key, value)
+        }
+
+        fn cas<T: Serialize>(
+            &self,
+            key: &str,
+            from: &T,
+            to: &T,
+            create_if_not_exists: bool,
+        ) -> Result<(), KvError> {
+            if let Some(racer) = &self.racer {
+                if !self.raced.swap(true, Ordering::SeqCst) {
+                    racer
+                        .apply(vec![op("append", 1, json!(2))])
+                        .expect("racing transaction failed");
+                }
+            }
+            self.kv.cas(key, from, to, create_if_not_exists)
+        }
+    }
+
+    #[test]
+    fn concurrent_commit_conflicts() -> Result<()> {
+        // Tests that a transaction fails with txn-conflict (30) when the root changed since it
+        // was read, and that none of its appends are visible.
+        let kv = MemoryKv::new();
+        let racing = |racer| Racing {
+            kv: &kv,
+            racer,
+            raced: AtomicBool::new(false),
+        };
+        let racer = ListAppend::new(racing(None));
+        racer.init("n2", &[]);
+        let store = ListAppend::new(racing(Some(Box::new(racer))));
+        let node =
+            Node::with_init_handler(handlers(&store), Box::new(|id, ids| store.init(id, ids)))?;
+        node.handle(msg(json!({
+            "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]
+        })))?;
+
+        let reply = node.handle(msg(json!({
+            "type": "txn", "msg_id": 2, "txn": [["append", 1, 1]]
+        })))?;
+        let read = node.handle(msg(json!({
+            "type": "txn", "msg_id": 3, "txn": [["r", 1, null]]
+        })))?;
+
+        assert_eq!(reply.body.typ, "error");
+        assert_eq!(reply.body.extra["code"], 30);
+        assert_eq!(read.body.extra["txn"], json!([["r", 1, [2]]]));
+        Ok(())
+    }
+}
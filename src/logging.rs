@@ -0,0 +1,56 @@
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::{
+    filter::EnvFilter, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, Registry,
+};
+
+use crate::error::NodeError;
+
+/// Changes the filter of the logs set up by [`init`].
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Sends logs to stderr, Maelstrom reserves stdout for messages.
+///
+/// The level is read from `RUST_LOG` (e.g. `RUST_LOG=maelstrom=debug`) and defaults to info,
+/// it can be changed while running with [`set_level`]. Every event is printed with the span of
+/// the message being handled, so logs of a node can be matched to the messages in Maelstrom's
+/// message log.
+pub fn init() -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr).with_ansi(false))
+        .try_init()
+        .map_err(|e| anyhow!("cannot set up logging: {e}"))?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// Replaces the level of the logs with `directives`, in the syntax of `RUST_LOG`, e.g. `debug`
+/// or `maelstrom::raft=trace,info`. Fails if [`init`] did not set up the logs.
+pub fn set_level(directives: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(directives)
+        .map_err(|e| NodeError::Malformed(format!("invalid log level {directives:?}: {e}")))?;
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| anyhow!("FailedPrecondition: logging is not set up"))?;
+    handle
+        .reload(filter)
+        .map_err(|e| anyhow!("cannot change the log level: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{error::NodeError, logging::set_level};
+
+    #[test]
+    fn invalid_levels_are_malformed() {
+        let e = set_level("maelstrom=loud").unwrap_err();
+        assert!(
+            matches!(e.downcast_ref(), Some(NodeError::Malformed(_))),
+            "{e:#}"
+        );
+    }
+}
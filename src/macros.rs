@@ -0,0 +1,128 @@
+//! Macros building bodies, replies and RPCs from JSON literals, for quick handlers.
+
+use serde_json::Value;
+
+use crate::message::Body;
+
+#[doc(hidden)]
+pub use serde_json;
+
+/// A [`Body`](crate::message::Body) of type `typ` with the fields of a JSON object literal.
+///
+/// ```
+/// let body = maelstrom::body!("read", { "key": "k1" });
+/// assert_eq!((body.typ.as_str(), &body.extra["key"]), ("read", &"k1".into()));
+/// ```
+#[macro_export]
+macro_rules! body {
+    ($typ:expr) => {
+        $crate::macros::body_of($typ, $crate::macros::serde_json::Value::Null)
+    };
+    ($typ:expr, $fields:tt) => {
+        $crate::macros::body_of($typ, $crate::macros::serde_json::json!($fields))
+    };
+}
+
+/// A reply to the request `msg` of type `typ`, with the fields of a JSON object literal, and
+/// with the msg_id `reply_id` if given.
+///
+/// ```
+/// # use maelstrom::message::Message;
+/// let msg = Message { src: "c1".into(), dest: "n1".into(), ..Default::default() };
+/// let reply = maelstrom::reply!(msg, 5, "echo_ok", { "echo": "hi" });
+/// assert_eq!((reply.dest.as_str(), reply.body.msg_id), ("c1", 5));
+/// ```
+#[macro_export]
+macro_rules! reply {
+    ($msg:expr, $typ:expr, $fields:tt) => {
+        $msg.reply_with($crate::body!($typ, $fields))
+    };
+    ($msg:expr, $reply_id:expr, $typ:expr, $fields:tt) => {
+        $msg.reply_with($crate::message::Body {
+            msg_id: $reply_id,
+            ..$crate::body!($typ, $fields)
+        })
+    };
+}
+
+/// Calls `dest` through `rpc`, any [`Rpc`](crate::rpc::Rpc), with a request of type `typ` and
+/// the fields of a JSON object literal, returns the body of the reply.
+///
+/// ```no_run
+/// # fn read(rpc: &maelstrom::rpc::RpcClient) -> anyhow::Result<()> {
+/// let reply = maelstrom::rpc!(rpc, "lin-kv", "read", { "key": "k1" })?;
+/// # Ok(())
+/// # }
+/// ```
+#[macro_export]
+macro_rules! rpc {
+    ($rpc:expr, $dest:expr, $typ:expr, $fields:tt) => {{
+        // Unused where the trait is in scope already, e.g. through a bound.
+        #[allow(unused_imports)]
+        use $crate::rpc::Rpc as _;
+        $rpc.call($dest, $crate::body!($typ, $fields))
+    }};
+    ($rpc:expr, $dest:expr, $typ:expr) => {{
+        // Unused where the trait is in scope already, e.g. through a bound.
+        #[allow(unused_imports)]
+        use $crate::rpc::Rpc as _;
+        $rpc.call($dest, $crate::body!($typ))
+    }};
+}
+
+/// A body of type `typ` with the fields of `fields`, if it is an object.
+#[doc(hidden)]
+pub fn body_of(typ: &str, fields: Value) -> Body {
+    Body {
+        typ: typ.to_string(),
+        extra: match fields {
+            Value::Object(extra) => extra,
+            _ => Default::default(),
+        },
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::message::{Body, Message};
+    use crate::rpc::Rpc;
+
+    /// Replies to every call with the request it got.
+    #[derive(Default)]
+    struct Echo(Mutex<Vec<String>>);
+
+    impl Rpc for Echo {
+        fn call(&self, dest: &str, body: Body) -> Result<Body> {
+            self.0.lock().unwrap().push(dest.to_string());
+            Ok(body)
+        }
+    }
+
+    #[test]
+    fn macros_build_messages() -> Result<()> {
+        let msg: Message = serde_json::from_value(json!({
+            "src": "c1", "dest": "n1", "body": { "type": "echo", "msg_id": 3 }
+        }))?;
+        let key = "k1";
+        let rpc = Echo::default();
+
+        let reply = crate::reply!(msg, "echo_ok", { "echo": key });
+        let read = crate::rpc!(rpc, "lin-kv", "read", { "key": key })?;
+
+        let expected: Message = serde_json::from_value(json!({
+            "src": "n1", "dest": "c1",
+            "body": { "type": "echo_ok", "in_reply_to": 3, "echo": "k1" }
+        }))?;
+        assert_eq!(reply, expected);
+        assert_eq!(read, crate::body!("read", { "key": "k1" }));
+        assert_eq!(*rpc.0.lock().unwrap(), vec!["lin-kv"]);
+        assert!(crate::body!("topology_ok").extra.is_empty());
+        Ok(())
+    }
+}
@@ -1,76 +1,47 @@
-mod message;
-mod node;
-
-use std::{collections::HashMap, io};
-
-use anyhow::Result;
-use node::Node;
-
-use crate::message::Message;
-
-fn echo_reply(msg: message::Message, msg_id: u64) -> Result<message::Message> {
-    let body = message::Body {
-        typ: "echo_ok".to_string(),
-        msg_id,
-        in_reply_to: msg.body.msg_id,
-        ..msg.body
-    };
-
-    Ok(message::Message {
-        src: msg.dest,
-        dest: msg.src,
-        body,
-    })
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use maelstrom::workload::{self, Options, Workload};
+
+/// A Maelstrom node, speaking JSON messages over stdin and stdout.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Workload whose handlers the node registers.
+    #[arg(long, value_enum, default_value_t = Workload::Echo)]
+    workload: Workload,
+    #[command(flatten)]
+    options: Options,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-
-
-/// Topolgy message handler.
-fn topology(msg: Message, msg_id: u64) -> Result<Message> {
-    Err(anyhow::anyhow!("unimplemented, got: {msg:?}"))
-}
-
-/// Broadcast message handler.
-fn broadcast(msg: Message, msg_id: u64) -> Result<Message> {
-    Err(anyhow::anyhow!("unimplemented, got: {msg:?}"))
-}
-
-/// Read message handler.
-fn read(msg: Message, msg_id: u64) -> Result<Message> {
-    Err(anyhow::anyhow!("unimplemented, got: {msg:?}"))
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Feeds the input of a run recorded with --record-dir back through the node, and prints
+    /// how its replies differ from the recorded output.
+    Replay {
+        /// Recorded input, the .in.jsonl file.
+        input: PathBuf,
+        /// Recorded output, the .out.jsonl file.
+        output: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
-    eprintln!("Node starting...");
-
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-
-    let handlers = {
-        let mut funs: HashMap<_, Box<dyn Fn(Message, u64) -> Result<Message>>> = HashMap::new();
-        funs.insert("echo".into(), Box::new(echo_reply));
-        funs.insert("topology".into(), Box::new(topology));
-        funs.insert("broadcast".into(), Box::new(broadcast));
-        funs.insert("read".into(), Box::new(read));
-        funs
-    };
-    let node = Node::new(handlers)?;
-    while stdin.read_line(&mut buffer).is_ok() {
-        eprintln!("Recieved msg: {}", buffer);
-        match serde_json::from_str::<message::Message>(&buffer) {
-            Ok(msg) => {
-                if let Ok(reply) = node.handle(msg) {
-                    println!(
-                        "{}",
-                        serde_json::to_string(&reply).expect("deserializing reply.")
-                    );
-                }
+    let args = Args::parse();
+    maelstrom::logging::init()?;
+    match args.command {
+        Some(Command::Replay { input, output }) => {
+            let differences = workload::replay(args.workload, args.options, &input, &output)?;
+            for difference in &differences {
+                println!("{difference}");
             }
-            Err(e) => {
-                eprintln!("Failed to parse json {}", e);
+            match differences.len() {
+                0 => Ok(()),
+                n => Err(anyhow!("{n} replies differ from the recording")),
             }
         }
-        buffer.clear();
+        None => workload::run(args.workload, args.options),
     }
-    Ok(())
 }
@@ -1,6 +1,31 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{Map, Value};
 
+use crate::error::NodeError;
+
+/// Maelstrom error code for a request that timed out, it may or may not have taken effect.
+pub const TIMEOUT: u64 = 0;
+/// Maelstrom error code for a request of a type the node does not support.
+pub const NOT_SUPPORTED: u64 = 10;
+/// Maelstrom error code for a request that cannot be served right now, but may be later.
+pub const TEMPORARILY_UNAVAILABLE: u64 = 11;
+/// Maelstrom error code for a request that is malformed.
+pub const MALFORMED_REQUEST: u64 = 12;
+/// Maelstrom error code for a request whose handler crashed, it may or may not have taken effect.
+pub const CRASH: u64 = 13;
+/// Maelstrom error code for a key that does not exist.
+pub const KEY_DOES_NOT_EXIST: u64 = 20;
+/// Maelstrom error code for a failed compare-and-set.
+pub const PRECONDITION_FAILED: u64 = 22;
+/// Maelstrom error code for a transaction aborted because it conflicted with another one.
+pub const TXN_CONFLICT: u64 = 30;
+
 // Maelstrom Message.
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Default)]
 pub struct Message {
@@ -31,18 +56,142 @@ pub struct Body {
     pub extra: Map<String, Value>,
 }
 
+impl Body {
+    /// The string field `name`.
+    pub fn get_str(&self, name: &str) -> Result<&str> {
+        let value = self.field(name)?;
+        value
+            .as_str()
+            .ok_or_else(|| self.invalid(name, "a string", value))
+    }
+
+    /// The integer field `name`.
+    pub fn get_u64(&self, name: &str) -> Result<u64> {
+        let value = self.field(name)?;
+        value
+            .as_u64()
+            .ok_or_else(|| self.invalid(name, "a non negative integer", value))
+    }
+
+    /// The array field `name`.
+    pub fn get_array(&self, name: &str) -> Result<&Vec<Value>> {
+        let value = self.field(name)?;
+        value
+            .as_array()
+            .ok_or_else(|| self.invalid(name, "an array", value))
+    }
+
+    /// The field `name`, deserialized into `T`.
+    pub fn get_as<T: DeserializeOwned>(&self, name: &str) -> Result<T> {
+        T::deserialize(self.field(name)?)
+            .map_err(|e| malformed(format!("{} field {name} is invalid: {e}", self.typ)))
+    }
+
+    fn field(&self, name: &str) -> Result<&Value> {
+        self.extra
+            .get(name)
+            .ok_or_else(|| malformed(format!("{} has no {name} field", self.typ)))
+    }
+
+    fn invalid(&self, name: &str, expected: &str, value: &Value) -> anyhow::Error {
+        malformed(format!(
+            "{} field {name} must be {expected}, got {value}",
+            self.typ
+        ))
+    }
+}
+
+/// A malformed request error, answered with error code 12.
+fn malformed(text: String) -> anyhow::Error {
+    NodeError::Malformed(text).into()
+}
+
+impl Message {
+    /// Creates the reply to this message with `body`: from its destination to its source, in
+    /// reply to its msg_id. The type, msg_id and fields of the reply are the ones of `body`.
+    pub fn reply_with(&self, body: Body) -> Message {
+        Message {
+            src: self.dest.clone(),
+            dest: self.src.clone(),
+            body: Body {
+                in_reply_to: self.body.msg_id,
+                ..body
+            },
+        }
+    }
+}
+
+/// Source of the msg_ids of the messages a node sends, shared by everything that sends messages
+/// from the node so ids stay unique.
+///
+/// Ids may start at an epoch (see [`MsgIds::with_epoch`]), so a node restarted with a later
+/// epoch never sends an id it sent before it crashed, which peers remembering ids, like
+/// [`Dedup`](crate::dedup::Dedup), would take for a duplicate.
+#[derive(Debug, Default)]
+pub struct MsgIds(AtomicU64);
+
+/// Bits of a msg_id below its epoch, ids of an epoch count up from `epoch << EPOCH_SHIFT`.
+const EPOCH_SHIFT: u32 = 20;
+
+impl MsgIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ids of `epoch`, counting up from `epoch << 20`, above every id of an earlier epoch that
+    /// sent fewer than 2^20 messages per epoch since.
+    pub fn with_epoch(epoch: u64) -> Self {
+        Self(AtomicU64::new(epoch << EPOCH_SHIFT))
+    }
+
+    /// Ids of the current epoch, the milliseconds since the Unix epoch. Ids stay unique across
+    /// restarts as long as the node sends fewer than a million messages a millisecond.
+    pub fn epoch_from_clock() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Self::with_epoch(millis as u64)
+    }
+
+    /// Returns a msg_id never returned before, ids count up from 0, or from their epoch.
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns a msg_id for a request, like [`MsgIds::next`] but never 0, which a reply cannot
+    /// tell apart from a missing in_reply_to.
+    pub fn next_request(&self) -> u64 {
+        match self.next() {
+            0 => self.next(),
+            id => id,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use anyhow::Result;
 
-    use crate::message::Message;
     use crate::message::Body;
+    use crate::message::{Message, MsgIds};
+
+    #[test]
+    fn later_epochs_have_higher_ids() {
+        let before = MsgIds::with_epoch(7);
+        let ids: Vec<u64> = (0..3).map(|_| before.next()).collect();
+        let restarted = MsgIds::with_epoch(8);
+
+        assert_eq!(ids, vec![7 << 20, (7 << 20) + 1, (7 << 20) + 2]);
+        assert!(restarted.next() > ids[2]);
+        assert!(MsgIds::epoch_from_clock().next() > before.next());
+    }
 
     #[test]
     fn parse_message() -> Result<()> {
         let echo = r#"{ "src": "c1", "dest": "n1", "body": { "type": "echo", "msg_id": 1, "echo": "Please echo 35" }}"#;
 
-        let msg = serde_json::from_str::<Message>(&echo)?;
+        let msg = serde_json::from_str::<Message>(echo)?;
         let mut expected = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
@@ -63,7 +212,7 @@ mod test {
     fn parse_empty_message_fails() -> anyhow::Result<()> {
         let echo = "";
 
-        let msg = serde_json::from_str::<Message>(&echo);
+        let msg = serde_json::from_str::<Message>(echo);
 
         assert!(msg.is_err(), "parsing empty message should fail.");
         Ok(())
@@ -74,7 +223,7 @@ mod test {
         let echo =
             r#"{ "dest": "n1", "body": { "type": "echo", "msg_id": 1, "echo": "Please echo 35" }}"#;
 
-        let msg = serde_json::from_str::<Message>(&echo);
+        let msg = serde_json::from_str::<Message>(echo);
 
         assert!(msg.is_err(), "parse should fail if src1");
         Ok(())
@@ -85,7 +234,7 @@ mod test {
         let echo =
             r#"{ "src": "c1",  "body": { "type": "echo", "msg_id": 1, "echo": "Please echo 35" }}"#;
 
-        let msg = serde_json::from_str::<Message>(&echo);
+        let msg = serde_json::from_str::<Message>(echo);
 
         assert!(msg.is_err(), "parse should fail when no dst.");
         Ok(())
@@ -95,9 +244,65 @@ mod test {
     fn parse_fails_when_no_body() -> anyhow::Result<()> {
         let echo = r#"{ "src": "c1", "dest": "n1" }"#;
 
-        let msg = serde_json::from_str::<Message>(&echo);
+        let msg = serde_json::from_str::<Message>(echo);
 
         assert!(msg.is_err(), "parse should fail when no body {:?}.", msg);
         Ok(())
     }
+
+    #[test]
+    fn typed_field_accessors() -> Result<()> {
+        let msg = serde_json::from_str::<Message>(
+            r#"{ "src": "c1", "dest": "n1", "body": {
+                "type": "init", "node_id": "n1", "node_ids": ["n1", "n2"], "offset": 3 }}"#,
+        )?;
+
+        assert_eq!(msg.body.get_str("node_id")?, "n1");
+        assert_eq!(msg.body.get_u64("offset")?, 3);
+        assert_eq!(msg.body.get_array("node_ids")?.len(), 2);
+        assert_eq!(
+            msg.body.get_as::<Vec<String>>("node_ids")?,
+            vec!["n1", "n2"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn typed_field_errors_name_the_field() {
+        let msg = serde_json::from_str::<Message>(
+            r#"{ "src": "c1", "dest": "n1", "body": { "type": "send", "key": 5 }}"#,
+        )
+        .expect("invalid message json.");
+
+        let missing = msg.body.get_u64("offset").unwrap_err().to_string();
+        let wrong_type = msg.body.get_str("key").unwrap_err().to_string();
+        let invalid = msg.body.get_as::<Vec<u64>>("key").unwrap_err().to_string();
+
+        assert_eq!(missing, "MalformedRequest: send has no offset field");
+        assert_eq!(
+            wrong_type,
+            "MalformedRequest: send field key must be a string, got 5"
+        );
+        assert!(
+            invalid.starts_with("MalformedRequest: send field key is invalid"),
+            "{invalid}"
+        );
+    }
+
+    #[test]
+    fn reply_with_swaps_src_and_dest() -> Result<()> {
+        let echo = r#"{ "src": "c1", "dest": "n1", "body": { "type": "echo", "msg_id": 4, "echo": "hi" }}"#;
+        let msg = serde_json::from_str::<Message>(echo)?;
+
+        let reply = msg.reply_with(Body {
+            typ: "echo_ok".into(),
+            msg_id: 9,
+            in_reply_to: 100,
+            extra: msg.body.extra.clone(),
+        });
+
+        let expected = r#"{ "src": "n1", "dest": "c1", "body": { "type": "echo_ok", "msg_id": 9, "in_reply_to": 4, "echo": "hi" }}"#;
+        assert_eq!(reply, serde_json::from_str(expected)?);
+        Ok(())
+    }
 }
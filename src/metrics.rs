@@ -0,0 +1,310 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::peers::Peers;
+
+/// Number of latency buckets, bucket `i` holds latencies of at most 2^i microseconds and the
+/// last one everything above.
+const BUCKETS: usize = 24;
+
+/// Counters, latency histograms and queue depths of a node, to tune batching and concurrency.
+///
+/// Shared between threads, every method can be called concurrently.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+    peers: Option<Arc<Peers>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // Messages recieved, by type.
+    received: BTreeMap<String, u64>,
+    // Messages sent, by type.
+    sent: BTreeMap<String, u64>,
+    // Time spent in handlers, by message type.
+    latency: BTreeMap<String, Histogram>,
+    // Retries of requests, by kind of request.
+    retries: BTreeMap<String, u64>,
+    // Handlers slower than the node's slow handler threshold, by message type.
+    slow: BTreeMap<String, u64>,
+    // (current, largest) depth of every queue, by queue name.
+    queues: BTreeMap<String, (usize, usize)>,
+}
+
+/// Histogram of durations with power of two buckets.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().max(1);
+        let bucket = (micros.next_power_of_two().trailing_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => self.total / count as u32,
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Upper bound of the `q` quantile (0.0 to 1.0), precise up to a power of two.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(1 << i).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// The count and the mean, median, p99 and max durations in microseconds, as JSON.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "count": self.count,
+            "mean_us": self.mean().as_micros() as u64,
+            "p50_us": self.quantile(0.5).as_micros() as u64,
+            "p99_us": self.quantile(0.99).as_micros() as u64,
+            "max_us": self.max.as_micros() as u64,
+        })
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "count={} mean={:?} p50={:?} p99={:?} max={:?}",
+            self.count,
+            self.mean(),
+            self.quantile(0.5),
+            self.quantile(0.99),
+            self.max
+        )
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports the statistics of every peer in `peers` too.
+    pub fn with_peers(mut self, peers: Arc<Peers>) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    pub fn record_received(&self, typ: &str) {
+        *self.lock().received.entry(typ.to_string()).or_default() += 1;
+    }
+
+    pub fn record_sent(&self, typ: &str) {
+        *self.lock().sent.entry(typ.to_string()).or_default() += 1;
+    }
+
+    /// Records that handling a message of type `typ` took `duration`.
+    pub fn record_latency(&self, typ: &str, duration: Duration) {
+        self.lock()
+            .latency
+            .entry(typ.to_string())
+            .or_default()
+            .record(duration);
+    }
+
+    /// Records that handling a message of type `typ` was slow.
+    pub fn record_slow(&self, typ: &str) {
+        *self.lock().slow.entry(typ.to_string()).or_default() += 1;
+    }
+
+    /// Records a retry of a request, `kind` is usually the type of the request.
+    pub fn record_retry(&self, kind: &str) {
+        *self.lock().retries.entry(kind.to_string()).or_default() += 1;
+    }
+
+    /// Records the current depth of the queue named `queue`.
+    pub fn record_queue_depth(&self, queue: &str, depth: usize) {
+        let mut inner = self.lock();
+        let (current, max) = inner.queues.entry(queue.to_string()).or_default();
+        *current = depth;
+        *max = (*max).max(depth);
+    }
+
+    pub fn received(&self, typ: &str) -> u64 {
+        self.lock().received.get(typ).copied().unwrap_or_default()
+    }
+
+    pub fn sent(&self, typ: &str) -> u64 {
+        self.lock().sent.get(typ).copied().unwrap_or_default()
+    }
+
+    pub fn retries(&self, kind: &str) -> u64 {
+        self.lock().retries.get(kind).copied().unwrap_or_default()
+    }
+
+    /// Number of slow handlers of messages of type `typ`.
+    pub fn slow(&self, typ: &str) -> u64 {
+        self.lock().slow.get(typ).copied().unwrap_or_default()
+    }
+
+    /// Latency histogram of handling messages of type `typ`.
+    pub fn latency(&self, typ: &str) -> Histogram {
+        self.lock().latency.get(typ).cloned().unwrap_or_default()
+    }
+
+    /// Renders every metric, one line per metric kind and message type, and one per peer.
+    pub fn report(&self) -> String {
+        let inner = self.lock();
+        let mut report = String::from("metrics:");
+        for (kind, counts) in [
+            ("received", &inner.received),
+            ("sent", &inner.sent),
+            ("retries", &inner.retries),
+            ("slow", &inner.slow),
+        ] {
+            if counts.is_empty() {
+                continue;
+            }
+            let _ = write!(report, "\n  {kind}");
+            for (typ, count) in counts {
+                let _ = write!(report, " {typ}={count}");
+            }
+        }
+        for (typ, histogram) in &inner.latency {
+            let _ = write!(report, "\n  latency {typ} {histogram}");
+        }
+        for (queue, (current, max)) in &inner.queues {
+            let _ = write!(report, "\n  queue {queue} depth={current} max={max}");
+        }
+        if let Some(peers) = &self.peers {
+            report.push_str(&peers.report(Instant::now()));
+        }
+        report
+    }
+
+    /// Every metric as JSON, like [`Metrics::report`], by metric kind then message type.
+    pub fn to_json(&self) -> Value {
+        let inner = self.lock();
+        let latency: serde_json::Map<String, Value> = inner
+            .latency
+            .iter()
+            .map(|(typ, histogram)| (typ.clone(), histogram.to_json()))
+            .collect();
+        let queues: serde_json::Map<String, Value> = inner
+            .queues
+            .iter()
+            .map(|(queue, (current, max))| (queue.clone(), json!({ "depth": current, "max": max })))
+            .collect();
+        json!({
+            "received": inner.received,
+            "sent": inner.sent,
+            "retries": inner.retries,
+            "slow": inner.slow,
+            "latency": latency,
+            "queues": queues,
+            "peers": self.peers.as_ref().map(|peers| peers.to_json(Instant::now())),
+        })
+    }
+
+    /// Logs the report to stderr.
+    pub fn dump(&self) {
+        info!("{}", self.report());
+    }
+
+    /// Logs the report every `interval` from a background thread, for the rest of the process.
+    pub fn dump_every(self: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()> {
+        let metrics = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            metrics.dump();
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        metrics::{Histogram, Metrics},
+        peers::Peers,
+    };
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut histogram = Histogram::default();
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(64));
+        assert_eq!(histogram.quantile(0.99), Duration::from_micros(100));
+        assert_eq!(histogram.max(), Duration::from_micros(100));
+        assert_eq!(histogram.mean(), Duration::from_nanos(50_500));
+    }
+
+    #[test]
+    fn report_has_every_metric() {
+        let peers = Arc::new(Peers::new());
+        peers.record_sent("n2");
+        let metrics = Metrics::new().with_peers(peers);
+        metrics.record_received("echo");
+        metrics.record_received("echo");
+        metrics.record_sent("echo_ok");
+        metrics.record_latency("echo", Duration::from_micros(3));
+        metrics.record_retry("broadcast");
+        metrics.record_slow("echo");
+        metrics.record_queue_depth("requests", 5);
+        metrics.record_queue_depth("requests", 2);
+
+        let report = metrics.report();
+
+        assert_eq!(metrics.received("echo"), 2);
+        assert!(report.contains("received echo=2"), "{report}");
+        assert!(report.contains("sent echo_ok=1"), "{report}");
+        assert!(report.contains("retries broadcast=1"), "{report}");
+        assert!(report.contains("slow echo=1"), "{report}");
+        assert!(report.contains("latency echo count=1"), "{report}");
+        assert!(report.contains("queue requests depth=2 max=5"), "{report}");
+        assert!(report.contains("peer n2 in_flight=1 retries=0"), "{report}");
+        let json = metrics.to_json();
+        assert_eq!(json["received"]["echo"], 2);
+        assert_eq!(json["latency"]["echo"]["max_us"], 3);
+        assert_eq!(json["queues"]["requests"]["max"], 5);
+        assert_eq!(json["peers"]["n2"]["in_flight"], 1);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, hash::Hash};
+
+/// Multi-version store, every key has the values written to it at every version.
+///
+/// A version is the `(timestamp, node_id)` of its write, versions are ordered like in an
+/// [`LWWRegister`](crate::crdt::LWWRegister) so the latest value of a key is the one an LWW
+/// register would have. Reads at a timestamp see the latest value written at or before it, so
+/// readers at a timestamp see the same snapshot whatever is written after it.
+#[derive(Debug, Clone)]
+pub struct MvccStore<K, V> {
+    // Values of every key, in version order.
+    versions: HashMap<K, Vec<(Version, V)>>,
+}
+
+/// The `(timestamp, node_id)` of a write.
+type Version = (u64, String);
+
+impl<K, V> Default for MvccStore<K, V> {
+    fn default() -> Self {
+        Self {
+            versions: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> MvccStore<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes `value` to `key` at the version `(timestamp, node_id)`, replacing the value of
+    /// that version if it has one. Returns whether it is the latest value of the key.
+    pub fn write(&mut self, key: K, timestamp: u64, node_id: &str, value: V) -> bool {
+        let versions = self.versions.entry(key).or_default();
+        let version = (timestamp, node_id.to_string());
+        match versions.binary_search_by(|(v, _)| v.cmp(&version)) {
+            Ok(i) => versions[i].1 = value,
+            Err(i) => versions.insert(i, (version, value)),
+        }
+        versions
+            .last()
+            .is_some_and(|(v, _)| v.0 == timestamp && v.1 == node_id)
+    }
+
+    /// The latest value of `key`.
+    pub fn latest(&self, key: &K) -> Option<&V> {
+        self.versions.get(key)?.last().map(|(_, value)| value)
+    }
+
+    /// The latest value of `key` written at or before `timestamp`.
+    pub fn read_at(&self, key: &K, timestamp: u64) -> Option<&V> {
+        let versions = self.versions.get(key)?;
+        let after = versions.partition_point(|((t, _), _)| *t <= timestamp);
+        after.checked_sub(1).map(|i| &versions[i].1)
+    }
+
+    /// Whether `key` has a value written after `timestamp`.
+    pub fn written_after(&self, key: &K, timestamp: u64) -> bool {
+        self.versions
+            .get(key)
+            .and_then(|versions| versions.last())
+            .is_some_and(|((t, _), _)| *t > timestamp)
+    }
+
+    /// Drops the values of `key` that no read at `timestamp` or later sees.
+    pub fn prune(&mut self, key: &K, timestamp: u64) {
+        if let Some(versions) = self.versions.get_mut(key) {
+            let seen = versions.partition_point(|((t, _), _)| *t <= timestamp);
+            versions.drain(..seen.saturating_sub(1));
+        }
+    }
+
+    /// Number of values kept of `key`.
+    pub fn versions(&self, key: &K) -> usize {
+        self.versions.get(key).map_or(0, Vec::len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mvcc::MvccStore;
+
+    #[test]
+    fn reads_see_snapshot_at_their_timestamp() {
+        let mut store = MvccStore::new();
+        assert!(store.write(1, 5, "n1", "b"));
+        assert!(
+            !store.write(1, 2, "n2", "a"),
+            "older write is not the latest"
+        );
+        assert!(store.write(1, 5, "n2", "c"));
+
+        assert_eq!(store.read_at(&1, 1), None);
+        assert_eq!(store.read_at(&1, 4), Some(&"a"));
+        assert_eq!(store.read_at(&1, 5), Some(&"c"));
+        assert_eq!(store.latest(&1), Some(&"c"));
+        assert!(store.written_after(&1, 4) && !store.written_after(&1, 5));
+
+        store.prune(&1, 4);
+        assert_eq!((store.versions(&1), store.read_at(&1, 4)), (3, Some(&"a")));
+        store.prune(&1, 5);
+        assert_eq!((store.versions(&1), store.read_at(&1, 9)), (1, Some(&"c")));
+    }
+}
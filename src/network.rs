@@ -0,0 +1,403 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{message::Message, node::Node};
+
+/// Most messages a single [`Network::pump`] delivers before giving up on reaching quiescence.
+const MAX_DELIVERIES: usize = 100_000;
+
+/// Function called periodically with the current virtual time.
+pub type Timer<'a> = Box<dyn Fn(Instant) + 'a>;
+
+/// In-process network of nodes, for testing workloads without Maelstrom.
+///
+/// Messages are routed by their `dest`, replies returned by handlers and messages sent through
+/// the network's channel are delivered after a random latency. Messages to anything that is not
+/// a node (e.g. clients) are kept for the test to inspect.
+///
+/// The network runs on a virtual clock and all randomness comes from its seed, so a run is
+/// reproduced exactly by using the same seed. With the default zero latency messages are
+/// delivered in the order they were sent.
+pub struct Network<'a> {
+    // Nodes of the cluster and services (like lin-kv), keyed by ID.
+    nodes: BTreeMap<String, Node<'a>>,
+    // IDs of the services, they are not part of the cluster's node_ids.
+    services: BTreeSet<String>,
+    // Messages sent by workloads outside of handler replies.
+    inbox: Receiver<Message>,
+    // Messages in flight, keyed by delivery time and send order.
+    queue: BTreeMap<(Instant, u64), Message>,
+    // Number of messages sent, breaks ties between messages delivered at the same time.
+    sent: u64,
+    // Range of the latency of each message.
+    latency: Range<Duration>,
+    // Periodic timers and the next time each fires.
+    timers: Vec<(Duration, Instant, Timer<'a>)>,
+    seed: u64,
+    rng: StdRng,
+    // Virtual time, only moves forward when messages are delivered or time is advanced.
+    now: Instant,
+    // Messages delivered to clients.
+    client_messages: Vec<Message>,
+    // Running count for client message ids.
+    msg_id: u64,
+}
+
+impl<'a> Network<'a> {
+    /// Creates an empty network that also delivers the messages recieved on `inbox`, the receiver
+    /// of the outbox given to workloads.
+    pub fn new(inbox: Receiver<Message>) -> Self {
+        Self::with_seed(inbox, 0)
+    }
+
+    /// Creates an empty network like [`Network::new`] whose randomness is seeded with `seed`.
+    pub fn with_seed(inbox: Receiver<Message>, seed: u64) -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+            services: BTreeSet::new(),
+            inbox,
+            queue: BTreeMap::new(),
+            sent: 0,
+            latency: Duration::ZERO..Duration::ZERO,
+            timers: vec![],
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            now: Instant::now(),
+            client_messages: vec![],
+            msg_id: 0,
+        }
+    }
+
+    /// The seed of the network, log it to reproduce a failing run.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Current virtual time.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Delays every message sent from now on by a random duration in `latency`.
+    pub fn set_latency(&mut self, latency: Range<Duration>) {
+        self.latency = latency;
+    }
+
+    /// Calls `timer` with the virtual time every `interval`, while time is advanced.
+    pub fn every(&mut self, interval: Duration, timer: Timer<'a>) {
+        self.timers.push((interval, self.now + interval, timer));
+    }
+
+    /// Adds a cluster node with ID `id`.
+    pub fn add_node(&mut self, id: &str, node: Node<'a>) {
+        self.nodes.insert(id.to_string(), node);
+    }
+
+    /// Adds a service node with ID `id`, like Maelstrom's lin-kv.
+    pub fn add_service(&mut self, id: &str, node: Node<'a>) {
+        self.services.insert(id.to_string());
+        self.add_node(id, node);
+    }
+
+    /// IDs of the cluster nodes.
+    pub fn node_ids(&self) -> Vec<String> {
+        self.nodes
+            .keys()
+            .filter(|id| !self.services.contains(*id))
+            .cloned()
+            .collect()
+    }
+
+    /// Sends an init message to every node and delivers what they send while initializing.
+    pub fn init(&mut self) -> Result<()> {
+        let node_ids = self.node_ids();
+        for (id, node) in &self.nodes {
+            let ids = if self.services.contains(id) {
+                vec![id.clone()]
+            } else {
+                node_ids.clone()
+            };
+            let init = serde_json::from_value(json!({
+                "src": "c0",
+                "dest": id,
+                "body": { "type": "init", "msg_id": 0, "node_id": id, "node_ids": ids },
+            }))?;
+            node.handle(init)?;
+        }
+        self.pump()?;
+        Ok(())
+    }
+
+    /// Queues `msg` for delivery.
+    pub fn send(&mut self, msg: Message) {
+        let latency = if self.latency.is_empty() {
+            self.latency.start
+        } else {
+            self.rng.gen_range(self.latency.clone())
+        };
+        self.queue.insert((self.now + latency, self.sent), msg);
+        self.sent += 1;
+    }
+
+    /// Delivers messages until none are in flight, returns how many were delivered.
+    ///
+    /// Virtual time moves to the delivery time of each message, but timers do not fire, see
+    /// [`Network::advance`]. Handler errors are logged and produce no reply, like under
+    /// Maelstrom.
+    pub fn pump(&mut self) -> Result<usize> {
+        let mut delivered = 0;
+        while let Some(at) = self.next_delivery() {
+            if delivered == MAX_DELIVERIES {
+                return Err(anyhow!(
+                    "DeadlineExceeded: network still busy after {MAX_DELIVERIES} deliveries"
+                ));
+            }
+            self.deliver_next(at);
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+
+    /// Moves virtual time forward by `duration`, delivering messages and firing timers in the
+    /// order they are due.
+    pub fn advance(&mut self, duration: Duration) {
+        let deadline = self.now + duration;
+        loop {
+            let message = self.next_delivery().filter(|at| *at <= deadline);
+            let timer = self
+                .timers
+                .iter()
+                .enumerate()
+                .map(|(i, (_, at, _))| (*at, i))
+                .min()
+                .filter(|(at, _)| *at <= deadline);
+            match (message, timer) {
+                (Some(at), Some((timer_at, _))) if at <= timer_at => self.deliver_next(at),
+                (Some(at), None) => self.deliver_next(at),
+                (_, Some((at, i))) => {
+                    self.now = at;
+                    let (interval, next, timer) = &mut self.timers[i];
+                    *next += *interval;
+                    timer(at);
+                }
+                (None, None) => break,
+            }
+        }
+        self.now = deadline;
+    }
+
+    /// Queues the messages sent by workloads, returns when the next message is due.
+    fn next_delivery(&mut self) -> Option<Instant> {
+        let sent: Vec<Message> = self.inbox.try_iter().collect();
+        for msg in sent {
+            self.send(msg);
+        }
+        self.queue.keys().next().map(|(at, _)| *at)
+    }
+
+    /// Delivers the first message in flight, which is due `at`.
+    fn deliver_next(&mut self, at: Instant) {
+        let Some((_, msg)) = self.queue.pop_first() else {
+            return;
+        };
+        self.now = self.now.max(at);
+        match self.nodes.get(&msg.dest) {
+            Some(node) => match node.dispatch(msg) {
+                Ok(Some(reply)) => self.send(reply),
+                Ok(None) => {}
+                Err(e) => warn!("failed to handle message: {e:#}"),
+            },
+            None => self.client_messages.push(msg),
+        }
+    }
+
+    /// Sends a request with `body` from `client` to `dest`, delivers messages until the network
+    /// is quiet and returns the reply to the request.
+    pub fn request(&mut self, client: &str, dest: &str, mut body: Value) -> Result<Message> {
+        self.msg_id += 1;
+        body["msg_id"] = self.msg_id.into();
+        let msg_id = self.msg_id;
+        self.send(serde_json::from_value(
+            json!({ "src": client, "dest": dest, "body": body }),
+        )?);
+        self.pump()?;
+
+        let position = self
+            .client_messages
+            .iter()
+            .position(|m| m.dest == client && m.body.in_reply_to == msg_id)
+            .ok_or(anyhow!("no reply from {dest} to {client} msg {msg_id}"))?;
+        Ok(self.client_messages.remove(position))
+    }
+
+    /// Returns and forgets the messages delivered to clients.
+    pub fn take_client_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.client_messages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, collections::HashSet, sync::mpsc, time::Duration};
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::kafka::{self, Kafka};
+    use crate::lin_kv::{self, LinKv};
+    use crate::network::Network;
+    use crate::node::Node;
+    use crate::txn::{self, Isolation, Txn};
+
+    #[test]
+    fn txn_writes_replicate_to_every_node() -> Result<()> {
+        let (outbox, inbox) = mpsc::channel();
+        let txns: Vec<Txn> = (0..3)
+            .map(|_| Txn::replicated(outbox.clone(), Isolation::ReadUncommitted))
+            .collect();
+        let mut net = Network::new(inbox);
+        for (i, t) in txns.iter().enumerate() {
+            let node = Node::with_init_handler(
+                txn::handlers(t),
+                Box::new(move |id, ids| t.init(id, ids)),
+            )?;
+            net.add_node(&format!("n{i}"), node);
+        }
+        net.init()?;
+
+        let reply = net.request("c1", "n0", json!({ "type": "txn", "txn": [["w", 1, 7]] }))?;
+        assert_eq!(reply.body.typ, "txn_ok");
+
+        for node in net.node_ids() {
+            let reply = net.request(
+                "c1",
+                &node,
+                json!({ "type": "txn", "txn": [["r", 1, null]] }),
+            )?;
+            assert_eq!(reply.body.extra["txn"], json!([["r", 1, 7]]), "on {node}");
+        }
+        assert!(net.take_client_messages().is_empty());
+        Ok(())
+    }
+
+    /// Runs 3 replicated txn nodes with random latency, sending a write to every node, returns
+    /// the nodes in the order their replies arrived.
+    fn reply_order(seed: u64) -> Result<Vec<String>> {
+        let (outbox, inbox) = mpsc::channel();
+        let txns: Vec<Txn> = (0..3)
+            .map(|_| Txn::replicated(outbox.clone(), Isolation::ReadUncommitted))
+            .collect();
+        let mut net = Network::with_seed(inbox, seed);
+        net.set_latency(Duration::from_millis(1)..Duration::from_millis(50));
+        for (i, t) in txns.iter().enumerate() {
+            let node = Node::with_init_handler(
+                txn::handlers(t),
+                Box::new(move |id, ids| t.init(id, ids)),
+            )?;
+            net.add_node(&format!("n{i}"), node);
+        }
+        net.init()?;
+
+        for (i, node) in net.node_ids().iter().enumerate() {
+            net.send(serde_json::from_value(json!({
+                "src": "c1", "dest": node,
+                "body": { "type": "txn", "msg_id": i, "txn": [["w", 1, i]] },
+            }))?);
+        }
+        net.pump()?;
+
+        Ok(net
+            .take_client_messages()
+            .into_iter()
+            .map(|m| m.src)
+            .collect())
+    }
+
+    #[test]
+    fn same_seed_same_run() -> Result<()> {
+        // Tests that a seed reproduces the delivery order, and that the seed matters.
+        assert_eq!(reply_order(7)?, reply_order(7)?);
+        let orders: HashSet<_> = (0..10).map(reply_order).collect::<Result<_>>()?;
+        assert!(
+            orders.len() > 1,
+            "every seed gave the same order {:?}",
+            orders
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn timers_fire_on_virtual_time() -> Result<()> {
+        let (_, inbox) = mpsc::channel();
+        let fired = &RefCell::new(vec![]);
+        let mut net = Network::new(inbox);
+        let start = net.now();
+        let fired_at = move |now| fired.borrow_mut().push(now - start);
+        net.every(Duration::from_millis(100), Box::new(fired_at));
+
+        net.advance(Duration::from_millis(350));
+
+        assert_eq!(
+            *fired.borrow(),
+            [100, 200, 300].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(net.now() - start, Duration::from_millis(350));
+        Ok(())
+    }
+
+    #[test]
+    fn latency_delays_delivery() -> Result<()> {
+        // Tests that a message is only delivered once its latency has passed.
+        let (_, inbox) = mpsc::channel();
+        let kv = LinKv::new();
+        let mut net = Network::with_seed(inbox, 3);
+        net.add_service("lin-kv", Node::new(lin_kv::handlers(&kv))?);
+        net.init()?;
+        net.set_latency(Duration::from_millis(10)..Duration::from_millis(11));
+
+        net.send(serde_json::from_value(json!({
+            "src": "c1", "dest": "lin-kv", "body": { "type": "read", "msg_id": 1, "key": 1 },
+        }))?);
+        net.advance(Duration::from_millis(15));
+        assert!(net.take_client_messages().is_empty());
+        net.advance(Duration::from_millis(10));
+
+        assert_eq!(net.take_client_messages().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn kafka_uses_lin_kv_service() -> Result<()> {
+        // Tests that requests to services are routed and their replies get back to the node.
+        let (_, inbox) = mpsc::channel();
+        let kv = LinKv::new();
+        let kafka = Kafka::new();
+        let mut net = Network::new(inbox);
+        net.add_service("lin-kv", Node::new(lin_kv::handlers(&kv))?);
+        net.add_node("n1", Node::new(kafka::handlers(&kafka))?);
+        net.init()?;
+        assert_eq!(net.node_ids(), vec!["n1"]);
+        // This lin-kv's cas cannot create keys, so the log must exist before the first send.
+        net.request(
+            "c1",
+            "lin-kv",
+            json!({ "type": "write", "key": "log-k", "value": [] }),
+        )?;
+
+        let reply = net.request("c1", "n1", json!({ "type": "send", "key": "k", "msg": 5 }))?;
+        assert_eq!(reply.body.extra["offset"], 0);
+        let reply = net.request("c1", "n1", json!({ "type": "poll", "offsets": { "k": 0 } }))?;
+
+        assert_eq!(reply.body.extra["msgs"], json!({ "k": [[0, 5]] }));
+        Ok(())
+    }
+}
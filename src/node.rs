@@ -1,11 +1,179 @@
 use core::fmt;
 use std::{
-    cell::{Cell, RefCell},
-    collections::HashMap,
+    any::Any,
+    collections::{HashMap, VecDeque},
+    io::{BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::message::{Body, Message};
+use crate::body::{self, MaelstromBody};
+use crate::checksum;
+use crate::clock::LamportClock;
+use crate::compress;
+use crate::dedup::{Dedup, IDEMPOTENCY_KEY_FIELD};
+use crate::error::NodeError;
+use crate::forward::Forwarder;
+use crate::frag::Fragments;
+use crate::heartbeat::Heartbeats;
+use crate::logging;
+use crate::message::{Body, Message, MsgIds, CRASH, TEMPORARILY_UNAVAILABLE};
+use crate::metrics::Metrics;
+use crate::outbox::Outbox;
+use crate::peers::Peers;
+use crate::persistence::Persist;
+use crate::rpc::RpcClient;
+use crate::sessions::Sessions;
+use crate::trace;
+use crate::transport::{StdioTransport, Transport};
+use crate::validate::Validator;
+use crate::watermark::Watermarks;
 use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::{debug, error, info, info_span, warn};
+
+/// Function that processes an incoming message.
+/// Args:
+///     - 1st arg: Request Message.
+///     - 2nd arg: The reply_id to use in the response.
+pub type Handler<'a> = Box<dyn Fn(Message, u64) -> Result<Message> + Send + Sync + 'a>;
+
+/// What a typed handler (see [`typed_handler`]) knows of the request besides its body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context<'m> {
+    pub src: &'m str,
+    // ID of this node.
+    pub dest: &'m str,
+    pub msg_id: u64,
+    // msg_id the reply is sent with.
+    pub reply_id: u64,
+}
+
+/// Creates a handler for messages of type `typ` that deserializes the body of the request into
+/// `Req` and replies with the fields of the `Resp` it returns.
+///
+/// The reply is of type `"{typ}_ok"`, unless `Resp` has a "type" field of its own. Requests
+/// whose body does not deserialize fail with a MalformedRequest error.
+pub fn typed_handler<'a, Req, Resp, F>(typ: &str, handler: F) -> Handler<'a>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    F: Fn(&Context, Req) -> Result<Resp> + Send + Sync + 'a,
+{
+    replying_typed_handler(format!("{typ}_ok"), handler)
+}
+
+/// Like [`typed_handler`], with replies of type `reply_typ` by default.
+pub(crate) fn replying_typed_handler<'a, Req, Resp, F>(reply_typ: String, handler: F) -> Handler<'a>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    F: Fn(&Context, Req) -> Result<Resp> + Send + Sync + 'a,
+{
+    Box::new(move |msg: Message, reply_id: u64| {
+        let Message { src, dest, body } = msg;
+        let ctx = Context {
+            src: &src,
+            dest: &dest,
+            msg_id: body.msg_id,
+            reply_id,
+        };
+        let request = serde_json::from_value(Value::Object(body.extra)).map_err(|e| {
+            NodeError::Malformed(format!("invalid {} body from {src}: {e}", body.typ))
+        })?;
+
+        let mut extra = match serde_json::to_value(handler(&ctx, request)?)? {
+            Value::Object(extra) => extra,
+            Value::Null => Map::new(),
+            other => {
+                return Err(anyhow!(
+                    "Internal: reply to {} must serialize as an object, got {other}",
+                    body.typ
+                ))
+            }
+        };
+        let typ = match extra.remove("type") {
+            Some(Value::String(typ)) => typ,
+            _ => reply_typ.clone(),
+        };
+        Ok(Message {
+            src: dest,
+            dest: src,
+            body: Body {
+                typ,
+                msg_id: reply_id,
+                in_reply_to: body.msg_id,
+                extra,
+            },
+        })
+    })
+}
+
+/// What a handler returns for a message it does not answer right away, e.g. gossip acked later
+/// together with more gossip. [`Node::dispatch`] sends nothing back for it.
+pub fn no_reply() -> Message {
+    Message::default()
+}
+
+/// Creates a handler for messages of type `typ` that replies with the body `handler` returns
+/// for the request, filling in the envelope, the msg_id and the in_reply_to of the reply.
+///
+/// The reply is of type `"{typ}_ok"`, unless the returned body has a type of its own.
+pub fn body_handler<'a, F>(typ: &str, handler: F) -> Handler<'a>
+where
+    F: Fn(&Message) -> Result<Body> + Send + Sync + 'a,
+{
+    let reply_typ = format!("{typ}_ok");
+    Box::new(move |msg: Message, reply_id: u64| {
+        let body = handler(&msg)?;
+        let typ = if body.typ.is_empty() {
+            reply_typ.clone()
+        } else {
+            body.typ
+        };
+        Ok(msg.reply_with(Body {
+            typ,
+            msg_id: reply_id,
+            ..body
+        }))
+    })
+}
+
+/// Function called once the node is initialized.
+/// Args:
+///     - 1st arg: The ID of this node.
+///     - 2nd arg: The IDs of all nodes in the cluster (including this one).
+pub type InitHandler<'a> = Box<dyn Fn(&str, &[String]) + Send + Sync + 'a>;
+
+/// Neighbors of every node, as sent in Maelstrom's `topology` message.
+pub type Topology = HashMap<String, Vec<String>>;
+
+/// Function called every time the topology of the node changes.
+/// Args:
+///     - 1st arg: The ID of this node.
+///     - 2nd arg: The new topology.
+pub type TopologyHandler<'a> = Box<dyn Fn(&str, &Topology) + Send + Sync + 'a>;
+
+/// Function that derives the idempotency key of a request from it, None if it has none, see
+/// [`NodeBuilder::idempotent`].
+pub type KeyFn<'a> = Box<dyn Fn(&Message) -> Option<String> + Send + Sync + 'a>;
+
+/// When a node was created, now by default.
+struct Started(Instant);
+
+impl Default for Started {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
 
 #[derive(Default)]
 /// A Maelstrom node, handles messages.
@@ -14,19 +182,536 @@ use anyhow::{anyhow, Result};
 ///
 /// After recieving an init message a node will its ID and topology.
 /// Messages recieved before an init message cannot be handled.
+///
+/// A node is `Send + Sync`, messages can be handled concurrently from several threads, so
+/// handlers must be `Send + Sync` too.
 pub struct Node<'a> {
     // State of the node,
     // -->Start(Init) --> Initiazlied (Final)
     // A node transitions into initialized after handling its first init message.
-    state: RefCell<State>,
-    // Running count for reply message ids.
-    msg_id: Cell<u64>,
-
-    /// Functions that process incoming messages.
-    /// Args:
-    ///     - 1st arg: Request Message.
-    ///     - 2nd arg: The reply_id to use in the response.
-    handlers: HashMap<String, Box<dyn Fn(Message, u64) -> Result<Message> + 'a>>,
+    state: Mutex<State>,
+    // Source of reply message ids.
+    msg_ids: Arc<MsgIds>,
+    // When the node was built, for its uptime.
+    started: Started,
+    // Requests waiting for a worker when running a worker pool, counted before they are
+    // queued so workers never take out more than was put in.
+    queued: AtomicUsize,
+
+    /// Functions that process incoming messages, keyed by message type.
+    handlers: HashMap<String, Handler<'a>>,
+
+    /// Called when the node transitions into the Initialized state.
+    init_handler: Option<InitHandler<'a>>,
+
+    /// Latest topology, from the last `topology` message or [`Node::set_topology`].
+    topology: Mutex<Topology>,
+
+    /// Called when the topology changes.
+    topology_handler: Option<TopologyHandler<'a>>,
+
+    /// Processes messages no handler is registered for.
+    fallback: Option<Handler<'a>>,
+
+    /// State of the workload, see [`Node::snapshot`].
+    workload_state: Option<&'a (dyn Persist + Sync)>,
+
+    /// Where message counts and handler latencies are recorded, if anywhere.
+    metrics: Option<Arc<Metrics>>,
+
+    /// Handlers that take longer than this are logged as slow, if set.
+    slow_handler: Option<Duration>,
+
+    /// Replies to recent requests, replayed when a request is recieved again.
+    dedup: Option<Mutex<Dedup>>,
+
+    /// Requests whose reply a later handler produces, see [`Awaiting`].
+    awaiting: Mutex<Awaiting>,
+
+    /// Derive the idempotency keys of requests, by type, see [`NodeBuilder::idempotent`].
+    idempotency_keys: HashMap<String, KeyFn<'a>>,
+
+    /// Replies to the latest requests of every client, see [`Node::with_sessions`].
+    sessions: Option<Sessions>,
+
+    /// Low watermarks of peers, below which their requests are forgotten by dedup.
+    watermarks: Option<Watermarks>,
+
+    /// Replies to requests from these are routed to them before the type handlers.
+    rpc: Option<Arc<RpcClient>>,
+    outbox: Option<Arc<Outbox>>,
+    forwarder: Option<Arc<Forwarder>>,
+
+    /// Stamps every message sent and merges the timestamp of every message recieved.
+    clock: Option<Arc<LamportClock>>,
+
+    /// Sees every message recieved, to track which peers are alive.
+    heartbeats: Option<Arc<Heartbeats>>,
+
+    /// Records when every peer was last heard from.
+    peers: Option<Arc<Peers>>,
+
+    /// Fetches the workload state of a random peer on init, see [`Node::request_snapshot`].
+    snapshot_on_start: bool,
+
+    /// Splits messages to peers that are too large, and reassembles the ones recieved.
+    fragments: Option<Fragments>,
+
+    /// Whether snapshots are compressed for peers that accept it, see [`Node::with_compression`].
+    compression: bool,
+
+    /// Rejects invalid messages before they are dispatched, if set.
+    validator: Option<Validator>,
+
+    /// Messages sent by workloads outside of replies, sent on by `run` and `run_pool`.
+    outgoing: Mutex<Option<Receiver<Message>>>,
+}
+
+/// What the worker pool does with a message that arrives while its queue is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    // Stop reading input until there is room in the queue.
+    #[default]
+    Block,
+    // Reply to the request right away with a temporarily-unavailable (11) error. Control
+    // messages, init, topology and replies to this node's own requests, are never shed, they
+    // wait for room instead.
+    Shed,
+}
+
+/// Configuration of [`Node::run_pool`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    // Number of threads handling messages.
+    pub workers: usize,
+    // Most messages waiting to be handled, and most replies waiting to be written.
+    pub capacity: usize,
+    pub overflow: Overflow,
+    // Most handlers of a message type running at once, by type. Types not in it are only
+    // limited by the number of workers.
+    pub limits: HashMap<String, usize>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            capacity: 1024,
+            overflow: Overflow::Block,
+            limits: HashMap::new(),
+        }
+    }
+}
+
+/// Queue of the worker pool, with a lane for control messages taken before any data message.
+///
+/// Control messages are `init`, `topology` and replies to this node's own requests, so a node
+/// gets initialized and its handlers waiting on RPCs get their replies during a backlog of
+/// requests. Each lane holds at most `capacity` messages.
+#[derive(Debug)]
+struct Lanes {
+    lanes: Mutex<LanesState>,
+    // Notified when a message is pushed or the queue is closed.
+    pushed: Condvar,
+    // Notified when a message is taken.
+    taken: Condvar,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct LanesState {
+    control: VecDeque<Message>,
+    data: VecDeque<Message>,
+    // Set once no more messages are pushed.
+    closed: bool,
+}
+
+impl Lanes {
+    fn new(capacity: usize) -> Self {
+        Self {
+            lanes: Mutex::default(),
+            pushed: Condvar::new(),
+            taken: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn is_control(msg: &Message) -> bool {
+        msg.body.in_reply_to != 0 || matches!(msg.body.typ.as_str(), "init" | "topology")
+    }
+
+    /// Pushes `msg` in its lane, waiting for room in it. Fails once the queue is closed.
+    fn push(&self, msg: Message) -> Result<()> {
+        let mut lanes = self.lanes.lock().unwrap();
+        loop {
+            if lanes.closed {
+                return Err(anyhow!("Internal: the pool stopped taking messages"));
+            }
+            let lane = match Self::is_control(&msg) {
+                true => &mut lanes.control,
+                false => &mut lanes.data,
+            };
+            if lane.len() < self.capacity {
+                lane.push_back(msg);
+                self.pushed.notify_one();
+                return Ok(());
+            }
+            lanes = self.taken.wait(lanes).unwrap();
+        }
+    }
+
+    /// Pushes `msg` in its lane if it has room, otherwise returns the message back.
+    fn try_push(&self, msg: Message) -> Option<Message> {
+        let mut lanes = self.lanes.lock().unwrap();
+        if lanes.closed {
+            return Some(msg);
+        }
+        let lane = match Self::is_control(&msg) {
+            true => &mut lanes.control,
+            false => &mut lanes.data,
+        };
+        if lane.len() >= self.capacity {
+            return Some(msg);
+        }
+        lane.push_back(msg);
+        self.pushed.notify_one();
+        None
+    }
+
+    /// Takes the next control message or else the next data message, waiting for one. Returns
+    /// None once the queue is closed and empty.
+    fn pop(&self) -> Option<Message> {
+        let mut lanes = self.lanes.lock().unwrap();
+        loop {
+            let next = match lanes.control.pop_front() {
+                Some(msg) => Some(msg),
+                None => lanes.data.pop_front(),
+            };
+            if next.is_some() {
+                self.taken.notify_one();
+                return next;
+            }
+            if lanes.closed {
+                return None;
+            }
+            lanes = self.pushed.wait(lanes).unwrap();
+        }
+    }
+
+    /// Stops taking messages, the ones queued are still handed out.
+    fn close(&self) {
+        self.lanes.lock().unwrap().closed = true;
+        self.pushed.notify_all();
+        self.taken.notify_all();
+    }
+}
+
+/// Permits of the message types limited by [`PoolConfig::limits`].
+///
+/// A request of a type without a permit left waits here instead of keeping its worker, and is
+/// handled by the worker giving a permit back, so workers never block on a limit while a handler
+/// waits for an RPC reply only a free worker can route.
+#[derive(Debug, Default)]
+struct TypeLimits {
+    // Permits left and requests waiting for one, of every limited type.
+    types: Mutex<HashMap<String, (usize, VecDeque<Message>)>>,
+}
+
+impl TypeLimits {
+    fn new(limits: &HashMap<String, usize>) -> Self {
+        let types = limits
+            .iter()
+            .map(|(typ, &limit)| (typ.clone(), (limit.max(1), VecDeque::new())))
+            .collect();
+        Self {
+            types: Mutex::new(types),
+        }
+    }
+
+    /// Takes a permit for `msg` and returns it to be handled, or keeps it until a permit is
+    /// given back. Replies and unlimited types need no permit.
+    fn acquire(&self, msg: Message) -> Option<Message> {
+        if msg.body.in_reply_to != 0 {
+            return Some(msg);
+        }
+        let mut types = self.types.lock().unwrap();
+        match types.get_mut(&msg.body.typ) {
+            Some((0, waiting)) => {
+                waiting.push_back(msg);
+                None
+            }
+            Some((permits, _)) => {
+                *permits -= 1;
+                Some(msg)
+            }
+            None => Some(msg),
+        }
+    }
+
+    /// Gives back the permit of a handled request of type `typ`, returns the next request
+    /// waiting for one, handed the permit.
+    fn release(&self, typ: &str) -> Option<Message> {
+        let mut types = self.types.lock().unwrap();
+        let (permits, waiting) = types.get_mut(typ)?;
+        let next = waiting.pop_front();
+        if next.is_none() {
+            *permits += 1;
+        }
+        next
+    }
+}
+
+/// Most requests whose reply is still being produced that are remembered, see [`Awaiting`].
+const AWAITING_CAPACITY: usize = 1024;
+
+/// Where the reply to a request is remembered, see [`Node::with_dedup`] and
+/// [`Node::with_sessions`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Remember {
+    // By the src and msg_id of the request.
+    request: bool,
+    // By the idempotency key of the request.
+    key: Option<String>,
+    // By the ID of the request in the session of its client, see [`Node::with_sessions`].
+    session: Option<String>,
+}
+
+impl Remember {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Requests whose handler sent another request on instead of replying, e.g. to lin-kv or to a
+/// peer, keyed by their src and msg_id, so their reply is remembered when the handler of the
+/// answer to that request produces it, or the workload sends it.
+///
+/// Holds at most [`AWAITING_CAPACITY`] requests, the oldest is forgotten first, so requests
+/// that are never replied to do not pile up.
+#[derive(Debug, Default)]
+struct Awaiting {
+    requests: HashMap<(String, u64), Remember>,
+    // Keys of `requests` from oldest to newest.
+    order: VecDeque<(String, u64)>,
+}
+
+impl Awaiting {
+    fn insert(&mut self, request: (String, u64), remember: Remember) {
+        if self.requests.insert(request.clone(), remember).is_none() {
+            self.order.push_back(request);
+        }
+        while self.order.len() > AWAITING_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.requests.remove(&oldest);
+            }
+        }
+    }
+
+    /// Takes out where the reply to `request` is remembered, if it is awaited.
+    fn take(&mut self, request: &(String, u64)) -> Option<Remember> {
+        let remember = self.requests.remove(request)?;
+        if let Some(at) = self.order.iter().position(|r| r == request) {
+            self.order.remove(at);
+        }
+        Some(remember)
+    }
+}
+
+/// Builds a [`Node`] handler by handler.
+///
+/// ```
+/// # use maelstrom::node::Node;
+/// let node = Node::builder()
+///     .handle("echo", |msg, _msg_id| Ok(msg))
+///     .on_init(|id, _ids| tracing::info!(id, "initialized"))
+///     .build();
+/// # assert!(node.is_ok());
+/// ```
+///
+/// Mistakes in the registered handlers, an "init" handler or a type registered twice, are
+/// errors from [`NodeBuilder::build`].
+#[derive(Default)]
+pub struct NodeBuilder<'a> {
+    handlers: HashMap<String, Handler<'a>>,
+    init_handler: Option<InitHandler<'a>>,
+    topology_handler: Option<TopologyHandler<'a>>,
+    fallback: Option<Handler<'a>>,
+    workload_state: Option<&'a (dyn Persist + Sync)>,
+    idempotency_keys: HashMap<String, KeyFn<'a>>,
+    // Types registered more than once, reported by build.
+    duplicates: Vec<String>,
+}
+
+impl<'a> NodeBuilder<'a> {
+    /// Handles messages of type `typ` with `handler`.
+    pub fn handle<F>(self, typ: &str, handler: F) -> Self
+    where
+        F: Fn(Message, u64) -> Result<Message> + Send + Sync + 'a,
+    {
+        self.handlers(HashMap::from([(
+            typ.to_string(),
+            Box::new(handler) as Handler,
+        )]))
+    }
+
+    /// Handles messages of type `typ` with a typed `handler`, see [`typed_handler`].
+    pub fn handle_typed<Req, Resp, F>(self, typ: &str, handler: F) -> Self
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: Fn(&Context, Req) -> Result<Resp> + Send + Sync + 'a,
+    {
+        self.handlers(HashMap::from([(
+            typ.to_string(),
+            typed_handler(typ, handler),
+        )]))
+    }
+
+    /// Handles messages of type `typ` with a `handler` returning the body of the reply, see
+    /// [`body_handler`].
+    pub fn handle_body<F>(self, typ: &str, handler: F) -> Self
+    where
+        F: Fn(&Message) -> Result<Body> + Send + Sync + 'a,
+    {
+        self.handlers(HashMap::from([(
+            typ.to_string(),
+            body_handler(typ, handler),
+        )]))
+    }
+
+    /// Handles requests whose body is a `Req`, of its type, with a typed `handler`, see
+    /// [`body::handler`].
+    pub fn handle_request<Req, Resp, F>(self, handler: F) -> Self
+    where
+        Req: MaelstromBody + DeserializeOwned,
+        Resp: Serialize,
+        F: Fn(&Context, Req) -> Result<Resp> + Send + Sync + 'a,
+    {
+        self.handlers(HashMap::from([body::handler(handler)]))
+    }
+
+    /// Handles messages with every handler of `handlers`, keyed by message type, like the
+    /// handlers returned by the workload modules.
+    pub fn handlers(mut self, handlers: HashMap<String, Handler<'a>>) -> Self {
+        for (typ, handler) in handlers {
+            if self.handlers.insert(typ.clone(), handler).is_some() {
+                self.duplicates.push(typ);
+            }
+        }
+        self
+    }
+
+    /// Calls `init_handler` with the node's ID and the IDs of all nodes once it is initialized.
+    pub fn on_init<F>(mut self, init_handler: F) -> Self
+    where
+        F: Fn(&str, &[String]) + Send + Sync + 'a,
+    {
+        self.init_handler = Some(Box::new(init_handler));
+        self
+    }
+
+    /// Calls `topology_handler` with the node's ID and the new topology every time the topology
+    /// changes, see [`Node::set_topology`].
+    pub fn on_topology_change<F>(mut self, topology_handler: F) -> Self
+    where
+        F: Fn(&str, &Topology) + Send + Sync + 'a,
+    {
+        self.topology_handler = Some(Box::new(topology_handler));
+        self
+    }
+
+    /// Handles messages of types without a handler with `fallback`, see [`Node::with_fallback`].
+    pub fn fallback<F>(mut self, fallback: F) -> Self
+    where
+        F: Fn(Message, u64) -> Result<Message> + Send + Sync + 'a,
+    {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+
+    /// Derives the idempotency key of requests of type `typ` with `key`, so with dedup (see
+    /// [`Node::with_dedup`]) requests with the same key are the same operation and get the
+    /// reply to the first, e.g. broadcasts of the same value. Clients can also send the key
+    /// of any request in its `idempotency_key` field. With sessions (see
+    /// [`Node::with_sessions`]) the key also identifies a client's requests without a
+    /// `request_id`.
+    pub fn idempotent<F>(mut self, typ: &str, key: F) -> Self
+    where
+        F: Fn(&Message) -> Option<String> + Send + Sync + 'a,
+    {
+        self.idempotency_keys.insert(typ.to_string(), Box::new(key));
+        self
+    }
+
+    /// Snapshots and restores `state` with the node, see [`Node::snapshot`].
+    pub fn state(mut self, state: &'a (dyn Persist + Sync)) -> Self {
+        self.workload_state = Some(state);
+        self
+    }
+
+    /// Creates the node.
+    ///
+    /// Preconditions:
+    ///  - Cannot have an "init" handler. The init handler is hard coded and it transitions the
+    ///    node into the Initalized state.
+    ///  - Cannot have two handlers for the same type.
+    pub fn build(self) -> Result<Node<'a>> {
+        if self.handlers.contains_key("init") {
+            return Err(anyhow::anyhow!(
+                "FailedPrecondition: Cannot create Node with an init handler."
+            ));
+        }
+        if !self.duplicates.is_empty() {
+            return Err(anyhow::anyhow!(
+                "FailedPrecondition: Cannot create Node with several handlers for {:?}.",
+                self.duplicates
+            ));
+        }
+
+        Ok(Node {
+            state: State::Start.into(),
+            msg_ids: Arc::default(),
+            started: Started::default(),
+            queued: AtomicUsize::new(0),
+            handlers: self.handlers,
+            init_handler: self.init_handler,
+            topology: Mutex::default(),
+            topology_handler: self.topology_handler,
+            fallback: self.fallback,
+            workload_state: self.workload_state,
+            idempotency_keys: self.idempotency_keys,
+            metrics: None,
+            slow_handler: None,
+            dedup: None,
+            awaiting: Mutex::default(),
+            sessions: None,
+            watermarks: None,
+            rpc: None,
+            outbox: None,
+            clock: None,
+            heartbeats: None,
+            peers: None,
+            snapshot_on_start: false,
+            fragments: None,
+            compression: false,
+            validator: None,
+            forwarder: None,
+            outgoing: Mutex::new(None),
+        })
+    }
+}
+
+impl<'a> fmt::Debug for NodeBuilder<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let handlers: Vec<&String> = self.handlers.keys().collect();
+        f.debug_struct("NodeBuilder")
+            .field("handlers", &handlers)
+            .field("init_handler", &self.init_handler.is_some())
+            .field("topology_handler", &self.topology_handler.is_some())
+            .field("fallback", &self.fallback.is_some())
+            .field("workload_state", &self.workload_state.is_some())
+            .finish()
+    }
 }
 
 /// Node states,
@@ -58,8 +743,32 @@ impl<'a> fmt::Debug for Node<'a> {
         let handlers: Vec<String> = self.handlers.keys().map(|x| x.to_string()).collect();
         f.debug_struct("Node")
             .field("state", &self.state)
-            .field("msg_id", &self.msg_id)
+            .field("msg_ids", &self.msg_ids)
             .field("handlers", &handlers)
+            .field("init_handler", &self.init_handler.is_some())
+            .field("topology", &self.topology)
+            .field("topology_handler", &self.topology_handler.is_some())
+            .field("fallback", &self.fallback.is_some())
+            .field("workload_state", &self.workload_state.is_some())
+            .field("metrics", &self.metrics.is_some())
+            .field("slow_handler", &self.slow_handler)
+            .field("dedup", &self.dedup)
+            .field(
+                "idempotency_keys",
+                &self.idempotency_keys.keys().collect::<Vec<_>>(),
+            )
+            .field("sessions", &self.sessions)
+            .field("watermarks", &self.watermarks)
+            .field("rpc", &self.rpc)
+            .field("outbox", &self.outbox)
+            .field("forwarder", &self.forwarder)
+            .field("clock", &self.clock)
+            .field("heartbeats", &self.heartbeats)
+            .field("peers", &self.peers)
+            .field("snapshot_on_start", &self.snapshot_on_start)
+            .field("fragments", &self.fragments)
+            .field("compression", &self.compression)
+            .field("validator", &self.validator)
             .finish()
     }
 }
@@ -71,367 +780,2544 @@ impl<'a> Node<'a> {
     ///
     /// Preconditions:
     ///  - Cannot have an "init" handler. The init handler is hard coded and it transitions the
-    ///  node into the Initalized state.
-    pub fn new(
-        handlers: HashMap<String, Box<dyn Fn(Message, u64) -> Result<Message> + 'a>>,
+    ///    node into the Initalized state.
+    pub fn new(handlers: HashMap<String, Handler<'a>>) -> Result<Self> {
+        Self::builder().handlers(handlers).build()
+    }
+
+    /// Creates a new node like [`Node::new`], that also calls `init_handler` with the node's ID
+    /// and the IDs of all nodes once it is initialized.
+    pub fn with_init_handler(
+        handlers: HashMap<String, Handler<'a>>,
+        init_handler: InitHandler<'a>,
     ) -> Result<Self> {
-        if let Some(_) = handlers.get("init") {
-            return Err(anyhow::anyhow!(
-                "FailedPrecondition: Cannot create Node with an init handler."
-            ));
-        }
+        Self::builder()
+            .handlers(handlers)
+            .on_init(init_handler)
+            .build()
+    }
 
-        Ok(Self {
-            state: State::Start.into(),
-            msg_id: 0.into(),
-            handlers,
-        })
+    /// Starts building a node, see [`NodeBuilder`].
+    pub fn builder() -> NodeBuilder<'a> {
+        NodeBuilder::default()
     }
 
-    fn reply_id(self: &Self) -> u64 {
-        let id = self.msg_id.get();
-        self.msg_id.set(id + 1);
-        id
+    /// Handles every message of a type no handler is registered for with `fallback`, instead of
+    /// failing with an UnimplementedError. The init message is never passed to it.
+    pub fn with_fallback(mut self, fallback: Handler<'a>) -> Self {
+        self.fallback = Some(fallback);
+        self
     }
 
-    pub fn handle(self: &Self, msg: Message) -> Result<Message> {
-        let msg_type = &msg.body.typ;
-        // Handle init message.
-        if msg_type == "init" {
-            let state = { self.state.borrow().clone() };
-            match state {
-                State::Start => {
-                    let initialized_node = InitializedNode::new(&msg.body)?;
-                    *self.state.borrow_mut() = State::Initialized(initialized_node);
-                    return Ok(init_reply(msg, self.reply_id()));
-                }
-                State::Initialized(node) => {
-                    eprintln!(
-                        "Ignoring init message {:?} recieved after node initialized {:?}",
-                        msg, node
-                    );
-                    return Ok(init_reply(msg, self.reply_id()));
-                }
-            }
-        }
+    /// Records the messages the node recieves and sends, and how long handlers take, in
+    /// `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
 
-        if *self.state.borrow() == State::Start {
-            return Err(anyhow!(
-                "Not Ready: recieved message {:?} before init message cannot handle.",
-                msg
-            ));
-        }
+    /// Warns about handlers that take longer than `threshold`, and counts them in the metrics.
+    /// A slow handler holds up its worker, and with a single worker every other message.
+    pub fn with_slow_handler_warning(mut self, threshold: Duration) -> Self {
+        self.slow_handler = Some(threshold);
+        self
+    }
 
-        // Otherwise try to find a handler.
-        if let Some(&ref handler) = self.handlers.get(msg_type) {
-            return handler(msg, self.reply_id());
-        }
+    /// Takes reply message ids from `msg_ids`, to share them with other senders of messages from
+    /// this node, like an [`Outbox`](crate::outbox::Outbox).
+    pub fn with_msg_ids(mut self, msg_ids: Arc<MsgIds>) -> Self {
+        self.msg_ids = msg_ids;
+        self
+    }
 
-        Err(anyhow!(
-            "UnimplementedError: No handler for message type {}, message: {:?}",
-            msg.body.typ,
-            msg
-        ))
+    /// Remembers the replies to the last `capacity` requests, and replies to a request
+    /// recieved again (same src and msg_id) with the remembered reply instead of running its
+    /// handler again.
+    ///
+    /// Replies to requests with an idempotency key, see [`NodeBuilder::idempotent`], are also
+    /// remembered by their key and replayed to later requests with the same key.
+    ///
+    /// Only replies to the request are remembered. When its handler sends another request on
+    /// instead, e.g. to lin-kv, the reply is remembered once the handler of the answer produces
+    /// it, or the workload sends it (see [`Node::with_outgoing`]). Requests without a msg_id and requests whose handler failed are not remembered. When
+    /// handling concurrently, duplicates that arrive while the first is still being handled
+    /// are handled again.
+    pub fn with_dedup(mut self, capacity: usize) -> Self {
+        self.dedup = Some(Mutex::new(Dedup::new(capacity)));
+        self
     }
-}
 
-impl InitializedNode {
-    fn new(body: &Body) -> Result<Self> {
-        if body.typ != "init" {
-            return Err(anyhow::anyhow!(
-                "Can only initialze node with an init message, got {:?}",
-                body
-            ));
-        }
+    /// Keeps a session for every client, with the replies to its last `capacity` requests, and
+    /// replies to a request its client sends again after a timeout with the original reply
+    /// instead of running its handler again, so client operations happen exactly once.
+    ///
+    /// Clients must send the same `request_id` field with every retry of a request, Maelstrom
+    /// clients never send a msg_id again. Requests without one are identified by the idempotency
+    /// key the workload derives from them instead, see [`NodeBuilder::idempotent`], and are not
+    /// tracked if they have neither.
+    ///
+    /// Unlike [`Node::with_dedup`] the replies are bounded per client, so one busy client does
+    /// not push the replies of the others out. Like it, only replies to the request are
+    /// remembered, also when a later handler produces them. Requests from other nodes and
+    /// requests whose handler failed are not remembered.
+    pub fn with_sessions(mut self, capacity: usize) -> Self {
+        self.sessions = Some(Sessions::new(capacity));
+        self
+    }
 
-        let id = body
-            .extra
-            .get("node_id")
-            .and_then(|n| Some(n.to_string().replace("\"", "")))
-            .ok_or(anyhow::anyhow!(
-                "can't init node if body has no node_id field: {:?}",
-                body
-            ))?;
-        let other_nodes: Vec<String> = body
-            .extra
-            .get("node_ids")
-            .and_then(|v| v.as_array())
-            .ok_or(anyhow::anyhow!(
-                "node_ids must be an array of node names... got {:?}",
-                body
-            ))?
-            .into_iter()
-            .map(|n| n.to_string().replace("\"", ""))
-            .collect();
+    /// Takes in the low watermarks peers send with [`Outbox::with_watermarks`], and forgets the
+    /// remembered replies (see [`Node::with_dedup`]) to requests of a peer below its watermark,
+    /// which it does not send again, so dedup only keeps the replies that may still be needed
+    /// during long runs.
+    pub fn with_watermarks(mut self) -> Self {
+        self.watermarks = Some(Watermarks::new());
+        self
+    }
 
-        Ok(Self { id, other_nodes })
+    /// Routes replies to requests of `rpc` to it, and sets its node ID on init.
+    pub fn with_rpc(mut self, rpc: Arc<RpcClient>) -> Self {
+        self.rpc = Some(rpc);
+        self
     }
-}
 
-fn init_reply(msg: Message, msg_id: u64) -> Message {
-    let body = Body {
-        typ: "init_ok".to_string(),
-        msg_id,
-        in_reply_to: msg.body.msg_id,
-        ..Default::default()
-    };
+    /// Acks messages of `outbox` with their replies, instead of handling the replies.
+    pub fn with_outbox(mut self, outbox: Arc<Outbox>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
 
-    Message {
-        src: msg.dest,
-        dest: msg.src,
-        body,
+    /// Relays replies to requests forwarded with `forwarder` to their original requesters,
+    /// instead of handling the replies.
+    pub fn with_forwarder(mut self, forwarder: Arc<Forwarder>) -> Self {
+        self.forwarder = Some(forwarder);
+        self
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::collections::HashMap;
+    /// Carries the time of `clock` in every message the node sends when running, and moves it
+    /// past the time of every message dispatched.
+    pub fn with_clock(mut self, clock: Arc<LamportClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Lets `heartbeats` see every message recieved, so any message from a peer shows it is
+    /// alive, and starts tracking the peers on init. The node answers heartbeats of peers
+    /// itself, and heartbeat replies are not handled.
+    pub fn with_heartbeats(mut self, heartbeats: Arc<Heartbeats>) -> Self {
+        self.heartbeats = Some(heartbeats);
+        self
+    }
+
+    /// Records in `peers` when every peer was last heard from, and answers `debug_dump`
+    /// messages with their statistics too.
+    pub fn with_peers(mut self, peers: Arc<Peers>) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    /// Asks a random peer for its workload state once the node is initialized, so a restarted
+    /// node catches up in one exchange instead of waiting for gossip. Needs an outbox and
+    /// workload state, see [`Node::request_snapshot`].
+    pub fn with_snapshot_on_start(mut self) -> Self {
+        self.snapshot_on_start = true;
+        self
+    }
+
+    /// Sends messages to peers that are too large for `fragments` as frags, and handles the
+    /// messages put back together from the frags recieved, see [`Fragments`].
+    pub fn with_fragments(mut self, fragments: Fragments) -> Self {
+        self.fragments = Some(fragments);
+        self
+    }
+
+    /// Advertises in snapshot requests that compressed snapshots are accepted, and compresses
+    /// the snapshots sent to peers that advertised it too, see [`compress`].
+    pub fn with_compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
+    /// Checks every message dispatched with `validator` first, messages it rejects fail with a
+    /// MalformedRequest error without being processed.
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Sends the messages recieved on `outgoing` when running, the receiver of the channels
+    /// given to workloads, RPC clients and outboxes.
+    pub fn with_outgoing(self, outgoing: Receiver<Message>) -> Self {
+        *self.outgoing.lock().unwrap() = Some(outgoing);
+        self
+    }
+
+    /// Serializes the state of the workload, Null if the node has none (see
+    /// [`NodeBuilder::state`]).
+    pub fn snapshot(&self) -> Value {
+        self.workload_state
+            .map(|state| state.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// The internal state of the node, as answered to a `debug_dump` message: its ID and the
+    /// IDs of all nodes, the topology, the RPC calls waiting for replies, the outbox messages
+    /// waiting for acks, the statistics of the peers and the state of the workload.
+    pub fn debug_dump(&self) -> Value {
+        let node = match &*self.state.lock().unwrap() {
+            State::Initialized(node) => json!({ "id": node.id, "node_ids": node.other_nodes }),
+            State::Start => Value::Null,
+        };
+        json!({
+            "node": node,
+            "topology": self.topology(),
+            "pending_rpcs": self.rpc.as_ref().map(|rpc| rpc.pending()),
+            "unacked": self.outbox.as_ref().map(|outbox| outbox.unacked()),
+            "peers": self.peers.as_ref().map(|peers| peers.to_json(Instant::now())),
+            "workload": self.snapshot(),
+        })
+    }
+
+    /// The metrics of the node, as answered to a `stats` message: message counts, handler
+    /// latencies and queue depths if the node has [`Metrics`], and how many RPC calls and
+    /// outbox messages are waiting for replies.
+    pub fn stats(&self) -> Value {
+        json!({
+            "metrics": self.metrics.as_ref().map(|metrics| metrics.to_json()),
+            "pending_rpcs": self.rpc.as_ref().map(|rpc| rpc.pending().len()),
+            "unacked": self.outbox.as_ref().map(|outbox| outbox.len()),
+        })
+    }
+
+    /// The health of the node, as answered to a `health` message: whether it is initialized
+    /// and ready for requests, how long it has been up, and the requests waiting for a worker,
+    /// RPC calls waiting for replies and outbox messages waiting for acks.
+    pub fn health(&self) -> Value {
+        let ready = matches!(*self.state.lock().unwrap(), State::Initialized(_));
+        json!({
+            "state": if ready { "initialized" } else { "start" },
+            "ready": ready,
+            "uptime_ms": self.started.0.elapsed().as_millis() as u64,
+            "backlog": {
+                "queued": self.queued.load(Ordering::Relaxed),
+                "pending_rpcs": self.rpc.as_ref().map_or(0, |rpc| rpc.pending().len()),
+                "unacked": self.outbox.as_ref().map_or(0, |outbox| outbox.len()),
+            },
+        })
+    }
+
+    /// Replaces the state of the workload with a `snapshot` from [`Node::snapshot`], of this
+    /// node or of a peer.
+    pub fn restore(&self, snapshot: Value) -> Result<()> {
+        match self.workload_state {
+            Some(state) => state.restore(snapshot),
+            None => Err(anyhow!(
+                "FailedPrecondition: cannot restore a node without workload state"
+            )),
+        }
+    }
+
+    /// Asks `peer` for its workload state in a `snapshot_request`, sent through the outbox until
+    /// answered. The `snapshot` reply is merged into the state of this node, see
+    /// [`Persist::merge_snapshot`], and incremental gossip goes on from there.
+    pub fn request_snapshot(&self, peer: &str) -> Result<u64> {
+        let id = self
+            .id()
+            .ok_or_else(|| NodeError::NotReady("cannot request a snapshot before init".into()))?;
+        self.send_snapshot_request(&id, peer)
+    }
+
+    fn send_snapshot_request(&self, id: &str, peer: &str) -> Result<u64> {
+        let outbox = self
+            .outbox
+            .as_ref()
+            .filter(|_| self.workload_state.is_some())
+            .ok_or_else(|| {
+                anyhow!("FailedPrecondition: snapshots need an outbox and workload state")
+            })?;
+        let mut request = Message {
+            src: id.to_string(),
+            dest: peer.to_string(),
+            body: Body {
+                typ: "snapshot_request".to_string(),
+                ..Default::default()
+            },
+        };
+        if self.compression {
+            compress::accept(&mut request.body);
+        }
+        info!(peer, "requesting snapshot");
+        outbox.send(request, Instant::now())
+    }
+
+    /// Merges the state in the `snapshot` reply `msg` into the workload state. A state that
+    /// does not match its checksum is dropped and requested again.
+    fn receive_snapshot(&self, msg: &Message, state: &(dyn Persist + Sync)) -> Result<()> {
+        let snapshot = match checksum::get(&msg.body, "state") {
+            Ok(snapshot) => snapshot,
+            Err(e) if matches!(e.downcast_ref(), Some(NodeError::Unavailable(_))) => {
+                warn!(peer = %msg.src, "dropping corrupt snapshot: {e:#}");
+                self.request_snapshot(&msg.src)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        state.merge_snapshot(snapshot)?;
+        info!(peer = %msg.src, "merged snapshot");
+        Ok(())
+    }
+
+    fn record(&self, f: impl FnOnce(&Metrics)) {
+        if let Some(metrics) = &self.metrics {
+            f(metrics);
+        }
+    }
+
+    /// Records that `msg` is sent and stamps it with the clock time.
+    fn sending(&self, msg: &mut Message) {
+        self.record(|m| m.record_sent(&msg.body.typ));
+        if let Some(clock) = &self.clock {
+            clock.stamp(&mut msg.body);
+        }
+    }
+
+    /// The messages to send for `msg`, its frags if it is too large.
+    fn split(&self, msg: Message) -> Vec<Message> {
+        match &self.fragments {
+            Some(fragments) => fragments.split(msg),
+            None => vec![msg],
+        }
+    }
+
+    fn reply_id(&self) -> u64 {
+        self.msg_ids.next()
+    }
+
+    pub fn handle(&self, msg: Message) -> Result<Message, NodeError> {
+        let span = info_span!(
+            "message",
+            src = %msg.src,
+            dest = %msg.dest,
+            typ = %msg.body.typ,
+            msg_id = msg.body.msg_id,
+            trace_id = trace::of(&msg.body),
+        );
+        let _entered = span.enter();
+        debug!("handling message");
+        self.record(|m| m.record_received(&msg.body.typ));
+
+        let msg_type = &msg.body.typ;
+        // Handle init message.
+        if msg_type == "init" {
+            // Held while initializing, so concurrent init messages initialize the node once.
+            let mut state = self.state.lock().unwrap();
+            match &*state {
+                State::Start => {
+                    let initialized_node = InitializedNode::new(&msg.body)?;
+                    if let Some(rpc) = &self.rpc {
+                        rpc.init(&initialized_node.id);
+                    }
+                    if let Some(heartbeats) = &self.heartbeats {
+                        heartbeats.init(
+                            &initialized_node.id,
+                            &initialized_node.other_nodes,
+                            Instant::now(),
+                        );
+                    }
+                    if let Some(init_handler) = &self.init_handler {
+                        init_handler(&initialized_node.id, &initialized_node.other_nodes);
+                    }
+                    if self.snapshot_on_start {
+                        let id = &initialized_node.id;
+                        let peers: Vec<&String> = initialized_node
+                            .other_nodes
+                            .iter()
+                            .filter(|peer| *peer != id)
+                            .collect();
+                        if let Some(peer) = peers.choose(&mut rand::thread_rng()) {
+                            if let Err(e) = self.send_snapshot_request(id, peer) {
+                                warn!("cannot request snapshot: {e:#}");
+                            }
+                        }
+                    }
+                    *state = State::Initialized(initialized_node);
+                    return Ok(init_reply(msg, self.reply_id()));
+                }
+                State::Initialized(node) => {
+                    warn!(
+                        ?node,
+                        "ignoring init message recieved after node initialized"
+                    );
+                    return Ok(init_reply(msg, self.reply_id()));
+                }
+            }
+        }
+
+        // Admin requests to diagnose stuck runs, answered even before init unless the workload
+        // handles them.
+        if msg_type == "debug_dump" && !self.handlers.contains_key(msg_type) {
+            let dump = self.debug_dump();
+            info!(%dump, "debug dump");
+            let mut body = Body {
+                typ: "debug_dump_ok".to_string(),
+                msg_id: self.reply_id(),
+                ..Default::default()
+            };
+            body.extra.insert("dump".into(), dump);
+            return Ok(msg.reply_with(body));
+        }
+        if msg_type == "stats" && !self.handlers.contains_key(msg_type) {
+            let mut body = Body {
+                typ: "stats_ok".to_string(),
+                msg_id: self.reply_id(),
+                ..Default::default()
+            };
+            body.extra.insert("stats".into(), self.stats());
+            return Ok(msg.reply_with(body));
+        }
+        // Turns the logs of a single node up or down mid-run.
+        if msg_type == "set_log_level" && !self.handlers.contains_key(msg_type) {
+            logging::set_level(msg.body.get_str("level")?)?;
+            return Ok(msg.reply_with(Body {
+                typ: "set_log_level_ok".to_string(),
+                msg_id: self.reply_id(),
+                ..Default::default()
+            }));
+        }
+        // Lets tooling wait for the node to be ready instead of sleeping.
+        if msg_type == "health" && !self.handlers.contains_key(msg_type) {
+            let mut body = Body {
+                typ: "health_ok".to_string(),
+                msg_id: self.reply_id(),
+                ..Default::default()
+            };
+            body.extra.insert("health".into(), self.health());
+            return Ok(msg.reply_with(body));
+        }
+
+        if *self.state.lock().unwrap() == State::Start {
+            return Err(NodeError::NotReady(format!(
+                "recieved message {:?} before init message cannot handle.",
+                msg
+            )));
+        }
+
+        // Hands a peer that was partitioned or restarted the whole workload state at once.
+        if msg_type == "snapshot_request" && !self.handlers.contains_key(msg_type) {
+            if let Some(state) = self.workload_state {
+                let mut body = Body {
+                    typ: "snapshot".to_string(),
+                    msg_id: self.reply_id(),
+                    ..Default::default()
+                };
+                let snapshot = state.snapshot();
+                checksum::stamp(&mut body, &snapshot);
+                let compressed = self.compression && compress::accepts(&msg.body);
+                compress::insert(&mut body, "state", snapshot, compressed);
+                return Ok(msg.reply_with(body));
+            }
+        }
+
+        // Topology can change after init, the node keeps the latest and the workload handler, if
+        // any, sees every topology message.
+        if msg_type == "topology" {
+            let topology = msg.body.get_as::<Topology>("topology")?;
+            self.set_topology(topology);
+            if !self.handlers.contains_key("topology") {
+                return Ok(msg.reply_with(Body {
+                    typ: "topology_ok".to_string(),
+                    msg_id: self.reply_id(),
+                    ..Default::default()
+                }));
+            }
+        }
+
+        // Requests seen before get the same reply again, as do retries of an operation.
+        let dedup = self.dedup.as_ref().filter(|_| msg.body.msg_id != 0);
+        let key = dedup.and_then(|_| self.idempotency_key(&msg));
+        if let Some(dedup) = dedup {
+            let dedup = dedup.lock().unwrap();
+            if let Some(reply) = dedup.get(&msg.src, msg.body.msg_id) {
+                debug!("replaying reply to duplicate request");
+                return Ok(reply.clone());
+            }
+            if let Some(reply) = key.as_ref().and_then(|key| dedup.get_key(key)) {
+                debug!(key, "replaying reply to retried operation");
+                return Ok(msg.reply_with(Body {
+                    msg_id: self.reply_id(),
+                    ..reply.body.clone()
+                }));
+            }
+        }
+
+        // As do requests clients send again after a timeout.
+        let session = self
+            .sessions
+            .as_ref()
+            .filter(|_| msg.src.starts_with('c') && msg.body.in_reply_to == 0)
+            .and_then(|sessions| Some((sessions, self.session_request_id(&msg)?)));
+        if let Some((sessions, request_id)) = &session {
+            if let Some(reply) = sessions.get(&msg.src, request_id) {
+                debug!(request_id, "replaying reply to request of session");
+                return Ok(msg.reply_with(Body {
+                    msg_id: self.reply_id(),
+                    ..reply.body
+                }));
+            }
+        }
+
+        // Otherwise try to find a handler, or fall back to the catch-all one.
+        if let Some(handler) = self.handlers.get(msg_type).or(self.fallback.as_ref()) {
+            let typ = msg.body.typ.clone();
+            let request = (msg.src.clone(), msg.body.msg_id);
+            let remember = Remember {
+                request: dedup.is_some(),
+                key: key.clone(),
+                session: session.as_ref().map(|(_, request_id)| request_id.clone()),
+            };
+            // Only the replies to requests are remembered, not what is sent for replies.
+            let remember = (msg.body.in_reply_to == 0).then_some(remember);
+            // Enough of the request to reply to it if the handler panics.
+            let header = Message {
+                src: msg.src.clone(),
+                dest: msg.dest.clone(),
+                body: Body {
+                    msg_id: msg.body.msg_id,
+                    ..Default::default()
+                },
+            };
+            let reply_id = self.reply_id();
+            let start = Instant::now();
+            // The handler runs within the trace of the request, which its reply and the
+            // messages it sends carry on.
+            let trace_id = trace::of(&msg.body).map(str::to_string);
+            let reply = trace::in_trace(trace_id, || {
+                panic::catch_unwind(AssertUnwindSafe(|| handler(msg, reply_id)))
+                    .unwrap_or_else(|panic| {
+                        let cause = panic_message(&*panic);
+                        error!(cause, "handler panicked");
+                        Ok(error_reply(
+                            header,
+                            reply_id,
+                            CRASH,
+                            &format!("handler for {typ} panicked: {cause}"),
+                        ))
+                    })
+                    .map(|mut reply| {
+                        trace::stamp(&mut reply.body);
+                        reply
+                    })
+            });
+            let elapsed = start.elapsed();
+            self.record(|m| m.record_latency(&typ, elapsed));
+            debug!(?elapsed, "handled message");
+            if let Some(threshold) = self.slow_handler.filter(|&t| elapsed > t) {
+                warn!(?elapsed, ?threshold, "slow handler");
+                self.record(|m| m.record_slow(&typ));
+            }
+            if let Ok(reply) = &reply {
+                self.remember_reply(request, remember, reply);
+            }
+            return reply.map_err(NodeError::from);
+        }
+
+        Err(NodeError::NoHandler(msg.body.typ))
+    }
+
+    /// Remembers `reply`, produced by the handler of `request` (its src and msg_id), as the reply
+    /// to it if it is one, see [`Node::with_dedup`]. Otherwise the handler sent another request
+    /// on and the reply to `request` is awaited, and `reply` may be the reply to another request
+    /// that was awaited.
+    fn remember_reply(&self, request: (String, u64), remember: Option<Remember>, reply: &Message) {
+        if self.dedup.is_none() && self.sessions.is_none() {
+            return;
+        }
+        let remember = remember.filter(|r| !r.is_empty());
+        if reply.dest == request.0 && reply.body.in_reply_to == request.1 {
+            if let Some(remember) = remember {
+                self.remember(&request, remember, reply);
+            }
+            return;
+        }
+        if let Some(remember) = remember {
+            self.awaiting.lock().unwrap().insert(request, remember);
+        }
+        self.remember_awaited(reply);
+    }
+
+    /// Remembers `reply`, produced by a handler or sent by a workload (see
+    /// [`Node::with_outgoing`]), as the reply to the request it answers if it is awaited.
+    fn remember_awaited(&self, reply: &Message) {
+        if reply.body.in_reply_to == 0 || (self.dedup.is_none() && self.sessions.is_none()) {
+            return;
+        }
+        let answered = (reply.dest.clone(), reply.body.in_reply_to);
+        let remember = self.awaiting.lock().unwrap().take(&answered);
+        if let Some(remember) = remember {
+            debug!(dest = %reply.dest, "remembering reply to awaited request");
+            self.remember(&answered, remember, reply);
+        }
+    }
+
+    /// Remembers `reply` as the reply to the request `(src, msg_id)` where `remember` says.
+    fn remember(&self, (src, msg_id): &(String, u64), remember: Remember, reply: &Message) {
+        if let Some(dedup) = &self.dedup {
+            let mut dedup = dedup.lock().unwrap();
+            if remember.request {
+                dedup.insert(src, *msg_id, reply.clone());
+            }
+            if let Some(key) = &remember.key {
+                dedup.insert_key(key, reply.clone());
+            }
+        }
+        if let (Some(sessions), Some(request_id)) = (&self.sessions, &remember.session) {
+            sessions.insert(src, request_id, reply.clone());
+        }
+    }
+
+    /// The ID of the client request `msg` within its session: the `request_id` its client sent,
+    /// or else the idempotency key derived from it, scoped to its type, if any.
+    fn session_request_id(&self, msg: &Message) -> Option<String> {
+        if let Some(request_id) = Sessions::request_id(msg) {
+            return Some(request_id);
+        }
+        let typ = &msg.body.typ;
+        let key = self.idempotency_keys.get(typ)?(msg)?;
+        Some(format!("{typ}:{key}"))
+    }
+
+    /// The idempotency key of the request `msg`, scoped to its type: the key its client sent,
+    /// scoped to the client too, or else the one derived from it, if any.
+    fn idempotency_key(&self, msg: &Message) -> Option<String> {
+        let typ = &msg.body.typ;
+        if let Some(key) = msg.body.extra.get(IDEMPOTENCY_KEY_FIELD) {
+            let key = key.as_str().map_or_else(|| key.to_string(), str::to_string);
+            return Some(format!("{}/{typ}:{key}", msg.src));
+        }
+        let key = self.idempotency_keys.get(typ)?(msg)?;
+        Some(format!("{typ}:{key}"))
+    }
+
+    /// The latest topology, empty until the first `topology` message.
+    pub fn topology(&self) -> Topology {
+        self.topology.lock().unwrap().clone()
+    }
+
+    /// ID of this node, None until it is initialized.
+    pub fn id(&self) -> Option<String> {
+        match &*self.state.lock().unwrap() {
+            State::Initialized(node) => Some(node.id.clone()),
+            State::Start => None,
+        }
+    }
+
+    /// IDs of every other node in the cluster, empty until the node is initialized.
+    pub fn peer_ids(&self) -> Vec<String> {
+        match &*self.state.lock().unwrap() {
+            State::Initialized(node) => node
+                .other_nodes
+                .iter()
+                .filter(|id| **id != node.id)
+                .cloned()
+                .collect(),
+            State::Start => Vec::new(),
+        }
+    }
+
+    /// Neighbors of this node in the latest topology.
+    pub fn neighbors(&self) -> Vec<String> {
+        let State::Initialized(node) = &*self.state.lock().unwrap() else {
+            return Vec::new();
+        };
+        let topology = self.topology.lock().unwrap();
+        topology.get(&node.id).cloned().unwrap_or_default()
+    }
+
+    /// Replaces the topology, as a `topology` message does, for reconfiguring the node at
+    /// runtime. The topology handler is called if the topology changed.
+    pub fn set_topology(&self, topology: Topology) {
+        let node_id = match &*self.state.lock().unwrap() {
+            State::Initialized(node) => node.id.clone(),
+            State::Start => String::new(),
+        };
+        let mut current = self.topology.lock().unwrap();
+        if *current == topology {
+            return;
+        }
+        *current = topology;
+        debug!(topology = ?*current, "topology changed");
+        if let Some(topology_handler) = &self.topology_handler {
+            topology_handler(&node_id, &current);
+        }
+    }
+
+    /// Handles `msg` like [`Node::handle`], except that replies to requests of the node's RPC
+    /// client or outbox are routed to them first, and heartbeats see every message. Those
+    /// replies and heartbeat replies produce no message, None is returned, as do handlers that
+    /// return [`no_reply`]. Replies to forwarded requests produce the reply relayed to the
+    /// requester.
+    pub fn dispatch(&self, msg: Message) -> Result<Option<Message>, NodeError> {
+        if let Some(fragments) = self.fragments.as_ref().filter(|_| msg.body.typ == "frag") {
+            return match fragments.reassemble(&msg, Instant::now())? {
+                Some(msg) => self.dispatch(msg),
+                None => Ok(None),
+            };
+        }
+        if let Some(validator) = &self.validator {
+            validator.check(&msg)?;
+        }
+        if let Some(clock) = &self.clock {
+            clock.receive(&msg.body);
+        }
+        if let Some(peers) = self.peers.as_ref().filter(|_| msg.src.starts_with('n')) {
+            peers.record_seen(&msg.src, Instant::now());
+        }
+        if let Some(low) = self.watermarks.as_ref().and_then(|w| w.observe(&msg)) {
+            if let Some(dedup) = &self.dedup {
+                let pruned = dedup.lock().unwrap().prune(&msg.src, low);
+                debug!(src = %msg.src, low, pruned, "pruned replies below watermark");
+            }
+        }
+        if let Some(heartbeats) = &self.heartbeats {
+            if heartbeats.observe(&msg, Instant::now()) {
+                return Ok(None);
+            }
+            if msg.body.typ == "heartbeat" {
+                return Ok(Some(msg.reply_with(Body {
+                    typ: "heartbeat_ok".to_string(),
+                    msg_id: self.reply_id(),
+                    ..Default::default()
+                })));
+            }
+        }
+        let msg = match &self.rpc {
+            Some(rpc) => match rpc.complete(msg) {
+                Some(msg) => msg,
+                None => return Ok(None),
+            },
+            None => msg,
+        };
+        if msg.body.typ == "snapshot" && !self.handlers.contains_key("snapshot") {
+            if let Some(state) = self.workload_state {
+                if let Some(outbox) = &self.outbox {
+                    outbox.ack(&msg);
+                }
+                self.receive_snapshot(&msg, state)?;
+                return Ok(None);
+            }
+        }
+        if self.outbox.as_ref().is_some_and(|outbox| outbox.ack(&msg)) {
+            return Ok(None);
+        }
+        if let Some(relayed) = self.forwarder.as_ref().and_then(|f| f.relay(&msg)) {
+            return Ok(Some(relayed));
+        }
+        self.handle(msg)
+            .map(|reply| (reply != no_reply()).then_some(reply))
+    }
+
+    /// Dispatches `msg`, returns the message to send back if any. Requests that fail are
+    /// answered with an error of the Maelstrom code of the failure, other messages that fail
+    /// are only logged.
+    fn serve(&self, msg: Message) -> Option<Message> {
+        let request = (msg.body.msg_id != 0 && msg.body.in_reply_to == 0).then(|| Message {
+            src: msg.src.clone(),
+            dest: msg.dest.clone(),
+            body: Body {
+                msg_id: msg.body.msg_id,
+                ..Default::default()
+            },
+        });
+        match self.dispatch(msg) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!(code = e.code(), "failed to handle message: {e}");
+                request
+                    .map(|request| error_reply(request, self.reply_id(), e.code(), &e.to_string()))
+            }
+        }
+    }
+
+    /// Dispatches every message recieved on `transport` and sends back the replies, until the
+    /// transport has no more messages.
+    ///
+    /// Requests that fail to be handled are answered with an error, see [`NodeError`], other
+    /// messages that fail are logged and get no reply. Outgoing messages (see
+    /// [`Node::with_outgoing`]) are sent after each message is handled.
+    pub fn run<T: Transport>(&self, transport: &mut T) -> Result<()> {
+        let outgoing = self.outgoing.lock().unwrap().take();
+        while let Some(msg) = transport.recv()? {
+            if let Some(mut reply) = self.serve(msg) {
+                self.sending(&mut reply);
+                for reply in self.split(reply) {
+                    transport.send(&reply)?
+                }
+            }
+            for mut msg in outgoing.iter().flat_map(|o| o.try_iter()) {
+                self.remember_awaited(&msg);
+                self.sending(&mut msg);
+                for msg in self.split(msg) {
+                    transport.send(&msg)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Node::run`], but reading, handling and writing messages happen on separate threads
+    /// connected by bounded channels, with several threads handling messages concurrently.
+    ///
+    /// Replies are written in the order handlers finish, not the order requests arrived. Requests
+    /// of a type limited by [`PoolConfig::limits`] are handled in the order they arrived.
+    pub fn run_pool<R, W>(&self, transport: StdioTransport<R, W>, config: PoolConfig) -> Result<()>
+    where
+        R: BufRead + Send,
+        W: Write + Send,
+    {
+        let (mut reader, writer) = transport.into_parts();
+        let capacity = config.capacity.max(1);
+        // Messages waiting for a worker, control messages first.
+        let lanes = &Lanes::new(capacity);
+        let (outbox, replies) = mpsc::sync_channel::<Message>(capacity);
+        let queued = &self.queued;
+        let enqueue = || {
+            let depth = queued.fetch_add(1, Ordering::Relaxed) + 1;
+            self.record(|m| m.record_queue_depth("requests", depth));
+        };
+
+        let workers = config.workers.max(1);
+        // Number of workers still handling messages.
+        let running = &AtomicUsize::new(workers);
+        let limits = &TypeLimits::new(&config.limits);
+
+        thread::scope(|s| {
+            let shed = outbox.clone();
+            let reading = s.spawn(move || -> Result<()> {
+                let mut read = || -> Result<()> {
+                    while let Some(msg) = reader.recv()? {
+                        // Handled before the messages after it are read, so no worker takes
+                        // a message before the node is initialized.
+                        if msg.body.typ == "init" {
+                            if let Some(reply) = self.serve(msg) {
+                                shed.send(reply)?;
+                            }
+                            continue;
+                        }
+                        enqueue();
+                        if config.overflow == Overflow::Block || Lanes::is_control(&msg) {
+                            lanes.push(msg)?;
+                            continue;
+                        }
+                        if let Some(msg) = lanes.try_push(msg) {
+                            queued.fetch_sub(1, Ordering::Relaxed);
+                            shed.send(error_reply(
+                                msg,
+                                self.reply_id(),
+                                TEMPORARILY_UNAVAILABLE,
+                                "node overloaded, request queue is full",
+                            ))?
+                        }
+                    }
+                    Ok(())
+                };
+                let read = read();
+                lanes.close();
+                read
+            });
+
+            for _ in 0..workers {
+                let outbox = outbox.clone();
+                s.spawn(move || {
+                    // The lanes are not held while handling, so a handler waiting on an RPC
+                    // does not keep other workers from routing its reply.
+                    'taking: while let Some(msg) = lanes.pop() {
+                        queued.fetch_sub(1, Ordering::Relaxed);
+                        let mut next = limits.acquire(msg);
+                        while let Some(msg) = next {
+                            let permit = (msg.body.in_reply_to == 0).then(|| msg.body.typ.clone());
+                            if let Some(reply) = self.serve(msg) {
+                                if outbox.send(reply).is_err() {
+                                    lanes.close();
+                                    break 'taking;
+                                }
+                            }
+                            next = permit.and_then(|typ| limits.release(&typ));
+                        }
+                    }
+                    running.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+
+            // Forwards outgoing messages until the input ends and every request is handled.
+            if let Some(outgoing) = self.outgoing.lock().unwrap().take() {
+                let outbox = outbox.clone();
+                s.spawn(move || loop {
+                    match outgoing.recv_timeout(Duration::from_millis(10)) {
+                        Ok(msg) => {
+                            self.remember_awaited(&msg);
+                            if outbox.send(msg).is_err() {
+                                return;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) if running.load(Ordering::Relaxed) > 0 => {}
+                        Err(_) => return,
+                    }
+                });
+            }
+            drop(outbox);
+
+            // Ends once the input ends and every worker is done.
+            for mut reply in replies {
+                self.sending(&mut reply);
+                for reply in self.split(reply) {
+                    writer.write(&reply)?;
+                }
+            }
+            reading
+                .join()
+                .map_err(|_| anyhow!("Internal: reader thread panicked"))?
+        })
+    }
+}
+
+impl InitializedNode {
+    fn new(body: &Body) -> Result<Self> {
+        if body.typ != "init" {
+            return Err(anyhow::anyhow!(
+                "Can only initialze node with an init message, got {:?}",
+                body
+            ));
+        }
+
+        Ok(Self {
+            id: body.get_str("node_id")?.to_string(),
+            other_nodes: body.get_as("node_ids")?,
+        })
+    }
+}
+
+pub(crate) fn error_reply(msg: Message, msg_id: u64, code: u64, text: &str) -> Message {
+    let mut body = Body {
+        typ: "error".to_string(),
+        msg_id,
+        ..Default::default()
+    };
+    body.extra.insert("code".into(), code.into());
+    body.extra.insert("text".into(), text.into());
+    msg.reply_with(body)
+}
+
+/// The message a panic was started with, if it has one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "unknown panic",
+    }
+}
+
+fn init_reply(msg: Message, msg_id: u64) -> Message {
+    msg.reply_with(Body {
+        typ: "init_ok".to_string(),
+        msg_id,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Cursor, Write},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+
+    use crate::checksum;
+    use crate::clock::{LamportClock, LAMPORT_FIELD};
+    use crate::compress;
+    use crate::frag::Fragments;
+    use crate::heartbeat::{HeartbeatConfig, Heartbeats};
+    use crate::kv::{Kv, KvClient};
+    use crate::message::{Body, Message, MsgIds};
+    use crate::metrics::Metrics;
+    use crate::node::{
+        error_reply, Handler, InitializedNode, Lanes, Node, Overflow, PoolConfig, State, Topology,
+    };
+    use crate::outbox::Outbox;
+    use crate::persistence::Persist;
+    use crate::rpc::RpcClient;
+    use crate::trace::{self, TRACE_FIELD};
+    use crate::transport::{InMemoryTransport, StdioTransport};
+    use crate::validate::Validator;
+    use crate::watermark;
+
+    fn init_msg() -> Message {
+        let msg = r#"{
+            "src":"c1", "dest":"n1",
+            "body":{ 
+                "type":"init",
+                "node_id":"n1",
+                "node_ids":["n1", "n2"],
+                "msg_id":1}
+        }"#;
+
+        serde_json::from_str::<Message>(msg).expect("invalid init json.")
+    }
+
+    #[test]
+    fn node_inital_state() -> anyhow::Result<()> {
+        // Tests that the initial state of a node is in the "Start" state
+        let node = Node::new(HashMap::new())?;
+        assert_eq!(
+            *node.state.lock().unwrap(),
+            State::Start,
+            "msg_id should start as Start, got {:?}",
+            node.state
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn node_initializes_after_init() -> anyhow::Result<()> {
+        // Test that node transitions into InializedNode state after recieving init msg.
+        let node = Node::new(HashMap::new())?;
+
+        node.handle(init_msg())?;
+
+        let expected_state = State::Initialized(InitializedNode {
+            id: "n1".into(),
+            other_nodes: vec!["n1".into(), "n2".into()],
+        });
+        assert_eq!(
+            *node.state.lock().unwrap(),
+            expected_state,
+            "node should transition into InitializedNode with id n1 and neighbor n2 got: {:?}",
+            node.state
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn identity_known_after_init() -> Result<()> {
+        let node = Node::new(HashMap::new())?;
+        assert_eq!((node.id(), node.peer_ids()), (None, vec![]));
+
+        node.handle(init_msg())?;
+
+        assert_eq!(node.id().as_deref(), Some("n1"));
+        assert_eq!(node.peer_ids(), vec!["n2".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn init_reply_is_valid() -> anyhow::Result<()> {
+        // Tests that the reply for the first init message meets the Maelstrom spec from
+        // https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#initialization
+        let node = Node::new(HashMap::new())?;
+
+        let reply = node.handle(init_msg())?;
+
+        // Note that we expect that the first reply will have a message_id of 0 from us.
+        let expected = r#"{
+            "src":"n1", "dest":"c1",
+            "body": { 
+                "type":"init_ok",
+                "in_reply_to": 1,
+                "msg_id":0
+                }
+        }"#;
+
+        let expected = serde_json::from_str::<Message>(expected)?;
+
+        assert_eq!(reply, expected);
+        Ok(())
+    }
+
+    fn identity_handler(msg: Message, _: u64) -> anyhow::Result<Message> {
+        Ok(msg)
+    }
+
+    /// Workload state of one value, snapshotted and restored as is.
+    #[derive(Default)]
+    struct Register(std::sync::Mutex<serde_json::Value>);
+
+    impl Persist for Register {
+        fn snapshot(&self) -> serde_json::Value {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn restore(&self, snapshot: serde_json::Value) -> Result<()> {
+            *self.0.lock().unwrap() = snapshot;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn cannot_create_node_with_init_handler() -> Result<()> {
+        // Test that creating node with a handler for "init" fails.
+        let handlers = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("init".into(), Box::new(identity_handler));
+            funs
+        };
+        let node = Node::new(handlers);
+        assert!(
+            node.is_err(),
+            "Creating a node with a handler for init is forbidden {:?}",
+            node.unwrap()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_init_messages_idempontent() -> anyhow::Result<()> {
+        // Tests that multiple init messages are valid.
+        let node = Node::new(HashMap::new())?;
+
+        node.handle(init_msg())?;
+        let expected_state = State::Initialized(InitializedNode {
+            id: "n1".into(),
+            other_nodes: vec!["n1".into(), "n2".into()],
+        });
+        assert_eq!(*node.state.lock().unwrap(), expected_state);
+        node.handle(init_msg())?;
+        assert_eq!(*node.state.lock().unwrap(), expected_state);
+        node.handle(init_msg())?;
+        assert_eq!(*node.state.lock().unwrap(), expected_state);
+        node.handle(init_msg())?;
+        assert_eq!(*node.state.lock().unwrap(), expected_state);
+
+        Ok(())
+    }
+
+    #[test]
+    fn init_handler_called_once() -> Result<()> {
+        // Tests that the init handler is called with the node ids, only on the first init.
+        let calls = std::sync::Mutex::new(vec![]);
+        let node = Node::with_init_handler(
+            HashMap::new(),
+            Box::new(|id: &str, ids: &[String]| {
+                calls.lock().unwrap().push((id.to_string(), ids.to_vec()));
+            }),
+        )?;
+
+        node.handle(init_msg())?;
+        node.handle(init_msg())?;
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![("n1".to_string(), vec!["n1".to_string(), "n2".to_string()])]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn builder_registers_handlers() -> Result<()> {
+        let inits = std::sync::Mutex::new(vec![]);
+        let node = Node::builder()
+            .handle("id", identity_handler)
+            .on_init(|id, _ids| inits.lock().unwrap().push(id.to_string()))
+            .fallback(|msg, msg_id| Ok(error_reply(msg, msg_id, 10, "not supported")))
+            .build()?;
+        let msg = |typ: &str| {
+            let mut msg = init_msg();
+            msg.body.typ = typ.into();
+            msg
+        };
+
+        node.handle(init_msg())?;
+
+        assert_eq!(*inits.lock().unwrap(), vec!["n1".to_string()]);
+        assert_eq!(node.handle(msg("id"))?, msg("id"));
+        assert_eq!(node.handle(msg("unknown"))?.body.typ, "error");
+        Ok(())
+    }
+
+    #[test]
+    fn topology_changes_call_handler() -> Result<()> {
+        // Tests that every changed topology, from messages or set at runtime, is kept and given
+        // to the topology handler, and that topology messages are answered without a handler.
+        let changes = Mutex::new(vec![]);
+        let node = Node::builder()
+            .on_topology_change(|id, topology| {
+                changes
+                    .lock()
+                    .unwrap()
+                    .push((id.to_string(), topology.clone()))
+            })
+            .build()?;
+        node.handle(init_msg())?;
+        let topology = |n1: &[&str]| -> Topology {
+            HashMap::from([
+                ("n1".into(), n1.iter().map(|n| n.to_string()).collect()),
+                ("n2".into(), vec!["n1".into()]),
+            ])
+        };
+        let message = |topology: &Topology| {
+            let mut msg = init_msg();
+            msg.body = Body {
+                typ: "topology".into(),
+                msg_id: 2,
+                ..Default::default()
+            };
+            msg.body
+                .extra
+                .insert("topology".into(), serde_json::to_value(topology).unwrap());
+            msg
+        };
+
+        let reply = node.handle(message(&topology(&["n2"])))?;
+        node.handle(message(&topology(&["n2"])))?;
+        node.set_topology(topology(&[]));
+
+        assert_eq!(reply.body.typ, "topology_ok");
+        assert_eq!(reply.body.in_reply_to, 2);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                ("n1".to_string(), topology(&["n2"])),
+                ("n1".to_string(), topology(&[]))
+            ]
+        );
+        assert_eq!(node.topology(), topology(&[]));
+        assert!(node.neighbors().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn typed_handler_builds_reply() -> Result<()> {
+        // Tests that typed handlers get the deserialized body and reply with the returned one.
+        #[derive(serde::Deserialize)]
+        struct Add {
+            a: u64,
+            b: u64,
+        }
+        #[derive(serde::Serialize)]
+        struct Sum {
+            sum: u64,
+        }
+        let node = Node::builder()
+            .handle_typed("add", |ctx, add: Add| {
+                assert_eq!((ctx.src, ctx.dest, ctx.msg_id), ("c1", "n1", 2));
+                Ok(Sum { sum: add.a + add.b })
+            })
+            .build()?;
+        node.handle(init_msg())?;
+        let add = |body: serde_json::Value| -> Result<Message> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "src": "c1", "dest": "n1", "body": body
+            }))?)
+        };
+
+        let reply = node.handle(add(
+            serde_json::json!({ "type": "add", "msg_id": 2, "a": 1, "b": 2 }),
+        )?)?;
+        let malformed = node.handle(add(
+            serde_json::json!({ "type": "add", "msg_id": 2, "a": 1 }),
+        )?);
+
+        let expected: Message = serde_json::from_value(serde_json::json!({
+            "src": "n1", "dest": "c1",
+            "body": { "type": "add_ok", "msg_id": 1, "in_reply_to": 2, "sum": 3 }
+        }))?;
+        assert_eq!(reply, expected);
+        assert!(malformed.is_err_and(|e| e.to_string().contains("MalformedRequest")));
+        Ok(())
+    }
+
+    #[test]
+    fn body_handler_fills_envelope() -> Result<()> {
+        let node = Node::builder()
+            .handle_body("ping", |msg| {
+                let mut body = Body::default();
+                body.extra.insert("from".into(), msg.src.clone().into());
+                Ok(body)
+            })
+            .handle_body("fail", |_| {
+                Ok(Body {
+                    typ: "error".into(),
+                    ..Default::default()
+                })
+            })
+            .build()?;
+        node.handle(init_msg())?;
+        let request = |typ: &str| -> Result<Message> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "src": "c1", "dest": "n1", "body": { "type": typ, "msg_id": 2 }
+            }))?)
+        };
+
+        let pong = node.handle(request("ping")?)?;
+        let error = node.handle(request("fail")?)?;
+
+        let expected: Message = serde_json::from_value(serde_json::json!({
+            "src": "n1", "dest": "c1",
+            "body": { "type": "ping_ok", "msg_id": 1, "in_reply_to": 2, "from": "c1" }
+        }))?;
+        assert_eq!(pong, expected);
+        assert_eq!(error.body.typ, "error");
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_moves_state_between_nodes() -> Result<()> {
+        // Tests that a snapshot of one node's workload restores into another node's workload.
+        let (a, b) = (Register::default(), Register::default());
+        let node_a = Node::builder().state(&a).build()?;
+        let node_b = Node::builder().state(&b).build()?;
+        *a.0.lock().unwrap() = serde_json::json!([1, 2]);
+
+        node_b.restore(node_a.snapshot())?;
+
+        assert_eq!(b.snapshot(), serde_json::json!([1, 2]));
+        assert_eq!(
+            Node::new(HashMap::new())?.snapshot(),
+            serde_json::Value::Null
+        );
+        assert!(Node::new(HashMap::new())?
+            .restore(serde_json::json!(1))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn restarted_node_fetches_snapshot_of_peer() -> Result<()> {
+        let (a, b) = (Register::default(), Register::default());
+        *a.0.lock().unwrap() = serde_json::json!([1, 2]);
+        let node_a = Node::builder().state(&a).build()?;
+        node_a.handle(init_msg())?;
+        let (sender, sent) = mpsc::channel();
+        let outbox = Arc::new(Outbox::new(
+            sender,
+            Arc::new(MsgIds::new()),
+            Duration::from_secs(1),
+        ));
+        let node_b = Node::builder()
+            .state(&b)
+            .build()?
+            .with_outbox(outbox.clone())
+            .with_snapshot_on_start();
+        let mut init = init_msg();
+        init.dest = "n2".into();
+        init.body.extra.insert("node_id".into(), "n2".into());
+
+        node_b.handle(init)?;
+        let request = sent.try_recv()?;
+        assert_eq!(
+            (request.dest.as_str(), request.body.typ.as_str()),
+            ("n1", "snapshot_request")
+        );
+        let snapshot = node_a.dispatch(request)?.expect("snapshot reply");
+        assert_eq!(snapshot.body.typ, "snapshot");
+        assert_eq!(node_b.dispatch(snapshot)?, None);
+
+        assert_eq!(b.snapshot(), serde_json::json!([1, 2]));
+        assert!(outbox.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_snapshots_are_requested_again() -> Result<()> {
+        let register = Register::default();
+        let (sender, sent) = mpsc::channel();
+        let outbox = Arc::new(Outbox::new(
+            sender,
+            Arc::new(MsgIds::new()),
+            Duration::from_secs(1),
+        ));
+        let node = Node::builder()
+            .state(&register)
+            .build()?
+            .with_outbox(outbox);
+        node.handle(init_msg())?;
+        let snapshot = |state: serde_json::Value| {
+            let mut body = Body {
+                typ: "snapshot".into(),
+                in_reply_to: 5,
+                ..Default::default()
+            };
+            checksum::stamp(&mut body, &serde_json::json!([1, 2]));
+            body.extra.insert("state".into(), state);
+            Message {
+                src: "n2".into(),
+                dest: "n1".into(),
+                body,
+            }
+        };
+
+        assert_eq!(node.dispatch(snapshot(serde_json::json!([1])))?, None);
+        assert_eq!(register.snapshot(), serde_json::Value::Null);
+        let request = sent.try_recv()?;
+        assert_eq!(
+            (request.dest.as_str(), request.body.typ.as_str()),
+            ("n2", "snapshot_request")
+        );
+        let mut truncated = snapshot(serde_json::Value::Null);
+        truncated.body.extra.remove("state");
+        let packed = compress::pack(&serde_json::json!([1, 2]));
+        truncated.body.extra.insert(
+            compress::COMPRESSED_FIELD.into(),
+            packed[..packed.len() - 4].into(),
+        );
+        assert_eq!(node.dispatch(truncated)?, None);
+        assert_eq!(register.snapshot(), serde_json::Value::Null);
+        assert_eq!(sent.try_recv()?.body.typ, "snapshot_request");
+
+        node.dispatch(snapshot(serde_json::json!([1, 2])))?;
+        assert_eq!(register.snapshot(), serde_json::json!([1, 2]));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots_are_compressed_for_peers_that_accept_it() -> Result<()> {
+        struct Fixed;
+        impl Persist for Fixed {
+            fn snapshot(&self) -> serde_json::Value {
+                serde_json::json!({ "messages": [1, 2, 3] })
+            }
+            fn restore(&self, _: serde_json::Value) -> Result<()> {
+                Ok(())
+            }
+        }
+        let node = Node::builder().state(&Fixed).build()?.with_compression();
+        node.handle(init_msg())?;
+        let request = |accept: bool| {
+            let mut msg = init_msg();
+            msg.src = "n2".into();
+            msg.body.typ = "snapshot_request".into();
+            msg.body.extra.clear();
+            if accept {
+                compress::accept(&mut msg.body);
+            }
+            msg
+        };
+
+        let plain = node.handle(request(false))?;
+        let compressed = node.handle(request(true))?;
+
+        assert_eq!(plain.body.extra["state"], Fixed.snapshot());
+        assert!(!compressed.body.extra.contains_key("state"));
+        let state: serde_json::Value = compress::get(&compressed.body, "state")?;
+        assert_eq!(state, Fixed.snapshot());
+        Ok(())
+    }
+
+    #[test]
+    fn messages_are_reassembled_from_frags() -> Result<()> {
+        let node = Node::builder()
+            .handle("id", identity_handler)
+            .build()?
+            .with_fragments(Fragments::new(16));
+        node.handle(init_msg())?;
+        let mut msg = init_msg();
+        msg.src = "n2".into();
+        msg.body.typ = "id".into();
+        msg.body.msg_id = 2;
+        let frags = Fragments::new(16).split(msg);
+        assert!(frags.len() > 1);
+
+        let (last, frags) = frags.split_last().unwrap();
+        for frag in frags {
+            assert_eq!(node.dispatch(frag.clone())?, None);
+        }
+        // The identity handler echoes the message it handled.
+        let handled = node.dispatch(last.clone())?.expect("the whole message");
+
+        assert_eq!(handled.src, "n2");
+        assert_eq!((handled.body.typ.as_str(), handled.body.msg_id), ("id", 2));
+        Ok(())
+    }
+
+    #[test]
+    fn handler_panic_replies_crash() -> Result<()> {
+        // Tests that a panicking handler gets a crash (13) error reply and the node keeps
+        // handling messages.
+        let node = Node::builder()
+            .handle("boom", |_, _| panic!("boom went the handler"))
+            .handle("id", identity_handler)
+            .build()?;
+        node.handle(init_msg())?;
+        let msg = |typ: &str| {
+            let mut msg = init_msg();
+            msg.body.typ = typ.into();
+            msg
+        };
+
+        let reply = node.handle(msg("boom"))?;
+
+        assert_eq!((reply.src.as_str(), reply.dest.as_str()), ("n1", "c1"));
+        assert_eq!(reply.body.typ, "error");
+        assert_eq!(reply.body.in_reply_to, 1);
+        assert_eq!(reply.body.extra["code"], 13);
+        assert!(reply
+            .body
+            .get_str("text")?
+            .contains("boom went the handler"));
+        assert_eq!(node.handle(msg("id"))?, msg("id"));
+        Ok(())
+    }
+
+    #[test]
+    fn dispatch_answers_heartbeats() -> Result<()> {
+        // Tests that the node answers heartbeats, tracks the peers from init and drops
+        // heartbeat replies.
+        let (sender, _) = mpsc::channel();
+        let heartbeats = Arc::new(Heartbeats::new(sender, HeartbeatConfig::default()));
+        let node = Node::new(HashMap::new())?.with_heartbeats(heartbeats.clone());
+        node.handle(init_msg())?;
+        let from_n2 = |typ: &str| {
+            let mut msg = init_msg();
+            msg.src = "n2".into();
+            msg.body.typ = typ.into();
+            msg
+        };
+
+        let reply = node
+            .dispatch(from_n2("heartbeat"))?
+            .expect("heartbeat reply");
+
+        assert_eq!(reply.body.typ, "heartbeat_ok");
+        assert_eq!((reply.dest.as_str(), reply.body.in_reply_to), ("n2", 1));
+        assert_eq!(node.dispatch(from_n2("heartbeat_ok"))?, None);
+        assert_eq!(heartbeats.alive_peers(Instant::now()), vec!["n2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_rejects_invalid_handlers() {
+        // Tests that an init handler and a type registered twice fail the build.
+        let init = Node::builder().handle("init", identity_handler).build();
+        let twice = Node::builder()
+            .handle("id", identity_handler)
+            .handle("id", identity_handler)
+            .build();
+
+        assert!(init.is_err_and(|e| e.to_string().contains("init handler")));
+        assert!(twice.is_err_and(|e| e.to_string().contains("several handlers for [\"id\"]")));
+    }
+
+    #[test]
+    fn reply_id_goes_up() -> anyhow::Result<()> {
+        // T
+        Ok(())
+    }
+
+    #[test]
+    fn unimplemented_type_returns_error_after_init() -> anyhow::Result<()> {
+        // Tests that an unknown message returns an error after init.
+        let node = Node::new(HashMap::new())?;
+
+        // Init
+        node.handle(init_msg())?;
+
+        // Known msg
+        let msg = {
+            let mut msg = init_msg();
+            msg.body.typ = "unknown...".into();
+            msg
+        };
+
+        let result = node.handle(msg);
+
+        assert!(
+            result.as_ref().is_err_and(|e| e
+                .to_string()
+                .contains("No handler for message type unknown...")),
+            "expected failure with unknown handler, got {:?}",
+            result
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_message_before_init_returns_error() -> anyhow::Result<()> {
+        // Tests that an unknown message returns an error before init.
+        let node = Node::new(HashMap::new())?;
+
+        let msg = {
+            let mut msg = init_msg();
+            msg.body.typ = "unknown...".into();
+            msg
+        };
+
+        let result = node.handle(msg);
+
+        assert!(
+            result
+                .as_ref()
+                .is_err_and(|e| e.to_string().contains("Not Ready")),
+            "expected failure with unknown handler, got {:?}",
+            result
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn message_before_init_returns_error() -> anyhow::Result<()> {
+        // Tests that a message returns an error before init.
+        let handlers = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("id".into(), Box::new(identity_handler));
+            funs
+        };
+        let node = Node::new(handlers)?;
+
+        let msg = {
+            let mut msg = init_msg();
+            msg.body.typ = "id".into();
+            msg
+        };
+
+        let result = node.handle(msg);
+
+        assert!(
+            result
+                .as_ref()
+                .is_err_and(|e| e.to_string().contains("Not Ready")),
+            "expected failure with unknown handler, got {:?}",
+            result
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn node_propagates_handler_error() -> anyhow::Result<()> {
+        // Tests handler errors are propagated correctly.
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            let err_handler = |_: Message, _: u64| Err(anyhow::anyhow!("error from handler"));
+            funs.insert("id".into(), Box::new(err_handler));
+            Node::new(funs)?
+        };
+
+        node.handle(init_msg())?;
+
+        let msg = {
+            let mut msg = init_msg();
+            msg.body.typ = "id".into();
+            msg
+        };
+        let result = node.handle(msg);
+
+        assert!(
+            result
+                .as_ref()
+                .is_err_and(|e| e.to_string().contains("error from handler")),
+            "expected failure from handler, got {:?}",
+            result
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn run_replies_until_transport_closes() -> Result<()> {
+        // Tests that run sends a reply for every handled message and skips failed ones.
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("id".into(), Box::new(identity_handler));
+            Node::new(funs)?
+        };
+        let (inbox, inbox_rx) = mpsc::channel();
+        let (outbox_tx, outbox) = mpsc::channel();
+        let mut transport = InMemoryTransport::new(inbox_rx, outbox_tx);
+        let msg = {
+            let mut msg = init_msg();
+            msg.body.typ = "id".into();
+            msg
+        };
+        inbox.send(init_msg())?;
+        inbox.send(Message {
+            body: Body {
+                typ: "unknown...".into(),
+                ..Default::default()
+            },
+            ..msg.clone()
+        })?;
+        inbox.send(msg)?;
+        drop(inbox);
+
+        node.run(&mut transport)?;
+
+        let types: Vec<String> = outbox.try_iter().map(|r| r.body.typ).collect();
+        assert_eq!(types, vec!["init_ok", "id"]);
+        Ok(())
+    }
+
+    #[test]
+    fn fallback_handles_unknown_types() -> Result<()> {
+        // Tests that only messages without a handler of their own go to the fallback handler.
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("id".into(), Box::new(identity_handler));
+            Node::new(funs)?.with_fallback(Box::new(|msg, msg_id| {
+                Ok(error_reply(msg, msg_id, 10, "not supported"))
+            }))
+        };
+        node.handle(init_msg())?;
+        let msg = |typ: &str| {
+            let mut msg = init_msg();
+            msg.body.typ = typ.into();
+            msg
+        };
+
+        let unknown = node.handle(msg("unknown"))?;
+
+        assert_eq!(node.handle(msg("id"))?, msg("id"));
+        assert_eq!(unknown.body.typ, "error");
+        assert_eq!(unknown.body.extra["code"], 10);
+        Ok(())
+    }
+
+    #[test]
+    fn run_answers_failed_requests_with_codes() -> Result<()> {
+        // Tests that each failure is answered with its Maelstrom error code, and that failed
+        // replies are not answered.
+        let node = Node::builder()
+            .handle("bad", |msg, _| {
+                msg.body.get_u64("missing")?;
+                Ok(msg)
+            })
+            .build()?;
+        let (inbox, inbox_rx) = mpsc::channel();
+        let (outbox_tx, outbox) = mpsc::channel();
+        let msg = |typ: &str, msg_id, in_reply_to| {
+            let mut msg = init_msg();
+            msg.body = Body {
+                typ: typ.into(),
+                msg_id,
+                in_reply_to,
+                ..Default::default()
+            };
+            msg
+        };
+        inbox.send(msg("bad", 2, 0))?;
+        inbox.send(init_msg())?;
+        inbox.send(msg("unknown", 3, 0))?;
+        inbox.send(msg("bad", 4, 0))?;
+        inbox.send(msg("unknown_ok", 0, 9))?;
+        drop(inbox);
+
+        node.run(&mut InMemoryTransport::new(inbox_rx, outbox_tx))?;
+
+        let replies: Vec<(String, u64, Option<u64>)> = outbox
+            .try_iter()
+            .map(|m| {
+                let code = m.body.extra.get("code").and_then(|c| c.as_u64());
+                (m.body.typ, m.body.in_reply_to, code)
+            })
+            .collect();
+        assert_eq!(
+            replies,
+            vec![
+                ("error".to_string(), 2, Some(11)),
+                ("init_ok".to_string(), 1, None),
+                ("error".to_string(), 3, Some(10)),
+                ("error".to_string(), 4, Some(12)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn validator_rejects_before_handling() -> Result<()> {
+        // Tests that a message the validator rejects never reaches its handler.
+        let handled = AtomicUsize::new(0);
+        let node = Node::builder()
+            .handle("echo", |msg, reply_id| {
+                handled.fetch_add(1, Ordering::Relaxed);
+                identity_handler(msg, reply_id)
+            })
+            .build()?
+            .with_validator(Validator::new().with_max_size(Some(200)));
+        node.dispatch(init_msg())?;
+        let mut msg = init_msg();
+        msg.body = Body {
+            typ: "echo".into(),
+            msg_id: 2,
+            ..Default::default()
+        };
+        node.dispatch(msg.clone())?;
+        msg.body.extra.insert("echo".into(), "x".repeat(200).into());
+
+        let e = node.dispatch(msg).unwrap_err();
+
+        assert_eq!(e.code(), 12);
+        assert_eq!(handled.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn metrics_count_messages() -> Result<()> {
+        let metrics = Arc::new(Metrics::new());
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("id".into(), Box::new(identity_handler));
+            Node::new(funs)?.with_metrics(metrics.clone())
+        };
+        let (inbox, inbox_rx) = mpsc::channel();
+        let (outbox_tx, _outbox) = mpsc::channel();
+        let msg = {
+            let mut msg = init_msg();
+            msg.body.typ = "id".into();
+            msg
+        };
+        inbox.send(init_msg())?;
+        inbox.send(msg.clone())?;
+        inbox.send(msg)?;
+        drop(inbox);
+
+        node.run(&mut InMemoryTransport::new(inbox_rx, outbox_tx))?;
 
-    use anyhow::Result;
+        assert_eq!(metrics.received("init"), 1);
+        assert_eq!(metrics.received("id"), 2);
+        assert_eq!(metrics.sent("init_ok"), 1);
+        assert_eq!(metrics.latency("id").count(), 2);
+        Ok(())
+    }
 
-    use crate::message::Message;
-    use crate::node::{InitializedNode, State};
-    use crate::Node;
+    #[test]
+    fn stats_reply_has_metrics() -> Result<()> {
+        let metrics = Arc::new(Metrics::new());
+        let (sender, _sent) = mpsc::channel();
+        let outbox = Arc::new(Outbox::new(
+            sender,
+            Arc::new(MsgIds::new()),
+            Duration::from_secs(1),
+        ));
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("id".into(), Box::new(identity_handler));
+            Node::new(funs)?
+                .with_metrics(metrics.clone())
+                .with_outbox(outbox)
+        };
+        let msg = |typ: &str| {
+            let mut msg = init_msg();
+            msg.body.typ = typ.into();
+            msg.body.msg_id = 2;
+            msg
+        };
 
-    fn init_msg() -> Message {
-        let msg = r#"{
-            "src":"c1", "dest":"n1",
-            "body":{ 
-                "type":"init",
-                "node_id":"n1",
-                "node_ids":["n1", "n2"],
-                "msg_id":1}
-        }"#;
+        node.handle(init_msg())?;
+        node.handle(msg("id"))?;
+        let reply = node.handle(msg("stats"))?;
 
-        serde_json::from_str::<Message>(&msg).expect("invalid init json.")
+        assert_eq!(reply.body.typ, "stats_ok");
+        let stats = &reply.body.extra["stats"];
+        assert_eq!(stats["metrics"]["received"]["id"], 1);
+        assert_eq!(stats["metrics"]["latency"]["id"]["count"], 1);
+        assert_eq!(stats["unacked"], 0);
+        assert_eq!(stats["pending_rpcs"], serde_json::Value::Null);
+        Ok(())
     }
 
     #[test]
-    fn node_inital_state() -> anyhow::Result<()> {
-        // Tests that the initial state of a node is in the "Start" state
+    fn health_reports_readiness() -> Result<()> {
         let node = Node::new(HashMap::new())?;
-        assert_eq!(
-            *node.state.borrow(),
-            State::Start,
-            "msg_id should start as Start, got {:?}",
-            node.state
-        );
+        let health = || {
+            let mut msg = init_msg();
+            msg.body.typ = "health".into();
+            msg.body.msg_id = 2;
+            node.handle(msg)
+        };
+
+        let before_init = health()?;
+        node.handle(init_msg())?;
+        let after_init = health()?;
 
+        assert_eq!(before_init.body.typ, "health_ok");
+        assert_eq!(before_init.body.extra["health"]["state"], "start");
+        assert_eq!(before_init.body.extra["health"]["ready"], false);
+        let health = &after_init.body.extra["health"];
+        assert_eq!(health["state"], "initialized");
+        assert_eq!(health["ready"], true);
+        assert!(health["uptime_ms"].is_u64());
+        assert_eq!(health["backlog"]["queued"], 0);
         Ok(())
     }
 
     #[test]
-    fn node_initializes_after_init() -> anyhow::Result<()> {
-        // Test that node transitions into InializedNode state after recieving init msg.
-        let node = Node::new(HashMap::new())?;
-
+    fn slow_handlers_are_counted() -> Result<()> {
+        let metrics = Arc::new(Metrics::new());
+        let node = Node::builder()
+            .handle("slow", |msg, _| {
+                thread::sleep(Duration::from_millis(20));
+                Ok(msg)
+            })
+            .handle("id", identity_handler)
+            .build()?
+            .with_metrics(metrics.clone())
+            .with_slow_handler_warning(Duration::from_millis(10));
+        let msg = |typ: &str| {
+            let mut msg = init_msg();
+            msg.body.typ = typ.into();
+            msg
+        };
         node.handle(init_msg())?;
 
-        let expected_state = State::Initialized(InitializedNode {
-            id: "n1".into(),
-            other_nodes: vec!["n1".into(), "n2".into()],
-        });
-        assert_eq!(
-            *node.state.borrow(),
-            expected_state,
-            "node should transition into InitializedNode with id n1 and neighbor n2 got: {:?}",
-            node.state
-        );
+        node.handle(msg("slow"))?;
+        node.handle(msg("id"))?;
 
+        assert_eq!(metrics.slow("slow"), 1);
+        assert_eq!(metrics.slow("id"), 0);
         Ok(())
     }
 
     #[test]
-    fn init_reply_is_valid() -> anyhow::Result<()> {
-        // Tests that the reply for the first init message meets the Maelstrom spec from
-        // https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#initialization
-        let node = Node::new(HashMap::new())?;
+    fn clock_stamps_messages_sent() -> Result<()> {
+        // Tests that replies carry a time past the time of the request they answer.
+        let clock = Arc::new(LamportClock::new());
+        let node = Node::new(HashMap::from([(
+            "id".to_string(),
+            Box::new(identity_handler) as Handler,
+        )]))?
+        .with_clock(clock.clone());
+        let (inbox, inbox_rx) = mpsc::channel();
+        let (outbox_tx, outbox) = mpsc::channel();
+        let mut msg = init_msg();
+        msg.body.typ = "id".into();
+        msg.body.extra.insert(LAMPORT_FIELD.into(), 41.into());
+        inbox.send(init_msg())?;
+        inbox.send(msg)?;
+        drop(inbox);
 
-        let reply = node.handle(init_msg())?;
+        node.run(&mut InMemoryTransport::new(inbox_rx, outbox_tx))?;
 
-        // Note that we expect that the first reply will have a message_id of 0 from us.
-        let expected = r#"{
-            "src":"n1", "dest":"c1",
-            "body": { 
-                "type":"init_ok",
-                "in_reply_to": 1,
-                "msg_id":0
-                }
-        }"#;
+        let stamps: Vec<_> = outbox
+            .try_iter()
+            .map(|m| m.body.extra[LAMPORT_FIELD].clone())
+            .collect();
+        assert_eq!(stamps, vec![1, 43]);
+        assert_eq!(clock.now(), 43);
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_replays_reply_to_duplicate() -> Result<()> {
+        // Tests that a duplicate request gets the first reply without running the handler again.
+        let cnt = std::sync::Mutex::new(0);
+        let node = {
+            let counting_handler = |msg: Message, msg_id: u64| {
+                *cnt.lock().unwrap() += 1;
+                Ok::<Message, anyhow::Error>(msg.reply_with(Body {
+                    typ: "count_ok".into(),
+                    msg_id,
+                    ..Default::default()
+                }))
+            };
+            let mut funs: HashMap<String, Handler> = HashMap::default();
+            funs.insert("count".to_string(), Box::new(counting_handler));
+            Node::new(funs)?.with_dedup(10)
+        };
+        node.handle(init_msg())?;
+        let msg = |msg_id| {
+            let mut msg = init_msg();
+            msg.body.typ = "count".into();
+            msg.body.msg_id = msg_id;
+            msg
+        };
 
-        let expected = serde_json::from_str::<Message>(&expected)?;
+        let first = node.handle(msg(5))?;
+        let again = node.handle(msg(5))?;
+        node.handle(msg(6))?;
 
-        assert_eq!(reply, expected);
+        assert_eq!(first, again);
+        assert_eq!(*cnt.lock().unwrap(), 2);
         Ok(())
     }
 
-    fn identity_handler(msg: Message, _: u64) -> anyhow::Result<Message> {
-        Ok(msg)
+    #[test]
+    fn dedup_collapses_retries_by_idempotency_key() -> Result<()> {
+        let cnt = std::sync::Mutex::new(0);
+        let node = Node::builder()
+            .handle("count", |msg, _| {
+                *cnt.lock().unwrap() += 1;
+                Ok(msg.reply_with(Body {
+                    typ: "count_ok".into(),
+                    ..Default::default()
+                }))
+            })
+            .idempotent("count", |msg| {
+                msg.body.get_u64("value").ok().map(|v| v.to_string())
+            })
+            .build()?
+            .with_dedup(10);
+        node.handle(init_msg())?;
+        let msg = |src: &str, msg_id, extra: serde_json::Value| {
+            let mut msg = init_msg();
+            msg.src = src.into();
+            msg.body.typ = "count".into();
+            msg.body.msg_id = msg_id;
+            msg.body.extra = serde_json::from_value(extra).unwrap();
+            msg
+        };
+
+        node.handle(msg("c1", 5, serde_json::json!({ "value": 1 })))?;
+        let retry = node.handle(msg("c2", 6, serde_json::json!({ "value": 1 })))?;
+        assert_eq!(*cnt.lock().unwrap(), 1);
+        assert_eq!((retry.dest.as_str(), retry.body.in_reply_to), ("c2", 6));
+        assert_eq!(retry.body.typ, "count_ok");
+
+        let keyed = serde_json::json!({ "idempotency_key": "k" });
+        node.handle(msg("c1", 7, keyed.clone()))?;
+        node.handle(msg("c1", 8, keyed.clone()))?;
+        node.handle(msg("c2", 9, keyed))?;
+        assert_eq!(*cnt.lock().unwrap(), 3, "client keys are per client");
+        Ok(())
     }
 
     #[test]
-    fn cannot_create_node_with_init_handler() -> Result<()> {
-        // Test that creating node with a handler for "init" fails.
-        let handlers = {
-            let mut funs: HashMap<_, Box<dyn Fn(Message, u64) -> Result<Message>>> = HashMap::new();
-            funs.insert("init".into(), Box::new(identity_handler));
-            funs
+    fn dedup_remembers_replies_produced_by_later_handlers() -> Result<()> {
+        // Tests that dedup remembers the reply to a request whose handler asks lin-kv first,
+        // not the request to lin-kv.
+        let cnt = std::sync::Mutex::new(0);
+        let pending = std::sync::Mutex::new(None::<Message>);
+        let node = Node::builder()
+            .handle("count", |msg, msg_id| {
+                *cnt.lock().unwrap() += 1;
+                let read = Message {
+                    src: msg.dest.clone(),
+                    dest: "lin-kv".into(),
+                    body: Body {
+                        typ: "read".into(),
+                        msg_id,
+                        ..Default::default()
+                    },
+                };
+                *pending.lock().unwrap() = Some(msg);
+                Ok(read)
+            })
+            .handle("read_ok", |_, msg_id| {
+                let request = pending.lock().unwrap().take().unwrap();
+                Ok(request.reply_with(Body {
+                    typ: "count_ok".into(),
+                    msg_id,
+                    ..Default::default()
+                }))
+            })
+            .idempotent("count", |msg| {
+                msg.body.get_u64("value").ok().map(|v| v.to_string())
+            })
+            .build()?
+            .with_dedup(10);
+        node.handle(init_msg())?;
+        let msg = |src: &str, typ: &str, msg_id, in_reply_to| {
+            let mut msg = init_msg();
+            msg.src = src.into();
+            msg.body.typ = typ.into();
+            msg.body.msg_id = msg_id;
+            msg.body.in_reply_to = in_reply_to;
+            msg.body.extra = serde_json::from_value(serde_json::json!({ "value": 1 })).unwrap();
+            msg
         };
-        let node = Node::new(handlers);
-        assert!(
-            node.is_err(),
-            "Creating a node with a handler for init is forbidden {:?}",
-            node.unwrap()
+
+        let read = node.handle(msg("c1", "count", 5, 0))?;
+        let reply = node.handle(msg("lin-kv", "read_ok", 1, read.body.msg_id))?;
+        assert_eq!((reply.dest.as_str(), reply.body.in_reply_to), ("c1", 5));
+
+        let again = node.handle(msg("c1", "count", 5, 0))?;
+        assert_eq!(again, reply);
+        let retry = node.handle(msg("c2", "count", 6, 0))?;
+        assert_eq!(
+            (
+                retry.dest.as_str(),
+                retry.body.typ.as_str(),
+                retry.body.in_reply_to
+            ),
+            ("c2", "count_ok", 6)
         );
+        assert_eq!(*cnt.lock().unwrap(), 1);
         Ok(())
     }
 
     #[test]
-    fn multiple_init_messages_idempontent() -> anyhow::Result<()> {
-        // Tests that multiple init messages are valid.
-        let node = Node::new(HashMap::new())?;
-
-        node.handle(init_msg())?;
-        let expected_state = State::Initialized(InitializedNode {
-            id: "n1".into(),
-            other_nodes: vec!["n1".into(), "n2".into()],
-        });
-        assert_eq!(*node.state.borrow(), expected_state);
-        node.handle(init_msg())?;
-        assert_eq!(*node.state.borrow(), expected_state);
-        node.handle(init_msg())?;
-        assert_eq!(*node.state.borrow(), expected_state);
+    fn sessions_replay_replies_to_client_retries() -> Result<()> {
+        let cnt = std::sync::Mutex::new(0);
+        let node = Node::builder()
+            .handle("write", |msg, _| {
+                *cnt.lock().unwrap() += 1;
+                Ok(msg.reply_with(Body {
+                    typ: "write_ok".into(),
+                    ..Default::default()
+                }))
+            })
+            .build()?
+            .with_sessions(10);
         node.handle(init_msg())?;
-        assert_eq!(*node.state.borrow(), expected_state);
+        let msg = |src: &str, msg_id, extra: serde_json::Value| {
+            let mut msg = init_msg();
+            msg.src = src.into();
+            msg.body.typ = "write".into();
+            msg.body.msg_id = msg_id;
+            msg.body.extra = serde_json::from_value(extra).unwrap();
+            msg
+        };
+
+        let retried = serde_json::json!({ "request_id": 1 });
+        node.handle(msg("c1", 6, retried.clone()))?;
+        let retry = node.handle(msg("c1", 7, retried.clone()))?;
+        assert_eq!(*cnt.lock().unwrap(), 1);
+        assert_eq!(
+            (retry.body.typ.as_str(), retry.body.in_reply_to),
+            ("write_ok", 7)
+        );
 
+        node.handle(msg("c1", 8, serde_json::json!({})))?;
+        node.handle(msg("c1", 9, serde_json::json!({})))?;
+        assert_eq!(*cnt.lock().unwrap(), 3, "requests without a request_id");
+
+        node.handle(msg("c2", 6, retried.clone()))?;
+        node.handle(msg("n2", 5, retried.clone()))?;
+        node.handle(msg("n2", 6, retried))?;
+        assert_eq!(*cnt.lock().unwrap(), 6, "sessions are per client");
         Ok(())
     }
 
     #[test]
-    fn reply_id_goes_up() -> anyhow::Result<()> {
-        // T
+    fn dedup_forgets_replies_below_watermark_of_peer() -> Result<()> {
+        let node = Node::builder()
+            .handle("gossip", |msg, _| {
+                Ok(msg.reply_with(Body {
+                    typ: "gossip_ok".into(),
+                    ..Default::default()
+                }))
+            })
+            .build()?
+            .with_dedup(10)
+            .with_watermarks();
+        node.handle(init_msg())?;
+        let gossip = |msg_id, low: u64| {
+            let mut msg = init_msg();
+            msg.src = "n2".into();
+            msg.body.typ = "gossip".into();
+            msg.body.msg_id = msg_id;
+            watermark::stamp(&mut msg.body, low);
+            msg
+        };
+
+        node.dispatch(gossip(1, 1))?;
+        node.dispatch(gossip(2, 1))?;
+        assert_eq!(node.dedup.as_ref().unwrap().lock().unwrap().len(), 2);
+        node.dispatch(gossip(3, 2))?;
+
+        let dedup = node.dedup.as_ref().unwrap().lock().unwrap();
+        assert_eq!(dedup.len(), 2);
+        assert!(dedup.get("n2", 1).is_none() && dedup.get("n2", 2).is_some());
         Ok(())
     }
 
     #[test]
-    fn unimplemented_type_returns_error_after_init() -> anyhow::Result<()> {
-        // Tests that an unknown message returns an error after init.
-        let node = Node::new(HashMap::new())?;
-
-        // Init
-        node.handle(init_msg())?;
-
-        // Known msg
-        let msg = {
+    fn debug_dump_has_node_state() -> Result<()> {
+        let (sender, _sent) = mpsc::channel();
+        let msg_ids = Arc::new(MsgIds::new());
+        let outbox = Arc::new(Outbox::new(sender, msg_ids.clone(), Duration::from_secs(1)));
+        let node = Node::new(HashMap::new())?
+            .with_msg_ids(msg_ids)
+            .with_outbox(outbox.clone());
+        let dump = || {
             let mut msg = init_msg();
-            msg.body.typ = "unknown...".into();
+            msg.body = Body {
+                typ: "debug_dump".into(),
+                msg_id: 3,
+                ..Default::default()
+            };
             msg
         };
 
-        let result = node.handle(msg);
+        let before_init = node.handle(dump())?;
+        node.handle(init_msg())?;
+        outbox.send(
+            Message {
+                src: "n1".into(),
+                dest: "n2".into(),
+                body: Body {
+                    typ: "gossip".into(),
+                    ..Default::default()
+                },
+            },
+            Instant::now(),
+        )?;
+        let reply = node.handle(dump())?;
 
-        assert!(
-            result.as_ref().is_err_and(|e| e
-                .to_string()
-                .contains("No handler for message type unknown...")),
-            "expected failure with unknown handler, got {:?}",
-            result
+        assert_eq!(
+            before_init.body.extra["dump"]["node"],
+            serde_json::Value::Null
         );
+        assert_eq!(reply.body.typ, "debug_dump_ok");
+        let dump = &reply.body.extra["dump"];
+        assert_eq!(dump["node"]["id"], "n1");
+        assert_eq!(dump["unacked"][0]["body"]["type"], "gossip");
+        assert_eq!(dump["pending_rpcs"], serde_json::Value::Null);
         Ok(())
     }
 
     #[test]
-    fn unknown_message_before_init_returns_error() -> anyhow::Result<()> {
-        // Tests that an unknown message returns an error before init.
-        let node = Node::new(HashMap::new())?;
-
-        let msg = {
+    fn trace_id_follows_handler_messages() -> Result<()> {
+        // Tests that the reply and the messages a handler sends carry the trace ID of the
+        // request, and that requests without one start no trace.
+        let (sender, sent) = mpsc::channel();
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_secs(1));
+        let node = Node::builder()
+            .handle("forward", |msg, msg_id| {
+                let forward = Message {
+                    src: "n1".into(),
+                    dest: "n2".into(),
+                    body: Body {
+                        typ: "forward".into(),
+                        ..Default::default()
+                    },
+                };
+                outbox.send(forward, Instant::now())?;
+                Ok(msg.reply_with(Body {
+                    typ: "forward_ok".into(),
+                    msg_id,
+                    ..Default::default()
+                }))
+            })
+            .build()?;
+        node.handle(init_msg())?;
+        let request = |trace_id: Option<&str>| {
             let mut msg = init_msg();
-            msg.body.typ = "unknown...".into();
+            msg.body = Body {
+                typ: "forward".into(),
+                msg_id: 2,
+                ..Default::default()
+            };
+            if let Some(trace_id) = trace_id {
+                msg.body.extra.insert(TRACE_FIELD.into(), trace_id.into());
+            }
             msg
         };
 
-        let result = node.handle(msg);
+        let traced = node.handle(request(Some("t1")))?;
+        let untraced = node.handle(request(None))?;
+        let forwards: Vec<Message> = sent.try_iter().collect();
 
-        assert!(
-            result
-                .as_ref()
-                .is_err_and(|e| e.to_string().contains("Not Ready")),
-            "expected failure with unknown handler, got {:?}",
-            result
-        );
+        assert_eq!(trace::of(&traced.body), Some("t1"));
+        assert_eq!(trace::of(&forwards[0].body), Some("t1"));
+        assert_eq!(trace::of(&untraced.body), None);
+        assert_eq!(trace::of(&forwards[1].body), None);
         Ok(())
     }
 
     #[test]
-    fn message_before_init_returns_error() -> anyhow::Result<()> {
-        // Tests that a message returns an error before init.
-        let handlers = {
-            let mut funs: HashMap<_, Box<dyn Fn(Message, u64) -> Result<Message>>> = HashMap::new();
-            funs.insert("id".into(), Box::new(identity_handler));
-            funs
+    fn dispatch_routes_replies_before_handlers() -> Result<()> {
+        // Tests that a reply to an outbox message acks it instead of going to the handler for
+        // its type, and that other replies still reach their handler.
+        let (sender, sent) = mpsc::channel();
+        let msg_ids = Arc::new(MsgIds::new());
+        let outbox = Arc::new(Outbox::new(sender, msg_ids.clone(), Duration::from_secs(1)));
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("gossip_ok".into(), Box::new(identity_handler));
+            Node::new(funs)?
+                .with_msg_ids(msg_ids)
+                .with_outbox(outbox.clone())
+        };
+        node.handle(init_msg())?;
+        outbox.send(
+            Message {
+                src: "n1".into(),
+                dest: "n2".into(),
+                body: Body {
+                    typ: "gossip".into(),
+                    ..Default::default()
+                },
+            },
+            Instant::now(),
+        )?;
+        let request = sent.try_recv()?;
+        let reply = |in_reply_to| Message {
+            src: "n2".into(),
+            dest: "n1".into(),
+            body: Body {
+                typ: "gossip_ok".into(),
+                in_reply_to,
+                ..Default::default()
+            },
         };
-        let node = Node::new(handlers)?;
 
-        let msg = {
+        assert_eq!(node.dispatch(reply(request.body.msg_id))?, None);
+        assert!(outbox.is_empty());
+        assert_eq!(node.dispatch(reply(99))?, Some(reply(99)));
+        Ok(())
+    }
+
+    #[test]
+    fn handler_rpc_in_pool() -> Result<()> {
+        // Tests that a handler can block on an RPC while another worker routes the reply to it.
+        let (sender, outgoing) = mpsc::channel();
+        let msg_ids = Arc::new(MsgIds::new());
+        let rpc = Arc::new(RpcClient::new(sender, msg_ids.clone()));
+        let node = {
+            let kv = KvClient::lin_kv(rpc.clone());
+            let get = move |msg: Message, msg_id: u64| {
+                let value: u64 = kv.read("x")?;
+                let mut body = Body {
+                    typ: "get_ok".into(),
+                    msg_id,
+                    in_reply_to: msg.body.msg_id,
+                    ..Default::default()
+                };
+                body.extra.insert("value".into(), value.into());
+                Ok(Message {
+                    src: msg.dest,
+                    dest: msg.src,
+                    body,
+                })
+            };
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("get".into(), Box::new(get));
+            Node::new(funs)?
+                .with_msg_ids(msg_ids)
+                .with_rpc(rpc)
+                .with_outgoing(outgoing)
+        };
+        let (input, mut to_node) = std::io::pipe()?;
+        let (from_node, output) = std::io::pipe()?;
+        let mut from_node = BufReader::new(from_node).lines();
+        let mut next_line = || -> Result<Message> {
+            let line = from_node.next().ok_or(anyhow::anyhow!("no output"))??;
+            Ok(serde_json::from_str(&line)?)
+        };
+
+        thread::scope(|s| -> Result<()> {
+            s.spawn(|| {
+                let transport = StdioTransport::from_io(BufReader::new(input), output);
+                node.run_pool(
+                    transport,
+                    PoolConfig {
+                        workers: 2,
+                        ..Default::default()
+                    },
+                )
+            });
+            let mut send = |msg: serde_json::Value| writeln!(to_node, "{msg}");
+
+            send(serde_json::to_value(init_msg())?)?;
+            assert_eq!(next_line()?.body.typ, "init_ok");
+            send(serde_json::json!({
+                "src": "c1", "dest": "n1", "body": { "type": "get", "msg_id": 2 }
+            }))?;
+            let read = next_line()?;
+            assert_eq!(
+                (read.dest.as_str(), read.body.typ.as_str()),
+                ("lin-kv", "read")
+            );
+            send(serde_json::json!({
+                "src": "lin-kv", "dest": "n1",
+                "body": { "type": "read_ok", "in_reply_to": read.body.msg_id, "value": 42 }
+            }))?;
+            let reply = next_line()?;
+
+            assert_eq!(reply.body.typ, "get_ok");
+            assert_eq!(reply.body.in_reply_to, 2);
+            assert_eq!(reply.body.extra["value"], 42);
+            drop(to_node);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn node_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Node>();
+    }
+
+    #[test]
+    fn run_pool_replies_to_every_message() -> Result<()> {
+        // Tests that with several workers every message still gets exactly one reply.
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            funs.insert("id".into(), Box::new(identity_handler));
+            Node::new(funs)?
+        };
+        let mut input = serde_json::to_string(&init_msg())? + "\n";
+        for i in 0..100 {
             let mut msg = init_msg();
             msg.body.typ = "id".into();
-            msg
-        };
+            msg.body.msg_id = i;
+            input += &(serde_json::to_string(&msg)? + "\n");
+        }
+        let mut output = vec![];
 
-        let result = node.handle(msg);
+        node.run_pool(
+            StdioTransport::from_io(Cursor::new(input), &mut output),
+            PoolConfig {
+                workers: 4,
+                capacity: 2,
+                ..Default::default()
+            },
+        )?;
 
-        assert!(
-            result
-                .as_ref()
-                .is_err_and(|e| e.to_string().contains("Not Ready")),
-            "expected failure with unknown handler, got {:?}",
-            result
-        );
+        let mut ids: Vec<u64> = String::from_utf8(output)?
+            .lines()
+            .map(serde_json::from_str::<Message>)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|m| m.body.typ == "id")
+            .map(|m| m.body.msg_id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, (0..100).collect::<Vec<_>>());
         Ok(())
     }
 
     #[test]
-    fn node_propagates_handler_error() -> anyhow::Result<()> {
-        // Tests handler errors are propagated correctly.
+    fn run_pool_limits_handlers_per_type() -> Result<()> {
+        // Tests that no more handlers of a limited type run at once than its limit, while
+        // every request of it still gets exactly one reply.
+        let (running, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
         let node = {
-            let mut funs: HashMap<_, Box<dyn Fn(Message, u64) -> Result<Message>>> = HashMap::new();
-            let err_handler = |_: Message, _: u64| Err(anyhow::anyhow!("error from handler"));
-            funs.insert("id".into(), Box::new(err_handler));
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            let counting_handler = |msg: Message, _: u64| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(2));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(msg)
+            };
+            funs.insert("txn".into(), Box::new(counting_handler));
             Node::new(funs)?
         };
+        let mut input = serde_json::to_string(&init_msg())? + "\n";
+        for i in 0..20 {
+            let mut msg = init_msg();
+            msg.body.typ = "txn".into();
+            msg.body.msg_id = i;
+            input += &(serde_json::to_string(&msg)? + "\n");
+        }
+        let mut output = vec![];
 
-        node.handle(init_msg())?;
+        node.run_pool(
+            StdioTransport::from_io(Cursor::new(input), &mut output),
+            PoolConfig {
+                workers: 4,
+                limits: HashMap::from([("txn".to_string(), 1)]),
+                ..Default::default()
+            },
+        )?;
 
-        let msg = {
+        let replies = String::from_utf8(output)?
+            .lines()
+            .filter(|line| line.contains("\"txn\""))
+            .count();
+        assert_eq!(replies, 20);
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn lanes_hand_out_control_messages_first() {
+        let lanes = Lanes::new(2);
+        let msg = |typ: &str, in_reply_to: u64| {
             let mut msg = init_msg();
-            msg.body.typ = "id".into();
+            msg.body.typ = typ.into();
+            msg.body.in_reply_to = in_reply_to;
             msg
         };
-        let result = node.handle(msg);
+        lanes.push(msg("txn", 0)).expect("lanes closed");
+        lanes.push(msg("txn", 0)).expect("lanes closed");
+        assert!(lanes.try_push(msg("txn", 0)).is_some(), "data lane is full");
+        lanes.push(msg("read_ok", 7)).expect("lanes closed");
+        lanes.push(msg("topology", 0)).expect("lanes closed");
+        lanes.close();
+
+        let order: Vec<String> = std::iter::from_fn(|| lanes.pop())
+            .map(|m| m.body.typ)
+            .collect();
+        assert_eq!(order, vec!["read_ok", "topology", "txn", "txn"]);
+        assert!(lanes.push(msg("txn", 0)).is_err());
+    }
+
+    #[test]
+    fn run_pool_sheds_when_full() -> Result<()> {
+        // Tests that requests arriving while the queue is full get error 11, and that every
+        // request still gets exactly one reply.
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let node = {
+            let mut funs: HashMap<_, Handler> = HashMap::new();
+            let blocking_handler = move |msg: Message, _: u64| {
+                blocked.lock().unwrap().recv().ok();
+                Ok(msg)
+            };
+            funs.insert("id".into(), Box::new(blocking_handler));
+            Node::new(funs)?
+        };
+        let mut input = serde_json::to_string(&init_msg())? + "\n";
+        for i in 1..=10 {
+            let mut msg = init_msg();
+            msg.body.typ = "id".into();
+            msg.body.msg_id = i;
+            input += &(serde_json::to_string(&msg)? + "\n");
+        }
+        let mut output = vec![];
+
+        thread::scope(|s| {
+            let running = s.spawn(|| {
+                node.run_pool(
+                    StdioTransport::from_io(Cursor::new(input), &mut output),
+                    PoolConfig {
+                        workers: 1,
+                        capacity: 1,
+                        overflow: Overflow::Shed,
+                        ..Default::default()
+                    },
+                )
+            });
+            // Gives the reader time to fill the queue before any request completes.
+            thread::sleep(Duration::from_millis(100));
+            for _ in 0..10 {
+                release.send(()).expect("handler dropped");
+            }
+            running.join().expect("run_pool panicked")
+        })?;
 
+        let replies: Vec<Message> = String::from_utf8(output)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        let shed: Vec<u64> = replies
+            .iter()
+            .filter(|m| m.body.typ == "error" && m.body.extra["code"] == 11)
+            .map(|m| m.body.in_reply_to)
+            .collect();
+        let mut answered: Vec<u64> = replies
+            .iter()
+            .filter(|m| m.body.typ == "id")
+            .map(|m| m.body.msg_id)
+            .chain(shed.iter().copied())
+            .collect();
+        answered.sort();
         assert!(
-            result
-                .as_ref()
-                .is_err_and(|e| e.to_string().contains("error from handler")),
-            "expected failure from handler, got {:?}",
-            result
+            !shed.is_empty(),
+            "expected shed requests, got {:?}",
+            replies
         );
+        assert_eq!(answered, (1..=10).collect::<Vec<_>>());
         Ok(())
     }
 
     #[test]
     fn handler_with_state() -> Result<()> {
         // Tests using a handler with some state (counts requests.)
-        let cnt = std::cell::RefCell::new(0);
+        let cnt = std::sync::Mutex::new(0);
         let node: Node = {
             let counting_handler = |msg: Message, _: u64| {
-                cnt.replace_with(|old| *old + 1);
+                *cnt.lock().unwrap() += 1;
                 // just return the message we recieve.
                 Ok::<Message, anyhow::Error>(msg)
             };
-            let mut funs: HashMap<String, Box<dyn Fn(Message, u64) -> Result<Message>>> =
-                HashMap::default();
+            let mut funs: HashMap<String, Handler> = HashMap::default();
             funs.insert("count".to_string(), Box::new(counting_handler));
             Node::new(funs)?
         };
@@ -447,14 +3333,14 @@ mod test {
         node.handle(msg.clone())?;
 
         assert_eq!(
-            *cnt.borrow(),
+            *cnt.lock().unwrap(),
             1,
             "After first message handled, count should be 1"
         );
         node.handle(msg.clone())?;
         node.handle(msg)?;
         assert_eq!(
-            *cnt.borrow(),
+            *cnt.lock().unwrap(),
             3,
             "After 3 messages handled, count should be 3"
         );
@@ -0,0 +1,576 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use tracing::{debug, warn};
+
+use crate::{
+    heartbeat::Heartbeats,
+    message::{Message, MsgIds, TEMPORARILY_UNAVAILABLE},
+    metrics::Metrics,
+    peers::Peers,
+    rate_limit::TokenBucket,
+    rtt::RttEstimator,
+    trace, watermark,
+};
+
+/// Sends messages with at-least-once delivery.
+///
+/// A message sent through the outbox is kept, and sent again every `retry_interval`, until a
+/// reply to it is acked with [`Outbox::ack`]. Like [`Raft`](crate::raft::Raft) the outbox does
+/// not keep time itself, [`Outbox::tick`] must be called regularly with the current time.
+///
+/// With [`Outbox::with_rate_limit`] messages are only sent while the rate limit has tokens
+/// left, the others wait for the next tick, so a node that comes back from a partition with a
+/// backlog of unacked messages sends it at the rate of the limit.
+///
+/// With [`Outbox::with_rtt`] messages to a peer are sent again after the retry timeout its
+/// measured round trip times give, see [`RttEstimator`], instead of `retry_interval`, which is
+/// only used for peers without replies yet.
+///
+/// With [`Outbox::with_watermarks`] every message sent carries the low watermark of its dest,
+/// see [`watermark`], so the dest can drop what it keeps about older messages of this node.
+#[derive(Debug)]
+pub struct Outbox {
+    sender: Sender<Message>,
+    msg_ids: Arc<MsgIds>,
+    retry_interval: Duration,
+    // Messages not acked yet, keyed by msg_id.
+    unacked: Mutex<BTreeMap<u64, Unacked>>,
+    metrics: Option<Arc<Metrics>>,
+    // Peers suspected dead are not sent messages again until they are heard from.
+    heartbeats: Option<Arc<Heartbeats>>,
+    rate_limit: Option<TokenBucket>,
+    rtt: Option<Arc<RttEstimator>>,
+    peers: Option<Arc<Peers>>,
+    // Whether messages carry the low watermark of their dest.
+    watermarks: bool,
+}
+
+/// A message waiting for an ack.
+#[derive(Debug)]
+struct Unacked {
+    msg: Message,
+    // When to send it again.
+    retry_at: Instant,
+    // When it was last sent, and how many times.
+    sent_at: Instant,
+    sends: usize,
+}
+
+impl Outbox {
+    /// Creates an outbox that sends to `sender`, numbering messages with `msg_ids`, which should
+    /// be shared with the node sending them, see [`crate::node::Node::with_msg_ids`].
+    pub fn new(sender: Sender<Message>, msg_ids: Arc<MsgIds>, retry_interval: Duration) -> Self {
+        Self {
+            sender,
+            msg_ids,
+            retry_interval,
+            unacked: Mutex::default(),
+            metrics: None,
+            heartbeats: None,
+            rate_limit: None,
+            rtt: None,
+            peers: None,
+            watermarks: false,
+        }
+    }
+
+    /// Counts retries in `metrics`, by message type.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Holds off sending messages again to peers `heartbeats` suspect are dead, they are sent
+    /// on the first tick after the peer is heard from again.
+    pub fn with_heartbeats(mut self, heartbeats: Arc<Heartbeats>) -> Self {
+        self.heartbeats = Some(heartbeats);
+        self
+    }
+
+    /// Sends messages, new ones and retries alike, only while `rate_limit` has tokens.
+    pub fn with_rate_limit(mut self, rate_limit: TokenBucket) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Measures the round trip time of every message acked, into `rtt`, and sends messages again
+    /// after the retry timeout of their dest.
+    pub fn with_rtt(mut self, rtt: Arc<RttEstimator>) -> Self {
+        self.rtt = Some(rtt);
+        self
+    }
+
+    /// Counts the messages in flight to every peer and their retries in `peers`.
+    pub fn with_peers(mut self, peers: Arc<Peers>) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    /// Stamps every message sent, and sent again, with the low watermark of its dest, see
+    /// [`Outbox::low_watermark`].
+    pub fn with_watermarks(mut self) -> Self {
+        self.watermarks = true;
+        self
+    }
+
+    /// The lowest msg_id of the messages to `dest` waiting for an ack, every message sent to
+    /// it before was acked. None if none are waiting.
+    pub fn low_watermark(&self, dest: &str) -> Option<u64> {
+        low_watermark(&self.unacked.lock().unwrap(), dest)
+    }
+
+    /// How long to wait for an ack from `dest` before sending a message again.
+    fn retry_interval(&self, dest: &str) -> Duration {
+        self.rtt
+            .as_ref()
+            .and_then(|rtt| rtt.timeout(dest))
+            .unwrap_or(self.retry_interval)
+    }
+
+    /// Gives `msg` a new msg_id and sends it, returns the msg_id. Past the rate limit the
+    /// message is only sent on a later tick.
+    pub fn send(&self, mut msg: Message, now: Instant) -> Result<u64> {
+        // The msg_id is taken with the lock held, so no message with a lower one is sent after
+        // a watermark past it.
+        let mut unacked = self.unacked.lock().unwrap();
+        let msg_id = self.msg_ids.next_request();
+        msg.body.msg_id = msg_id;
+        trace::stamp(&mut msg.body);
+        if self.watermarks {
+            let low = low_watermark(&unacked, &msg.dest).unwrap_or(msg_id);
+            watermark::stamp(&mut msg.body, low);
+        }
+        let (retry_at, sends) = match self.allowed(now) {
+            true => {
+                self.sender
+                    .send(msg.clone())
+                    .map_err(|_| anyhow!("Unavailable: outbox receiver dropped"))?;
+                (now + self.retry_interval(&msg.dest), 1)
+            }
+            false => (now, 0),
+        };
+        if let Some(peers) = &self.peers {
+            peers.record_sent(&msg.dest);
+        }
+        let entry = Unacked {
+            msg,
+            retry_at,
+            sent_at: now,
+            sends,
+        };
+        unacked.insert(msg_id, entry);
+        Ok(msg_id)
+    }
+
+    /// Whether the rate limit, if any, allows sending a message at `now`.
+    fn allowed(&self, now: Instant) -> bool {
+        self.rate_limit
+            .as_ref()
+            .is_none_or(|limit| limit.try_take(now))
+    }
+
+    /// Every message waiting for an ack, in the order they were numbered.
+    pub fn unacked(&self) -> Vec<Message> {
+        let unacked = self.unacked.lock().unwrap();
+        unacked
+            .values()
+            .map(|unacked| unacked.msg.clone())
+            .collect()
+    }
+
+    /// Whether the message numbered `msg_id` was sent and is still waiting for an ack.
+    pub fn is_unacked(&self, msg_id: u64) -> bool {
+        self.unacked.lock().unwrap().contains_key(&msg_id)
+    }
+
+    /// Stops sending the message `reply` answers, returns whether it was waiting for an ack.
+    ///
+    /// An error reply acks too, unless its code is temporarily unavailable, e.g. the peer was
+    /// not initialized yet, then the message is sent again. A reply with an `acks` field, a
+    /// cumulative ack, also acks every message to the replying node whose msg_id is in it.
+    ///
+    /// The reply to a message sent once is a round trip time sample of its dest, replies to
+    /// messages sent again could answer any of the sends and are not.
+    pub fn ack(&self, reply: &Message) -> bool {
+        let mut unacked = self.unacked.lock().unwrap();
+        let acked = match unacked.get(&reply.body.in_reply_to) {
+            Some(_) if is_unavailable(reply) => return false,
+            Some(acked) if acked.msg.dest == reply.src => {
+                if let (Some(rtt), 1) = (&self.rtt, acked.sends) {
+                    rtt.observe(&reply.src, acked.sent_at.elapsed());
+                }
+                unacked.remove(&reply.body.in_reply_to);
+                self.record_acked(&reply.src, 1);
+                true
+            }
+            _ => false,
+        };
+        let Ok(acks) = reply.body.get_as::<Vec<u64>>("acks") else {
+            return acked;
+        };
+        let before = unacked.len();
+        unacked.retain(|msg_id, unacked| !acks.contains(msg_id) || unacked.msg.dest != reply.src);
+        self.record_acked(&reply.src, before - unacked.len());
+        acked || unacked.len() < before
+    }
+
+    fn record_acked(&self, dest: &str, count: usize) {
+        if let Some(peers) = &self.peers {
+            (0..count).for_each(|_| peers.record_acked(dest));
+        }
+    }
+
+    /// Sends `msg` as is, once, without waiting for an ack, e.g. the reply to a message whose
+    /// handler did not reply, see [`no_reply`](crate::node::no_reply).
+    pub fn send_once(&self, msg: Message) -> Result<()> {
+        self.sender
+            .send(msg)
+            .map_err(|_| anyhow!("Unavailable: outbox receiver dropped"))
+    }
+
+    /// Sends again every message whose retry is due at `now`, returns how many were sent. Past
+    /// the rate limit the rest stay due until a later tick.
+    pub fn tick(&self, now: Instant) -> Result<usize> {
+        let mut unacked = self.unacked.lock().unwrap();
+        let mut resent = 0;
+        let mut watermarks: HashMap<String, u64> = HashMap::new();
+        if self.watermarks {
+            for (&msg_id, unacked) in unacked.iter() {
+                watermarks.entry(unacked.msg.dest.clone()).or_insert(msg_id);
+            }
+        }
+        let suspected = |dest: &str| {
+            self.heartbeats
+                .as_ref()
+                .is_some_and(|heartbeats| heartbeats.is_suspected(dest, now))
+        };
+        // Due messages, the ones due the longest first, so messages held back by the rate
+        // limit are not passed by retries.
+        let mut due: Vec<&mut Unacked> = unacked
+            .values_mut()
+            .filter(|unacked| unacked.retry_at <= now && !suspected(&unacked.msg.dest))
+            .collect();
+        due.sort_by_key(|unacked| unacked.retry_at);
+        for unacked in due {
+            if let Some(&low) = watermarks.get(&unacked.msg.dest) {
+                watermark::stamp(&mut unacked.msg.body, low);
+            }
+            let msg = &unacked.msg;
+            if !self.allowed(now) {
+                debug!("rate limited, resending the rest later");
+                break;
+            }
+            debug!(dest = %msg.dest, msg_id = msg.body.msg_id, "resending unacked message");
+            self.sender
+                .send(msg.clone())
+                .map_err(|_| anyhow!("Unavailable: outbox receiver dropped"))?;
+            unacked.retry_at = now + self.retry_interval(&msg.dest);
+            unacked.sent_at = now;
+            unacked.sends += 1;
+            if unacked.sends > 1 {
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_retry(&msg.body.typ);
+                }
+                if let Some(peers) = &self.peers {
+                    peers.record_retry(&msg.dest);
+                }
+            }
+            resent += 1;
+        }
+        Ok(resent)
+    }
+
+    /// Calls [`Outbox::tick`] every `interval` from a background thread, until the receiver of
+    /// the outbox is dropped.
+    pub fn tick_every(self: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()> {
+        let outbox = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = outbox.tick(Instant::now()) {
+                warn!("stopped resending unacked messages: {e:#}");
+                return;
+            }
+        })
+    }
+
+    /// Number of messages not acked yet.
+    pub fn len(&self) -> usize {
+        self.unacked.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The lowest msg_id of the messages in `unacked` to `dest`.
+fn low_watermark(unacked: &BTreeMap<u64, Unacked>, dest: &str) -> Option<u64> {
+    unacked
+        .iter()
+        .find(|(_, unacked)| unacked.msg.dest == dest)
+        .map(|(&msg_id, _)| msg_id)
+}
+
+/// Whether `reply` is an error reply saying the request can be retried later.
+fn is_unavailable(reply: &Message) -> bool {
+    reply.body.typ == "error"
+        && reply.body.extra.get("code").and_then(|code| code.as_u64())
+            == Some(TEMPORARILY_UNAVAILABLE)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+
+    use crate::heartbeat::{HeartbeatConfig, Heartbeats};
+    use crate::message::{Body, Message, MsgIds};
+    use crate::metrics::Metrics;
+    use crate::outbox::Outbox;
+    use crate::peers::Peers;
+    use crate::rate_limit::TokenBucket;
+    use crate::rtt::RttEstimator;
+    use crate::watermark::WATERMARK_FIELD;
+
+    fn gossip(dest: &str) -> Message {
+        Message {
+            src: "n1".into(),
+            dest: dest.into(),
+            body: Body {
+                typ: "gossip".into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn reply_to(msg: &Message) -> Message {
+        Message {
+            src: msg.dest.clone(),
+            dest: msg.src.clone(),
+            body: Body {
+                typ: "gossip_ok".into(),
+                in_reply_to: msg.body.msg_id,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn retries_until_acked() -> Result<()> {
+        let (sender, sent) = mpsc::channel();
+        let metrics = Arc::new(Metrics::new());
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100))
+            .with_metrics(metrics.clone());
+        let now = Instant::now();
+
+        outbox.send(gossip("n2"), now)?;
+        let first = sent.try_recv()?;
+        assert_eq!(outbox.tick(now + Duration::from_millis(50))?, 0);
+        assert_eq!(outbox.tick(now + Duration::from_millis(100))?, 1);
+        assert_eq!(sent.try_recv()?, first, "retry should be the same message");
+
+        assert!(outbox.ack(&reply_to(&first)));
+        assert_eq!(outbox.tick(now + Duration::from_secs(1))?, 0);
+        assert!(outbox.is_empty());
+        assert_eq!(metrics.retries("gossip"), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn peers_count_messages_in_flight_and_retries() -> Result<()> {
+        let (sender, sent) = mpsc::channel();
+        let peers = Arc::new(Peers::new());
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100))
+            .with_peers(peers.clone());
+        let now = Instant::now();
+
+        outbox.send(gossip("n2"), now)?;
+        outbox.send(gossip("n2"), now)?;
+        outbox.send(gossip("n3"), now)?;
+        assert_eq!(outbox.tick(now + Duration::from_millis(100))?, 3);
+        let first = sent.try_recv()?;
+        assert!(outbox.ack(&reply_to(&first)));
+
+        let n2 = peers.stats("n2");
+        assert_eq!((n2.in_flight, n2.retries), (1, 2));
+        assert_eq!(peers.stats("n3").in_flight, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn cumulative_ack_acks_every_listed_message() -> Result<()> {
+        // Tests that one reply acks every message to its sender listed in it, and no message
+        // to another node.
+        let (sender, sent) = mpsc::channel();
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100));
+        let now = Instant::now();
+        for dest in ["n2", "n2", "n3", "n2"] {
+            outbox.send(gossip(dest), now)?;
+        }
+        let sent: Vec<Message> = sent.try_iter().collect();
+
+        let mut reply = reply_to(&sent[3]);
+        let acks: Vec<u64> = sent.iter().map(|m| m.body.msg_id).take(3).collect();
+        reply.body.extra.insert("acks".into(), acks.into());
+
+        assert!(outbox.ack(&reply));
+        assert_eq!(outbox.unacked(), vec![sent[2].clone()]);
+        Ok(())
+    }
+
+    #[test]
+    fn retries_follow_measured_rtt() -> Result<()> {
+        // Tests that a peer answering fast is retried after its retry timeout, while a peer
+        // without replies yet waits the whole retry interval.
+        let (sender, sent) = mpsc::channel();
+        let rtt = Arc::new(RttEstimator::new());
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_secs(1))
+            .with_rtt(rtt.clone());
+
+        outbox.send(gossip("n2"), Instant::now())?;
+        assert!(outbox.ack(&reply_to(&sent.try_recv()?)));
+        let timeout = rtt.timeout("n2").expect("the ack is a sample");
+        assert!(timeout < Duration::from_millis(100), "{timeout:?}");
+
+        let now = Instant::now();
+        outbox.send(gossip("n2"), now)?;
+        outbox.send(gossip("n3"), now)?;
+        assert_eq!(outbox.tick(now + Duration::from_millis(100))?, 1);
+        let resent: Vec<String> = sent.try_iter().skip(2).map(|m| m.dest).collect();
+        assert_eq!(resent, vec!["n2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn ack_must_come_from_dest() -> Result<()> {
+        // Tests that a reply from another node with the same in_reply_to does not ack.
+        let (sender, sent) = mpsc::channel();
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100));
+
+        outbox.send(gossip("n2"), Instant::now())?;
+        let mut reply = reply_to(&sent.try_recv()?);
+        reply.src = "n3".into();
+
+        assert!(!outbox.ack(&reply));
+        assert_eq!(outbox.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn unavailable_errors_do_not_ack() -> Result<()> {
+        // Tests that a peer that was not ready yet is sent the message again.
+        let (sender, sent) = mpsc::channel();
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100));
+
+        outbox.send(gossip("n2"), Instant::now())?;
+        let mut reply = reply_to(&sent.try_recv()?);
+        reply.body.typ = "error".into();
+        reply.body.extra.insert("code".into(), 11.into());
+
+        assert!(!outbox.ack(&reply));
+        reply.body.extra.insert("code".into(), 12.into());
+        assert!(outbox.ack(&reply));
+        Ok(())
+    }
+
+    #[test]
+    fn suspected_peers_are_not_retried() -> Result<()> {
+        // Tests that retries to a peer that stopped answering wait until it is heard from.
+        let (sender, sent) = mpsc::channel();
+        let (heartbeat_sender, _) = mpsc::channel();
+        let heartbeats = Arc::new(Heartbeats::new(
+            heartbeat_sender,
+            HeartbeatConfig {
+                interval: Duration::from_millis(100),
+                timeout: Duration::from_millis(300),
+                ..Default::default()
+            },
+        ));
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100))
+            .with_heartbeats(heartbeats.clone());
+        let now = Instant::now();
+        heartbeats.init("n1", &["n1".into(), "n2".into(), "n3".into()], now);
+
+        outbox.send(gossip("n2"), now)?;
+        outbox.send(gossip("n3"), now)?;
+        let to_n2 = sent.try_recv()?;
+        sent.try_recv()?;
+        // n2 is heard from, by a message that is not an ack, n3 is not.
+        heartbeats.observe(&reply_to(&gossip("n2")), now + Duration::from_millis(400));
+
+        assert_eq!(outbox.tick(now + Duration::from_millis(500))?, 1);
+        assert_eq!(sent.try_recv()?, to_n2);
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limit_holds_back_sends_and_retries() -> Result<()> {
+        // Tests that messages past the limit are sent on the first tick with tokens again,
+        // retries included.
+        let (sender, sent) = mpsc::channel();
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100))
+            .with_rate_limit(TokenBucket::new(10, 2));
+        let now = Instant::now();
+
+        for dest in ["n2", "n3", "n4"] {
+            outbox.send(gossip(dest), now)?;
+        }
+        assert_eq!(sent.try_iter().count(), 2);
+        assert_eq!(outbox.tick(now)?, 0, "no token left");
+        assert_eq!(outbox.tick(now + Duration::from_millis(100))?, 1);
+        assert_eq!(sent.try_recv()?.dest, "n4");
+
+        assert_eq!(outbox.tick(now + Duration::from_millis(300))?, 2);
+        assert_eq!(outbox.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn messages_carry_the_low_watermark_of_their_dest() -> Result<()> {
+        // Tests that the watermark of a dest stays at its oldest unacked message, and moves
+        // past it once it is acked, on retries too.
+        let (sender, sent) = mpsc::channel();
+        let outbox = Outbox::new(sender, Arc::new(MsgIds::new()), Duration::from_millis(100))
+            .with_watermarks();
+        let now = Instant::now();
+        let watermark = |msg: &Message| msg.body.extra[WATERMARK_FIELD].as_u64();
+
+        for dest in ["n2", "n3", "n2"] {
+            outbox.send(gossip(dest), now)?;
+        }
+        let sent_first: Vec<Message> = sent.try_iter().collect();
+        let watermarks: Vec<_> = sent_first.iter().map(watermark).collect();
+        assert_eq!(watermarks, vec![Some(1), Some(2), Some(1)]);
+
+        assert!(outbox.ack(&reply_to(&sent_first[0])));
+        assert_eq!(outbox.low_watermark("n2"), Some(3));
+        outbox.tick(now + Duration::from_millis(100))?;
+        let resent: Vec<_> = sent.try_iter().map(|m| (watermark(&m), m.dest)).collect();
+        assert_eq!(resent, vec![(Some(2), "n3".into()), (Some(3), "n2".into())]);
+        assert_eq!(outbox.low_watermark("n4"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn msg_ids_shared_with_node() -> Result<()> {
+        let (sender, sent) = mpsc::channel();
+        let msg_ids = Arc::new(MsgIds::new());
+        let outbox = Outbox::new(sender, msg_ids.clone(), Duration::from_millis(100));
+
+        assert_eq!(msg_ids.next(), 0);
+        outbox.send(gossip("n2"), Instant::now())?;
+
+        assert_eq!(sent.try_recv()?.body.msg_id, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::rtt::RttEstimator;
+
+/// Latency and delivery statistics of every peer, so workloads can route around slow or
+/// unresponsive peers, e.g. prefer the neighbors with the lowest round trip times.
+///
+/// The [`Outbox`](crate::outbox::Outbox) counts the messages in flight to a peer and their
+/// retries, the [`Node`](crate::node::Node) when it last heard from the peer, and with
+/// [`Peers::with_rtt`] the round trip times come from an [`RttEstimator`]. Shared between
+/// threads, every method can be called concurrently.
+#[derive(Debug, Default)]
+pub struct Peers {
+    peers: Mutex<BTreeMap<String, Counts>>,
+    rtt: Option<Arc<RttEstimator>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    in_flight: usize,
+    retries: u64,
+    last_seen: Option<Instant>,
+}
+
+/// Statistics of a peer, see [`Peers::stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Smoothed round trip time, None without an estimator or before any reply.
+    pub rtt: Option<Duration>,
+    /// Messages sent to the peer and not acked yet.
+    pub in_flight: usize,
+    /// Messages sent to the peer again.
+    pub retries: u64,
+    /// When a message from the peer was last recieved.
+    pub last_seen: Option<Instant>,
+}
+
+impl PeerStats {
+    /// The statistics as JSON, with the time since the peer was last seen at `now`.
+    pub fn to_json(&self, now: Instant) -> Value {
+        json!({
+            "rtt_ms": self.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            "in_flight": self.in_flight,
+            "retries": self.retries,
+            "last_seen_ms_ago": self
+                .last_seen
+                .map(|seen| now.saturating_duration_since(seen).as_millis() as u64),
+        })
+    }
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the round trip times of peers from `rtt`.
+    pub fn with_rtt(mut self, rtt: Arc<RttEstimator>) -> Self {
+        self.rtt = Some(rtt);
+        self
+    }
+
+    /// Records that a message to `peer` is waiting for an ack.
+    pub fn record_sent(&self, peer: &str) {
+        self.update(peer, |counts| counts.in_flight += 1);
+    }
+
+    /// Records that a message to `peer` was acked.
+    pub fn record_acked(&self, peer: &str) {
+        self.update(peer, |counts| {
+            counts.in_flight = counts.in_flight.saturating_sub(1)
+        });
+    }
+
+    /// Records that a message to `peer` was sent again.
+    pub fn record_retry(&self, peer: &str) {
+        self.update(peer, |counts| counts.retries += 1);
+    }
+
+    /// Records that a message from `peer` was recieved at `now`.
+    pub fn record_seen(&self, peer: &str, now: Instant) {
+        self.update(peer, |counts| {
+            counts.last_seen = Some(counts.last_seen.map_or(now, |seen| seen.max(now)))
+        });
+    }
+
+    fn update(&self, peer: &str, f: impl FnOnce(&mut Counts)) {
+        f(self
+            .peers
+            .lock()
+            .unwrap()
+            .entry(peer.to_string())
+            .or_default());
+    }
+
+    /// Statistics of `peer`, all empty if nothing was recorded about it.
+    pub fn stats(&self, peer: &str) -> PeerStats {
+        let counts = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .copied()
+            .unwrap_or_default();
+        self.with_counts(peer, counts)
+    }
+
+    /// Statistics of every peer something was recorded about, by ID.
+    pub fn all(&self) -> BTreeMap<String, PeerStats> {
+        let peers = self.peers.lock().unwrap().clone();
+        peers
+            .into_iter()
+            .map(|(peer, counts)| {
+                let stats = self.with_counts(&peer, counts);
+                (peer, stats)
+            })
+            .collect()
+    }
+
+    fn with_counts(&self, peer: &str, counts: Counts) -> PeerStats {
+        PeerStats {
+            rtt: self.rtt.as_ref().and_then(|rtt| rtt.rtt(peer)),
+            in_flight: counts.in_flight,
+            retries: counts.retries,
+            last_seen: counts.last_seen,
+        }
+    }
+
+    /// `peers` from the lowest round trip time to the highest, peers without one last.
+    pub fn by_rtt(&self, peers: &[String]) -> Vec<String> {
+        let mut peers = peers.to_vec();
+        peers.sort_by_cached_key(|peer| self.stats(peer).rtt.unwrap_or(Duration::MAX));
+        peers
+    }
+
+    /// The statistics of every peer as JSON, by ID, see [`PeerStats::to_json`].
+    pub fn to_json(&self, now: Instant) -> Value {
+        let peers = self.all();
+        Value::Object(
+            peers
+                .iter()
+                .map(|(peer, stats)| (peer.clone(), stats.to_json(now)))
+                .collect(),
+        )
+    }
+
+    /// Renders the statistics of every peer, one line per peer, as of `now`.
+    pub fn report(&self, now: Instant) -> String {
+        let mut report = String::new();
+        for (peer, stats) in self.all() {
+            let _ = write!(report, "\n  peer {peer}");
+            if let Some(rtt) = stats.rtt {
+                let _ = write!(report, " rtt={rtt:?}");
+            }
+            let _ = write!(
+                report,
+                " in_flight={} retries={}",
+                stats.in_flight, stats.retries
+            );
+            if let Some(seen) = stats.last_seen {
+                let _ = write!(
+                    report,
+                    " last_seen={:?}",
+                    now.saturating_duration_since(seen)
+                );
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use crate::{peers::Peers, rtt::RttEstimator};
+
+    #[test]
+    fn stats_of_every_peer() {
+        let rtt = Arc::new(RttEstimator::new());
+        let peers = Peers::new().with_rtt(rtt.clone());
+        let now = Instant::now();
+        peers.record_sent("n2");
+        peers.record_sent("n2");
+        peers.record_retry("n2");
+        peers.record_acked("n2");
+        peers.record_seen("n2", now);
+        peers.record_sent("n3");
+        rtt.observe("n2", Duration::from_millis(40));
+        rtt.observe("n3", Duration::from_millis(10));
+
+        let n2 = peers.stats("n2");
+        assert_eq!(n2.rtt, Some(Duration::from_millis(40)));
+        assert_eq!((n2.in_flight, n2.retries), (1, 1));
+        assert_eq!(n2.last_seen, Some(now));
+        assert_eq!(peers.stats("n4"), Default::default());
+        let ids = ["n2", "n3", "n4"].map(String::from);
+        assert_eq!(peers.by_rtt(&ids), ["n3", "n2", "n4"]);
+
+        let report = peers.report(now + Duration::from_secs(1));
+        assert!(
+            report.contains("peer n2 rtt=40ms in_flight=1 retries=1 last_seen=1s"),
+            "{report}"
+        );
+        let json = peers.to_json(now);
+        assert_eq!(json["n3"]["in_flight"], 1);
+        assert_eq!(json["n2"]["last_seen_ms_ago"], 0);
+    }
+}
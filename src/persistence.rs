@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    durability::{Durability, Syncer},
+    node::{Handler, InitHandler},
+};
+
+/// State of a workload that can be saved and restored, to survive the node crashing.
+///
+/// Only state lost in a crash needs to be in the snapshot, not state kept by Maelstrom services
+/// like lin-kv.
+pub trait Persist {
+    /// Serializes the current state.
+    fn snapshot(&self) -> Value;
+
+    /// Replaces the current state with a `snapshot` from [`Persist::snapshot`].
+    fn restore(&self, snapshot: Value) -> Result<()>;
+
+    /// Merges a `snapshot` of a peer into the current state, replacing it by default. States
+    /// that can be merged, like CRDTs, keep what only this node had.
+    fn merge_snapshot(&self, snapshot: Value) -> Result<()> {
+        self.restore(snapshot)
+    }
+}
+
+/// Saves the state of a workload to a file, and restores it when the node starts again.
+///
+/// The file is `<dir>/<node id>.json`, so it is only known once the node is initialized. The
+/// state is saved after every message is handled and before its reply is sent, so nothing a
+/// client saw acked is lost in a crash. Files are replaced by renaming, a crash while saving
+/// leaves the previous state. They are synced to disk as [`Persistence::with_durability`]
+/// says, by default never.
+pub struct Persistence<'a> {
+    dir: PathBuf,
+    state: &'a (dyn Persist + Sync),
+    // Set on init.
+    path: OnceLock<PathBuf>,
+    // Snapshot last saved, to skip saving when nothing changed. Held while saving so saves from
+    // concurrent handlers do not race.
+    saved: Mutex<Option<Value>>,
+    syncer: Syncer,
+}
+
+impl<'a> Persistence<'a> {
+    /// Creates a persistence of `state` under the directory `dir`, created if missing.
+    pub fn new(dir: impl Into<PathBuf>, state: &'a (dyn Persist + Sync)) -> Self {
+        Self {
+            dir: dir.into(),
+            state,
+            path: OnceLock::new(),
+            saved: Mutex::new(None),
+            syncer: Syncer::default(),
+        }
+    }
+
+    /// Syncs saved files to disk as `durability` says, before they replace the previous state.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.syncer = Syncer::new(durability);
+        self
+    }
+
+    /// Starts saving and restores the state saved by the node `node_id` before, if any.
+    pub fn init(&self, node_id: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("cannot create state dir {}", self.dir.display()))?;
+        let path = self
+            .path
+            .get_or_init(|| self.dir.join(format!("{node_id}.json")));
+
+        let snapshot = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("corrupt state file {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context(format!("cannot read {}", path.display())),
+        };
+        info!(path = %path.display(), "restoring saved state");
+        self.state.restore(snapshot)?;
+        *self.saved.lock().unwrap() = Some(self.state.snapshot());
+        Ok(())
+    }
+
+    /// Saves the current state if it changed since it was last saved, does nothing before init.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = self.path.get() else {
+            return Ok(());
+        };
+        let mut saved = self.saved.lock().unwrap();
+        let snapshot = self.state.snapshot();
+        if saved.as_ref() == Some(&snapshot) {
+            return Ok(());
+        }
+
+        let tmp = path.with_extension("json.tmp");
+        let mut file =
+            File::create(&tmp).with_context(|| format!("cannot write {}", tmp.display()))?;
+        file.write_all(&serde_json::to_vec(&snapshot)?)
+            .with_context(|| format!("cannot write {}", tmp.display()))?;
+        self.syncer.written(&file)?;
+        fs::rename(&tmp, path).with_context(|| format!("cannot replace {}", path.display()))?;
+        *saved = Some(snapshot);
+        Ok(())
+    }
+}
+
+/// Wraps every handler of `handlers` to save the state with `persistence` after it succeeds,
+/// handlers are unchanged without a persistence.
+pub fn persisted<'a>(
+    persistence: Option<&'a Persistence<'a>>,
+    handlers: HashMap<String, Handler<'a>>,
+) -> HashMap<String, Handler<'a>> {
+    let Some(persistence) = persistence else {
+        return handlers;
+    };
+    handlers
+        .into_iter()
+        .map(|(typ, handler)| {
+            let handler: Handler<'a> = Box::new(move |msg, msg_id| {
+                let reply = handler(msg, msg_id)?;
+                persistence
+                    .save()
+                    .map_err(|e| anyhow!("Internal: cannot save state: {e:#}"))?;
+                Ok(reply)
+            });
+            (typ, handler)
+        })
+        .collect()
+}
+
+/// Wraps `init_handler` to also restore the state saved with `persistence` once the node ID is
+/// known. A state that cannot be restored is logged and the node starts empty.
+pub fn persisted_init<'a>(
+    persistence: Option<&'a Persistence<'a>>,
+    init_handler: impl Fn(&str, &[String]) + Send + Sync + 'a,
+) -> InitHandler<'a> {
+    Box::new(move |id, ids| {
+        init_handler(id, ids);
+        if let Some(persistence) = persistence {
+            if let Err(e) = persistence.init(id) {
+                warn!("cannot restore saved state: {e:#}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, fs, path::PathBuf, sync::Mutex};
+
+    use anyhow::Result;
+    use serde_json::{json, Value};
+
+    use crate::message::Message;
+    use crate::node::{Handler, Node};
+    use crate::persistence::{persisted, persisted_init, Persist, Persistence};
+
+    #[derive(Default)]
+    struct Register(Mutex<Value>);
+
+    impl Persist for Register {
+        fn snapshot(&self) -> Value {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn restore(&self, snapshot: Value) -> Result<()> {
+            *self.0.lock().unwrap() = snapshot;
+            Ok(())
+        }
+    }
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("maelstrom-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn state_survives_restart() -> Result<()> {
+        let dir = state_dir("restart");
+        let register = Register::default();
+        let persistence = Persistence::new(&dir, &register);
+        persistence.init("n1")?;
+
+        *register.0.lock().unwrap() = json!({ "x": 1 });
+        persistence.save()?;
+        let restarted = Register::default();
+        Persistence::new(&dir, &restarted).init("n1")?;
+        let other_node = Register::default();
+        Persistence::new(&dir, &other_node).init("n2")?;
+
+        assert_eq!(restarted.snapshot(), json!({ "x": 1 }));
+        assert_eq!(other_node.snapshot(), Value::Null);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn handlers_save_before_replying() -> Result<()> {
+        // Tests that the state a handler changed is on disk by the time the node replies.
+        let dir = state_dir("handlers");
+        let register = Register::default();
+        let persistence = Persistence::new(&dir, &register);
+        let node = {
+            let mut funs: HashMap<String, Handler> = HashMap::new();
+            funs.insert(
+                "write".into(),
+                Box::new(|msg: Message, _| {
+                    *register.0.lock().unwrap() = msg.body.extra["value"].clone();
+                    Ok(msg)
+                }),
+            );
+            Node::builder()
+                .handlers(persisted(Some(&persistence), funs))
+                .on_init(persisted_init(Some(&persistence), |_, _| {}))
+                .build()?
+        };
+        node.handle(serde_json::from_value(json!({
+            "src": "c0", "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }
+        }))?)?;
+
+        node.handle(serde_json::from_value(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "write", "msg_id": 2, "value": 7 }
+        }))?)?;
+
+        let saved: Value = serde_json::from_slice(&fs::read(dir.join("n1.json"))?)?;
+        assert_eq!(saved, json!(7));
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::{
+    body,
+    message::{Body, Message},
+    node::Handler,
+    rpc::Rpc,
+};
+
+/// A value with the version it was written at. Versions are (timestamp, node) pairs, e.g. from
+/// a [`LamportClock`](crate::clock::LamportClock), so no two writes have the same version and
+/// the newest value is the one with the largest version.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Versioned {
+    pub version: (u64, String),
+    pub value: Value,
+}
+
+/// Replicated key value state kept on this node, for [`Quorum`] clients on other nodes.
+///
+/// Every key keeps the value with the largest version written to it, so writes can arrive in
+/// any order and more than once.
+#[derive(Debug, Default)]
+pub struct Replica {
+    store: Mutex<HashMap<String, Versioned>>,
+}
+
+/// Returns the handlers of a replica, backed by `replica`.
+pub fn handlers(replica: &Replica) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    for typ in ["replica_read", "replica_write"] {
+        funs.insert(typ.into(), Box::new(|msg, id| replica.handle(msg, id)));
+    }
+    funs
+}
+
+impl Replica {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of `key` on this replica.
+    pub fn get(&self, key: &str) -> Option<Versioned> {
+        self.store.lock().unwrap().get(key).cloned()
+    }
+
+    /// Applies the `replica_read` or `replica_write` in `op`, returns the body of the reply.
+    pub fn apply(&self, op: &Body) -> Result<Body> {
+        let key = op.get_str("key")?;
+        match op.typ.as_str() {
+            "replica_read" => Ok(body!("replica_read_ok", { "value": self.get(key) })),
+            "replica_write" => {
+                let value: Versioned = op.get_as("value")?;
+                let mut store = self.store.lock().unwrap();
+                if store
+                    .get(key)
+                    .is_none_or(|held| held.version < value.version)
+                {
+                    store.insert(key.to_string(), value);
+                }
+                Ok(body!("replica_write_ok", {}))
+            }
+            typ => Err(anyhow!("replica cannot apply {typ}: {:?}", op)),
+        }
+    }
+
+    fn handle(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let mut body = self.apply(&msg.body)?;
+        body.msg_id = msg_id;
+        Ok(msg.reply_with(body))
+    }
+}
+
+/// Reads and writes keys replicated on a set of [`Replica`] nodes, waiting for a majority.
+///
+/// A write is sent to every replica and succeeds once a majority stored it, a read asks every
+/// replica and returns the newest value of a majority. Every majority overlaps the majority of
+/// the last successful write, so reads see it.
+///
+/// With [`Quorum::with_read_repair`] a read that finds replicas with an older value, or none,
+/// writes the newest value back to them and to the replicas that did not answer in time, in the
+/// background, so replicas that missed a write
+/// catch up without waiting for the next write of the key.
+#[derive(Debug)]
+pub struct Quorum<R> {
+    rpc: Arc<R>,
+    replicas: Vec<String>,
+    read_repair: bool,
+}
+
+impl<R: Rpc + Send + Sync + 'static> Quorum<R> {
+    /// Creates a client of the keys replicated on `replicas`, through `rpc`.
+    pub fn new(rpc: Arc<R>, replicas: Vec<String>) -> Self {
+        Self {
+            rpc,
+            replicas,
+            read_repair: false,
+        }
+    }
+
+    /// Writes the newest value back to stale replicas found by reads.
+    pub fn with_read_repair(mut self) -> Self {
+        self.read_repair = true;
+        self
+    }
+
+    /// Number of replicas that make a majority.
+    fn majority(&self) -> usize {
+        self.replicas.len() / 2 + 1
+    }
+
+    /// Sends `op` to every replica, returns the replies of the first majority to answer with
+    /// their replica, replies of type `error` included.
+    fn ask_majority(&self, op: Body) -> Result<Vec<(String, Body)>> {
+        let (sender, replies) = mpsc::channel();
+        for replica in &self.replicas {
+            let (rpc, replica, op, sender) = (
+                self.rpc.clone(),
+                replica.clone(),
+                op.clone(),
+                sender.clone(),
+            );
+            thread::spawn(move || {
+                let reply = rpc.call(&replica, op);
+                // The quorum may be reached already, then the reply is dropped.
+                let _ = sender.send((replica, reply));
+            });
+        }
+        drop(sender);
+
+        let mut answered = Vec::new();
+        for (replica, reply) in replies {
+            match reply {
+                Ok(reply) => answered.push((replica, reply)),
+                Err(e) => debug!(%replica, "replica did not answer: {e}"),
+            }
+            if answered.len() >= self.majority() {
+                return Ok(answered);
+            }
+        }
+        Err(anyhow!(
+            "Unavailable: only {} of {} replicas answered",
+            answered.len(),
+            self.replicas.len()
+        ))
+    }
+
+    /// Reads the newest value of `key` on a majority of replicas, None if none has a value.
+    pub fn read(&self, key: &str) -> Result<Option<Versioned>> {
+        let replies = self.ask_majority(body!("replica_read", { "key": key }))?;
+        let mut values = Vec::with_capacity(replies.len());
+        for (replica, reply) in replies {
+            let value: Option<Versioned> = reply.get_as("value")?;
+            values.push((replica, value));
+        }
+        let newest = values
+            .iter()
+            .filter_map(|(_, value)| value.clone())
+            .max_by(|a, b| a.version.cmp(&b.version));
+
+        if let (true, Some(newest)) = (self.read_repair, &newest) {
+            // Replicas that did not answer in time may be stale too, writes of a value they
+            // already have change nothing.
+            let stale: Vec<String> = self
+                .replicas
+                .iter()
+                .filter(|&replica| {
+                    !values
+                        .iter()
+                        .any(|(r, value)| r == replica && value.as_ref() == Some(newest))
+                })
+                .cloned()
+                .collect();
+            if !stale.is_empty() {
+                self.repair(key, newest.clone(), stale);
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Writes `value` to `key` on a majority of replicas.
+    pub fn write(&self, key: &str, value: Versioned) -> Result<()> {
+        let replies = self.ask_majority(body!("replica_write", { "key": key, "value": value }))?;
+        match replies.into_iter().find(|(_, reply)| reply.typ == "error") {
+            Some((replica, reply)) => Err(anyhow!("replica {replica} failed write: {:?}", reply)),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes `newest` to `key` on every `stale` replica, in the background.
+    fn repair(&self, key: &str, newest: Versioned, stale: Vec<String>) {
+        debug!(key, ?stale, "repairing stale replicas");
+        let rpc = self.rpc.clone();
+        let write = body!("replica_write", { "key": key, "value": newest });
+        thread::spawn(move || {
+            for replica in stale {
+                if let Err(e) = rpc.call(&replica, write.clone()) {
+                    warn!(%replica, "read repair failed: {e}");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::{anyhow, Result};
+    use serde_json::json;
+
+    use crate::message::Body;
+    use crate::quorum::{Quorum, Replica, Versioned};
+    use crate::rpc::Rpc;
+
+    /// Replicas called in place of sending them messages, the ones not in the map are down.
+    #[derive(Default)]
+    struct Replicas(HashMap<String, Replica>);
+
+    impl Rpc for Replicas {
+        fn call(&self, dest: &str, body: Body) -> Result<Body> {
+            let replica = self.0.get(dest).ok_or(anyhow!("Timeout: {dest} is down"))?;
+            replica.apply(&body)
+        }
+    }
+
+    fn versioned(timestamp: u64, value: u64) -> Versioned {
+        Versioned {
+            version: (timestamp, "n1".into()),
+            value: json!(value),
+        }
+    }
+
+    fn replicas(up: &[&str]) -> Arc<Replicas> {
+        Arc::new(Replicas(
+            up.iter()
+                .map(|&id| (id.to_string(), Replica::new()))
+                .collect(),
+        ))
+    }
+
+    fn ids() -> Vec<String> {
+        vec!["n1".into(), "n2".into(), "n3".into()]
+    }
+
+    #[test]
+    fn reads_see_newest_write_of_majority() -> Result<()> {
+        let rpc = replicas(&["n1", "n2"]);
+        let quorum = Quorum::new(rpc.clone(), ids());
+
+        quorum.write("k", versioned(2, 20))?;
+        quorum.write("k", versioned(1, 10))?;
+
+        assert_eq!(quorum.read("k")?, Some(versioned(2, 20)));
+        assert_eq!(quorum.read("missing")?, None);
+        let down = Quorum::new(replicas(&["n1"]), ids());
+        assert!(down
+            .read("k")
+            .is_err_and(|e| e.to_string().starts_with("Unavailable")));
+        Ok(())
+    }
+
+    #[test]
+    fn read_repair_updates_stale_replicas() -> Result<()> {
+        // Tests that a read finding replicas without the newest value writes it to them, and
+        // that reads without read repair leave them stale.
+        let rpc = replicas(&["n1", "n2", "n3"]);
+        let apply = |replica: &str, op: serde_json::Value| {
+            let op: Body = serde_json::from_value(op).expect("invalid op json.");
+            rpc.0[replica].apply(&op)
+        };
+        apply(
+            "n1",
+            json!({ "type": "replica_write", "key": "k", "value": versioned(2, 20) }),
+        )?;
+        apply(
+            "n2",
+            json!({ "type": "replica_write", "key": "k", "value": versioned(1, 10) }),
+        )?;
+
+        let quorum = Quorum::new(rpc.clone(), ids());
+        quorum.read("k")?;
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(rpc.0["n2"].get("k"), Some(versioned(1, 10)));
+
+        let quorum = quorum.with_read_repair();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let repaired = |replica: &str| rpc.0[replica].get("k") == Some(versioned(2, 20));
+        while !repaired("n2") || !repaired("n3") {
+            quorum.read("k")?;
+            assert!(
+                Instant::now() < deadline,
+                "stale replicas were not repaired"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+}
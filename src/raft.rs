@@ -0,0 +1,1217 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::message::{Body, Message};
+
+/// Timing of a Raft node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    // A follower that hears nothing from a leader for a random duration in this range starts
+    // an election.
+    pub election_timeout: Range<Duration>,
+    // How often a leader sends heartbeats, must be well below the election timeout.
+    pub heartbeat_interval: Duration,
+    // Once the log has more entries than this, the applied entries are replaced by a snapshot
+    // of the state machine.
+    pub max_log_entries: usize,
+    // Whether a follower whose election timeout passes first asks its peers if they would vote
+    // for it, and only starts an election, bumping its term, once a majority would.
+    pub pre_vote: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            election_timeout: Duration::from_millis(150)..Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            max_log_entries: 1000,
+            pre_vote: true,
+        }
+    }
+}
+
+/// Role of a Raft node in its current term.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    // A follower asking its peers if they would vote for it, see `Config::pre_vote`.
+    PreCandidate,
+    Candidate,
+    Leader,
+}
+
+/// The state machine replicated by Raft, committed commands are applied to it in log order on
+/// every node.
+pub trait StateMachine {
+    /// Applies a committed command and returns its result. Must be deterministic, so every node
+    /// ends up in the same state.
+    fn apply(&mut self, command: &Value) -> Value;
+
+    /// Serializes the current state, used to truncate the log.
+    fn snapshot(&self) -> Value;
+
+    /// Replaces the current state with a `snapshot` from [`StateMachine::snapshot`].
+    fn restore(&mut self, snapshot: &Value) -> Result<()>;
+}
+
+/// An entry of the Raft log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    // Term of the leader that created the entry.
+    pub term: u64,
+    pub command: Value,
+}
+
+/// The result of applying a committed entry to the state machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Applied {
+    pub index: u64,
+    // Term of the entry, a proposal whose entry was replaced by another leader's entry at the
+    // same index sees a different term here.
+    pub term: u64,
+    pub result: Value,
+}
+
+/// The Raft consensus algorithm: leader election and log replication.
+///
+/// `Raft` is a state machine, it does no IO and reads no clock itself. Incoming Raft messages are
+/// fed to [`Raft::handle`] and time is advanced with [`Raft::tick`], both send the resulting
+/// messages to other nodes through the outbox. This keeps it usable from any runtime and
+/// deterministic in tests.
+///
+/// Commands are added to the log of the leader with [`Raft::propose`]. Once replicated on a
+/// majority they are committed and applied to the [`StateMachine`] on every node, the results are
+/// collected with [`Raft::take_applied`].
+///
+/// With `Config::pre_vote` a follower whose election timeout passes runs a pre-vote first: it
+/// asks its peers if they would vote for it in the next term, without bumping its own term or
+/// theirs. Peers that heard from a leader within the shortest election timeout say no, so a
+/// node coming back from a partition cannot depose a stable leader by bumping terms, it
+/// follows the leader on its next heartbeat instead.
+///
+/// Log indexes start at 1, index 0 is the empty log. When the log grows past
+/// `Config::max_log_entries` the applied entries are replaced by a snapshot of the state machine,
+/// followers that are missing entries already in the snapshot are sent the snapshot instead.
+///
+/// Messages:
+///   - `pre_vote {term, candidate_id, last_log_index, last_log_term}`, sent by pre-candidates
+///     to all peers, `term` is the term the pre-candidate would start an election in.
+///   - `pre_vote_ok {term, vote_granted}`, the reply to `pre_vote`, `term` is the term proposed
+///     if the vote is granted, so grants are counted only in the round they were asked in, and
+///     the voter's term if not.
+///   - `request_vote {term, candidate_id, last_log_index, last_log_term}`, sent by candidates
+///     to all peers.
+///   - `request_vote_ok {term, vote_granted}`, the reply to `request_vote`.
+///   - `append_entries {term, leader_id, prev_log_index, prev_log_term, entries, leader_commit}`,
+///     sent by the leader to replicate entries, or as heartbeats without entries.
+///   - `append_entries_ok {term, success, match_index}`, the reply to `append_entries`.
+///   - `install_snapshot {term, leader_id, last_included_index, last_included_term, data}`,
+///     sent by the leader to followers missing entries that were truncated.
+///   - `install_snapshot_ok {term, match_index}`, the reply to `install_snapshot`.
+#[derive(Debug)]
+pub struct Raft<S> {
+    // ID of this node.
+    id: String,
+    // IDs of all other nodes in the cluster.
+    peers: Vec<String>,
+    config: Config,
+
+    // Latest term this node has seen.
+    term: u64,
+    // Candidate this node voted for in the current term.
+    voted_for: Option<String>,
+    role: Role,
+    // Leader of the current term, if known.
+    leader: Option<String>,
+    // Votes recieved in the current term when a candidate, or pre-votes when a pre-candidate.
+    votes: HashSet<String>,
+    // When this node last heard from the leader of its term.
+    leader_contact: Option<Instant>,
+
+    // Entries after the snapshot, the entry at index i is log[i - snapshot_index - 1].
+    log: Vec<Entry>,
+    // Index and term of the last entry included in the snapshot.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    // State machine snapshot at snapshot_index.
+    snapshot: Value,
+    // Index of the highest entry known to be committed.
+    commit_index: u64,
+    // Index of the highest entry applied to the state machine.
+    last_applied: u64,
+    state_machine: S,
+    // Results of applied entries not yet taken by the caller.
+    applied: Vec<Applied>,
+
+    // Leader only, per peer: index of the next entry to send.
+    next_index: HashMap<String, u64>,
+    // Leader only, per peer: index of the highest entry known to be replicated.
+    match_index: HashMap<String, u64>,
+
+    // When a follower or candidate starts a new election.
+    election_deadline: Instant,
+    // When a leader sends its next heartbeats.
+    heartbeat_deadline: Instant,
+
+    // Randomizes election timeouts, seeded so runs can be reproduced.
+    rng: StdRng,
+    // Where messages to other nodes are sent.
+    outbox: Sender<Message>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct RequestVote {
+    term: u64,
+    candidate_id: String,
+    last_log_index: u64,
+    last_log_term: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct RequestVoteOk {
+    term: u64,
+    vote_granted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct AppendEntries {
+    term: u64,
+    leader_id: String,
+    // Index and term of the entry just before `entries`.
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<Entry>,
+    leader_commit: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct InstallSnapshot {
+    term: u64,
+    leader_id: String,
+    last_included_index: u64,
+    last_included_term: u64,
+    data: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct InstallSnapshotOk {
+    term: u64,
+    // Index of the last entry in the installed snapshot.
+    match_index: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct AppendEntriesOk {
+    term: u64,
+    success: bool,
+    // Index of the last entry matching the leader's log, when successful.
+    match_index: u64,
+}
+
+impl<S: StateMachine> Raft<S> {
+    /// Creates a follower in term 0 with an empty log.
+    ///
+    /// Args:
+    ///   - id, node_ids: The ID of this node and of all nodes in the cluster (may include id).
+    ///   - seed: Seeds the randomized election timeouts.
+    ///   - now: The current time, the first election timeout starts from it.
+    pub fn new(
+        id: &str,
+        node_ids: &[String],
+        config: Config,
+        state_machine: S,
+        seed: u64,
+        outbox: Sender<Message>,
+        now: Instant,
+    ) -> Self {
+        let mut raft = Self {
+            id: id.to_string(),
+            peers: node_ids.iter().filter(|&n| n != id).cloned().collect(),
+            config,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            leader_contact: None,
+            log: vec![],
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot: Value::Null,
+            commit_index: 0,
+            last_applied: 0,
+            state_machine,
+            applied: vec![],
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_deadline: now,
+            heartbeat_deadline: now,
+            rng: StdRng::seed_from_u64(seed),
+            outbox,
+        };
+        raft.reset_election_deadline(now);
+        raft
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The leader of the current term, if known.
+    pub fn leader(&self) -> Option<&str> {
+        match self.role {
+            Role::Leader => Some(&self.id),
+            _ => self.leader.as_deref(),
+        }
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+
+    /// Number of entries kept in the log, entries in the snapshot are not counted.
+    pub fn log_len(&self) -> usize {
+        self.log.len()
+    }
+
+    /// Appends `command` to the log, only the leader can do so. Returns the index of the
+    /// new entry, its result shows up in [`Raft::take_applied`] once committed.
+    pub fn propose(&mut self, command: Value) -> Result<u64> {
+        if self.role != Role::Leader {
+            return Err(anyhow!(
+                "NotLeader: {} cannot propose, leader is {:?}",
+                self.id,
+                self.leader
+            ));
+        }
+
+        self.log.push(Entry {
+            term: self.term,
+            command,
+        });
+        let index = self.last_log_index();
+        if self.peers.is_empty() {
+            self.advance_commit_index();
+        }
+        for peer in self.peers.clone() {
+            self.send_append_entries(&peer)?;
+        }
+        Ok(index)
+    }
+
+    /// Takes the results of the entries applied since the last call, in log order.
+    pub fn take_applied(&mut self) -> Vec<Applied> {
+        std::mem::take(&mut self.applied)
+    }
+
+    /// Advances time to `now`: starts an election if the election timeout passed, or sends
+    /// heartbeats if this node is the leader.
+    pub fn tick(&mut self, now: Instant) -> Result<()> {
+        match self.role {
+            Role::Leader if now >= self.heartbeat_deadline => self.send_heartbeats(now),
+            Role::Follower | Role::PreCandidate | Role::Candidate
+                if now >= self.election_deadline =>
+            {
+                match self.config.pre_vote {
+                    true => self.start_pre_vote(now),
+                    false => self.start_election(now),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles a Raft message from another node.
+    pub fn handle(&mut self, msg: &Message, now: Instant) -> Result<()> {
+        match msg.body.typ.as_str() {
+            "pre_vote" => self.pre_vote(msg, parse(msg)?, now),
+            "pre_vote_ok" => self.pre_vote_ok(msg, parse(msg)?, now),
+            "request_vote" => self.request_vote(msg, parse(msg)?, now),
+            "request_vote_ok" => self.request_vote_ok(msg, parse(msg)?, now),
+            "append_entries" => self.append_entries(msg, parse(msg)?, now),
+            "append_entries_ok" => self.append_entries_ok(msg, parse(msg)?),
+            "install_snapshot" => self.install_snapshot(msg, parse(msg)?, now),
+            "install_snapshot_ok" => self.install_snapshot_ok(msg, parse(msg)?),
+            typ => Err(anyhow!("not a raft message type {typ}: {:?}", msg)),
+        }
+    }
+
+    fn last_log_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    /// Position of the entry at `index` in `log`.
+    fn position(&self, index: u64) -> usize {
+        (index - self.snapshot_index - 1) as usize
+    }
+
+    /// Term of the entry at `index`, None if the entry is in the snapshot or past the end of the
+    /// log. The empty log has term 0.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index {
+            return None;
+        }
+        self.log.get(self.position(index)).map(|e| e.term)
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.last_log_index()).unwrap_or_default()
+    }
+
+    fn reset_election_deadline(&mut self, now: Instant) {
+        let timeout = self.rng.gen_range(self.config.election_timeout.clone());
+        self.election_deadline = now + timeout;
+    }
+
+    /// Steps down to follower if `term` is newer than ours.
+    fn observe_term(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+    }
+
+    /// Asks every peer if it would vote for this node in the next term, the election starts
+    /// once a majority would.
+    fn start_pre_vote(&mut self, now: Instant) -> Result<()> {
+        debug!(term = self.term + 1, "starting pre-vote");
+        self.role = Role::PreCandidate;
+        self.leader = None;
+        self.votes = HashSet::from([self.id.clone()]);
+        self.reset_election_deadline(now);
+
+        if self.has_quorum(self.votes.len()) {
+            return self.start_election(now);
+        }
+        let request = RequestVote {
+            term: self.term + 1,
+            candidate_id: self.id.clone(),
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+        };
+        for peer in self.peers.clone() {
+            self.send(&peer, "pre_vote", &request)?;
+        }
+        Ok(())
+    }
+
+    fn start_election(&mut self, now: Instant) -> Result<()> {
+        self.term += 1;
+        debug!(term = self.term, "starting election");
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id.clone());
+        self.votes = HashSet::from([self.id.clone()]);
+        self.reset_election_deadline(now);
+
+        if self.has_quorum(self.votes.len()) {
+            return self.become_leader(now);
+        }
+        let request = RequestVote {
+            term: self.term,
+            candidate_id: self.id.clone(),
+            last_log_index: self.last_log_index(),
+            last_log_term: self.last_log_term(),
+        };
+        for peer in self.peers.clone() {
+            self.send(&peer, "request_vote", &request)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `count` nodes are a majority of the cluster.
+    fn has_quorum(&self, count: usize) -> bool {
+        count * 2 > self.peers.len() + 1
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Result<()> {
+        info!(term = self.term, "became leader");
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        let next = self.last_log_index() + 1;
+        self.next_index = self.peers.iter().map(|p| (p.clone(), next)).collect();
+        self.match_index = self.peers.iter().map(|p| (p.clone(), 0)).collect();
+        self.send_heartbeats(now)
+    }
+
+    /// Sends every peer the entries it is missing, or an empty heartbeat if it has them all.
+    fn send_heartbeats(&mut self, now: Instant) -> Result<()> {
+        self.heartbeat_deadline = now + self.config.heartbeat_interval;
+        for peer in self.peers.clone() {
+            self.send_append_entries(&peer)?;
+        }
+        Ok(())
+    }
+
+    fn send_append_entries(&self, peer: &str) -> Result<()> {
+        let next = self.next_index.get(peer).copied().unwrap_or(1).max(1);
+        if next <= self.snapshot_index {
+            // The entries the peer needs were truncated, send the snapshot instead.
+            let request = InstallSnapshot {
+                term: self.term,
+                leader_id: self.id.clone(),
+                last_included_index: self.snapshot_index,
+                last_included_term: self.snapshot_term,
+                data: self.snapshot.clone(),
+            };
+            return self.send(peer, "install_snapshot", &request);
+        }
+
+        let request = AppendEntries {
+            term: self.term,
+            leader_id: self.id.clone(),
+            prev_log_index: next - 1,
+            prev_log_term: self.term_at(next - 1).unwrap_or_default(),
+            entries: self.log[self.position(next)..].to_vec(),
+            leader_commit: self.commit_index,
+        };
+        self.send(peer, "append_entries", &request)
+    }
+
+    /// Whether the log of a candidate that has `request` has every entry we have. Votes only
+    /// go to such candidates, so the elected leader has all committed entries.
+    fn up_to_date(&self, request: &RequestVote) -> bool {
+        (request.last_log_term, request.last_log_index)
+            >= (self.last_log_term(), self.last_log_index())
+    }
+
+    /// Answers whether we would vote for the pre-candidate, without changing our term or vote.
+    fn pre_vote(&mut self, msg: &Message, request: RequestVote, now: Instant) -> Result<()> {
+        // A leader heard from recently is still there, the pre-candidate is cut off from it.
+        let leader_alive = self.role == Role::Leader
+            || self
+                .leader_contact
+                .is_some_and(|contact| now < contact + self.config.election_timeout.start);
+        let vote_granted = request.term > self.term && !leader_alive && self.up_to_date(&request);
+        let reply = RequestVoteOk {
+            term: match vote_granted {
+                true => request.term,
+                false => self.term,
+            },
+            vote_granted,
+        };
+        self.send(&msg.src, "pre_vote_ok", &reply)
+    }
+
+    fn pre_vote_ok(&mut self, msg: &Message, reply: RequestVoteOk, now: Instant) -> Result<()> {
+        if !reply.vote_granted {
+            self.observe_term(reply.term);
+            return Ok(());
+        }
+        // A grant proposing another term is a late reply to an earlier pre-vote.
+        if self.role != Role::PreCandidate || reply.term != self.term + 1 {
+            return Ok(());
+        }
+
+        self.votes.insert(msg.src.clone());
+        if self.has_quorum(self.votes.len()) {
+            return self.start_election(now);
+        }
+        Ok(())
+    }
+
+    fn request_vote(&mut self, msg: &Message, request: RequestVote, now: Instant) -> Result<()> {
+        self.observe_term(request.term);
+
+        let up_to_date = self.up_to_date(&request);
+        let vote_granted = request.term == self.term
+            && up_to_date
+            && self
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted| *voted == request.candidate_id);
+        if vote_granted {
+            self.voted_for = Some(request.candidate_id);
+            self.reset_election_deadline(now);
+        }
+
+        let reply = RequestVoteOk {
+            term: self.term,
+            vote_granted,
+        };
+        self.send(&msg.src, "request_vote_ok", &reply)
+    }
+
+    fn request_vote_ok(&mut self, msg: &Message, reply: RequestVoteOk, now: Instant) -> Result<()> {
+        self.observe_term(reply.term);
+        if self.role != Role::Candidate || reply.term != self.term || !reply.vote_granted {
+            return Ok(());
+        }
+
+        self.votes.insert(msg.src.clone());
+        if self.has_quorum(self.votes.len()) {
+            return self.become_leader(now);
+        }
+        Ok(())
+    }
+
+    fn append_entries(
+        &mut self,
+        msg: &Message,
+        request: AppendEntries,
+        now: Instant,
+    ) -> Result<()> {
+        self.observe_term(request.term);
+
+        let mut reply = AppendEntriesOk {
+            term: self.term,
+            success: false,
+            match_index: 0,
+        };
+        if request.term < self.term {
+            return self.send(&msg.src, "append_entries_ok", &reply);
+        }
+
+        // A candidate that hears from the leader of its term steps down.
+        self.role = Role::Follower;
+        self.leader = Some(request.leader_id);
+        self.leader_contact = Some(now);
+        self.reset_election_deadline(now);
+
+        // Entries in our snapshot are committed, so they match the leader's log.
+        let prev = request.prev_log_index;
+        if prev > self.last_log_index()
+            || self
+                .term_at(prev)
+                .is_some_and(|term| term != request.prev_log_term)
+        {
+            // Our log diverges before the new entries, the leader retries from further back.
+            return self.send(&msg.src, "append_entries_ok", &reply);
+        }
+
+        let matched = prev + request.entries.len() as u64;
+        for (offset, entry) in request.entries.into_iter().enumerate() {
+            let index = prev + 1 + offset as u64;
+            if index <= self.last_log_index() {
+                if self.term_at(index).is_none_or(|term| term == entry.term) {
+                    continue;
+                }
+                // Conflicting entry, drop it and everything after it.
+                self.log.truncate(self.position(index));
+            }
+            self.log.push(entry);
+        }
+
+        reply.success = true;
+        reply.match_index = matched;
+        if request.leader_commit > self.commit_index {
+            // Only entries known to match the leader can be committed.
+            self.commit_index = self.commit_index.max(request.leader_commit.min(matched));
+            self.apply_committed();
+        }
+        self.send(&msg.src, "append_entries_ok", &reply)
+    }
+
+    fn append_entries_ok(&mut self, msg: &Message, reply: AppendEntriesOk) -> Result<()> {
+        self.observe_term(reply.term);
+        if self.role != Role::Leader || reply.term != self.term {
+            return Ok(());
+        }
+
+        let peer = msg.src.clone();
+        if reply.success {
+            let matched = self.match_index.entry(peer.clone()).or_default();
+            *matched = (*matched).max(reply.match_index);
+            let next = *matched + 1;
+            self.next_index.insert(peer, next);
+            self.advance_commit_index();
+            return Ok(());
+        }
+
+        // The peer's log diverges, back up one entry and try again.
+        let next = self.next_index.entry(peer.clone()).or_insert(1);
+        *next = next.saturating_sub(1).max(1);
+        self.send_append_entries(&peer)
+    }
+
+    /// Commits the highest entry of the current term replicated on a majority.
+    ///
+    /// Entries of older terms are only committed indirectly, by committing a later entry.
+    fn advance_commit_index(&mut self) {
+        for index in (self.commit_index + 1..=self.last_log_index()).rev() {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|&&m| m >= index).count();
+            if self.has_quorum(replicas) {
+                self.commit_index = index;
+                break;
+            }
+        }
+        self.apply_committed();
+    }
+
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.position(self.last_applied)];
+            let result = self.state_machine.apply(&entry.command);
+            self.applied.push(Applied {
+                index: self.last_applied,
+                term: entry.term,
+                result,
+            });
+        }
+        self.maybe_snapshot();
+    }
+
+    /// Replaces the applied entries with a snapshot once the log is too long.
+    fn maybe_snapshot(&mut self) {
+        if self.log.len() <= self.config.max_log_entries || self.last_applied == self.snapshot_index
+        {
+            return;
+        }
+        let term = self.term_at(self.last_applied).unwrap_or_default();
+        let applied = self.position(self.last_applied) + 1;
+        self.log.drain(..applied);
+        self.snapshot = self.state_machine.snapshot();
+        self.snapshot_index = self.last_applied;
+        self.snapshot_term = term;
+    }
+
+    fn install_snapshot(
+        &mut self,
+        msg: &Message,
+        request: InstallSnapshot,
+        now: Instant,
+    ) -> Result<()> {
+        self.observe_term(request.term);
+        if request.term < self.term {
+            let reply = InstallSnapshotOk {
+                term: self.term,
+                match_index: 0,
+            };
+            return self.send(&msg.src, "install_snapshot_ok", &reply);
+        }
+
+        self.role = Role::Follower;
+        self.leader = Some(request.leader_id);
+        self.leader_contact = Some(now);
+        self.reset_election_deadline(now);
+
+        let index = request.last_included_index;
+        if index > self.snapshot_index {
+            if self.term_at(index) == Some(request.last_included_term) {
+                // We have the snapshot's last entry, keep the entries after it.
+                let included = self.position(index) + 1;
+                self.log.drain(..included);
+            } else {
+                self.log.clear();
+            }
+            if index > self.last_applied {
+                self.state_machine.restore(&request.data)?;
+                self.last_applied = index;
+            }
+            self.snapshot_index = index;
+            self.snapshot_term = request.last_included_term;
+            self.snapshot = request.data;
+            self.commit_index = self.commit_index.max(index);
+        }
+
+        let reply = InstallSnapshotOk {
+            term: self.term,
+            match_index: index,
+        };
+        self.send(&msg.src, "install_snapshot_ok", &reply)
+    }
+
+    fn install_snapshot_ok(&mut self, msg: &Message, reply: InstallSnapshotOk) -> Result<()> {
+        self.observe_term(reply.term);
+        if self.role != Role::Leader || reply.term != self.term {
+            return Ok(());
+        }
+
+        let matched = self.match_index.entry(msg.src.clone()).or_default();
+        *matched = (*matched).max(reply.match_index);
+        let next = *matched + 1;
+        self.next_index.insert(msg.src.clone(), next);
+        self.advance_commit_index();
+        // Send whatever was appended after the snapshot right away.
+        self.send_append_entries(&msg.src)
+    }
+
+    fn send<T: Serialize>(&self, dest: &str, typ: &str, body: &T) -> Result<()> {
+        let extra = match serde_json::to_value(body)? {
+            Value::Object(extra) => extra,
+            other => return Err(anyhow!("raft body must be an object, got {other}")),
+        };
+        self.outbox.send(Message {
+            src: self.id.clone(),
+            dest: dest.to_string(),
+            body: Body {
+                typ: typ.to_string(),
+                extra,
+                ..Default::default()
+            },
+        })?;
+        Ok(())
+    }
+}
+
+fn parse<T: DeserializeOwned>(msg: &Message) -> Result<T> {
+    Ok(serde_json::from_value(Value::Object(
+        msg.body.extra.clone(),
+    ))?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::mpsc::{self, Receiver},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+
+    use serde_json::{json, Value};
+
+    use crate::lin_kv::LinKv;
+    use crate::message::Message;
+    use crate::raft::{Config, Raft, Role, StateMachine};
+
+    /// Raft nodes connected through their outboxes.
+    struct Cluster {
+        nodes: HashMap<String, Raft<LinKv>>,
+        outboxes: Vec<Receiver<Message>>,
+        now: Instant,
+        // Nodes whose messages are dropped.
+        partitioned: Vec<String>,
+    }
+
+    impl Cluster {
+        fn new(size: usize) -> Self {
+            Self::with_config(size, Config::default())
+        }
+
+        fn with_config(size: usize, config: Config) -> Self {
+            let ids: Vec<String> = (1..=size).map(|i| format!("n{i}")).collect();
+            let now = Instant::now();
+            let mut nodes = HashMap::new();
+            let mut outboxes = vec![];
+            for (seed, id) in ids.iter().enumerate() {
+                let (tx, rx) = mpsc::channel();
+                nodes.insert(
+                    id.clone(),
+                    Raft::new(id, &ids, config.clone(), LinKv::new(), seed as u64, tx, now),
+                );
+                outboxes.push(rx);
+            }
+            Self {
+                nodes,
+                outboxes,
+                now,
+                partitioned: vec![],
+            }
+        }
+
+        /// Delivers messages until there are none left.
+        fn deliver(&mut self) -> Result<()> {
+            loop {
+                let msgs: Vec<Message> =
+                    self.outboxes.iter().flat_map(|rx| rx.try_iter()).collect();
+                if msgs.is_empty() {
+                    return Ok(());
+                }
+                for msg in msgs {
+                    if self.partitioned.contains(&msg.src) || self.partitioned.contains(&msg.dest) {
+                        continue;
+                    }
+                    let now = self.now;
+                    self.nodes.get_mut(&msg.dest).unwrap().handle(&msg, now)?;
+                }
+            }
+        }
+
+        /// Advances time by `step` `times` times, ticking and delivering after each step.
+        fn run(&mut self, step: Duration, times: usize) -> Result<()> {
+            for _ in 0..times {
+                self.now += step;
+                let now = self.now;
+                for node in self.nodes.values_mut() {
+                    node.tick(now)?;
+                }
+                self.deliver()?;
+            }
+            Ok(())
+        }
+
+        fn leader_id(&self) -> String {
+            self.leaders()[0].id().to_string()
+        }
+
+        /// Proposes `command` on the current leader.
+        fn propose(&mut self, command: Value) -> Result<u64> {
+            let leader = self.leader_id();
+            self.nodes.get_mut(&leader).unwrap().propose(command)
+        }
+
+        fn leaders(&self) -> Vec<&Raft<LinKv>> {
+            self.nodes
+                .values()
+                .filter(|n| n.role() == Role::Leader && !self.partitioned.contains(&n.id))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn single_node_elects_itself() -> Result<()> {
+        let mut cluster = Cluster::new(1);
+
+        cluster.run(Duration::from_millis(10), 50)?;
+
+        assert_eq!(cluster.nodes["n1"].role(), Role::Leader);
+        assert_eq!(cluster.nodes["n1"].term(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn cluster_elects_one_leader() -> Result<()> {
+        // Tests that a cluster converges on a single leader that everyone follows.
+        let mut cluster = Cluster::new(5);
+
+        cluster.run(Duration::from_millis(10), 100)?;
+
+        let leaders = cluster.leaders();
+        assert_eq!(leaders.len(), 1, "expected exactly one leader");
+        let (leader, term) = (leaders[0].id.clone(), leaders[0].term());
+        for node in cluster.nodes.values() {
+            assert_eq!(node.leader(), Some(leader.as_str()));
+            assert_eq!(node.term(), term);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn heartbeats_keep_leader_stable() -> Result<()> {
+        // Tests that once elected no new elections happen while the leader is reachable.
+        let mut cluster = Cluster::new(3);
+        cluster.run(Duration::from_millis(10), 100)?;
+        let term = cluster.leaders()[0].term();
+
+        cluster.run(Duration::from_millis(10), 500)?;
+
+        assert_eq!(cluster.leaders().len(), 1);
+        assert_eq!(cluster.leaders()[0].term(), term);
+        Ok(())
+    }
+
+    #[test]
+    fn new_leader_elected_when_leader_partitioned() -> Result<()> {
+        let mut cluster = Cluster::new(3);
+        cluster.run(Duration::from_millis(10), 100)?;
+        let old_leader = cluster.leaders()[0].id.clone();
+        let old_term = cluster.leaders()[0].term();
+
+        cluster.partitioned.push(old_leader.clone());
+        cluster.run(Duration::from_millis(10), 100)?;
+
+        let leaders = cluster.leaders();
+        assert_eq!(leaders.len(), 1);
+        assert_ne!(leaders[0].id, old_leader);
+        assert!(leaders[0].term() > old_term);
+        Ok(())
+    }
+
+    #[test]
+    fn pre_vote_keeps_leader_when_partitioned_follower_returns() -> Result<()> {
+        // Tests that a follower cut off from the leader does not bump its term, so it cannot
+        // depose the leader once it is back. Without pre-vote it does.
+        for pre_vote in [true, false] {
+            let mut cluster = Cluster::with_config(
+                3,
+                Config {
+                    pre_vote,
+                    ..Default::default()
+                },
+            );
+            cluster.run(Duration::from_millis(10), 100)?;
+            let (leader, term) = (cluster.leader_id(), cluster.leaders()[0].term());
+            let follower = cluster
+                .nodes
+                .keys()
+                .find(|&id| *id != leader)
+                .unwrap()
+                .clone();
+
+            cluster.partitioned.push(follower.clone());
+            cluster.run(Duration::from_millis(10), 100)?;
+            cluster.partitioned.clear();
+            cluster.run(Duration::from_millis(10), 100)?;
+
+            let stable = cluster.leader_id() == leader && cluster.leaders()[0].term() == term;
+            assert_eq!(stable, pre_vote, "pre_vote: {pre_vote}");
+            assert_eq!(cluster.leaders().len(), 1);
+            assert_eq!(
+                cluster.nodes[&follower].leader(),
+                Some(cluster.leader_id().as_str())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn vote_granted_once_per_term() -> Result<()> {
+        // Tests that a node votes for at most one candidate per term.
+        let (tx, rx) = mpsc::channel();
+        let ids = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        let now = Instant::now();
+        let mut raft = Raft::new("n1", &ids, Config::default(), LinKv::new(), 0, tx, now);
+
+        for candidate in ["n2", "n3"] {
+            let request: Message = serde_json::from_value(json!({
+                "src": candidate, "dest": "n1",
+                "body": {
+                    "type": "request_vote", "term": 1, "candidate_id": candidate,
+                    "last_log_index": 0, "last_log_term": 0
+                }
+            }))?;
+            raft.handle(&request, now)?;
+        }
+
+        let granted: Vec<bool> = rx
+            .try_iter()
+            .map(|m| m.body.extra["vote_granted"].as_bool().unwrap())
+            .collect();
+        assert_eq!(granted, vec![true, false]);
+        Ok(())
+    }
+
+    #[test]
+    fn pre_vote_grants_of_earlier_rounds_are_ignored() -> Result<()> {
+        // Tests that grants proposing the term of an earlier pre-vote round do not count
+        // toward the current round.
+        let (tx, _rx) = mpsc::channel();
+        let ids: Vec<String> = (1..=5).map(|i| format!("n{i}")).collect();
+        let mut now = Instant::now();
+        let mut raft = Raft::new("n1", &ids, Config::default(), LinKv::new(), 0, tx, now);
+        let message = |src: &str, body: Value| -> Result<Message> {
+            Ok(serde_json::from_value(
+                json!({ "src": src, "dest": "n1", "body": body }),
+            )?)
+        };
+        let grant = |src: &str, term: u64| {
+            message(
+                src,
+                json!({ "type": "pre_vote_ok", "term": term, "vote_granted": true }),
+            )
+        };
+
+        now += Duration::from_secs(10);
+        raft.tick(now)?;
+        assert_eq!((raft.role(), raft.term()), (Role::PreCandidate, 0));
+        // A candidate of term 1 wins the election instead, and is lost.
+        raft.handle(
+            &message(
+                "n5",
+                json!({
+                    "type": "request_vote", "term": 1, "candidate_id": "n5",
+                    "last_log_index": 0, "last_log_term": 0
+                }),
+            )?,
+            now,
+        )?;
+        now += Duration::from_secs(10);
+        raft.tick(now)?;
+        assert_eq!((raft.role(), raft.term()), (Role::PreCandidate, 1));
+
+        for src in ["n2", "n3"] {
+            raft.handle(&grant(src, 1)?, now)?;
+        }
+        assert_eq!((raft.role(), raft.term()), (Role::PreCandidate, 1));
+        for src in ["n2", "n3"] {
+            raft.handle(&grant(src, 2)?, now)?;
+        }
+        assert_eq!((raft.role(), raft.term()), (Role::Candidate, 2));
+        Ok(())
+    }
+
+    fn write(key: u64, value: u64) -> Value {
+        json!({ "type": "write", "key": key, "value": value })
+    }
+
+    fn read(key: u64) -> Value {
+        json!({ "type": "read", "key": key })
+    }
+
+    #[test]
+    fn committed_entries_applied_on_every_node() -> Result<()> {
+        // Tests that proposed commands are committed and applied in order on all nodes.
+        let mut cluster = Cluster::new(3);
+        cluster.run(Duration::from_millis(10), 100)?;
+
+        cluster.propose(write(1, 10))?;
+        cluster.propose(write(1, 11))?;
+        let index = cluster.propose(read(1))?;
+        cluster.run(Duration::from_millis(10), 10)?;
+
+        for node in cluster.nodes.values_mut() {
+            assert_eq!(node.commit_index(), index, "node {}", node.id());
+            let applied = node.take_applied();
+            assert_eq!(applied.len(), 3);
+            assert_eq!(applied[2].result["value"], 11);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn propose_on_follower_fails() -> Result<()> {
+        let mut cluster = Cluster::new(3);
+        cluster.run(Duration::from_millis(10), 100)?;
+        let leader = cluster.leader_id();
+
+        let follower = cluster
+            .nodes
+            .values_mut()
+            .find(|n| n.id() != leader)
+            .unwrap();
+        let result = follower.propose(write(1, 1));
+
+        assert!(
+            result
+                .as_ref()
+                .is_err_and(|e| e.to_string().contains("NotLeader")),
+            "expected NotLeader, got {:?}",
+            result
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn entries_not_committed_without_quorum() -> Result<()> {
+        // Tests that a leader cut off from the majority cannot commit, and that its
+        // uncommitted entries are replaced by the new leader's log once it rejoins.
+        let mut cluster = Cluster::new(3);
+        cluster.run(Duration::from_millis(10), 100)?;
+        let old_leader = cluster.leader_id();
+
+        cluster.partitioned.push(old_leader.clone());
+        cluster
+            .nodes
+            .get_mut(&old_leader)
+            .unwrap()
+            .propose(write(1, 1))?;
+        cluster.run(Duration::from_millis(10), 100)?;
+        assert_eq!(cluster.nodes[&old_leader].commit_index(), 0);
+
+        let index = cluster.propose(write(1, 2))?;
+        cluster.run(Duration::from_millis(10), 10)?;
+        cluster.partitioned.clear();
+        cluster.run(Duration::from_millis(10), 50)?;
+
+        for node in cluster.nodes.values_mut() {
+            assert_eq!(node.commit_index(), index, "node {}", node.id());
+            let values: Vec<Value> = node
+                .take_applied()
+                .into_iter()
+                .map(|a| a.result["type"].clone())
+                .collect();
+            assert_eq!(values, vec![json!("write_ok")], "node {}", node.id());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn single_node_commits_immediately() -> Result<()> {
+        let mut cluster = Cluster::new(1);
+        cluster.run(Duration::from_millis(10), 50)?;
+
+        cluster.propose(write(1, 5))?;
+        let node = cluster.nodes.get_mut("n1").unwrap();
+
+        assert_eq!(node.commit_index(), 1);
+        assert_eq!(node.take_applied()[0].result["type"], "write_ok");
+        Ok(())
+    }
+
+    #[test]
+    fn log_truncated_after_snapshot() -> Result<()> {
+        // Tests that the log of every node stays bounded once entries are applied.
+        let config = Config {
+            max_log_entries: 5,
+            ..Default::default()
+        };
+        let mut cluster = Cluster::with_config(3, config);
+        cluster.run(Duration::from_millis(10), 100)?;
+
+        for i in 0..20 {
+            cluster.propose(write(i, i))?;
+            cluster.run(Duration::from_millis(10), 1)?;
+        }
+        cluster.run(Duration::from_millis(10), 10)?;
+
+        for node in cluster.nodes.values() {
+            assert!(
+                node.log_len() <= 5,
+                "node {} log {}",
+                node.id(),
+                node.log_len()
+            );
+            assert_eq!(node.commit_index(), 20);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lagging_follower_catches_up_from_snapshot() -> Result<()> {
+        // Tests that a follower missing truncated entries is sent a snapshot and ends up with
+        // the same state as the others.
+        let config = Config {
+            max_log_entries: 3,
+            ..Default::default()
+        };
+        let mut cluster = Cluster::with_config(3, config);
+        cluster.run(Duration::from_millis(10), 100)?;
+        let leader = cluster.leader_id();
+        let follower = cluster
+            .nodes
+            .keys()
+            .find(|&n| *n != leader)
+            .unwrap()
+            .clone();
+
+        cluster.partitioned.push(follower.clone());
+        for i in 0..10 {
+            cluster.propose(write(i, i * 10))?;
+            cluster.run(Duration::from_millis(10), 1)?;
+        }
+        assert_eq!(cluster.nodes[&follower].commit_index(), 0);
+        cluster.partitioned.clear();
+        cluster.run(Duration::from_millis(10), 10)?;
+
+        let expected = cluster.nodes[&leader].state_machine().snapshot();
+        assert_eq!(cluster.nodes[&follower].commit_index(), 10);
+        assert_eq!(
+            cluster.nodes[&follower].state_machine().snapshot(),
+            expected
+        );
+        Ok(())
+    }
+}
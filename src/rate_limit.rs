@@ -0,0 +1,72 @@
+use std::{sync::Mutex, time::Instant};
+
+/// Token bucket rate limiter.
+///
+/// The bucket holds up to `burst` tokens and gains `rate` tokens a second, every message sent
+/// takes one. Sending at most `rate` messages a second on average, with bursts of `burst`,
+/// keeps a node catching up after a partition from sending its whole backlog at once. Like the
+/// [`Outbox`](crate::outbox::Outbox) the bucket does not keep time itself, every call is given
+/// the current time.
+#[derive(Debug)]
+pub struct TokenBucket {
+    // Tokens gained per second.
+    rate: f64,
+    burst: f64,
+    // Tokens left, as of the instant they were counted, None until first used, full then.
+    tokens: Mutex<Option<(f64, Instant)>>,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket of `burst` tokens that gains `rate` tokens a second.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            tokens: Mutex::new(None),
+        }
+    }
+
+    /// Creates a bucket that gains `rate` tokens a second, and holds a second's worth of them.
+    pub fn per_second(rate: u32) -> Self {
+        Self::new(rate, rate)
+    }
+
+    /// Takes a token at `now`, returns false if there is none left.
+    pub fn try_take(&self, now: Instant) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let (left, at) = tokens.unwrap_or((self.burst, now));
+        let gained = now.saturating_duration_since(at).as_secs_f64() * self.rate;
+        let left = (left + gained).min(self.burst);
+        // Time only moves forward, so a call from a thread with an older now gains nothing.
+        let at = at.max(now);
+        if left < 1.0 {
+            *tokens = Some((left, at));
+            return false;
+        }
+        *tokens = Some((left - 1.0, at));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limit::TokenBucket;
+
+    #[test]
+    fn bucket_refills_at_rate_up_to_burst() {
+        let bucket = TokenBucket::new(10, 3);
+        let now = Instant::now();
+
+        let taken = (0..5).filter(|_| bucket.try_take(now)).count();
+        assert_eq!(taken, 3, "a full bucket allows a burst");
+
+        let later = now + Duration::from_millis(100);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+        let much_later = later + Duration::from_secs(60);
+        let taken = (0..5).filter(|_| bucket.try_take(much_later)).count();
+        assert_eq!(taken, 3, "tokens do not pile up past the burst");
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, LineWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde_json::Value;
+use tracing::info;
+
+use crate::{clock::LAMPORT_FIELD, message::Message};
+
+/// Reads from a reader and copies everything read to a log.
+#[derive(Debug)]
+pub struct TeeReader<R> {
+    reader: R,
+    log: LineWriter<File>,
+}
+
+impl<R: Read> Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.log.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for TeeReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // Only what is consumed was read, what fill_buf returned may be returned again.
+        if let Ok(buf) = self.reader.fill_buf() {
+            let _ = self.log.write_all(&buf[..amt.min(buf.len())]);
+        }
+        self.reader.consume(amt)
+    }
+}
+
+/// Writes to a writer and copies everything written to a log.
+#[derive(Debug)]
+pub struct TeeWriter<W> {
+    writer: W,
+    log: LineWriter<File>,
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.log.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.log.flush()
+    }
+}
+
+/// Records every line read from `reader` and written to `writer` to files in `dir`, named
+/// after the time and process they were recorded in, `<millis>-<pid>.in.jsonl` and
+/// `<millis>-<pid>.out.jsonl`.
+///
+/// The input file can be fed back through a node with [`replay`](crate::workload::replay), to
+/// reproduce a run offline.
+pub fn record<R, W>(dir: &Path, reader: R, writer: W) -> Result<(TeeReader<R>, TeeWriter<W>)> {
+    fs::create_dir_all(dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path =
+        |side: &str| -> PathBuf { dir.join(format!("{millis}-{}.{side}.jsonl", process::id())) };
+    let (input, output) = (path("in"), path("out"));
+    info!(?input, ?output, "recording messages");
+    Ok((
+        TeeReader {
+            reader,
+            log: LineWriter::new(File::create(input)?),
+        },
+        TeeWriter {
+            writer,
+            log: LineWriter::new(File::create(output)?),
+        },
+    ))
+}
+
+/// Output shared with whoever wrote it, to read what a node wrote once it is done.
+#[derive(Debug, Clone, Default)]
+pub struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    /// Everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compares the replies in the `replayed` output of a node with the replies in the `recorded`
+/// output, returns a line describing every difference.
+///
+/// Replies are matched by their destination and the request they reply to, and compared
+/// without their msg_id and Lamport timestamp. Other messages, like gossip, depend on timing
+/// and are not compared.
+pub fn diff(recorded: &str, replayed: &str) -> Vec<String> {
+    let (recorded, replayed) = (replies(recorded), replies(replayed));
+    let mut differences = Vec::new();
+    for (request, reply) in &recorded {
+        match replayed.get(request) {
+            None => differences.push(format!("missing reply to {request:?}: {reply}")),
+            Some(replayed) if replayed != reply => differences.push(format!(
+                "reply to {request:?} differs, recorded {reply} replayed {replayed}"
+            )),
+            Some(_) => {}
+        }
+    }
+    for (request, reply) in &replayed {
+        if !recorded.contains_key(request) {
+            differences.push(format!("unexpected reply to {request:?}: {reply}"));
+        }
+    }
+    differences
+}
+
+/// Replies in the `output` of a node, without their msg_id and Lamport timestamp, keyed by their
+/// destination and the msg_id they reply to.
+fn replies(output: &str) -> BTreeMap<(String, u64), Value> {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Message>(line).ok())
+        .filter(|msg| msg.body.in_reply_to != 0)
+        .map(|mut msg| {
+            msg.body.msg_id = 0;
+            msg.body.extra.remove(LAMPORT_FIELD);
+            let reply = serde_json::to_value(&msg.body).unwrap_or_default();
+            ((msg.dest, msg.body.in_reply_to), reply)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::{BufRead, Cursor, Write},
+    };
+
+    use anyhow::Result;
+
+    use crate::replay::{diff, record};
+
+    #[test]
+    fn record_tees_both_streams() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("maelstrom-record-{}", std::process::id()));
+        let mut output = vec![];
+        let (mut reader, mut writer) = record(&dir, Cursor::new("a\nb\n"), &mut output)?;
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        writer.write_all(b"c\n")?;
+        writer.flush()?;
+        drop((reader, writer));
+
+        let mut recorded: Vec<_> = fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<_>>()?;
+        recorded.sort();
+        let contents: Vec<String> = recorded
+            .iter()
+            .map(fs::read_to_string)
+            .collect::<Result<_, _>>()?;
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(output, b"c\n");
+        assert_eq!(contents, vec!["a\n", "c\n"]);
+        Ok(())
+    }
+
+    #[test]
+    fn diff_compares_replies() {
+        let recorded = [
+            r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":1,"in_reply_to":1,"echo":"a"}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":2,"in_reply_to":2,"echo":"b"}}"#,
+            r#"{"src":"n1","dest":"n2","body":{"type":"gossip","msg_id":3}}"#,
+        ]
+        .join("\n");
+        let replayed = [
+            r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":5,"in_reply_to":1,"echo":"a","lamport":4}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":6,"in_reply_to":2,"echo":"x"}}"#,
+            r#"{"src":"n1","dest":"c2","body":{"type":"echo_ok","msg_id":7,"in_reply_to":1,"echo":"c"}}"#,
+        ]
+        .join("\n");
+
+        let differences = diff(&recorded, &replayed);
+
+        assert_eq!(differences.len(), 2, "{differences:?}");
+        assert!(differences[0].starts_with(r#"reply to ("c1", 2) differs"#));
+        assert!(differences[1].starts_with(r#"unexpected reply to ("c2", 1)"#));
+    }
+}
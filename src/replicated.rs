@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{body, crdt::Crdt, message::Message, outbox::Outbox, persistence::Persist, reply};
+
+/// A [`Crdt`] replicated to every other node through an [`Outbox`].
+///
+/// Updates only change the state of this node. The part of it every peer is not known to have
+/// is sent to that peer in a `merge` on a timer, see [`Replicated::sync`], through the outbox so
+/// it is sent again until acked. Merging is idempotent and order does not matter, so resent and
+/// reordered merges are harmless and all nodes converge once partitions heal.
+#[derive(Debug)]
+pub struct Replicated<C> {
+    state: Mutex<C>,
+    // ID of this node, set on init.
+    node_id: Mutex<String>,
+    // Nodes to send the state to, set on init.
+    peers: Mutex<Vec<String>>,
+    // The state every peer is known to have, because it sent it or acked it.
+    synced: Mutex<HashMap<String, C>>,
+    // Merges not acked yet, the msg_id and delta of it keyed by dest.
+    in_flight: Mutex<HashMap<String, (u64, C)>>,
+    outbox: Arc<Outbox>,
+}
+
+impl<C: Crdt> Replicated<C> {
+    /// Creates an empty state that is sent to peers through `outbox`.
+    pub fn new(outbox: Arc<Outbox>) -> Self {
+        Self {
+            state: Mutex::default(),
+            node_id: Mutex::default(),
+            peers: Mutex::default(),
+            synced: Mutex::default(),
+            in_flight: Mutex::default(),
+            outbox,
+        }
+    }
+
+    /// Sets the identity of this node and its peers, meant to be used as the node's init handler.
+    pub fn init(&self, node_id: &str, node_ids: &[String]) {
+        *self.node_id.lock().unwrap() = node_id.to_string();
+        *self.peers.lock().unwrap() = node_ids
+            .iter()
+            .filter(|&id| id != node_id)
+            .cloned()
+            .collect();
+    }
+
+    /// ID of this node, empty before init.
+    pub fn node_id(&self) -> String {
+        self.node_id.lock().unwrap().clone()
+    }
+
+    /// The state of this node, to read or update.
+    pub fn state(&self) -> MutexGuard<'_, C> {
+        self.state.lock().unwrap()
+    }
+
+    /// Sends every peer the part of the state it is not known to have, unless it has a merge
+    /// waiting for its ack, returns the number of merges sent.
+    pub fn sync(&self, now: Instant) -> Result<usize> {
+        let node_id = self.node_id();
+        let state = self.state().clone();
+        let mut synced = self.synced.lock().unwrap();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.retain(|dest, (msg_id, sent)| {
+            let acked = !self.outbox.is_unacked(*msg_id);
+            if acked {
+                synced.entry(dest.clone()).or_default().merge(sent);
+            }
+            !acked
+        });
+
+        let unknown = C::default();
+        let mut sent = 0;
+        for peer in self.peers.lock().unwrap().iter() {
+            if in_flight.contains_key(peer) {
+                continue;
+            }
+            let Some(delta) = state.missing_for(synced.get(peer).unwrap_or(&unknown)) else {
+                continue;
+            };
+            let merge = Message {
+                src: node_id.clone(),
+                dest: peer.clone(),
+                body: body!("merge", { "delta": delta }),
+            };
+            let msg_id = self.outbox.send(merge, now)?;
+            in_flight.insert(peer.clone(), (msg_id, delta));
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Handles a `merge`, the delta of another node, which so has it.
+    pub fn merge(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let delta: C = msg.body.get_as("delta")?;
+        self.state().merge(&delta);
+        self.synced
+            .lock()
+            .unwrap()
+            .entry(msg.src.clone())
+            .or_default()
+            .merge(&delta);
+        Ok(reply!(msg, msg_id, "merge_ok", {}))
+    }
+}
+
+impl<C: Crdt + Send + 'static> Replicated<C> {
+    /// Calls [`Replicated::sync`] every `interval` from a background thread, until merges cannot
+    /// be sent anymore.
+    pub fn sync_every(self: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()> {
+        let replicated = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = replicated.sync(Instant::now()) {
+                warn!("stopped syncing: {e:#}");
+                return;
+            }
+        })
+    }
+}
+
+impl<C: Crdt> Persist for Replicated<C> {
+    fn snapshot(&self) -> Value {
+        serde_json::to_value(&*self.state()).unwrap_or_default()
+    }
+
+    fn restore(&self, snapshot: Value) -> Result<()> {
+        *self.state() = serde_json::from_value(snapshot)?;
+        Ok(())
+    }
+
+    fn merge_snapshot(&self, snapshot: Value) -> Result<()> {
+        let other: C = serde_json::from_value(snapshot)?;
+        self.state().merge(&other);
+        Ok(())
+    }
+}
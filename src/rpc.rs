@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+    error::NodeError,
+    message::{Body, Message, MsgIds},
+    rtt::RttEstimator,
+    trace,
+};
+
+/// Synchronous request/response to another node or Maelstrom service.
+pub trait Rpc {
+    /// Sends a request with `body` to `dest` and waits for the reply, returns the reply's body.
+    ///
+    /// Error replies from `dest` are returned as bodies of type `error`, `Err` is only for
+    /// failing to get a reply at all, a [`NodeError::Timeout`] if none came in time. A handler
+    /// that fails with it answers its request with a Maelstrom timeout error (code 0).
+    fn call(&self, dest: &str, body: Body) -> Result<Body>;
+}
+
+/// Sends requests from a node and hands their replies back to the callers waiting for them.
+///
+/// Give the client to the node with [`Node::with_rpc`](crate::node::Node::with_rpc), the node
+/// then routes replies to the client's requests to it instead of to the handler for the reply's
+/// type. [`Rpc::call`] blocks the calling handler until the reply arrives, so it must only be
+/// used when another thread recieves messages, as with
+/// [`Node::run_pool`](crate::node::Node::run_pool).
+#[derive(Debug)]
+pub struct RpcClient {
+    sender: Sender<Message>,
+    msg_ids: Arc<MsgIds>,
+    timeout: Duration,
+    // ID of the node sending requests, set on init.
+    node_id: Mutex<String>,
+    // Where to send the reply of every outstanding request, with the request's dest and when
+    // it was sent, keyed by the request's msg_id.
+    waiting: Mutex<HashMap<u64, Waiting>>,
+    // Where the round trip times of calls are measured, if anywhere.
+    rtt: Option<Arc<RttEstimator>>,
+}
+
+/// The dest of a call waiting for a reply, when it was sent and where its reply goes.
+type Waiting = (String, Instant, Sender<Message>);
+
+impl RpcClient {
+    /// Creates a client that sends requests to `sender`, numbered with `msg_ids`. Calls wait at
+    /// most a second for a reply.
+    pub fn new(sender: Sender<Message>, msg_ids: Arc<MsgIds>) -> Self {
+        Self {
+            sender,
+            msg_ids,
+            timeout: Duration::from_secs(1),
+            node_id: Mutex::default(),
+            waiting: Mutex::default(),
+            rtt: None,
+        }
+    }
+
+    /// Sets how long calls wait for a reply.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Measures the round trip time of every call that gets a reply, into `rtt`.
+    pub fn with_rtt(mut self, rtt: Arc<RttEstimator>) -> Self {
+        self.rtt = Some(rtt);
+        self
+    }
+
+    /// Sets the ID of the node requests are sent from.
+    pub fn init(&self, node_id: &str) {
+        *self.node_id.lock().unwrap() = node_id.to_string();
+    }
+
+    /// Hands `reply` to the call waiting for it, returns the message back if no call is waiting
+    /// for it.
+    pub fn complete(&self, reply: Message) -> Option<Message> {
+        let mut waiting = self.waiting.lock().unwrap();
+        match waiting.get(&reply.body.in_reply_to) {
+            Some((dest, _, _)) if *dest == reply.src => {
+                let (dest, sent_at, caller) = waiting
+                    .remove(&reply.body.in_reply_to)
+                    .expect("entry was just found");
+                if let Some(rtt) = &self.rtt {
+                    rtt.observe(&dest, sent_at.elapsed());
+                }
+                // The caller may have timed out already, then the reply is dropped.
+                let _ = caller.send(reply);
+                None
+            }
+            _ => Some(reply),
+        }
+    }
+
+    /// The msg_id and dest of every call waiting for a reply, by msg_id.
+    pub fn pending(&self) -> Vec<(u64, String)> {
+        let waiting = self.waiting.lock().unwrap();
+        let mut pending: Vec<(u64, String)> = waiting
+            .iter()
+            .map(|(&msg_id, (dest, _, _))| (msg_id, dest.clone()))
+            .collect();
+        pending.sort();
+        pending
+    }
+
+    /// Number of calls waiting for a reply.
+    pub fn outstanding(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+}
+
+impl Rpc for RpcClient {
+    fn call(&self, dest: &str, mut body: Body) -> Result<Body> {
+        let msg_id = self.msg_ids.next_request();
+        body.msg_id = msg_id;
+        trace::stamp(&mut body);
+        let (caller, reply) = mpsc::channel();
+        self.waiting
+            .lock()
+            .unwrap()
+            .insert(msg_id, (dest.to_string(), Instant::now(), caller));
+
+        let request = Message {
+            src: self.node_id.lock().unwrap().clone(),
+            dest: dest.to_string(),
+            body,
+        };
+        if self.sender.send(request).is_err() {
+            self.waiting.lock().unwrap().remove(&msg_id);
+            return Err(NodeError::Unavailable("rpc client receiver dropped".into()).into());
+        }
+
+        match reply.recv_timeout(self.timeout) {
+            Ok(reply) => Ok(reply.body),
+            Err(_) => {
+                self.waiting.lock().unwrap().remove(&msg_id);
+                Err(NodeError::Timeout(format!(
+                    "no reply from {dest} to msg {msg_id} after {:?}",
+                    self.timeout
+                ))
+                .into())
+            }
+        }
+    }
+}
+
+impl<T: Rpc> Rpc for Arc<T> {
+    fn call(&self, dest: &str, body: Body) -> Result<Body> {
+        T::call(self, dest, body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+
+    use crate::error::NodeError;
+    use crate::message::{Body, Message, MsgIds};
+    use crate::rpc::{Rpc, RpcClient};
+
+    fn reply_to(request: &Message, src: &str) -> Message {
+        Message {
+            src: src.into(),
+            dest: request.src.clone(),
+            body: Body {
+                typ: "read_ok".into(),
+                in_reply_to: request.body.msg_id,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn call_returns_routed_reply() -> Result<()> {
+        let (sender, sent) = mpsc::channel();
+        let client = RpcClient::new(sender, Arc::new(MsgIds::new()));
+        client.init("n1");
+
+        let reply = thread::scope(|s| {
+            let call = s.spawn(|| {
+                client.call(
+                    "lin-kv",
+                    Body {
+                        typ: "read".into(),
+                        ..Default::default()
+                    },
+                )
+            });
+            let request = sent.recv().expect("no request sent");
+            assert_eq!(
+                (request.src.as_str(), request.dest.as_str()),
+                ("n1", "lin-kv")
+            );
+            assert_ne!(request.body.msg_id, 0);
+
+            // A reply from the wrong node is not for this call.
+            assert!(client.complete(reply_to(&request, "n2")).is_some());
+            assert!(client.complete(reply_to(&request, "lin-kv")).is_none());
+            call.join().expect("call panicked")
+        })?;
+
+        assert_eq!(reply.typ, "read_ok");
+        assert_eq!(client.outstanding(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn call_times_out() {
+        let (sender, _sent) = mpsc::channel();
+        let client =
+            RpcClient::new(sender, Arc::new(MsgIds::new())).with_timeout(Duration::from_millis(10));
+
+        let result = client.call("n2", Body::default());
+
+        assert!(
+            result
+                .as_ref()
+                .is_err_and(|e| e.to_string().contains("Timeout")),
+            "expected timeout, got {:?}",
+            result
+        );
+        assert_eq!(NodeError::from(result.unwrap_err()).code(), 0);
+        assert_eq!(client.outstanding(), 0);
+    }
+}
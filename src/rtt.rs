@@ -0,0 +1,101 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// Shortest and longest retry timeouts derived from round trip times by default.
+const MIN_TIMEOUT: Duration = Duration::from_millis(10);
+const MAX_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Estimates the round trip time (RTT) to every peer from the replies of its requests, and the
+/// timeout after which a request without a reply is worth sending again.
+///
+/// Like TCP's retransmission timer (RFC 6298), the estimate is a moving average of the RTT
+/// samples of a peer, and the timeout adds four times their mean deviation, so a peer with
+/// steady latency is retried soon after a reply is overdue and a jittery one is given more
+/// time. Timeouts are clamped to the bounds set with [`RttEstimator::with_bounds`].
+#[derive(Debug)]
+pub struct RttEstimator {
+    // Smoothed RTT and mean deviation of every peer with samples, in seconds.
+    peers: Mutex<HashMap<String, (f64, f64)>>,
+    min: Duration,
+    max: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            peers: Mutex::default(),
+            min: MIN_TIMEOUT,
+            max: MAX_TIMEOUT,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clamps timeouts between `min` and `max`.
+    pub fn with_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.min = min;
+        self.max = max.max(min);
+        self
+    }
+
+    /// Adds the round trip time `sample` of a request to `peer` and its reply.
+    pub fn observe(&self, peer: &str, sample: Duration) {
+        let sample = sample.as_secs_f64();
+        let mut peers = self.peers.lock().unwrap();
+        let Some((srtt, rttvar)) = peers.get_mut(peer) else {
+            peers.insert(peer.to_string(), (sample, sample / 2.0));
+            return;
+        };
+        *rttvar = 0.75 * *rttvar + 0.25 * (*srtt - sample).abs();
+        *srtt = 0.875 * *srtt + 0.125 * sample;
+    }
+
+    /// The smoothed round trip time to `peer`, None before any sample.
+    pub fn rtt(&self, peer: &str) -> Option<Duration> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .get(peer)
+            .map(|&(srtt, _)| Duration::from_secs_f64(srtt))
+    }
+
+    /// How long to wait for a reply from `peer` before sending a request again, None before
+    /// any sample.
+    pub fn timeout(&self, peer: &str) -> Option<Duration> {
+        let peers = self.peers.lock().unwrap();
+        peers.get(peer).map(|&(srtt, rttvar)| {
+            Duration::from_secs_f64(srtt + 4.0 * rttvar).clamp(self.min, self.max)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::rtt::RttEstimator;
+
+    #[test]
+    fn timeout_follows_rtt_and_its_variance() {
+        let rtt = RttEstimator::new().with_bounds(Duration::from_millis(1), Duration::from_secs(1));
+        assert_eq!(rtt.timeout("n2"), None);
+
+        rtt.observe("n2", Duration::from_millis(100));
+        assert_eq!(rtt.timeout("n2"), Some(Duration::from_millis(300)));
+        for _ in 0..50 {
+            rtt.observe("n2", Duration::from_millis(100));
+        }
+        let steady = rtt.timeout("n2").expect("n2 has samples");
+        assert!(steady < Duration::from_millis(101), "{steady:?}");
+
+        for sample in [20, 180, 20, 180] {
+            rtt.observe("n3", Duration::from_millis(sample));
+        }
+        let jittery = rtt.timeout("n3").expect("n3 has samples");
+        assert!(jittery > steady, "{jittery:?} <= {steady:?}");
+        rtt.observe("n4", Duration::from_secs(10));
+        assert_eq!(rtt.timeout("n4"), Some(Duration::from_secs(1)));
+    }
+}
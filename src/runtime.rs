@@ -0,0 +1,300 @@
+use std::{
+    io::{BufRead, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tracing::{debug, warn};
+
+use crate::{
+    error::NodeError,
+    message::{Body, Message, MsgIds},
+    node::error_reply,
+    transport::{BackgroundReader, Input, StdioTransport},
+};
+
+/// A workload as a single struct the runtime drives with [`run`], instead of handlers sharing
+/// state behind locks, like [`Echo`](crate::echo::Echo).
+///
+/// The runtime handles one message at a time on one thread, and answers init itself. Every
+/// message the service sends, replies included, goes through the [`Ctx`] it is given.
+pub trait Service {
+    /// Called once the node is initialized, with its ID and the IDs of all nodes in `ctx`.
+    fn init(&mut self, ctx: &mut Ctx);
+
+    /// Handles `msg`, any message but init. Requests whose handling fails are answered with an
+    /// error, by the code of its [`NodeError`].
+    fn handle(&mut self, ctx: &mut Ctx, msg: Message) -> Result<()>;
+
+    /// Called every tick interval once the node is initialized, to gossip or retry say.
+    fn tick(&mut self, ctx: &mut Ctx);
+}
+
+/// What a [`Service`] knows of its node, and how it sends messages.
+#[derive(Debug)]
+pub struct Ctx {
+    node_id: String,
+    node_ids: Vec<String>,
+    msg_ids: MsgIds,
+    // Messages sent but not written yet, written once the service returns.
+    sent: Vec<Message>,
+}
+
+impl Default for Ctx {
+    /// A context before init. Maelstrom may restart the node, its msg_ids start at the time it
+    /// starts so they are not reused.
+    fn default() -> Self {
+        Self {
+            node_id: String::new(),
+            node_ids: Vec::new(),
+            msg_ids: MsgIds::epoch_from_clock(),
+            sent: Vec::new(),
+        }
+    }
+}
+
+impl Ctx {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ID of this node, empty until init.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// IDs of all nodes in the cluster, including this one.
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Sends a message with `body` to `dest`, returns its msg_id.
+    pub fn send(&mut self, dest: &str, body: Body) -> u64 {
+        let msg_id = self.msg_ids.next_request();
+        self.sent.push(Message {
+            src: self.node_id.clone(),
+            dest: dest.to_string(),
+            body: Body { msg_id, ..body },
+        });
+        msg_id
+    }
+
+    /// Replies to `request` with `body`.
+    pub fn reply(&mut self, request: &Message, body: Body) {
+        let msg_id = self.msg_ids.next();
+        self.sent.push(request.reply_with(Body { msg_id, ..body }));
+    }
+}
+
+/// Runs `service` on `transport` until its input ends, ticking it every `tick`.
+pub fn run<T, R, W>(service: &mut T, transport: StdioTransport<R, W>, tick: Duration) -> Result<()>
+where
+    T: Service,
+    R: BufRead + Send + 'static,
+    W: Write,
+{
+    let (reader, writer) = transport.into_parts();
+    let reader = BackgroundReader::spawn(reader);
+    let mut ctx = Ctx::new();
+    let mut next_tick = Instant::now() + tick;
+    loop {
+        let input = reader.recv_timeout(next_tick.saturating_duration_since(Instant::now()))?;
+        match input {
+            Input::Message(msg) => handle(service, &mut ctx, msg),
+            Input::Timeout if !ctx.node_id.is_empty() => {
+                next_tick = Instant::now() + tick;
+                service.tick(&mut ctx);
+            }
+            Input::Timeout => next_tick = Instant::now() + tick,
+            Input::End => return Ok(()),
+        }
+        for msg in ctx.sent.drain(..) {
+            writer.write(&msg)?;
+        }
+    }
+}
+
+/// Handles `msg`, an init or a message for `service`.
+fn handle<T: Service>(service: &mut T, ctx: &mut Ctx, msg: Message) {
+    debug!(?msg, "handling message");
+    let request = msg.body.msg_id != 0 && msg.body.in_reply_to == 0;
+    let header = Message {
+        src: msg.src.clone(),
+        dest: msg.dest.clone(),
+        body: Body {
+            msg_id: msg.body.msg_id,
+            ..Default::default()
+        },
+    };
+    let handled = match msg.body.typ.as_str() {
+        "init" => init(service, ctx, msg),
+        _ if ctx.node_id.is_empty() => Err(NodeError::NotReady(format!(
+            "recieved message {msg:?} before init message cannot handle."
+        ))),
+        _ => service.handle(ctx, msg).map_err(NodeError::from),
+    };
+    if let Err(e) = handled {
+        warn!(code = e.code(), "failed to handle message: {e}");
+        if request {
+            let reply = error_reply(header, ctx.msg_ids.next(), e.code(), &e.to_string());
+            ctx.sent.push(reply);
+        }
+    }
+}
+
+/// Initializes the node from the init message `msg`, once, and answers it.
+fn init<T: Service>(service: &mut T, ctx: &mut Ctx, msg: Message) -> Result<(), NodeError> {
+    if ctx.node_id.is_empty() {
+        ctx.node_id = msg.body.get_str("node_id")?.to_string();
+        ctx.node_ids = msg.body.get_as("node_ids")?;
+        service.init(ctx);
+    } else {
+        warn!("ignoring init message recieved after node initialized");
+    }
+    ctx.reply(
+        &msg,
+        Body {
+            typ: "init_ok".to_string(),
+            ..Default::default()
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufReader, Cursor, Write},
+        os::unix::net::UnixStream,
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::message::{Body, Message};
+    use crate::replay::SharedOutput;
+    use crate::runtime::{run, Ctx, Service};
+    use crate::transport::StdioTransport;
+
+    /// Counts `add`s, and gossips the count to every other node each tick.
+    #[derive(Default)]
+    struct Count {
+        count: u64,
+        ticks: usize,
+    }
+
+    impl Service for Count {
+        fn init(&mut self, ctx: &mut Ctx) {
+            assert_eq!(ctx.node_ids(), ["n1", "n2"]);
+        }
+
+        fn handle(&mut self, ctx: &mut Ctx, msg: Message) -> Result<()> {
+            self.count += msg.body.get_u64("delta")?;
+            let mut body = Body {
+                typ: "add_ok".into(),
+                ..Default::default()
+            };
+            body.extra.insert("count".into(), self.count.into());
+            ctx.reply(&msg, body);
+            Ok(())
+        }
+
+        fn tick(&mut self, ctx: &mut Ctx) {
+            self.ticks += 1;
+            let peers: Vec<String> = ctx
+                .node_ids()
+                .iter()
+                .filter(|id| *id != ctx.node_id())
+                .cloned()
+                .collect();
+            for peer in peers {
+                let mut body = Body {
+                    typ: "gossip".into(),
+                    ..Default::default()
+                };
+                body.extra.insert("count".into(), self.count.into());
+                ctx.send(&peer, body);
+            }
+        }
+    }
+
+    fn line(value: serde_json::Value) -> String {
+        format!("{value}\n")
+    }
+
+    #[test]
+    fn drives_workload() -> Result<()> {
+        let input = [
+            line(json!({"src": "c1", "dest": "n1", "body": {"type": "add", "msg_id": 1}})),
+            line(json!({"src": "c0", "dest": "n1", "body": {
+                "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]
+            }})),
+            line(json!({"src": "c1", "dest": "n1", "body": {"type": "add", "msg_id": 2, "delta": 2}})),
+            line(json!({"src": "c1", "dest": "n1", "body": {"type": "add", "msg_id": 3}})),
+        ]
+        .concat();
+        let output = SharedOutput::default();
+        let mut count = Count::default();
+
+        run(
+            &mut count,
+            StdioTransport::from_io(Cursor::new(input), output.clone()),
+            Duration::from_secs(60),
+        )?;
+
+        let replies: Vec<(String, u64, serde_json::Value)> = String::from_utf8(output.contents())?
+            .lines()
+            .map(|line| {
+                let msg: Message = serde_json::from_str(line)?;
+                let code = msg.body.extra.get("code").or(msg.body.extra.get("count"));
+                Ok((msg.body.typ, msg.body.in_reply_to, code.cloned().into()))
+            })
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            replies,
+            vec![
+                ("error".to_string(), 1, json!(11)),
+                ("init_ok".to_string(), 1, json!(null)),
+                ("add_ok".to_string(), 2, json!(2)),
+                ("error".to_string(), 3, json!(12)),
+            ]
+        );
+        assert_eq!(count.ticks, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn ticks_while_waiting_for_input() -> Result<()> {
+        let (mut input, reader) = UnixStream::pair()?;
+        input.write_all(
+            line(json!({"src": "c0", "dest": "n1", "body": {
+                "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1", "n2"]
+            }}))
+            .as_bytes(),
+        )?;
+        let output = SharedOutput::default();
+        let closing = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(input);
+        });
+        let mut count = Count::default();
+
+        run(
+            &mut count,
+            StdioTransport::from_io(BufReader::new(reader), output.clone()),
+            Duration::from_millis(5),
+        )?;
+
+        closing.join().unwrap();
+        let gossip = String::from_utf8(output.contents())?
+            .lines()
+            .map(serde_json::from_str::<Message>)
+            .filter(|msg| msg.as_ref().is_ok_and(|msg| msg.body.typ == "gossip"))
+            .count();
+        assert!(count.ticks > 0);
+        assert_eq!(gossip, count.ticks);
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::{body, message::Message, node::Handler, outbox::Outbox, reply};
+
+/// Total order broadcast through a sequencer, every node delivers the same values in the same
+/// order.
+///
+/// The sequencer is the node with the lowest ID. Other nodes send every value broadcast to them
+/// to the sequencer in an `order`, which gives it the next sequence number and sends it to every
+/// other node in a `sequenced`. Nodes hold back values until every value before them is
+/// delivered, so values are delivered in sequence order whatever order they arrive in. All
+/// messages go through an [`Outbox`], and the sequencer remembers which orders it sequenced,
+/// so resent messages are not delivered twice.
+///
+/// Nothing is delivered while the sequencer is unreachable, total order needs a single node to
+/// decide it. The delivered values are a log every node agrees on, for state machine
+/// replication or a replicated log.
+#[derive(Debug)]
+pub struct TotalOrder {
+    outbox: Arc<Outbox>,
+    // ID of this node and of the sequencer, set on init.
+    node_id: Mutex<String>,
+    sequencer: Mutex<String>,
+    // Every other node, set on init.
+    peers: Mutex<Vec<String>>,
+    // Sequencer only, the orders sequenced so far.
+    sequenced: Mutex<Sequenced>,
+    // Values delivered so far in order, and values received ahead of their turn keyed by
+    // sequence number.
+    log: Mutex<(Vec<Value>, BTreeMap<u64, Value>)>,
+}
+
+/// Orders sequenced by the sequencer.
+#[derive(Debug, Default)]
+struct Sequenced {
+    // Sequence number of every order, keyed by its (src, msg_id).
+    orders: HashMap<(String, u64), u64>,
+    // Next sequence number.
+    next: u64,
+}
+
+/// Returns the handlers of total order broadcast, for the broadcast workload, backed by
+/// `order`.
+pub fn handlers(order: &TotalOrder) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert(
+        "broadcast".into(),
+        Box::new(|msg, id| order.broadcast(msg, id)),
+    );
+    funs.insert("read".into(), Box::new(|msg, id| order.read(msg, id)));
+    funs.insert(
+        "topology".into(),
+        Box::new(|msg, id| order.topology(msg, id)),
+    );
+    funs.insert("order".into(), Box::new(|msg, id| order.order(msg, id)));
+    funs.insert(
+        "sequenced".into(),
+        Box::new(|msg, id| order.receive_sequenced(msg, id)),
+    );
+    funs
+}
+
+impl TotalOrder {
+    /// Creates a total order broadcast that sends its messages through `outbox`.
+    pub fn new(outbox: Arc<Outbox>) -> Self {
+        Self {
+            outbox,
+            node_id: Mutex::default(),
+            sequencer: Mutex::default(),
+            peers: Mutex::default(),
+            sequenced: Mutex::default(),
+            log: Mutex::default(),
+        }
+    }
+
+    /// Picks the sequencer among `node_ids`, meant to be used as the node's init handler.
+    pub fn init(&self, node_id: &str, node_ids: &[String]) {
+        *self.node_id.lock().unwrap() = node_id.to_string();
+        // Compared by length first, so n10 comes after n9.
+        let sequencer = node_ids
+            .iter()
+            .min_by_key(|id| (id.len(), id.as_str()))
+            .map_or(node_id, |id| id.as_str());
+        *self.sequencer.lock().unwrap() = sequencer.to_string();
+        *self.peers.lock().unwrap() = node_ids
+            .iter()
+            .filter(|&id| id != node_id)
+            .cloned()
+            .collect();
+    }
+
+    /// Whether this node is the sequencer.
+    pub fn is_sequencer(&self) -> bool {
+        *self.node_id.lock().unwrap() == *self.sequencer.lock().unwrap()
+    }
+
+    /// Every value delivered so far, in sequence order.
+    pub fn delivered(&self) -> Vec<Value> {
+        self.log.lock().unwrap().0.clone()
+    }
+
+    /// Broadcasts `value`, from the sequencer right away, otherwise through the sequencer.
+    pub fn submit(&self, value: Value, now: Instant) -> Result<()> {
+        if self.is_sequencer() {
+            let node_id = self.node_id.lock().unwrap().clone();
+            let seq = self.next_seq(None);
+            return self.sequence(&node_id, seq, value, now);
+        }
+        let order = Message {
+            src: self.node_id.lock().unwrap().clone(),
+            dest: self.sequencer.lock().unwrap().clone(),
+            body: body!("order", { "value": value }),
+        };
+        self.outbox.send(order, now)?;
+        Ok(())
+    }
+
+    /// The sequence number of the order `id`, a new one unless it was sequenced before.
+    /// Returns None for an order sequenced before.
+    fn next_seq(&self, id: Option<(String, u64)>) -> Option<u64> {
+        let mut sequenced = self.sequenced.lock().unwrap();
+        let seq = sequenced.next;
+        if let Some(id) = id {
+            if sequenced.orders.contains_key(&id) {
+                return None;
+            }
+            sequenced.orders.insert(id, seq);
+        }
+        sequenced.next += 1;
+        Some(seq)
+    }
+
+    /// Delivers `value` as number `seq` and sends it to every other node, if it was not
+    /// sequenced before.
+    fn sequence(&self, src: &str, seq: Option<u64>, value: Value, now: Instant) -> Result<()> {
+        let Some(seq) = seq else {
+            return Ok(());
+        };
+        for peer in self.peers.lock().unwrap().iter() {
+            let sequenced = Message {
+                src: src.to_string(),
+                dest: peer.clone(),
+                body: body!("sequenced", { "seq": seq, "value": value }),
+            };
+            self.outbox.send(sequenced, now)?;
+        }
+        self.deliver(seq, value);
+        Ok(())
+    }
+
+    /// Delivers `value` once every value before `seq` is delivered, and any held back value
+    /// that can then be delivered.
+    fn deliver(&self, seq: u64, value: Value) {
+        let mut log = self.log.lock().unwrap();
+        let (delivered, held_back) = &mut *log;
+        if seq >= delivered.len() as u64 {
+            held_back.insert(seq, value);
+        }
+        while let Some(value) = held_back.remove(&(delivered.len() as u64)) {
+            delivered.push(value);
+        }
+    }
+
+    /// Handles a `broadcast` from a client.
+    fn broadcast(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let value = msg.body.get_as::<Value>("message")?;
+        self.submit(value, Instant::now())?;
+        Ok(reply!(msg, msg_id, "broadcast_ok", {}))
+    }
+
+    fn read(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        Ok(reply!(msg, msg_id, "read_ok", { "messages": self.delivered() }))
+    }
+
+    /// Handles a `topology`, which does not matter, every node talks to the sequencer.
+    fn topology(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        Ok(reply!(msg, msg_id, "topology_ok", {}))
+    }
+
+    /// Handles an `order` of a value broadcast to another node, on the sequencer.
+    fn order(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        if !self.is_sequencer() {
+            return Err(anyhow!(
+                "FailedPrecondition: order sent to a node that is not the sequencer: {:?}",
+                msg
+            ));
+        }
+        let value = msg.body.get_as::<Value>("value")?;
+        let seq = self.next_seq(Some((msg.src.clone(), msg.body.msg_id)));
+        self.sequence(&msg.dest, seq, value, Instant::now())?;
+        Ok(reply!(msg, msg_id, "order_ok", {}))
+    }
+
+    /// Handles a value sequenced by the sequencer.
+    fn receive_sequenced(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let seq = msg.body.get_u64("seq")?;
+        let value = msg.body.get_as::<Value>("value")?;
+        self.deliver(seq, value);
+        Ok(reply!(msg, msg_id, "sequenced_ok", {}))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use serde_json::{json, Value};
+
+    use crate::message::{Message, MsgIds};
+    use crate::node::Node;
+    use crate::outbox::Outbox;
+    use crate::sequencer::{handlers, TotalOrder};
+
+    fn msg(src: &str, dest: &str, body: Value) -> Message {
+        serde_json::from_value(json!({ "src": src, "dest": dest, "body": body }))
+            .expect("invalid message json.")
+    }
+
+    #[test]
+    fn nodes_deliver_in_sequence_order() -> Result<()> {
+        // Tests that values broadcast to different nodes are delivered in the same order on
+        // every node, even when sequenced values arrive out of order and twice.
+        let ids = ["n1", "n2", "n3"];
+        let mut parts = vec![];
+        for _ in ids {
+            let (tx, rx) = mpsc::channel();
+            let outbox = Arc::new(Outbox::new(
+                tx,
+                Arc::new(MsgIds::new()),
+                Duration::from_secs(1),
+            ));
+            parts.push((TotalOrder::new(outbox), rx));
+        }
+        let nodes = parts
+            .iter()
+            .zip(ids)
+            .map(|((order, _), id)| -> Result<Node> {
+                let node = Node::with_init_handler(
+                    handlers(order),
+                    Box::new(|id, ids| order.init(id, ids)),
+                )?;
+                node.handle(msg(
+                    "c0",
+                    id,
+                    json!({ "type": "init", "msg_id": 1, "node_id": id, "node_ids": ["n2", "n1", "n3"] }),
+                ))?;
+                Ok(node)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert!(parts[0].0.is_sequencer());
+
+        nodes[1].handle(msg(
+            "c1",
+            "n2",
+            json!({ "type": "broadcast", "msg_id": 2, "message": "a" }),
+        ))?;
+        nodes[0].handle(msg(
+            "c1",
+            "n1",
+            json!({ "type": "broadcast", "msg_id": 3, "message": "b" }),
+        ))?;
+        let order = parts[1].1.try_recv()?;
+        nodes[0].handle(order.clone())?;
+        nodes[0].handle(order)?;
+        // n3 gets the sequenced values last first.
+        let mut to_n3: Vec<Message> = parts[0].1.try_iter().filter(|m| m.dest == "n3").collect();
+        to_n3.reverse();
+        for sequenced in to_n3 {
+            nodes[2].handle(sequenced.clone())?;
+            nodes[2].handle(sequenced)?;
+        }
+
+        assert_eq!(parts[0].0.delivered(), vec![json!("b"), json!("a")]);
+        assert_eq!(parts[2].0.delivered(), vec![json!("b"), json!("a")]);
+        let read = nodes[2].handle(msg("c1", "n3", json!({ "type": "read", "msg_id": 4 })))?;
+        assert_eq!(read.body.extra["messages"], json!(["b", "a"]));
+        Ok(())
+    }
+}
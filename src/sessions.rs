@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::message::Message;
+
+/// Field of a client request with the ID the client gave the operation, kept across retries
+/// of it, see [`Sessions`].
+pub const REQUEST_ID_FIELD: &str = "request_id";
+
+/// A session for every client, with the replies to its latest requests, so a request the
+/// client sends again after a timeout gets the original reply instead of running twice, see
+/// [`Node::with_sessions`](crate::node::Node::with_sessions).
+///
+/// Requests are identified within their client's session by their `request_id` field, which a
+/// client keeps when it retries, or else by the idempotency key the workload derives from the
+/// operation, see [`NodeBuilder::idempotent`](crate::node::NodeBuilder::idempotent). Clients
+/// never send a msg_id again, so requests with neither are not tracked, (src, msg_id) dedup
+/// already covers them. Every session holds the replies to at most `capacity` requests, the
+/// least recently used is forgotten first, so a busy client does not push the replies of other
+/// clients out. Shared between threads, every method can be called concurrently.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    replies: HashMap<String, Message>,
+    // Request IDs of `replies` from least to most recently used.
+    order: VecDeque<String>,
+}
+
+impl Sessions {
+    /// Sessions that each hold the replies to `capacity` requests.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sessions: Mutex::default(),
+            capacity,
+        }
+    }
+
+    /// The ID the client of `msg` gave it in its `request_id` field, None if it has none.
+    pub fn request_id(msg: &Message) -> Option<String> {
+        let id = msg.body.extra.get(REQUEST_ID_FIELD)?;
+        Some(id.as_str().map_or_else(|| id.to_string(), str::to_string))
+    }
+
+    /// The reply sent to the request `request_id` of `client`, if it is remembered.
+    pub fn get(&self, client: &str, request_id: &str) -> Option<Message> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(client)?;
+        let reply = session.replies.get(request_id)?.clone();
+        session.touch(request_id);
+        Some(reply)
+    }
+
+    /// Remembers `reply` as the reply to the request `request_id` of `client`.
+    pub fn insert(&self, client: &str, request_id: &str, reply: Message) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(client.to_string()).or_default();
+        if session
+            .replies
+            .insert(request_id.to_string(), reply)
+            .is_some()
+        {
+            session.touch(request_id);
+        } else {
+            session.order.push_back(request_id.to_string());
+        }
+        while session.order.len() > self.capacity {
+            if let Some(oldest) = session.order.pop_front() {
+                session.replies.remove(&oldest);
+            }
+        }
+    }
+
+    /// Number of clients with a session.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of replies remembered in the session of `client`.
+    pub fn replies(&self, client: &str) -> usize {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(client)
+            .map_or(0, |session| session.replies.len())
+    }
+}
+
+impl Session {
+    /// Makes `request_id` the most recently used.
+    fn touch(&mut self, request_id: &str) {
+        if let Some(at) = self.order.iter().position(|id| id == request_id) {
+            self.order.remove(at);
+        }
+        self.order.push_back(request_id.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::Message;
+    use crate::sessions::Sessions;
+
+    fn reply(in_reply_to: u64) -> Message {
+        let mut reply = Message::default();
+        reply.body.in_reply_to = in_reply_to;
+        reply
+    }
+
+    #[test]
+    fn sessions_forget_least_recently_used_replies() {
+        let sessions = Sessions::new(2);
+        sessions.insert("c1", "1", reply(1));
+        sessions.insert("c1", "2", reply(2));
+        sessions.insert("c2", "1", reply(10));
+        assert_eq!(sessions.get("c1", "1"), Some(reply(1)));
+
+        sessions.insert("c1", "3", reply(3));
+
+        assert_eq!(sessions.get("c1", "2"), None, "least recently used");
+        assert_eq!(sessions.get("c1", "1"), Some(reply(1)));
+        assert_eq!(sessions.get("c2", "1"), Some(reply(10)));
+        assert_eq!((sessions.len(), sessions.replies("c1")), (2, 2));
+    }
+}
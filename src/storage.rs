@@ -0,0 +1,325 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::durability::{Durability, Syncer};
+
+/// Records in the log of a [`FileStorage`] before it is compacted, if most are stale by then.
+const COMPACT_AFTER: usize = 1024;
+
+/// A key-value storage engine, for workloads to keep state that must survive the node
+/// crashing, key by key instead of in whole snapshots like
+/// [`Persistence`](crate::persistence::Persistence).
+///
+/// A write has hit the storage once it returns, so workloads write before replying. Shared
+/// between threads, every method can be called concurrently.
+pub trait Storage: fmt::Debug + Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Value>>;
+
+    fn put(&self, key: &str, value: Value) -> Result<()>;
+
+    /// Removes `key`, if it is there.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Every key starting with `prefix` and its value, in key order.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>>;
+}
+
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    fn get(&self, key: &str) -> Result<Option<Value>> {
+        (**self).get(key)
+    }
+
+    fn put(&self, key: &str, value: Value) -> Result<()> {
+        (**self).put(key, value)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        (**self).delete(key)
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>> {
+        (**self).scan(prefix)
+    }
+}
+
+/// Storage in memory, which does not survive a crash, for tests and nodes without a state dir.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<String, Value>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<Value>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: Value) -> Result<()> {
+        self.entries.lock().unwrap().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>> {
+        Ok(scan(&self.entries.lock().unwrap(), prefix))
+    }
+}
+
+/// Storage in a file, an append-only log of writes, one JSON record per line.
+///
+/// Opening the file replays the log into memory, so reads never touch the file. A crash while
+/// appending leaves a partial last record, which is dropped on open: the write it held never
+/// returned. Once the log is mostly overwritten or deleted records it is compacted, rewritten
+/// with the live entries only and renamed over the log. Writes are synced to disk as
+/// [`FileStorage::with_durability`] says, by default never.
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+    syncer: Syncer,
+}
+
+#[derive(Debug)]
+struct Inner {
+    entries: BTreeMap<String, Value>,
+    log: File,
+    // Records in the log, live or not.
+    records: usize,
+}
+
+/// A line of the log, a put with a value or a delete without.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+}
+
+impl FileStorage {
+    /// Opens the storage logged to `path`, created with its directory if missing.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("cannot create storage dir {}", dir.display()))?;
+        }
+        let (entries, records, valid) = replay(&path)?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        if log.metadata()?.len() > valid {
+            warn!(path = %path.display(), "dropping partial last record");
+            log.set_len(valid)?;
+        }
+        if records > 0 {
+            info!(path = %path.display(), keys = entries.len(), "restored storage");
+        }
+        Ok(Self {
+            path,
+            inner: Mutex::new(Inner {
+                entries,
+                log,
+                records,
+            }),
+            syncer: Syncer::default(),
+        })
+    }
+
+    /// Syncs writes to disk as `durability` says.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.syncer = Syncer::new(durability);
+        self
+    }
+
+    /// Appends `record` to the log, and applies it.
+    fn append(&self, record: Record) -> Result<()> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .log
+            .write_all(&line)
+            .with_context(|| format!("cannot write {}", self.path.display()))?;
+        self.syncer.written(&inner.log)?;
+        inner.records += 1;
+        match record.value {
+            Some(value) => inner.entries.insert(record.key, value),
+            None => inner.entries.remove(&record.key),
+        };
+        if inner.records >= COMPACT_AFTER && inner.records > 2 * inner.entries.len() {
+            self.compact(&mut inner)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log with one record per live entry.
+    fn compact(&self, inner: &mut Inner) -> Result<()> {
+        let tmp = self.path.with_extension("compact");
+        let mut out = Vec::new();
+        for (key, value) in &inner.entries {
+            serde_json::to_writer(
+                &mut out,
+                &Record {
+                    key: key.clone(),
+                    value: Some(value.clone()),
+                },
+            )?;
+            out.push(b'\n');
+        }
+        let mut file =
+            File::create(&tmp).with_context(|| format!("cannot write {}", tmp.display()))?;
+        file.write_all(&out)
+            .with_context(|| format!("cannot write {}", tmp.display()))?;
+        self.syncer.written(&file)?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("cannot replace {}", self.path.display()))?;
+        inner.log = OpenOptions::new().append(true).open(&self.path)?;
+        inner.records = inner.entries.len();
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, key: &str) -> Result<Option<Value>> {
+        Ok(self.inner.lock().unwrap().entries.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: Value) -> Result<()> {
+        self.append(Record {
+            key: key.to_string(),
+            value: Some(value),
+        })
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.append(Record {
+            key: key.to_string(),
+            value: None,
+        })
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Value)>> {
+        Ok(scan(&self.inner.lock().unwrap().entries, prefix))
+    }
+}
+
+/// Replays the log at `path`, returns its entries, how many records it has and the length of
+/// the log up to the end of the last whole record.
+fn replay(path: &Path) -> Result<(BTreeMap<String, Value>, usize, u64)> {
+    let mut entries = BTreeMap::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((entries, 0, 0)),
+        Err(e) => return Err(e).context(format!("cannot read {}", path.display())),
+    };
+    let mut reader = BufReader::new(file);
+    let (mut records, mut valid) = (0, 0);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let Some(json) = line.strip_suffix('\n') else {
+            break;
+        };
+        let record: Record = serde_json::from_str(json)
+            .with_context(|| format!("corrupt record in {}: {json}", path.display()))?;
+        match record.value {
+            Some(value) => entries.insert(record.key, value),
+            None => entries.remove(&record.key),
+        };
+        records += 1;
+        valid += line.len() as u64;
+        line.clear();
+    }
+    Ok((entries, records, valid))
+}
+
+fn scan(entries: &BTreeMap<String, Value>, prefix: &str) -> Vec<(String, Value)> {
+    entries
+        .range(prefix.to_string()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Write, path::PathBuf};
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::storage::{FileStorage, Storage, COMPACT_AFTER};
+
+    fn log_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("maelstrom-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("n1.kv")
+    }
+
+    #[test]
+    fn file_storage_survives_reopening() -> Result<()> {
+        let path = log_path("storage-reopen");
+        let storage = FileStorage::open(&path)?;
+        storage.put("log-a", json!([1, 2]))?;
+        storage.put("log-b", json!([3]))?;
+        storage.put("offset-a", json!(1))?;
+        storage.delete("log-b")?;
+        storage.put("log-a", json!([1, 2, 4]))?;
+        drop(storage);
+        // A crash in the middle of a write.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(br#"{"key":"log-c","val"#)?;
+
+        let storage = FileStorage::open(&path)?;
+        assert_eq!(storage.get("log-a")?, Some(json!([1, 2, 4])));
+        assert_eq!(storage.get("log-b")?, None);
+        assert_eq!(storage.get("log-c")?, None);
+        assert_eq!(
+            storage.scan("log-")?,
+            vec![("log-a".into(), json!([1, 2, 4]))]
+        );
+        storage.put("log-c", json!([5]))?;
+        drop(storage);
+        assert_eq!(FileStorage::open(&path)?.get("log-c")?, Some(json!([5])));
+        Ok(())
+    }
+
+    #[test]
+    fn file_storage_compacts_stale_records() -> Result<()> {
+        let path = log_path("storage-compact");
+        let storage = FileStorage::open(&path)?;
+        for i in 0..COMPACT_AFTER {
+            storage.put("counter", json!(i))?;
+        }
+
+        let lines = fs::read_to_string(&path)?.lines().count();
+        assert!(lines < COMPACT_AFTER, "{lines} records");
+        drop(storage);
+        let storage = FileStorage::open(&path)?;
+        assert_eq!(storage.get("counter")?, Some(json!(COMPACT_AFTER - 1)));
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::cell::RefCell;
+
+use crate::message::Body;
+
+/// Body field trace IDs are carried in, reserved for tracing.
+///
+/// Trace IDs follow a client operation across nodes. A client or a node that wants an operation
+/// traced puts a trace ID in this field of its request.
+/// [`Node::handle`](crate::node::Node::handle) runs the handler of every request within the
+/// request's trace, which it adds to the handler's log span and to its reply, and
+/// [`Outbox`](crate::outbox::Outbox) and [`RpcClient`](crate::rpc::RpcClient) add it to every
+/// message the handler sends through them. Messages the handler sends any other way get it with
+/// [`stamp`].
+pub const TRACE_FIELD: &str = "trace_id";
+
+thread_local! {
+    // Trace of the message the handler running on this thread is handling, if it has one.
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The trace ID of a message, if it has one.
+pub fn of(body: &Body) -> Option<&str> {
+    body.extra.get(TRACE_FIELD)?.as_str()
+}
+
+/// The trace of the handler running on this thread, if any.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Adds the current trace ID to `body`, unless it has one already or there is no current
+/// trace.
+pub fn stamp(body: &mut Body) {
+    if body.extra.contains_key(TRACE_FIELD) {
+        return;
+    }
+    if let Some(trace_id) = current() {
+        body.extra.insert(TRACE_FIELD.into(), trace_id.into());
+    }
+}
+
+/// Runs `f` within the trace `trace_id` on this thread, or within no trace if None. The
+/// previous trace is current again once `f` returns, or panics.
+pub fn in_trace<T>(trace_id: Option<String>, f: impl FnOnce() -> T) -> T {
+    /// Makes the previous trace current again when dropped.
+    struct Restore(Option<String>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|current| current.replace(trace_id)));
+    f()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::Body;
+    use crate::trace::{self, TRACE_FIELD};
+
+    #[test]
+    fn stamps_within_trace_only() {
+        let mut outside = Body::default();
+        let mut inside = Body::default();
+        let mut own = Body::default();
+        own.extra.insert(TRACE_FIELD.into(), "t0".into());
+
+        trace::stamp(&mut outside);
+        let nested = trace::in_trace(Some("t1".into()), || {
+            trace::stamp(&mut inside);
+            trace::stamp(&mut own);
+            trace::in_trace(None, trace::current)
+        });
+
+        assert_eq!(trace::of(&outside), None);
+        assert_eq!(trace::of(&inside), Some("t1"));
+        assert_eq!(trace::of(&own), Some("t0"));
+        assert_eq!(nested, None);
+        assert_eq!(trace::current(), None);
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, LineWriter, StdinLock, Stdout, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde_json::{value::RawValue, Deserializer};
+use tracing::{debug, warn};
+
+use crate::{message::Message, writer::Writer};
+
+/// Where a node recieves messages from and sends messages to.
+pub trait Transport {
+    /// Blocks until the next message arrives, returns None once no more messages will arrive.
+    fn recv(&mut self) -> Result<Option<Message>>;
+
+    /// Sends `msg` to its destination.
+    fn send(&mut self, msg: &Message) -> Result<()>;
+}
+
+/// Transport used under Maelstrom, one JSON message per line on stdin and stdout.
+#[derive(Debug)]
+pub struct StdioTransport<R = StdinLock<'static>, W = LineWriter<Stdout>> {
+    reader: LineReader<R>,
+    writer: Writer<W>,
+}
+
+impl StdioTransport {
+    pub fn new() -> Self {
+        Self::from_io(io::stdin().lock(), LineWriter::new(io::stdout()))
+    }
+}
+
+impl Default for StdioTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: BufRead, W: Write> StdioTransport<R, W> {
+    /// Creates a transport reading lines from `reader` and writing lines to `writer`.
+    pub fn from_io(reader: R, writer: W) -> Self {
+        Self {
+            reader: LineReader::new(reader),
+            writer: Writer::new(writer),
+        }
+    }
+
+    /// Splits the transport into its reading and writing halves, to use them from separate
+    /// threads.
+    pub fn into_parts(self) -> (LineReader<R>, Writer<W>) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: BufRead, W: Write> Transport for StdioTransport<R, W> {
+    fn recv(&mut self) -> Result<Option<Message>> {
+        self.reader.recv()
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        self.writer.write(msg)
+    }
+}
+
+/// Reads messages, JSON objects separated by any whitespace.
+///
+/// Maelstrom writes one message per line, but a line may hold several messages, and a message,
+/// pretty-printed say, may span several lines.
+#[derive(Debug)]
+pub struct LineReader<R> {
+    reader: R,
+    // Input read but not parsed yet, the start of a message that continues on the next line.
+    buffer: String,
+    // Messages parsed but not returned yet, the rest of a line holding several.
+    parsed: VecDeque<Message>,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: String::new(),
+            parsed: VecDeque::new(),
+        }
+    }
+
+    /// Blocks until the next message is read, returns None at the end of the input.
+    ///
+    /// Messages that are not valid are logged and skipped, as is the rest of their line when it
+    /// is not valid JSON.
+    pub fn recv(&mut self) -> Result<Option<Message>> {
+        loop {
+            if let Some(msg) = self.parsed.pop_front() {
+                return Ok(Some(msg));
+            }
+            if self.reader.read_line(&mut self.buffer)? == 0 {
+                let rest = self.buffer.trim();
+                if !rest.is_empty() {
+                    warn!(rest, "input ended within a message");
+                }
+                return Ok(None);
+            }
+            self.parse();
+        }
+    }
+
+    /// Parses every complete message in the buffer, keeps what may be the start of another.
+    fn parse(&mut self) {
+        let mut values = Deserializer::from_str(&self.buffer).into_iter::<&RawValue>();
+        let parsed = loop {
+            match values.next() {
+                Some(Ok(value)) => {
+                    let json = value.get();
+                    debug!(json, "recieved message");
+                    match serde_json::from_str::<Message>(json) {
+                        Ok(msg) => self.parsed.push_back(msg),
+                        Err(e) => warn!(json, "failed to parse message: {e}"),
+                    }
+                }
+                // The last message is incomplete, the rest of it is on the next lines.
+                Some(Err(e)) if e.is_eof() => break values.byte_offset(),
+                Some(Err(e)) => {
+                    let rest = &self.buffer[values.byte_offset()..];
+                    warn!(rest = rest.trim(), "failed to parse message: {e}");
+                    break self.buffer.len();
+                }
+                None => break self.buffer.len(),
+            }
+        };
+        self.buffer.drain(..parsed);
+    }
+}
+
+/// Reads messages on a thread of its own, so a caller waiting for input can give up after a
+/// timeout to run its timers, or be cancelled at shutdown without waiting for the input to end.
+#[derive(Debug)]
+pub struct BackgroundReader {
+    // None once cancelled.
+    messages: Receiver<Option<Result<Message>>>,
+    cancel: Cancel,
+}
+
+/// Cancels a [`BackgroundReader`], from any thread.
+#[derive(Debug, Clone)]
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+    wake: Sender<Option<Result<Message>>>,
+}
+
+impl Cancel {
+    /// Makes the reader return None, at once if it is waiting for input. A read already under
+    /// way on the reading thread is finished, and what it reads is dropped.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        let _ = self.wake.send(None);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl BackgroundReader {
+    /// Starts reading messages from `reader` on a new thread, which ends at the end of the input
+    /// or after the first read once cancelled.
+    pub fn spawn<R: BufRead + Send + 'static>(mut reader: LineReader<R>) -> Self {
+        let (sender, messages) = mpsc::channel();
+        let cancel = Cancel {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            wake: sender.clone(),
+        };
+        let cancelled = cancel.cancelled.clone();
+        thread::spawn(move || loop {
+            let read = reader.recv().transpose();
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            let end = !matches!(read, Some(Ok(_)));
+            if sender.send(read).is_err() || end {
+                return;
+            }
+        });
+        Self { messages, cancel }
+    }
+
+    /// Handle to cancel the reader with.
+    pub fn canceller(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    /// Blocks until the next message is read, returns None at the end of the input or once
+    /// cancelled.
+    pub fn recv(&self) -> Result<Option<Message>> {
+        if self.cancel.is_cancelled() {
+            return Ok(None);
+        }
+        match self.messages.recv() {
+            Ok(Some(read)) => read.map(Some),
+            Ok(None) | Err(_) => Ok(None),
+        }
+    }
+
+    /// Like [`BackgroundReader::recv`], but gives up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Input> {
+        if self.cancel.is_cancelled() {
+            return Ok(Input::End);
+        }
+        match self.messages.recv_timeout(timeout) {
+            Ok(Some(read)) => read.map(Input::Message),
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => Ok(Input::End),
+            Err(RecvTimeoutError::Timeout) => Ok(Input::Timeout),
+        }
+    }
+}
+
+/// What [`BackgroundReader::recv_timeout`] got.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Message(Message),
+    // Nothing was read before the timeout.
+    Timeout,
+    // The input ended, or the reader was cancelled.
+    End,
+}
+
+/// Transport backed by channels, for running nodes in process.
+#[derive(Debug)]
+pub struct InMemoryTransport {
+    inbox: Receiver<Message>,
+    outbox: Sender<Message>,
+}
+
+impl InMemoryTransport {
+    /// Creates a transport that recieves from `inbox` and sends to `outbox`.
+    pub fn new(inbox: Receiver<Message>, outbox: Sender<Message>) -> Self {
+        Self { inbox, outbox }
+    }
+
+    /// Creates two transports connected to each other, what one sends the other recieves.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+        (Self::new(a_rx, b_tx), Self::new(b_rx, a_tx))
+    }
+}
+
+impl Transport for InMemoryTransport {
+    /// Returns None once every sender of the inbox is dropped.
+    fn recv(&mut self) -> Result<Option<Message>> {
+        Ok(self.inbox.recv().ok())
+    }
+
+    fn send(&mut self, msg: &Message) -> Result<()> {
+        self.outbox
+            .send(msg.clone())
+            .map_err(|e| anyhow::anyhow!("Unavailable: cannot send {:?}, receiver dropped", e.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufReader, Cursor, Write},
+        os::unix::net::UnixStream,
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::message::Message;
+    use crate::transport::{
+        BackgroundReader, InMemoryTransport, Input, LineReader, StdioTransport, Transport,
+    };
+
+    fn msg(typ: &str) -> Message {
+        serde_json::from_value(json!({
+            "src": "c1", "dest": "n1", "body": { "type": typ, "msg_id": 1 }
+        }))
+        .expect("invalid message json.")
+    }
+
+    #[test]
+    fn stdio_skips_invalid_lines() -> Result<()> {
+        // Tests that garbage and empty lines are skipped and EOF ends the stream.
+        let input = format!("not json\n\n{}\n", serde_json::to_string(&msg("echo"))?);
+        let mut transport = StdioTransport::from_io(Cursor::new(input), vec![]);
+
+        assert_eq!(transport.recv()?, Some(msg("echo")));
+        assert_eq!(transport.recv()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn stdio_reads_messages_across_lines() -> Result<()> {
+        // Tests that messages sharing a line or spanning lines are each read once.
+        let (a, b) = (serde_json::to_string(&msg("a"))?, msg("b"));
+        let input = format!(
+            "{a} {a}\n{}\n{{\"src\": 1}} {a}\n{{\"src\":",
+            serde_json::to_string_pretty(&b)?
+        );
+        let mut transport = StdioTransport::from_io(Cursor::new(input), vec![]);
+
+        let mut read = vec![];
+        while let Some(msg) = transport.recv()? {
+            read.push(msg);
+        }
+        assert_eq!(read, vec![msg("a"), msg("a"), b, msg("a")]);
+        Ok(())
+    }
+
+    #[test]
+    fn stdio_writes_one_message_per_line() -> Result<()> {
+        let mut output = vec![];
+        let mut transport = StdioTransport::from_io(Cursor::new(""), &mut output);
+
+        transport.send(&msg("a"))?;
+        transport.send(&msg("b"))?;
+
+        let lines: Vec<Message> = String::from_utf8(output)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines, vec![msg("a"), msg("b")]);
+        Ok(())
+    }
+
+    #[test]
+    fn background_reader_can_be_cancelled() -> Result<()> {
+        // Tests that a reader waiting on input that never comes returns once cancelled.
+        let (mut writer, input) = UnixStream::pair()?;
+        writeln!(writer, "{}", serde_json::to_string(&msg("a"))?)?;
+        let reader = BackgroundReader::spawn(LineReader::new(BufReader::new(input)));
+
+        assert_eq!(reader.recv()?, Some(msg("a")));
+        assert_eq!(
+            reader.recv_timeout(Duration::from_millis(10))?,
+            Input::Timeout
+        );
+        let cancel = reader.canceller();
+        let cancelling = thread::spawn(move || cancel.cancel());
+        assert_eq!(reader.recv()?, None);
+        assert_eq!(reader.recv_timeout(Duration::from_secs(1))?, Input::End);
+        cancelling.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_pair_is_connected() -> Result<()> {
+        let (mut a, mut b) = InMemoryTransport::pair();
+
+        a.send(&msg("ping"))?;
+        b.send(&msg("pong"))?;
+
+        assert_eq!(b.recv()?, Some(msg("ping")));
+        assert_eq!(a.recv()?, Some(msg("pong")));
+        drop(a);
+        assert_eq!(b.recv()?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,600 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    body,
+    error::NodeError,
+    message::{Body, Message},
+    node::Handler,
+    reply,
+    rpc::Rpc,
+    txn::MicroOp,
+};
+
+/// How long a participant holds a prepared transaction before asking its coordinator for the
+/// decision.
+const RECOVER_AFTER: Duration = Duration::from_secs(2);
+
+/// Transactional registers sharded across nodes, committed with two-phase commit (2PC).
+///
+/// Every key is owned by one node, the shard `key % nodes` of the sorted node IDs. The node a
+/// `txn` arrives at coordinates it: it sends every shard its ops in a `prepare`, and each
+/// participant applies the reads, buffers the writes and locks the keys. A participant votes
+/// no, with a [`NodeError::TxnConflict`] reply, if a key is locked by another prepared
+/// transaction, so transactions never wait on each other. Once every shard voted yes the
+/// coordinator decides to commit and sends `commit` to every shard, otherwise it sends `abort`.
+///
+/// The coordinator records its decision before sending it, and participants that hold a
+/// prepared transaction for too long ask it for the decision, see [`TwoPhase::recover`]. A
+/// coordinator that has not decided yet then decides to abort, so a transaction whose commit or
+/// abort was lost still ends, and one whose coordinator timed out on a vote is aborted on every
+/// shard. A decision is forgotten once every participant acked it or asked for it, and a
+/// transaction the coordinator is not deciding and has no decision for is answered with an
+/// abort without recording one.
+#[derive(Debug)]
+pub struct TwoPhase<R> {
+    rpc: R,
+    // Registers of the keys this node owns.
+    store: Mutex<HashMap<u64, u64>>,
+    // Transactions prepared on this node waiting for a decision, by txn ID.
+    prepared: Mutex<HashMap<String, Prepared>>,
+    // Decisions of the transactions this node coordinates, until every participant has it.
+    decisions: Mutex<HashMap<String, Decided>>,
+    // Transactions this node coordinates that are not decided yet. Locked after `decisions`.
+    deciding: Mutex<HashSet<String>>,
+    // ID of this node, set on init.
+    node_id: Mutex<String>,
+    // Every node, sorted, set on init.
+    node_ids: Mutex<Vec<String>>,
+    // Numbers the transactions this node coordinates.
+    next_txn: AtomicU64,
+    recover_after: Duration,
+}
+
+/// Outcome of a transaction, decided by its coordinator.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Commit,
+    Abort,
+}
+
+/// A decision of a transaction, with the participants that do not have it yet.
+#[derive(Debug)]
+struct Decided {
+    decision: Decision,
+    unacked: BTreeSet<String>,
+}
+
+/// A transaction prepared on a participant.
+#[derive(Debug)]
+struct Prepared {
+    // Node that coordinates the transaction.
+    coordinator: String,
+    // Keys the transaction reads or writes, locked until it ends.
+    keys: BTreeSet<u64>,
+    // Last write to every key in the transaction.
+    writes: BTreeMap<u64, u64>,
+    // When it was prepared.
+    at: Instant,
+}
+
+/// Returns the handlers of the sharded txn workload, backed by `two_phase`.
+pub fn handlers<R: Rpc + Sync>(two_phase: &TwoPhase<R>) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert("txn".into(), Box::new(|msg, id| two_phase.txn(msg, id)));
+    for typ in ["prepare", "commit", "abort", "decision"] {
+        funs.insert(typ.into(), Box::new(|msg, id| two_phase.handle(msg, id)));
+    }
+    funs
+}
+
+impl<R: Rpc + Sync> TwoPhase<R> {
+    /// Creates a shard that talks to the other shards through `rpc`.
+    pub fn new(rpc: R) -> Self {
+        Self {
+            rpc,
+            store: Mutex::default(),
+            prepared: Mutex::default(),
+            decisions: Mutex::default(),
+            deciding: Mutex::default(),
+            node_id: Mutex::default(),
+            node_ids: Mutex::default(),
+            next_txn: AtomicU64::new(0),
+            recover_after: RECOVER_AFTER,
+        }
+    }
+
+    /// Sets how long a prepared transaction is held before asking its coordinator for the
+    /// decision.
+    pub fn with_recover_after(mut self, recover_after: Duration) -> Self {
+        self.recover_after = recover_after;
+        self
+    }
+
+    /// Sets the identity of this node and of the shards, meant to be used as the node's init
+    /// handler.
+    pub fn init(&self, node_id: &str, node_ids: &[String]) {
+        *self.node_id.lock().unwrap() = node_id.to_string();
+        let mut node_ids = node_ids.to_vec();
+        node_ids.sort();
+        *self.node_ids.lock().unwrap() = node_ids;
+    }
+
+    /// The node owning `key`.
+    fn owner(&self, key: u64) -> Result<String> {
+        let node_ids = self.node_ids.lock().unwrap();
+        if node_ids.is_empty() {
+            return Err(NodeError::NotReady("no shards before init".into()).into());
+        }
+        Ok(node_ids[(key % node_ids.len() as u64) as usize].clone())
+    }
+
+    /// Applies all the micro-ops of `ops` on the shards owning their keys, coordinating the
+    /// transaction, returns the completed ops.
+    ///
+    /// Either every shard commits its ops or none does. Fails with a
+    /// [`NodeError::TxnConflict`] if a shard voted no, and with a [`NodeError::Unavailable`] if
+    /// a shard did not vote, the transaction is aborted in both cases.
+    pub fn apply(&self, ops: Vec<MicroOp>) -> Result<Vec<MicroOp>> {
+        if let Some(invalid) = ops.iter().find(|op| !op.is_valid()) {
+            return Err(anyhow!("invalid micro-op {:?}", invalid));
+        }
+        let node_id = self.node_id.lock().unwrap().clone();
+        let txn_id = format!(
+            "{node_id}-{}",
+            self.next_txn.fetch_add(1, Ordering::Relaxed)
+        );
+
+        // Ops of every shard, with their index in the transaction.
+        let mut shards: BTreeMap<String, Vec<(usize, MicroOp)>> = BTreeMap::new();
+        for (i, op) in ops.into_iter().enumerate() {
+            shards.entry(self.owner(op.1)?).or_default().push((i, op));
+        }
+
+        self.deciding.lock().unwrap().insert(txn_id.clone());
+        let votes = self.ask_shards(shards.keys(), |shard| {
+            let ops: Vec<&MicroOp> = shards[shard].iter().map(|(_, op)| op).collect();
+            body!("prepare", { "txn_id": txn_id, "coordinator": node_id, "txn": ops })
+        });
+
+        let mut completed = Vec::new();
+        let mut failure = None;
+        for (shard, vote) in votes {
+            let vote = vote.map_err(|e| {
+                anyhow::Error::from(NodeError::Unavailable(format!("{shard} did not vote: {e}")))
+            });
+            match vote.and_then(|reply| vote_of(&shard, reply)) {
+                Ok(ops) => {
+                    let indexes = shards[&shard].iter().map(|(i, _)| *i);
+                    completed.extend(indexes.zip(ops));
+                }
+                Err(e) => failure = failure.or(Some(e)),
+            }
+        }
+
+        let decision = match failure {
+            None => Decision::Commit,
+            Some(_) => Decision::Abort,
+        };
+        let decision = {
+            let mut decisions = self.decisions.lock().unwrap();
+            let decided = decisions.entry(txn_id.clone()).or_insert(Decided {
+                decision,
+                unacked: BTreeSet::new(),
+            });
+            decided.unacked = shards.keys().cloned().collect();
+            self.deciding.lock().unwrap().remove(&txn_id);
+            decided.decision
+        };
+        debug!(%txn_id, ?decision, "decided");
+        self.send_decision(&txn_id, decision, shards.keys());
+
+        match (decision, failure) {
+            (Decision::Commit, _) => {
+                completed.sort_by_key(|(i, _)| *i);
+                Ok(completed.into_iter().map(|(_, op)| op).collect())
+            }
+            (Decision::Abort, Some(e)) => Err(e),
+            (Decision::Abort, None) => Err(NodeError::TxnConflict(format!(
+                "{txn_id} was aborted by a participant asking for the decision"
+            ))
+            .into()),
+        }
+    }
+
+    /// Sends `decision` to every shard, and forgets it once all of them have it. Shards that
+    /// do not answer ask for it later, see [`TwoPhase::recover`].
+    fn send_decision<'a>(
+        &self,
+        txn_id: &str,
+        decision: Decision,
+        shards: impl Iterator<Item = &'a String>,
+    ) {
+        let typ = match decision {
+            Decision::Commit => "commit",
+            Decision::Abort => "abort",
+        };
+        let replies = self.ask_shards(shards, |_| body!(typ, { "txn_id": txn_id }));
+        for (shard, reply) in replies {
+            match reply {
+                Ok(_) => self.acked(txn_id, &shard),
+                Err(e) => warn!(%shard, txn_id, "{typ} was not acked: {e}"),
+            }
+        }
+    }
+
+    /// Notes that `shard` has the decision of `txn_id`, forgetting the decision once every
+    /// participant has it.
+    fn acked(&self, txn_id: &str, shard: &str) {
+        let mut decisions = self.decisions.lock().unwrap();
+        let Some(decided) = decisions.get_mut(txn_id) else {
+            return;
+        };
+        decided.unacked.remove(shard);
+        if decided.unacked.is_empty() {
+            decisions.remove(txn_id);
+        }
+    }
+
+    /// Sends every shard of `shards` the request `request` makes for it, in parallel, returns
+    /// the reply of every shard.
+    fn ask_shards<'a>(
+        &self,
+        shards: impl Iterator<Item = &'a String>,
+        request: impl Fn(&str) -> Body,
+    ) -> Vec<(String, Result<Body>)> {
+        thread::scope(|s| {
+            let calls: Vec<_> = shards
+                .map(|shard| {
+                    let request = request(shard);
+                    (shard.clone(), s.spawn(move || self.call(shard, request)))
+                })
+                .collect();
+            calls
+                .into_iter()
+                .map(|(shard, call)| {
+                    let reply = call
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow!("call to {shard} panicked")));
+                    (shard, reply)
+                })
+                .collect()
+        })
+    }
+
+    /// Sends `request` to `dest`, applying it right away if this node is `dest`.
+    fn call(&self, dest: &str, request: Body) -> Result<Body> {
+        if *dest == *self.node_id.lock().unwrap() {
+            return self.apply_request(&request);
+        }
+        self.rpc.call(dest, request)
+    }
+
+    /// Applies a `prepare`, `commit`, `abort` or `decision` in `request`, returns the body of
+    /// the reply.
+    pub fn apply_request(&self, request: &Body) -> Result<Body> {
+        let txn_id = request.get_str("txn_id")?;
+        match request.typ.as_str() {
+            "prepare" => {
+                let coordinator = request.get_str("coordinator")?;
+                let ops: Vec<MicroOp> = request.get_as("txn")?;
+                let completed = self.prepare(txn_id, coordinator, ops)?;
+                Ok(body!("prepare_ok", { "txn": completed }))
+            }
+            "commit" => {
+                self.end(txn_id, Decision::Commit);
+                Ok(body!("commit_ok"))
+            }
+            "abort" => {
+                self.end(txn_id, Decision::Abort);
+                Ok(body!("abort_ok"))
+            }
+            "decision" => {
+                let participant = request.get_str("participant")?;
+                let decision = self.decision(txn_id, participant);
+                Ok(body!("decision_ok", { "decision": decision }))
+            }
+            typ => Err(anyhow!(
+                "two-phase commit cannot apply {typ}: {:?}",
+                request
+            )),
+        }
+    }
+
+    /// The decision of `txn_id`, that `participant` asked for.
+    ///
+    /// A coordinator asked while deciding presumes an abort, so it cannot commit anymore and the
+    /// participant can let go of the keys. A transaction that is neither deciding nor decided
+    /// was never coordinated here, or its decision was forgotten when the node restarted, and
+    /// is aborted without recording it.
+    fn decision(&self, txn_id: &str, participant: &str) -> Decision {
+        let decision = {
+            let mut decisions = self.decisions.lock().unwrap();
+            if self.deciding.lock().unwrap().contains(txn_id) {
+                let decided = decisions.entry(txn_id.to_string()).or_insert(Decided {
+                    decision: Decision::Abort,
+                    unacked: BTreeSet::new(),
+                });
+                return decided.decision;
+            }
+            match decisions.get(txn_id) {
+                Some(decided) => decided.decision,
+                None => return Decision::Abort,
+            }
+        };
+        self.acked(txn_id, participant);
+        decision
+    }
+
+    /// Prepares the ops of transaction `txn_id` on this shard, locking their keys, returns the
+    /// completed ops. Votes no with a [`NodeError::TxnConflict`] if a key is locked by another
+    /// transaction.
+    fn prepare(&self, txn_id: &str, coordinator: &str, ops: Vec<MicroOp>) -> Result<Vec<MicroOp>> {
+        let keys: BTreeSet<u64> = ops.iter().map(|op| op.1).collect();
+        let mut prepared = self.prepared.lock().unwrap();
+        let locked = prepared
+            .iter()
+            .filter(|(id, _)| *id != txn_id)
+            .find_map(|(id, other)| {
+                keys.iter()
+                    .find(|k| other.keys.contains(k))
+                    .map(|k| (id, k))
+            });
+        if let Some((other, key)) = locked {
+            return Err(NodeError::TxnConflict(format!("key {key} is locked by {other}")).into());
+        }
+
+        let store = self.store.lock().unwrap();
+        let mut writes = BTreeMap::new();
+        let completed = ops
+            .into_iter()
+            .map(|MicroOp(op, key, value)| {
+                if op == "w" {
+                    let value = value.unwrap_or_default();
+                    writes.insert(key, value);
+                    return MicroOp(op, key, Some(value));
+                }
+                let value = writes.get(&key).or_else(|| store.get(&key)).copied();
+                MicroOp(op, key, value)
+            })
+            .collect();
+        prepared.insert(
+            txn_id.to_string(),
+            Prepared {
+                coordinator: coordinator.to_string(),
+                keys,
+                writes,
+                at: Instant::now(),
+            },
+        );
+        Ok(completed)
+    }
+
+    /// Ends transaction `txn_id` on this shard with `decision`, applying its writes on a commit
+    /// and unlocking its keys. Transactions not prepared here are ignored, so decisions can
+    /// arrive more than once.
+    fn end(&self, txn_id: &str, decision: Decision) {
+        let Some(txn) = self.prepared.lock().unwrap().remove(txn_id) else {
+            return;
+        };
+        if decision == Decision::Commit {
+            self.store.lock().unwrap().extend(txn.writes);
+        }
+    }
+
+    /// Asks the coordinators of the transactions prepared longer than the recovery timeout for
+    /// their decision, and ends the ones they answer for. Returns the number of transactions
+    /// ended.
+    pub fn recover(&self, now: Instant) -> usize {
+        let stuck: Vec<(String, String)> = self
+            .prepared
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, txn)| now.duration_since(txn.at) >= self.recover_after)
+            .map(|(id, txn)| (id.clone(), txn.coordinator.clone()))
+            .collect();
+
+        let mut ended = 0;
+        for (txn_id, coordinator) in stuck {
+            let node_id = self.node_id.lock().unwrap().clone();
+            let request = body!("decision", { "txn_id": txn_id, "participant": node_id });
+            let asked = self
+                .call(&coordinator, request)
+                .and_then(|reply| reply.get_as::<Decision>("decision"));
+            match asked {
+                Ok(decision) => {
+                    debug!(%txn_id, ?decision, "recovered");
+                    self.end(&txn_id, decision);
+                    ended += 1;
+                }
+                Err(e) => warn!(%txn_id, %coordinator, "no decision: {e}"),
+            }
+        }
+        ended
+    }
+
+    fn txn(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let ops: Vec<MicroOp> = msg.body.get_as("txn")?;
+        let completed = self.apply(ops)?;
+        Ok(reply!(msg, msg_id, "txn_ok", { "txn": completed }))
+    }
+
+    fn handle(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let mut body = self.apply_request(&msg.body)?;
+        body.msg_id = msg_id;
+        Ok(msg.reply_with(body))
+    }
+}
+
+impl<R: Rpc + Send + Sync + 'static> TwoPhase<R> {
+    /// Calls [`TwoPhase::recover`] every `interval` from a background thread.
+    pub fn recover_every(self: &Arc<Self>, interval: Duration) -> thread::JoinHandle<()> {
+        let two_phase = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            two_phase.recover(Instant::now());
+        })
+    }
+}
+
+/// The completed ops of the yes vote of `shard` in `reply`, or the error of its no vote.
+fn vote_of(shard: &str, reply: Body) -> Result<Vec<MicroOp>> {
+    match reply.typ.as_str() {
+        "prepare_ok" => reply.get_as("txn"),
+        _ => {
+            let text = reply.get_str("text").unwrap_or_default();
+            Err(NodeError::TxnConflict(format!("{shard} voted no: {text}")).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeSet, HashMap, HashSet},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::Result;
+
+    use crate::error::NodeError;
+    use crate::message::{Body, TXN_CONFLICT};
+    use crate::rpc::Rpc;
+    use crate::two_phase::{Decided, Decision, TwoPhase};
+    use crate::txn::MicroOp;
+
+    /// Shards called in place of sending them messages, the ones in `down` do not answer.
+    #[derive(Default)]
+    struct Cluster {
+        shards: Mutex<HashMap<String, Arc<Shard>>>,
+        down: Mutex<HashSet<String>>,
+    }
+
+    impl Rpc for Cluster {
+        fn call(&self, dest: &str, body: Body) -> Result<Body> {
+            if self.down.lock().unwrap().contains(dest) {
+                return Err(NodeError::Timeout(format!("{dest} is down")).into());
+            }
+            let shard = self.shards.lock().unwrap()[dest].clone();
+            shard.apply_request(&body).or_else(|e| {
+                let e = NodeError::from(e);
+                Ok(serde_json::from_value(serde_json::json!({
+                    "type": "error", "code": e.code(), "text": e.to_string()
+                }))?)
+            })
+        }
+    }
+
+    type Shard = TwoPhase<Arc<Cluster>>;
+
+    fn cluster() -> (Arc<Cluster>, Vec<Arc<Shard>>) {
+        let cluster = Arc::new(Cluster::default());
+        let ids = vec!["n0".to_string(), "n1".to_string()];
+        let shards: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                let shard = Arc::new(
+                    TwoPhase::new(cluster.clone()).with_recover_after(Duration::from_millis(0)),
+                );
+                shard.init(id, &ids);
+                cluster
+                    .shards
+                    .lock()
+                    .unwrap()
+                    .insert(id.clone(), shard.clone());
+                shard
+            })
+            .collect();
+        (cluster, shards)
+    }
+
+    fn op(op: &str, key: u64, value: Option<u64>) -> MicroOp {
+        MicroOp(op.to_string(), key, value)
+    }
+
+    #[test]
+    fn transaction_commits_on_every_shard() -> Result<()> {
+        // Tests that a transaction writing keys of both shards is seen whole by another
+        // coordinator, and that reads come back in the order of the transaction.
+        let (_cluster, shards) = cluster();
+
+        let completed = shards[0].apply(vec![
+            op("w", 1, Some(10)),
+            op("w", 2, Some(20)),
+            op("r", 1, None),
+        ])?;
+        assert_eq!(completed[2], op("r", 1, Some(10)));
+
+        let read = shards[1].apply(vec![op("r", 2, None), op("r", 1, None)])?;
+        assert_eq!(read, vec![op("r", 2, Some(20)), op("r", 1, Some(10))]);
+        assert!(shards.iter().all(|s| s.prepared.lock().unwrap().is_empty()));
+        assert!(shards
+            .iter()
+            .all(|s| s.decisions.lock().unwrap().is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn stuck_transaction_is_aborted_on_recovery() -> Result<()> {
+        // Tests that a transaction locking a key makes others writing it vote no, until the
+        // participant asks the coordinator, which never decided and so aborts it.
+        let (cluster, shards) = cluster();
+        shards[1].prepare("n0-99", "n0", vec![op("w", 1, Some(5))])?;
+
+        let conflict = shards[0].apply(vec![op("w", 1, Some(6)), op("w", 2, Some(6))]);
+        assert_eq!(
+            conflict.map_err(|e| NodeError::from(e).code()),
+            Err(TXN_CONFLICT)
+        );
+        assert_eq!(shards[0].apply(vec![op("r", 2, None)])?[0].2, None);
+
+        cluster.down.lock().unwrap().insert("n0".into());
+        assert_eq!(shards[1].recover(Instant::now()), 0, "coordinator is down");
+        cluster.down.lock().unwrap().clear();
+        assert_eq!(shards[1].recover(Instant::now()), 1);
+        assert!(
+            shards[0].decisions.lock().unwrap().is_empty(),
+            "not recorded"
+        );
+
+        shards[0].apply(vec![op("w", 1, Some(7))])?;
+        assert_eq!(shards[0].apply(vec![op("r", 1, None)])?[0].2, Some(7));
+        Ok(())
+    }
+
+    #[test]
+    fn unacked_decision_is_forgotten_once_asked_for() -> Result<()> {
+        // Tests that a decision a participant did not ack is kept until the participant asks
+        // for it, and then forgotten.
+        let (_cluster, shards) = cluster();
+        shards[1].prepare("n0-7", "n0", vec![op("w", 1, Some(5))])?;
+        shards[0].decisions.lock().unwrap().insert(
+            "n0-7".into(),
+            Decided {
+                decision: Decision::Commit,
+                unacked: BTreeSet::from(["n0".to_string(), "n1".to_string()]),
+            },
+        );
+        shards[0].acked("n0-7", "n0");
+
+        assert_eq!(shards[1].recover(Instant::now()), 1);
+
+        assert!(shards[0].decisions.lock().unwrap().is_empty());
+        assert_eq!(shards[0].apply(vec![op("r", 1, None)])?[0].2, Some(5));
+        Ok(())
+    }
+}
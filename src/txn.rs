@@ -0,0 +1,670 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{mpsc::Sender, Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    body, clock::LamportClock, error::NodeError, message::Message, mvcc::MvccStore, node::Handler,
+    reply, storage::Storage,
+};
+
+/// A single operation of a transaction, either a read `["r", key, null]` or a write
+/// `["w", key, value]`. Reads have their value filled in when the transaction is applied.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct MicroOp(pub String, pub u64, pub Option<u64>);
+
+impl MicroOp {
+    /// Whether the op is a read, or a write with a value.
+    pub(crate) fn is_valid(&self) -> bool {
+        match self.0.as_str() {
+            "r" => true,
+            "w" => self.2.is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Isolation level of the txn workload.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum Isolation {
+    // Writes are applied to the store as soon as they are seen.
+    #[default]
+    ReadUncommitted,
+    // Writes are buffered and applied to the store together when the transaction commits. Reads
+    // see the transaction's own buffered writes.
+    ReadCommitted,
+    // Like ReadCommitted, and reads see the snapshot of the store when the transaction starts.
+    // A transaction writing a key written since it started is aborted with a conflict, the
+    // first one to commit wins.
+    Snapshot,
+}
+
+/// Transactional read/write register workload (txn-rw-register).
+///
+/// Transactions are applied one at a time against an in-memory map of registers. When created
+/// with [`Txn::replicated`] the write-set of every transaction is also sent to all other nodes
+/// in a single message, without waiting for them, so transactions keep completing during
+/// partitions and peers apply a transaction's writes all at once.
+///
+/// Every transaction gets a Lamport timestamp and each register keeps the write with the largest
+/// (timestamp, node) pair. All nodes thus agree on the order of writes to every key, which rules
+/// out write cycles (G0) and gives read uncommitted.
+///
+/// Registers keep the value of every write still visible to a running transaction, in an
+/// [`MvccStore`]. Read-only transactions read the snapshot at the timestamp they start at,
+/// without holding the store while other transactions write.
+#[derive(Debug, Default)]
+pub struct Txn {
+    // Values of every register, versioned by the (timestamp, node) of their write.
+    store: Mutex<MvccStore<u64, u64>>,
+    // Timestamps of the running read-only transactions, with how many run at each.
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+    // Timestamps transactions, moved past the timestamps of replicated transactions.
+    clock: LamportClock,
+    // ID of this node, set on init.
+    node_id: Mutex<String>,
+    // Nodes to replicate writes to, set on init.
+    peers: Mutex<Vec<String>>,
+    // Where replication messages are sent, None for a single node.
+    outbox: Option<Sender<Message>>,
+    isolation: Isolation,
+    // Where the latest value of every register is kept to survive crashes, if anywhere, set by
+    // `Txn::restore_from`.
+    storage: OnceLock<Box<dyn Storage>>,
+}
+
+/// A register value as kept in storage, with the version of its write.
+#[derive(Serialize, Deserialize, Debug)]
+struct Stored {
+    value: u64,
+    timestamp: u64,
+    node: String,
+}
+
+/// Writes of a transaction sent to other nodes.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+struct Replicate {
+    // Timestamp of the transaction.
+    timestamp: u64,
+    // (key, value) of the last write to every key in the transaction.
+    writes: Vec<(u64, u64)>,
+}
+
+/// Returns the handlers of the txn workload, backed by `txn`.
+pub fn handlers(txn: &Txn) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert("txn".into(), Box::new(|msg, id| txn.txn(msg, id)));
+    funs.insert(
+        "replicate".into(),
+        Box::new(|msg, id| txn.replicate(msg, id)),
+    );
+    funs
+}
+
+impl Txn {
+    /// Creates a single node txn store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a txn store that sends the writes of every transaction to `outbox`, addressed
+    /// to every other node.
+    pub fn replicated(outbox: Sender<Message>, isolation: Isolation) -> Self {
+        Self {
+            outbox: Some(outbox),
+            isolation,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the identity of this node and its peers, meant to be used as the node's init handler.
+    pub fn init(&self, node_id: &str, node_ids: &[String]) {
+        *self.node_id.lock().unwrap() = node_id.to_string();
+        *self.peers.lock().unwrap() = node_ids
+            .iter()
+            .filter(|&id| id != node_id)
+            .cloned()
+            .collect();
+    }
+
+    /// Restores the registers kept in `storage` by a previous run of this node, and keeps the
+    /// latest value of every register written from now on there, before transactions are
+    /// acked. Meant to be called on init, as the storage is usually per node.
+    pub fn restore_from(&self, storage: impl Storage + 'static) -> Result<()> {
+        let mut store = self.store.lock().unwrap();
+        for (key, stored) in storage.scan("")? {
+            let Stored {
+                value,
+                timestamp,
+                node,
+            } = serde_json::from_value(stored)?;
+            store.write(key.parse()?, timestamp, &node, value);
+            self.clock.observe(timestamp);
+        }
+        self.storage
+            .set(Box::new(storage))
+            .map_err(|_| anyhow!("txn storage is already set"))
+    }
+
+    /// Applies all the micro-ops of `ops` in order, returns the completed ops.
+    ///
+    /// Either all ops are applied or, if any op is invalid, none are.
+    pub fn apply(&self, ops: Vec<MicroOp>) -> Result<Vec<MicroOp>> {
+        if let Some(invalid) = ops.iter().find(|op| !op.is_valid()) {
+            return Err(anyhow!("invalid micro-op {:?}", invalid));
+        }
+        if ops.iter().all(|op| op.0 == "r") {
+            return Ok(self.read_snapshot(ops));
+        }
+        if self.isolation == Isolation::Snapshot {
+            return self.apply_snapshot(ops);
+        }
+
+        let node_id = self.node_id.lock().unwrap().clone();
+        // Held for the whole transaction, transactions are applied one at a time.
+        let mut store = self.store.lock().unwrap();
+        let timestamp = self.clock.tick();
+
+        // Last write to every key in this transaction.
+        let mut writes = BTreeMap::new();
+        let completed = ops
+            .into_iter()
+            .map(|MicroOp(op, key, value)| {
+                if op == "w" {
+                    let value = value.unwrap_or_default();
+                    writes.insert(key, value);
+                    if self.isolation == Isolation::ReadUncommitted {
+                        self.write(&mut store, key, value, timestamp, &node_id)?;
+                    }
+                    return Ok(MicroOp(op, key, Some(value)));
+                }
+
+                let buffered = match self.isolation {
+                    Isolation::ReadCommitted | Isolation::Snapshot => writes.get(&key).copied(),
+                    Isolation::ReadUncommitted => None,
+                };
+                let value = buffered.or_else(|| store.latest(&key).copied());
+                Ok(MicroOp(op, key, value))
+            })
+            .collect::<Result<_>>()?;
+
+        if self.isolation == Isolation::ReadCommitted {
+            for (&key, &value) in &writes {
+                self.write(&mut store, key, value, timestamp, &node_id)?;
+            }
+        }
+
+        drop(store);
+        if !writes.is_empty() {
+            self.send_replicate(Replicate {
+                timestamp,
+                writes: writes.into_iter().collect(),
+            })?;
+        }
+        Ok(completed)
+    }
+
+    /// Applies the reads of a read-only transaction at the snapshot of the current timestamp,
+    /// taking the store for every read only.
+    fn read_snapshot(&self, ops: Vec<MicroOp>) -> Vec<MicroOp> {
+        let timestamp = self.begin_snapshot();
+        let completed = ops
+            .into_iter()
+            .map(|MicroOp(op, key, _)| {
+                let value = self.store.lock().unwrap().read_at(&key, timestamp).copied();
+                MicroOp(op, key, value)
+            })
+            .collect();
+        self.end_snapshot(timestamp);
+        completed
+    }
+
+    /// Applies the micro-ops of `ops` with snapshot isolation: reads at the snapshot of the
+    /// current timestamp, then commits the writes unless a key written was written since.
+    ///
+    /// Only writes this node knows of conflict, writes of other nodes once they are replicated
+    /// here, and only if their timestamps are after the start of the transaction.
+    fn apply_snapshot(&self, ops: Vec<MicroOp>) -> Result<Vec<MicroOp>> {
+        let node_id = self.node_id.lock().unwrap().clone();
+        let start = self.begin_snapshot();
+
+        let mut writes = BTreeMap::new();
+        let completed = ops
+            .into_iter()
+            .map(|MicroOp(op, key, value)| {
+                if op == "w" {
+                    let value = value.unwrap_or_default();
+                    writes.insert(key, value);
+                    return MicroOp(op, key, Some(value));
+                }
+                let value = writes.get(&key).copied().or_else(|| {
+                    let store = self.store.lock().unwrap();
+                    store.read_at(&key, start).copied()
+                });
+                MicroOp(op, key, value)
+            })
+            .collect();
+
+        let timestamp = self.commit(start, &writes, &node_id)?;
+        self.send_replicate(Replicate {
+            timestamp,
+            writes: writes.into_iter().collect(),
+        })?;
+        Ok(completed)
+    }
+
+    /// Ends the snapshot of a transaction started at `start` and commits its `writes`, unless a
+    /// key of them was written since, returns the timestamp of the commit.
+    fn commit(&self, start: u64, writes: &BTreeMap<u64, u64>, node_id: &str) -> Result<u64> {
+        let mut store = self.store.lock().unwrap();
+        self.end_snapshot(start);
+        if let Some(key) = writes.keys().find(|&key| store.written_after(key, start)) {
+            return Err(NodeError::TxnConflict(format!(
+                "key {key} was written since the transaction started"
+            ))
+            .into());
+        }
+        let timestamp = self.clock.tick();
+        for (&key, &value) in writes {
+            self.write(&mut store, key, value, timestamp, node_id)?;
+        }
+        Ok(timestamp)
+    }
+
+    /// Starts a snapshot at the current timestamp, whose values are kept until it ends.
+    fn begin_snapshot(&self) -> u64 {
+        let timestamp = self.clock.now();
+        *self.snapshots.lock().unwrap().entry(timestamp).or_default() += 1;
+        timestamp
+    }
+
+    /// Ends a snapshot started with [`Txn::begin_snapshot`] at `timestamp`.
+    fn end_snapshot(&self, timestamp: u64) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        if let Some(running) = snapshots.get_mut(&timestamp) {
+            *running -= 1;
+            if *running == 0 {
+                snapshots.remove(&timestamp);
+            }
+        }
+    }
+
+    /// Writes `value` to `key` in `store` at the version `(timestamp, node_id)`, and drops the
+    /// values of the key no running transaction sees anymore. The value is kept in the storage
+    /// too if it is the latest of the key.
+    fn write(
+        &self,
+        store: &mut MvccStore<u64, u64>,
+        key: u64,
+        value: u64,
+        timestamp: u64,
+        node_id: &str,
+    ) -> Result<()> {
+        let latest_write = store.write(key, timestamp, node_id, value);
+        let oldest = self.snapshots.lock().unwrap().keys().next().copied();
+        let latest = timestamp.max(self.clock.now());
+        store.prune(&key, oldest.unwrap_or(latest).min(latest));
+        match self.storage.get() {
+            Some(storage) if latest_write => {
+                let stored = Stored {
+                    value,
+                    timestamp,
+                    node: node_id.to_string(),
+                };
+                storage
+                    .put(&key.to_string(), json!(stored))
+                    .map_err(|e| anyhow!("Internal: cannot store write: {e:#}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the writes of a transaction to all peers.
+    fn send_replicate(&self, replicate: Replicate) -> Result<()> {
+        let Some(outbox) = &self.outbox else {
+            return Ok(());
+        };
+        let node_id = self.node_id.lock().unwrap().clone();
+        let replicate = serde_json::to_value(&replicate)?;
+
+        for peer in self.peers.lock().unwrap().iter() {
+            outbox.send(Message {
+                src: node_id.clone(),
+                dest: peer.clone(),
+                body: body!("replicate", replicate),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Handles a `txn` message.
+    fn txn(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let ops: Vec<MicroOp> = msg.body.get_as("txn")?;
+
+        let completed = self.apply(ops)?;
+
+        Ok(reply!(msg, msg_id, "txn_ok", { "txn": completed }))
+    }
+
+    /// Handles the writes of a transaction from another node.
+    ///
+    /// Replication is fire and forget, the returned message is a `replicate_ok` the peer ignores.
+    fn replicate(&self, msg: Message, msg_id: u64) -> Result<Message> {
+        let replicate: Replicate =
+            serde_json::from_value(serde_json::Value::Object(msg.body.extra.clone()))?;
+
+        let mut store = self.store.lock().unwrap();
+        self.clock.observe(replicate.timestamp);
+        for (key, value) in replicate.writes {
+            self.write(&mut store, key, value, replicate.timestamp, &msg.src)?;
+        }
+
+        Ok(reply!(msg, msg_id, "replicate_ok", {}))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{mpsc, Arc},
+    };
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::error::NodeError;
+    use crate::message::{Message, TXN_CONFLICT};
+    use crate::node::Node;
+    use crate::storage::MemoryStorage;
+    use crate::txn::{handlers, Isolation, MicroOp, Txn};
+
+    fn init_msg(node_id: &str) -> Message {
+        serde_json::from_value(json!({
+            "src": "c0", "dest": node_id,
+            "body": { "type": "init", "msg_id": 1, "node_id": node_id, "node_ids": ["n1", "n2", "n3"] }
+        }))
+        .expect("invalid init json.")
+    }
+
+    fn txn_msg(node_id: &str, txn: serde_json::Value) -> Message {
+        serde_json::from_value(json!({
+            "src": "c1", "dest": node_id,
+            "body": { "type": "txn", "msg_id": 3, "txn": txn }
+        }))
+        .expect("invalid txn json.")
+    }
+
+    fn op(op: &str, key: u64, value: Option<u64>) -> MicroOp {
+        MicroOp(op.to_string(), key, value)
+    }
+
+    #[test]
+    fn reads_see_earlier_writes() -> Result<()> {
+        // Tests that reads within a transaction observe the writes before them.
+        let txn = Txn::new();
+
+        let completed = txn.apply(vec![
+            op("r", 1, None),
+            op("w", 1, Some(3)),
+            op("r", 1, None),
+        ])?;
+
+        assert_eq!(
+            completed,
+            vec![op("r", 1, None), op("w", 1, Some(3)), op("r", 1, Some(3))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_persist_across_transactions() -> Result<()> {
+        let txn = Txn::new();
+
+        txn.apply(vec![op("w", 2, Some(9))])?;
+        let completed = txn.apply(vec![op("r", 2, None)])?;
+
+        assert_eq!(completed, vec![op("r", 2, Some(9))]);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_micro_op_fails() {
+        let txn = Txn::new();
+
+        assert!(txn.apply(vec![op("x", 1, None)]).is_err());
+        assert!(
+            txn.apply(vec![op("w", 1, None)]).is_err(),
+            "write without value must fail"
+        );
+    }
+
+    #[test]
+    fn invalid_transaction_is_not_applied() -> Result<()> {
+        // Tests that no op of a transaction with an invalid op is applied.
+        let txn = Txn::new();
+
+        assert!(txn
+            .apply(vec![op("w", 1, Some(3)), op("x", 1, None)])
+            .is_err());
+        let completed = txn.apply(vec![op("r", 1, None)])?;
+
+        assert_eq!(completed, vec![op("r", 1, None)]);
+        Ok(())
+    }
+
+    #[test]
+    fn txn_reply_is_valid() -> Result<()> {
+        // Tests the txn_ok reply of the txn-rw-register workload.
+        let txn = Txn::new();
+        let node = Node::new(handlers(&txn))?;
+        node.handle(serde_json::from_value(json!({
+            "src": "c0", "dest": "n1",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"] }
+        }))?)?;
+
+        let reply = node.handle(serde_json::from_value(json!({
+            "src": "c1", "dest": "n1",
+            "body": { "type": "txn", "msg_id": 3, "txn": [["r", 1, null], ["w", 1, 6]] }
+        }))?)?;
+
+        let expected = serde_json::from_value::<Message>(json!({
+            "src": "n1", "dest": "c1",
+            "body": {
+                "type": "txn_ok", "msg_id": 1, "in_reply_to": 3,
+                "txn": [["r", 1, null], ["w", 1, 6]]
+            }
+        }))?;
+        assert_eq!(reply, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_are_replicated_to_peers() -> Result<()> {
+        // Tests that the writes of a transaction are sent to every other node.
+        let (tx, rx) = mpsc::channel();
+        let txn = Txn::replicated(tx, Isolation::ReadUncommitted);
+        let node = Node::with_init_handler(handlers(&txn), Box::new(|id, ids| txn.init(id, ids)))?;
+        node.handle(init_msg("n1"))?;
+
+        node.handle(txn_msg("n1", json!([["w", 1, 6], ["r", 2, null]])))?;
+
+        let sent: Vec<Message> = rx.try_iter().collect();
+        let dests: Vec<&str> = sent.iter().map(|m| m.dest.as_str()).collect();
+        assert_eq!(dests, vec!["n2", "n3"]);
+        assert!(sent
+            .iter()
+            .all(|m| m.src == "n1" && m.body.typ == "replicate"));
+        assert_eq!(sent[0].body.extra["writes"], json!([[1, 6]]));
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_transactions_are_not_replicated() -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let txn = Txn::replicated(tx, Isolation::ReadUncommitted);
+        txn.init("n1", &["n1".to_string(), "n2".to_string()]);
+
+        txn.apply(vec![op("r", 1, None)])?;
+
+        assert_eq!(rx.try_iter().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn replicas_converge_on_concurrent_writes() -> Result<()> {
+        // Tests that two nodes writing the same key concurrently agree on the final value
+        // after exchanging their writes, no matter the order.
+        let (tx1, rx1) = mpsc::channel();
+        let txn1 = Txn::replicated(tx1, Isolation::ReadUncommitted);
+        let node1 =
+            Node::with_init_handler(handlers(&txn1), Box::new(|id, ids| txn1.init(id, ids)))?;
+        node1.handle(init_msg("n1"))?;
+        let (tx2, rx2) = mpsc::channel();
+        let txn2 = Txn::replicated(tx2, Isolation::ReadUncommitted);
+        let node2 =
+            Node::with_init_handler(handlers(&txn2), Box::new(|id, ids| txn2.init(id, ids)))?;
+        node2.handle(init_msg("n2"))?;
+
+        node1.handle(txn_msg("n1", json!([["w", 1, 10]])))?;
+        node2.handle(txn_msg("n2", json!([["w", 1, 20]])))?;
+        for msg in rx1.try_iter().filter(|m| m.dest == "n2") {
+            node2.handle(msg)?;
+        }
+        for msg in rx2.try_iter().filter(|m| m.dest == "n1") {
+            node1.handle(msg)?;
+        }
+
+        let read1 = txn1.apply(vec![op("r", 1, None)])?;
+        let read2 = txn2.apply(vec![op("r", 1, None)])?;
+        assert_eq!(read1, read2);
+        assert_eq!(
+            read1,
+            vec![op("r", 1, Some(20))],
+            "n2 wins the timestamp tie"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn last_write_in_transaction_wins() -> Result<()> {
+        // Tests that writing a key twice in one transaction keeps, and replicates, the last write.
+        for isolation in [Isolation::ReadUncommitted, Isolation::ReadCommitted] {
+            let (tx, rx) = mpsc::channel();
+            let txn = Txn::replicated(tx, isolation);
+            txn.init("n1", &["n1".to_string(), "n2".to_string()]);
+
+            let completed = txn.apply(vec![
+                op("w", 1, Some(5)),
+                op("w", 1, Some(6)),
+                op("r", 1, None),
+            ])?;
+
+            assert_eq!(completed[2], op("r", 1, Some(6)), "{isolation:?}");
+            let sent: Vec<Message> = rx.try_iter().collect();
+            assert_eq!(
+                sent[0].body.extra["writes"],
+                json!([[1, 6]]),
+                "{isolation:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_committed_replicates_whole_write_set() -> Result<()> {
+        // Tests that a peer applies all writes of a transaction from a single message.
+        let (tx, rx) = mpsc::channel();
+        let txn1 = Txn::replicated(tx, Isolation::ReadCommitted);
+        txn1.init("n1", &["n1".to_string(), "n2".to_string()]);
+        let (tx, _rx) = mpsc::channel();
+        let txn2 = Txn::replicated(tx, Isolation::ReadCommitted);
+        let node2 =
+            Node::with_init_handler(handlers(&txn2), Box::new(|id, ids| txn2.init(id, ids)))?;
+        node2.handle(init_msg("n2"))?;
+
+        txn1.apply(vec![op("w", 1, Some(1)), op("w", 2, Some(2))])?;
+        let sent: Vec<Message> = rx.try_iter().collect();
+        assert_eq!(sent.len(), 1);
+        node2.handle(sent[0].clone())?;
+
+        let read = txn2.apply(vec![op("r", 1, None), op("r", 2, None)])?;
+        assert_eq!(read, vec![op("r", 1, Some(1)), op("r", 2, Some(2))]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_committed_reads_own_writes() -> Result<()> {
+        let txn = Txn::replicated(mpsc::channel().0, Isolation::ReadCommitted);
+        txn.init("n1", &["n1".to_string()]);
+
+        txn.apply(vec![op("w", 1, Some(1))])?;
+        let completed = txn.apply(vec![
+            op("r", 1, None),
+            op("w", 1, Some(2)),
+            op("r", 1, None),
+        ])?;
+
+        assert_eq!(
+            completed,
+            vec![
+                op("r", 1, Some(1)),
+                op("w", 1, Some(2)),
+                op("r", 1, Some(2))
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_isolation_aborts_later_committer() -> Result<()> {
+        // Tests that of two transactions writing the same key, the one committing second is
+        // aborted with a conflict, while one writing another key commits.
+        let txn = Txn::replicated(mpsc::channel().0, Isolation::Snapshot);
+        txn.init("n1", &["n1".to_string()]);
+        txn.apply(vec![op("w", 1, Some(1))])?;
+
+        let start = txn.begin_snapshot();
+        txn.apply(vec![op("r", 1, None), op("w", 1, Some(2))])?;
+        let conflict = txn.commit(start, &BTreeMap::from([(1, 3)]), "n1");
+        let other = txn.begin_snapshot();
+        txn.commit(other, &BTreeMap::from([(2, 3)]), "n1")?;
+
+        let code = conflict.map_err(|e| NodeError::from(e).code());
+        assert_eq!(code, Err(TXN_CONFLICT));
+        assert_eq!(
+            txn.apply(vec![op("r", 1, None), op("r", 2, None)])?,
+            vec![op("r", 1, Some(2)), op("r", 2, Some(3))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn registers_survive_restart_from_storage() -> Result<()> {
+        let storage = Arc::new(MemoryStorage::new());
+        let txn = Txn::new();
+        txn.restore_from(storage.clone())?;
+        txn.apply(vec![op("w", 1, Some(10)), op("w", 2, Some(20))])?;
+        txn.apply(vec![op("w", 1, Some(11))])?;
+
+        let restarted = Txn::new();
+        restarted.restore_from(storage.clone())?;
+        let read = restarted.apply(vec![op("r", 1, None), op("r", 2, None)])?;
+        assert_eq!(read, vec![op("r", 1, Some(11)), op("r", 2, Some(20))]);
+
+        // Writes after the restart come after the restored ones.
+        restarted.apply(vec![op("w", 1, Some(12))])?;
+        let again = Txn::new();
+        again.restore_from(storage)?;
+        assert_eq!(
+            again.apply(vec![op("r", 1, None)])?,
+            vec![op("r", 1, Some(12))]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::NodeError,
+    ids::FlakeIds,
+    kv::{Kv, KvError},
+    node::{typed_handler, Handler},
+};
+
+#[derive(Deserialize, Debug)]
+struct Generate {}
+
+#[derive(Serialize, Debug)]
+struct GenerateOk {
+    id: Value,
+}
+
+/// How unique IDs are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IdScheme {
+    // 128 bit flake IDs of the node, as 32 hex digits.
+    #[default]
+    Hex,
+    // Snowflake IDs as integers: milliseconds, index of the node and a counter in 63 bits.
+    Snowflake,
+    // Integers out of blocks of IDs every node leases from seq-kv, see `BlockIds`.
+    Blocks,
+}
+
+/// seq-kv key that holds the first ID not leased yet.
+const NEXT_BLOCK_KEY: &str = "unique-ids-next-block";
+
+/// Number of IDs a node leases at once by default.
+pub const BLOCK_SIZE: u64 = 10_000;
+
+/// Unique ID generation workload (unique-ids).
+///
+/// IDs are 128 bit [`FlakeIds`] of the node, written as 32 hex digits so they sort like the IDs
+/// do, or snowflake IDs with [`IdScheme::Snowflake`]. Nodes generate IDs without any
+/// coordination, so the workload stays available during partitions.
+#[derive(Debug, Default)]
+pub struct UniqueIds {
+    // Set on init, once the node ID is known.
+    ids: OnceLock<FlakeIds>,
+    scheme: IdScheme,
+}
+
+/// Returns the handlers of the unique-ids workload, backed by `unique_ids`.
+pub fn handlers(unique_ids: &UniqueIds) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert(
+        "generate".into(),
+        typed_handler("generate", |_, _: Generate| unique_ids.generate()),
+    );
+    funs
+}
+
+impl UniqueIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a workload that writes IDs as `scheme` says. Fails for [`IdScheme::Blocks`],
+    /// whose IDs are leased by [`BlockIds`] instead.
+    pub fn with_scheme(scheme: IdScheme) -> Result<Self> {
+        if scheme == IdScheme::Blocks {
+            return Err(anyhow!(
+                "FailedPrecondition: block IDs are leased by BlockIds, not UniqueIds"
+            ));
+        }
+        Ok(Self {
+            scheme,
+            ..Default::default()
+        })
+    }
+
+    /// Starts generating the IDs of `node_id`, meant to be used as the node's init handler.
+    pub fn init(&self, node_id: &str, node_ids: &[String]) {
+        let ids = match self.scheme {
+            IdScheme::Hex | IdScheme::Blocks => FlakeIds::new(node_id),
+            IdScheme::Snowflake => match node_ids.iter().position(|id| id == node_id) {
+                Some(index) => FlakeIds::with_node_index(index),
+                None => FlakeIds::new(node_id),
+            },
+        };
+        let _ = self.ids.set(ids);
+    }
+
+    fn generate(&self) -> Result<GenerateOk> {
+        let ids = self.ids.get().ok_or(NodeError::NotReady(
+            "cannot generate ids before init".into(),
+        ))?;
+        let id = match self.scheme {
+            IdScheme::Hex | IdScheme::Blocks => format!("{:032x}", ids.next_u128()).into(),
+            IdScheme::Snowflake => ids.next_snowflake().into(),
+        };
+        Ok(GenerateOk { id })
+    }
+}
+
+/// Unique ID generation workload (unique-ids), with IDs leased in blocks from a key value store.
+///
+/// The store has the first ID not leased yet under one key. A node leases the next block of IDs
+/// by raising it by the block size with a cas, retried when another node leased a block first,
+/// and then hands out the IDs of the block without talking to the store. IDs are small integers
+/// that go up, but a node cannot generate IDs once its block runs out while the store is
+/// unreachable, and the IDs left of a block are lost when the node crashes.
+#[derive(Debug)]
+pub struct BlockIds<K> {
+    kv: K,
+    block_size: u64,
+    // IDs of the block leased last not handed out yet. Held while leasing the next block.
+    block: Mutex<Range<u64>>,
+}
+
+/// Returns the handlers of the unique-ids workload, backed by `ids`.
+pub fn block_handlers<K: Kv + Sync>(ids: &BlockIds<K>) -> HashMap<String, Handler<'_>> {
+    let mut funs: HashMap<String, Handler> = HashMap::new();
+    funs.insert(
+        "generate".into(),
+        typed_handler("generate", |_, _: Generate| {
+            Ok(GenerateOk {
+                id: ids.next()?.into(),
+            })
+        }),
+    );
+    funs
+}
+
+impl<K: Kv> BlockIds<K> {
+    /// Creates IDs leased from `kv` in blocks of [`BLOCK_SIZE`].
+    pub fn new(kv: K) -> Self {
+        Self {
+            kv,
+            block_size: BLOCK_SIZE,
+            block: Mutex::new(0..0),
+        }
+    }
+
+    /// Leases blocks of `block_size` IDs instead.
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    /// Returns the next ID, leasing a new block first if the last one ran out.
+    pub fn next(&self) -> Result<u64> {
+        let mut block = self.block.lock().unwrap();
+        if block.is_empty() {
+            *block = self.lease()?;
+        }
+        let id = block.start;
+        block.start += 1;
+        Ok(id)
+    }
+
+    /// Leases the next block of IDs from the store.
+    fn lease(&self) -> Result<Range<u64>> {
+        loop {
+            let start = match self.kv.read::<u64>(NEXT_BLOCK_KEY) {
+                Ok(start) => start,
+                Err(KvError::KeyDoesNotExist(_)) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            let end = start + self.block_size;
+            match self.kv.cas(NEXT_BLOCK_KEY, &start, &end, true) {
+                Ok(()) => return Ok(start..end),
+                Err(KvError::PreconditionFailed(_)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, sync::Arc};
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::kv::MemoryKv;
+    use crate::node::Node;
+    use crate::unique_ids::{handlers, BlockIds, IdScheme, UniqueIds};
+
+    #[test]
+    fn generated_ids_are_unique() -> Result<()> {
+        // Tests that ids are unique within a node and across nodes.
+        let mut ids = HashSet::new();
+        for node_id in ["n1", "n2"] {
+            let unique_ids = UniqueIds::new();
+            let node = Node::builder()
+                .handlers(handlers(&unique_ids))
+                .on_init(|id, ids| unique_ids.init(id, ids))
+                .build()?;
+            node.handle(serde_json::from_value(json!({
+                "src": "c0", "dest": node_id,
+                "body": { "type": "init", "msg_id": 1, "node_id": node_id, "node_ids": ["n1", "n2"] }
+            }))?)?;
+
+            for i in 0..100 {
+                let reply = node.handle(serde_json::from_value(json!({
+                    "src": "c1", "dest": node_id,
+                    "body": { "type": "generate", "msg_id": i + 2 }
+                }))?)?;
+                assert_eq!(reply.body.typ, "generate_ok");
+                ids.insert(reply.body.extra["id"].clone());
+            }
+        }
+
+        assert_eq!(ids.len(), 200);
+        Ok(())
+    }
+
+    #[test]
+    fn snowflake_ids_are_integers() -> Result<()> {
+        let unique_ids = UniqueIds::with_scheme(IdScheme::Snowflake)?;
+        let node = Node::builder()
+            .handlers(handlers(&unique_ids))
+            .on_init(|id, ids| unique_ids.init(id, ids))
+            .build()?;
+        node.handle(serde_json::from_value(json!({
+            "src": "c0", "dest": "n2",
+            "body": { "type": "init", "msg_id": 1, "node_id": "n2", "node_ids": ["n1", "n2"] }
+        }))?)?;
+
+        let reply = node.handle(serde_json::from_value(json!({
+            "src": "c1", "dest": "n2",
+            "body": { "type": "generate", "msg_id": 2 }
+        }))?)?;
+
+        let id = reply.body.extra["id"].as_u64().expect("id is an integer");
+        assert_eq!((id >> 12) & 1023, 1, "node index");
+        assert!(UniqueIds::with_scheme(IdScheme::Blocks).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn block_ids_are_unique_across_nodes() -> Result<()> {
+        // Tests that nodes sharing a store lease different blocks, and lease a new one once
+        // theirs runs out.
+        let kv = Arc::new(MemoryKv::new());
+        let (n1, n2) = (
+            BlockIds::new(kv.clone()).with_block_size(3),
+            BlockIds::new(kv).with_block_size(3),
+        );
+
+        let ids: Vec<u64> = [&n1, &n2, &n1, &n1, &n1, &n2]
+            .into_iter()
+            .map(|ids| ids.next())
+            .collect::<Result<_>>()?;
+
+        assert_eq!(ids, vec![0, 3, 1, 2, 6, 4]);
+        Ok(())
+    }
+}
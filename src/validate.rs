@@ -0,0 +1,139 @@
+use std::{collections::HashSet, io};
+
+use crate::{error::NodeError, message::Message};
+
+/// Checks messages before they are dispatched, see
+/// [`Node::with_validator`](crate::node::Node::with_validator).
+///
+/// Every message must have a src and a dest, and requests from clients a msg_id. Limits on the
+/// size and type of messages are opt-in. A message that fails is rejected with a
+/// [`NodeError::Malformed`] error, which requests are answered with, before any of it is
+/// processed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validator {
+    // Most bytes a message may take serialized, if limited.
+    max_size: Option<usize>,
+    // Types requests may have, if limited. Replies are not limited, they answer requests sent
+    // by this node.
+    types: Option<HashSet<String>>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rejects messages that take more than `max_size` bytes serialized, if set.
+    pub fn with_max_size(mut self, max_size: Option<usize>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Rejects requests of any type but `types`, and init.
+    pub fn with_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.types = Some(types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Checks `msg`, returns why it is rejected if it is.
+    pub fn check(&self, msg: &Message) -> Result<(), NodeError> {
+        let malformed = |text: String| Err(NodeError::Malformed(text));
+        if msg.src.is_empty() || msg.dest.is_empty() {
+            return malformed(format!("message without src or dest: {msg:?}"));
+        }
+        let body = &msg.body;
+        let request = body.in_reply_to == 0;
+        if request && body.msg_id == 0 && msg.src.starts_with('c') {
+            return malformed(format!(
+                "{} request from {} without msg_id",
+                body.typ, msg.src
+            ));
+        }
+        if let Some(max_size) = self.max_size {
+            let size = serialized_size(msg);
+            if size > max_size {
+                return malformed(format!(
+                    "{} message from {} is {size} bytes, more than the limit of {max_size}",
+                    body.typ, msg.src
+                ));
+            }
+        }
+        match &self.types {
+            Some(types) if request && body.typ != "init" && !types.contains(&body.typ) => {
+                malformed(format!("requests of type {} are not accepted", body.typ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Number of bytes `msg` takes serialized, counted without keeping them.
+fn serialized_size(msg: &Message) -> usize {
+    struct Count(usize);
+
+    impl io::Write for Count {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut count = Count(0);
+    // Writing to a counter cannot fail, and a message always serializes.
+    let _ = serde_json::to_writer(&mut count, msg);
+    count.0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::{Body, Message};
+    use crate::validate::Validator;
+
+    fn msg(src: &str, typ: &str, msg_id: u64) -> Message {
+        Message {
+            src: src.into(),
+            dest: "n1".into(),
+            body: Body {
+                typ: typ.into(),
+                msg_id,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        let validator = Validator::new()
+            .with_max_size(Some(80))
+            .with_types(["echo"]);
+        let mut large = msg("c1", "echo", 1);
+        large
+            .body
+            .extra
+            .insert("echo".into(), "x".repeat(80).into());
+        let mut reply = msg("n2", "read_ok", 2);
+        reply.body.in_reply_to = 1;
+
+        assert!(validator.check(&msg("c1", "echo", 1)).is_ok());
+        assert!(validator.check(&msg("c1", "init", 1)).is_ok());
+        assert!(validator.check(&reply).is_ok());
+        assert!(validator.check(&msg("n2", "echo", 0)).is_ok());
+        for invalid in [
+            msg("", "echo", 1),
+            msg("c1", "echo", 0),
+            msg("c1", "read", 1),
+            large,
+        ] {
+            let e = validator.check(&invalid).unwrap_err();
+            assert_eq!(e.code(), 12, "{invalid:?}: {e}");
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::durability::{Durability, Syncer};
+
+/// Entries in a segment of a [`Wal`] before it moves on to the next one, by default.
+const SEGMENT_ENTRIES: usize = 10_000;
+
+/// Write-ahead log, entries appended to files in a directory, one JSON entry per line.
+///
+/// Entries go to the latest segment, `wal-<n>.log`, until it has the segment limit of entries
+/// and a new segment is started, so no single file grows forever and whole segments can be
+/// archived or dropped. An entry is in the log once [`Wal::append`] returns, so workloads
+/// append before acking, and it is synced to disk as [`Wal::with_durability`] says, by default
+/// never. [`Wal::replay`] reads every entry back in the order appended, a
+/// partial last entry, from a crash while appending, is dropped.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    segment_entries: usize,
+    // The segment appended to, its number and how many entries it has.
+    current: Mutex<Option<(File, u64, usize)>>,
+    syncer: Syncer,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, created if missing.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create wal dir {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            segment_entries: SEGMENT_ENTRIES,
+            current: Mutex::new(None),
+            syncer: Syncer::default(),
+        })
+    }
+
+    /// Syncs appended entries to disk as `durability` says.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.syncer = Syncer::new(durability);
+        self
+    }
+
+    /// Starts a new segment once the current one has `entries` entries.
+    pub fn with_segment_entries(mut self, entries: usize) -> Self {
+        self.segment_entries = entries.max(1);
+        self
+    }
+
+    /// Numbers of the segments in the log, in order.
+    pub fn segments(&self) -> Result<Vec<u64>> {
+        let mut segments: Vec<u64> = fs::read_dir(&self.dir)
+            .with_context(|| format!("cannot list {}", self.dir.display()))?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_prefix("wal-")?
+                    .strip_suffix(".log")?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+        segments.sort();
+        Ok(segments)
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("wal-{segment:08}.log"))
+    }
+
+    /// Every entry in the log, in the order appended. Appends go on after the last one.
+    pub fn replay(&self) -> Result<Vec<Value>> {
+        let mut current = self.current.lock().unwrap();
+        let mut entries = Vec::new();
+        let segments = self.segments()?;
+        let mut last = None;
+        for &segment in &segments {
+            let (segment_entries, valid) = read_segment(&self.segment_path(segment))?;
+            last = Some((segment, segment_entries.len(), valid));
+            entries.extend(segment_entries);
+        }
+        if let Some((segment, count, valid)) = last {
+            let path = self.segment_path(segment);
+            let file = OpenOptions::new().append(true).open(&path)?;
+            if file.metadata()?.len() > valid {
+                warn!(path = %path.display(), "dropping partial last wal entry");
+                file.set_len(valid)?;
+            }
+            *current = Some((file, segment, count));
+            info!(
+                dir = %self.dir.display(),
+                segments = segments.len(),
+                entries = entries.len(),
+                "replayed wal"
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Appends `entry` to the log, starting a new segment if the current one is full.
+    pub fn append(&self, entry: &Value) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut current = self.current.lock().unwrap();
+        let full = current
+            .as_ref()
+            .is_none_or(|&(_, _, count)| count >= self.segment_entries);
+        if full {
+            let next = match &*current {
+                Some((_, segment, _)) => segment + 1,
+                None => self.segments()?.last().map_or(1, |last| last + 1),
+            };
+            let path = self.segment_path(next);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("cannot create {}", path.display()))?;
+            *current = Some((file, next, 0));
+        }
+        let (file, segment, count) = current.as_mut().expect("a segment is open");
+        file.write_all(&line)
+            .with_context(|| format!("cannot append to wal segment {segment}"))?;
+        *count += 1;
+        self.syncer.written(file)
+    }
+}
+
+/// The entries of the segment at `path`, and the length of the segment up to the end of the
+/// last whole entry.
+fn read_segment(path: &Path) -> Result<(Vec<Value>, u64)> {
+    let file = File::open(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let (mut entries, mut valid) = (Vec::new(), 0);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let Some(json) = line.strip_suffix('\n') else {
+            break;
+        };
+        entries.push(
+            serde_json::from_str(json)
+                .with_context(|| format!("corrupt entry in {}: {json}", path.display()))?,
+        );
+        valid += line.len() as u64;
+        line.clear();
+    }
+    Ok((entries, valid))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Write, path::PathBuf};
+
+    use anyhow::Result;
+    use serde_json::{json, Value};
+
+    use crate::wal::Wal;
+
+    fn wal_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("maelstrom-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn entries_are_replayed_across_segments() -> Result<()> {
+        let dir = wal_dir("wal-replay");
+        let wal = Wal::open(&dir)?.with_segment_entries(2);
+        assert!(wal.replay()?.is_empty());
+        for i in 0..5 {
+            wal.append(&json!({ "i": i }))?;
+        }
+        assert_eq!(wal.segments()?, vec![1, 2, 3]);
+        drop(wal);
+        // A crash in the middle of an append.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("wal-00000003.log"))?
+            .write_all(br#"{"i":"#)?;
+
+        let wal = Wal::open(&dir)?.with_segment_entries(2);
+        let replayed: Vec<Value> = wal.replay()?;
+        assert_eq!(
+            replayed,
+            (0..5).map(|i| json!({ "i": i })).collect::<Vec<_>>()
+        );
+        wal.append(&json!({ "i": 5 }))?;
+        wal.append(&json!({ "i": 6 }))?;
+        assert_eq!(wal.segments()?, vec![1, 2, 3, 4]);
+        let replayed = Wal::open(&dir)?.replay()?;
+        assert_eq!(replayed.len(), 7);
+        assert_eq!(replayed[6], json!({ "i": 6 }));
+        Ok(())
+    }
+}
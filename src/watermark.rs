@@ -0,0 +1,86 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::message::{Body, Message};
+
+/// Field of a message between nodes with the low watermark of its sender for its dest: every
+/// request the sender sent the dest through its outbox with a lower msg_id was acked, so it is
+/// never sent again.
+pub const WATERMARK_FIELD: &str = "low_watermark";
+
+/// The low watermarks peers announced to this node, see [`WATERMARK_FIELD`], so the bookkeeping
+/// kept about their requests in case they are sent again, e.g. the replies remembered by
+/// [`Node::with_dedup`](crate::node::Node::with_dedup), can be dropped once they are below it.
+///
+/// Shared between threads, every method can be called concurrently.
+#[derive(Debug, Default)]
+pub struct Watermarks {
+    low: Mutex<HashMap<String, u64>>,
+}
+
+impl Watermarks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes in the watermark `msg` carries, if any. Returns it if it is past the watermark of
+    /// its sender so far, i.e. there is bookkeeping that can be dropped now.
+    pub fn observe(&self, msg: &Message) -> Option<u64> {
+        let watermark = of(&msg.body)?;
+        let mut low = self.low.lock().unwrap();
+        let low = low.entry(msg.src.clone()).or_default();
+        if watermark <= *low {
+            return None;
+        }
+        *low = watermark;
+        Some(watermark)
+    }
+
+    /// The latest watermark `peer` announced, 0 if none.
+    pub fn low(&self, peer: &str) -> u64 {
+        self.low
+            .lock()
+            .unwrap()
+            .get(peer)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// The watermark in `body`, if any.
+pub fn of(body: &Body) -> Option<u64> {
+    body.extra.get(WATERMARK_FIELD)?.as_u64()
+}
+
+/// Puts `watermark` in `body`.
+pub fn stamp(body: &mut Body, watermark: u64) {
+    body.extra.insert(WATERMARK_FIELD.into(), watermark.into());
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::Message;
+    use crate::watermark::{stamp, Watermarks};
+
+    fn from(src: &str, watermark: Option<u64>) -> Message {
+        let mut msg = Message {
+            src: src.into(),
+            ..Default::default()
+        };
+        if let Some(watermark) = watermark {
+            stamp(&mut msg.body, watermark);
+        }
+        msg
+    }
+
+    #[test]
+    fn watermarks_only_advance() {
+        let watermarks = Watermarks::new();
+
+        assert_eq!(watermarks.observe(&from("n2", Some(5))), Some(5));
+        assert_eq!(watermarks.observe(&from("n2", Some(3))), None, "reordered");
+        assert_eq!(watermarks.observe(&from("n2", None)), None);
+        assert_eq!(watermarks.observe(&from("n3", Some(1))), Some(1));
+
+        assert_eq!((watermarks.low("n2"), watermarks.low("n4")), (5, 0));
+    }
+}
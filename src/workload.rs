@@ -0,0 +1,570 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use tracing::{info, warn};
+
+use crate::{
+    broadcast::{self, Broadcast},
+    config::Config,
+    echo::Echo,
+    failure_detector::{PhiAccrualDetector, PhiConfig},
+    frag::Fragments,
+    g_counter::{self, Counter},
+    g_set::{self, Set},
+    heartbeat::{HeartbeatConfig, Heartbeats},
+    kafka::{self, Kafka, KafkaMode, Retention},
+    kv::KvClient,
+    lin_kv::{self, LinKv},
+    list_append::{self, ListAppend},
+    message::{Message, MsgIds},
+    metrics::Metrics,
+    node::{Node, PoolConfig},
+    outbox::Outbox,
+    peers::Peers,
+    persistence::{persisted, persisted_init, Persistence},
+    rate_limit::TokenBucket,
+    replay::{self, SharedOutput},
+    rpc::RpcClient,
+    rtt::RttEstimator,
+    runtime,
+    sequencer::{self, TotalOrder},
+    storage::FileStorage,
+    transport::StdioTransport,
+    two_phase::{self, TwoPhase},
+    txn::{self, Txn},
+    unique_ids::{self, BlockIds, IdScheme, UniqueIds},
+    validate::Validator,
+    wal::Wal,
+};
+
+/// What every workload's node shares with the rest of the process.
+type NodeParts = (
+    Arc<MsgIds>,
+    Arc<Outbox>,
+    Option<Arc<Heartbeats>>,
+    Arc<Peers>,
+    Receiver<Message>,
+    Config,
+);
+
+/// The workloads a node can run, named like Maelstrom names them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Workload {
+    Echo,
+    UniqueIds,
+    Broadcast,
+    GCounter,
+    GSet,
+    Kafka,
+    Txn,
+    TxnListAppend,
+    // Served by every node on its own, linearizable when the cluster is a single node.
+    LinKv,
+}
+
+/// Options of a node, whatever its workload.
+#[derive(Debug, Clone, Default, Parser)]
+pub struct Options {
+    /// Directory to save the state of the node to, and restore it from after a crash. State is
+    /// not saved if unset.
+    #[arg(long)]
+    pub state_dir: Option<PathBuf>,
+    /// Send heartbeats to peers, and hold off resending messages to peers that stop answering
+    /// them until they are heard from again.
+    #[arg(long)]
+    pub heartbeats: bool,
+    /// Suspect peers with a phi accrual failure detector, once their phi goes over this, instead
+    /// of after a fixed timeout. Only used with heartbeats.
+    #[arg(long)]
+    pub phi_threshold: Option<f64>,
+    /// Deliver broadcast values in the same order on every node, as sequenced by the node with
+    /// the lowest ID.
+    #[arg(long)]
+    pub total_order: bool,
+    /// Directory to record every line read from stdin and written to stdout to, to replay the
+    /// run later. Nothing is recorded if unset.
+    #[arg(long)]
+    pub record_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub config: Config,
+}
+
+/// Runs a node with the handlers of `workload` on stdin and stdout, until stdin is closed.
+pub fn run(workload: Workload, options: Options) -> Result<()> {
+    let (stdin, stdout) = (BufReader::new(io::stdin()), LineWriter::new(io::stdout()));
+    match &options.record_dir {
+        Some(dir) => {
+            let (reader, writer) = replay::record(dir, stdin, stdout)?;
+            run_on(workload, options, reader, writer)
+        }
+        None => run_on(workload, options, stdin, stdout),
+    }
+}
+
+/// Feeds the messages recorded in the `input` file back through a node with the handlers of
+/// `workload`, returns the differences between its replies and the ones recorded in the
+/// `output` file, see [`replay::diff`].
+pub fn replay(
+    workload: Workload,
+    options: Options,
+    input: &Path,
+    output: &Path,
+) -> Result<Vec<String>> {
+    let options = Options {
+        record_dir: None,
+        ..options
+    };
+    let replayed = SharedOutput::default();
+    run_on(
+        workload,
+        options,
+        BufReader::new(File::open(input)?),
+        replayed.clone(),
+    )?;
+    Ok(replay::diff(
+        &fs::read_to_string(output)?,
+        &String::from_utf8_lossy(&replayed.contents()),
+    ))
+}
+
+/// Runs a node with the handlers of `workload`, reading messages from `reader` and writing
+/// messages to `writer` one per line, until the end of `reader`.
+fn run_on<R, W>(workload: Workload, options: Options, reader: R, writer: W) -> Result<()>
+where
+    R: BufRead + Send + 'static,
+    W: Write + Send,
+{
+    info!(?workload, ?options, "node starting");
+    let config = &options.config;
+    let transport = StdioTransport::from_io(reader, writer);
+
+    // Messages that workloads send on their own, besides replies.
+    let (sender, outgoing) = mpsc::channel();
+    // Maelstrom may restart the node, its ids start at the time it starts so they are not reused.
+    let msg_ids = Arc::new(MsgIds::epoch_from_clock());
+    let heartbeats = options.heartbeats.then(|| {
+        let heartbeat_config = HeartbeatConfig {
+            interval: config.heartbeat_interval,
+            timeout: config.heartbeat_timeout,
+            missed_limit: config.heartbeat_missed_limit,
+        };
+        let mut heartbeats = Heartbeats::new(sender.clone(), heartbeat_config);
+        if let Some(threshold) = options.phi_threshold {
+            heartbeats = heartbeats.with_detector(PhiAccrualDetector::new(PhiConfig {
+                threshold,
+                ..Default::default()
+            }));
+        }
+        let heartbeats = Arc::new(heartbeats);
+        heartbeats.tick_every_interval();
+        heartbeats
+    });
+    let mut outbox = Outbox::new(sender.clone(), msg_ids.clone(), config.retry_interval);
+    if let Some(heartbeats) = &heartbeats {
+        outbox = outbox.with_heartbeats(heartbeats.clone());
+    }
+    if config.watermarks {
+        outbox = outbox.with_watermarks();
+    }
+    if let Some(rate) = config.send_rate {
+        outbox = outbox.with_rate_limit(TokenBucket::per_second(rate));
+    }
+    let mut rpc = RpcClient::new(sender.clone(), msg_ids.clone()).with_timeout(config.rpc_timeout);
+    let mut peers = Peers::new();
+    if config.adaptive_retries {
+        // Replies to RPCs and to the outbox's messages both measure the round trip times.
+        let rtt = Arc::new(RttEstimator::new());
+        outbox = outbox.with_rtt(rtt.clone());
+        rpc = rpc.with_rtt(rtt.clone());
+        peers = peers.with_rtt(rtt);
+    }
+    let peers = Arc::new(peers);
+    let outbox = Arc::new(outbox.with_peers(peers.clone()));
+    outbox.tick_every(config.retry_interval);
+    let rpc = Arc::new(rpc);
+    let parts = (
+        msg_ids,
+        outbox.clone(),
+        heartbeats,
+        peers,
+        outgoing,
+        config.clone(),
+    );
+
+    match workload {
+        Workload::Echo => runtime::run(&mut Echo, transport, config.gossip_interval),
+        Workload::UniqueIds if config.unique_id_scheme == IdScheme::Blocks => {
+            let ids = BlockIds::new(KvClient::seq_kv(rpc.clone()));
+            let node = Node::builder()
+                .handlers(unique_ids::block_handlers(&ids))
+                .build()?
+                .with_rpc(rpc);
+            run_node(node, parts, transport)
+        }
+        Workload::UniqueIds => {
+            let unique_ids = UniqueIds::with_scheme(config.unique_id_scheme)?;
+            let node = Node::builder()
+                .handlers(unique_ids::handlers(&unique_ids))
+                .on_init(|id, ids| unique_ids.init(id, ids))
+                .build()?;
+            run_node(node, parts, transport)
+        }
+        Workload::Broadcast if options.total_order => {
+            let order = TotalOrder::new(outbox.clone());
+            let node = Node::builder()
+                .handlers(sequencer::handlers(&order))
+                .on_init(|id, ids| order.init(id, ids))
+                .build()?;
+            run_node(node, parts, transport)
+        }
+        Workload::Broadcast => {
+            let broadcast = Arc::new(configured_broadcast(outbox.clone(), config));
+            broadcast.gossip_every(config.gossip_interval);
+            broadcast.anti_entropy_every(config.anti_entropy_interval);
+            let persistence = options
+                .state_dir
+                .map(|dir| Persistence::new(dir, &*broadcast).with_durability(config.durability));
+            let node = Node::builder()
+                .handlers(persisted(
+                    persistence.as_ref(),
+                    broadcast::handlers(&broadcast),
+                ))
+                // Broadcasting a value again is the same operation, whoever sends it.
+                .idempotent("broadcast", |msg| {
+                    msg.body.get_u64("message").ok().map(|m| m.to_string())
+                })
+                .on_init(persisted_init(persistence.as_ref(), |id, ids| {
+                    broadcast.init(id, ids)
+                }))
+                .state(&*broadcast)
+                .build()?;
+            run_node(node, parts, transport)
+        }
+        Workload::GCounter => {
+            let counter = Arc::new(Counter::new(outbox.clone()));
+            counter.sync_every(config.gossip_interval);
+            let persistence = options
+                .state_dir
+                .map(|dir| Persistence::new(dir, &*counter).with_durability(config.durability));
+            let node = Node::builder()
+                .handlers(persisted(
+                    persistence.as_ref(),
+                    g_counter::handlers(&counter),
+                ))
+                .on_init(persisted_init(persistence.as_ref(), |id, ids| {
+                    counter.init(id, ids)
+                }))
+                .state(&*counter)
+                .build()?;
+            run_node(node, parts, transport)
+        }
+        Workload::GSet => {
+            let set = Arc::new(Set::new(outbox.clone()));
+            set.sync_every(config.gossip_interval);
+            let persistence = options
+                .state_dir
+                .map(|dir| Persistence::new(dir, &*set).with_durability(config.durability));
+            let node = Node::builder()
+                .handlers(persisted(persistence.as_ref(), g_set::handlers(&set)))
+                .on_init(persisted_init(persistence.as_ref(), |id, ids| {
+                    set.init(id, ids)
+                }))
+                .state(&*set)
+                .build()?;
+            run_node(node, parts, transport)
+        }
+        Workload::Kafka => {
+            let kafka = match config.kafka_mode {
+                KafkaMode::Leases => Kafka::with_leases(config.kafka_lease),
+                KafkaMode::Owners => Kafka::with_owners(),
+            }
+            .with_poll_limit(config.kafka_poll_limit)
+            .with_batched_appends(sender)
+            .with_retention(Retention {
+                committed: config.kafka_drop_committed,
+                max_entries: config.kafka_max_log_entries,
+            });
+            // The write-ahead log replaces snapshots, which would replay the logs twice.
+            let wal_dir = options
+                .state_dir
+                .clone()
+                .filter(|_| config.kafka_wal && config.kafka_mode == KafkaMode::Owners);
+            let persistence = match wal_dir {
+                Some(_) => None,
+                None => options
+                    .state_dir
+                    .map(|dir| Persistence::new(dir, &kafka).with_durability(config.durability)),
+            };
+            let node = Node::builder()
+                .handlers(persisted(persistence.as_ref(), kafka::handlers(&kafka)))
+                .on_init(persisted_init(persistence.as_ref(), |id, ids| {
+                    kafka.init(id, ids);
+                    if let Some(dir) = &wal_dir {
+                        let wal = Wal::open(dir.join(format!("{id}-wal")))
+                            .map(|wal| wal.with_durability(config.durability));
+                        if let Err(e) = wal.and_then(|wal| kafka.recover_from(wal)) {
+                            warn!("cannot replay kafka wal: {e:#}");
+                        }
+                    }
+                }))
+                .state(&kafka)
+                .build()?;
+            run_node(node, parts, transport)
+        }
+        Workload::Txn if config.txn_two_phase => {
+            let shard = Arc::new(TwoPhase::new(rpc.clone()));
+            shard.recover_every(config.rpc_timeout);
+            let node = Node::builder()
+                .handlers(two_phase::handlers(&shard))
+                .on_init(|id, ids| shard.init(id, ids))
+                .build()?
+                .with_rpc(rpc);
+            run_node(node, parts, transport)
+        }
+        Workload::Txn => {
+            let txn = Txn::replicated(sender, config.txn_isolation);
+            let node = Node::builder()
+                .handlers(txn::handlers(&txn))
+                .on_init(|id, ids| {
+                    txn.init(id, ids);
+                    if let Some(dir) = &options.state_dir {
+                        let storage = FileStorage::open(dir.join(format!("{id}.kv")))
+                            .map(|storage| storage.with_durability(config.durability));
+                        if let Err(e) = storage.and_then(|storage| txn.restore_from(storage)) {
+                            warn!("cannot restore stored registers: {e:#}");
+                        }
+                    }
+                })
+                .build()?;
+            run_node(node, parts, transport)
+        }
+        Workload::TxnListAppend => {
+            let store = ListAppend::new(KvClient::lin_kv(rpc.clone()));
+            let node = Node::builder()
+                .handlers(list_append::handlers(&store))
+                .on_init(|id, ids| store.init(id, ids))
+                .build()?
+                .with_rpc(rpc);
+            run_node(node, parts, transport)
+        }
+        Workload::LinKv => {
+            let kv = LinKv::new();
+            let node = Node::builder().handlers(lin_kv::handlers(&kv)).build()?;
+            run_node(node, parts, transport)
+        }
+    }
+}
+
+/// A broadcast store that gossips through `outbox` as `config` says.
+fn configured_broadcast(outbox: Arc<Outbox>, config: &Config) -> Broadcast {
+    let mut broadcast = Broadcast::new(outbox)
+        .with_fanout(config.gossip_fanout)
+        .with_mode(config.gossip_mode);
+    if config.gossip_batch_acks {
+        broadcast = broadcast.with_batched_acks();
+    }
+    if config.compress_payloads {
+        broadcast = broadcast.with_compression();
+    }
+    broadcast
+}
+
+/// Runs `node` on `transport` until its input ends, sending what workloads send to `outgoing`
+/// and routing acks to `outbox`.
+fn run_node<R, W>(
+    node: Node,
+    (msg_ids, outbox, heartbeats, peers, outgoing, config): NodeParts,
+    transport: StdioTransport<R, W>,
+) -> Result<()>
+where
+    R: BufRead + Send,
+    W: Write + Send,
+{
+    let mut node = node
+        .with_msg_ids(msg_ids)
+        .with_outbox(outbox)
+        .with_metrics(Arc::new(Metrics::new().with_peers(peers.clone())))
+        .with_peers(peers)
+        .with_outgoing(outgoing)
+        .with_slow_handler_warning(config.slow_handler)
+        .with_validator(Validator::new().with_max_size(config.max_message_size));
+    if let Some(heartbeats) = heartbeats {
+        node = node.with_heartbeats(heartbeats);
+    }
+    if config.snapshot_on_start {
+        node = node.with_snapshot_on_start();
+    }
+    if config.compress_payloads {
+        node = node.with_compression();
+    }
+    if let Some(capacity) = config.dedup_capacity {
+        node = node.with_dedup(capacity);
+    }
+    if config.watermarks {
+        node = node.with_watermarks();
+    }
+    if let Some(capacity) = config.session_replies {
+        node = node.with_sessions(capacity);
+    }
+    if let Some(size) = config.fragment_size {
+        node = node
+            .with_fragments(Fragments::new(size).with_max_message_size(config.max_message_size));
+    }
+    // Handlers may wait on RPCs, whose replies are routed by other workers, so there are a few
+    // workers even on a single core.
+    let pool = PoolConfig {
+        workers: config
+            .workers
+            .unwrap_or(PoolConfig::default().workers.max(4)),
+        capacity: config.queue_capacity,
+        limits: config.handler_limits.iter().cloned().collect(),
+        ..Default::default()
+    };
+    node.run_pool(transport, pool)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        sync::{mpsc, Arc},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use clap::ValueEnum;
+
+    use crate::broadcast::GossipMode;
+    use crate::config::Config;
+    use crate::message::MsgIds;
+    use crate::outbox::Outbox;
+    use crate::workload::{configured_broadcast, replay, Options, Workload};
+
+    #[test]
+    fn workloads_have_maelstrom_names() {
+        let names: Vec<String> = Workload::value_variants()
+            .iter()
+            .filter_map(|w| w.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect();
+
+        assert_eq!(
+            names,
+            vec![
+                "echo",
+                "unique-ids",
+                "broadcast",
+                "g-counter",
+                "g-set",
+                "kafka",
+                "txn",
+                "txn-list-append",
+                "lin-kv"
+            ]
+        );
+    }
+
+    #[test]
+    fn broadcast_gossips_in_configured_mode() {
+        let (tx, _rx) = mpsc::channel();
+        let outbox = Arc::new(Outbox::new(
+            tx,
+            Arc::new(MsgIds::new()),
+            Duration::from_secs(1),
+        ));
+        let config = Config {
+            gossip_mode: GossipMode::Epidemic,
+            ..Config::default()
+        };
+
+        let default = configured_broadcast(outbox.clone(), &Config::default());
+        let epidemic = configured_broadcast(outbox, &config);
+
+        assert_eq!(default.mode(), GossipMode::Neighbors);
+        assert_eq!(epidemic.mode(), GossipMode::Epidemic);
+    }
+
+    #[test]
+    fn replay_diffs_replies() -> Result<()> {
+        // Tests that replaying a recorded echo run matches the recorded replies, except for
+        // the one edited after recording.
+        let dir = std::env::temp_dir().join(format!("maelstrom-replay-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let (input, output) = (dir.join("run.in.jsonl"), dir.join("run.out.jsonl"));
+        fs::write(
+            &input,
+            [
+                r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"a"}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"b"}}"#,
+            ]
+            .join("\n"),
+        )?;
+        fs::write(
+            &output,
+            [
+                r#"{"src":"n1","dest":"c0","body":{"type":"init_ok","in_reply_to":1}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":9,"in_reply_to":2,"echo":"a"}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":9,"in_reply_to":3,"echo":"edited"}}"#,
+            ]
+            .join("\n"),
+        )?;
+
+        let differences = replay(Workload::Echo, Options::default(), &input, &output)?;
+
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(differences.len(), 1, "{differences:?}");
+        assert!(differences[0].contains("edited"), "{differences:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn lin_kv_workload_serves_reads_and_writes() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("maelstrom-lin-kv-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let (input, output) = (dir.join("run.in.jsonl"), dir.join("run.out.jsonl"));
+        fs::write(
+            &input,
+            [
+                r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":2,"key":0,"value":5}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":3,"key":0,"from":5,"to":6}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4,"key":0}}"#,
+            ]
+            .join("\n"),
+        )?;
+        fs::write(
+            &output,
+            [
+                r#"{"src":"n1","dest":"c0","body":{"type":"init_ok","in_reply_to":1}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"write_ok","in_reply_to":2}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"cas_ok","in_reply_to":3}}"#,
+                r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":4,"value":6}}"#,
+            ]
+            .join("\n"),
+        )?;
+
+        // One worker, so the write, cas and read are handled in order.
+        let options = Options {
+            config: Config {
+                workers: Some(1),
+                ..Config::default()
+            },
+            ..Options::default()
+        };
+        let differences = replay(Workload::LinKv, options, &input, &output)?;
+
+        fs::remove_dir_all(&dir)?;
+        assert!(differences.is_empty(), "{differences:?}");
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    io::{self, LineWriter, Stdout, Write},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::message::Message;
+
+/// Writes messages as one JSON object per line, shared by every thread that sends messages.
+///
+/// Each message is serialized before taking the lock and written with a single `write_all`, so
+/// lines from concurrent senders never interleave. The lock is on the writer rather than a held
+/// `StdoutLock`, which cannot be sent between threads.
+#[derive(Debug)]
+pub struct Writer<W = LineWriter<Stdout>> {
+    out: Mutex<W>,
+}
+
+impl Writer {
+    /// Creates a writer to a line buffered stdout.
+    pub fn stdout() -> Self {
+        Self::new(LineWriter::new(io::stdout()))
+    }
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Writes `msg` followed by a newline and flushes it.
+    pub fn write(&self, msg: &Message) -> Result<()> {
+        let mut line = serde_json::to_vec(msg)?;
+        line.push(b'\n');
+
+        let mut out = self
+            .out
+            .lock()
+            .map_err(|_| anyhow!("Internal: writer poisoned by a panic while writing"))?;
+        out.write_all(&line)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> Result<W> {
+        self.out
+            .into_inner()
+            .map_err(|_| anyhow!("Internal: writer poisoned by a panic while writing"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::message::Message;
+    use crate::writer::Writer;
+
+    fn msg(i: u64) -> Message {
+        serde_json::from_value(json!({
+            "src": "n1", "dest": "c1", "body": { "type": "echo_ok", "msg_id": i, "echo": "x".repeat(100) }
+        }))
+        .expect("invalid message json.")
+    }
+
+    #[test]
+    fn concurrent_writes_stay_on_their_own_line() -> Result<()> {
+        let writer = Writer::new(vec![]);
+
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let writer = &writer;
+                s.spawn(move || {
+                    for i in 0..50 {
+                        writer.write(&msg(t * 100 + i)).expect("write failed");
+                    }
+                });
+            }
+        });
+
+        let output = String::from_utf8(writer.into_inner()?)?;
+        let mut ids: Vec<u64> = output
+            .lines()
+            .map(|l| serde_json::from_str::<Message>(l).map(|m| m.body.msg_id))
+            .collect::<Result<_, _>>()?;
+        ids.sort();
+        let expected: Vec<u64> = (0..4)
+            .flat_map(|t| (0..50).map(move |i| t * 100 + i))
+            .collect();
+        assert_eq!(ids, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn write_errors_are_returned() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let result = Writer::new(Closed).write(&msg(1));
+
+        assert!(result.is_err(), "expected broken pipe, got {:?}", result);
+    }
+}